@@ -0,0 +1,5 @@
+;; Run with lustc --test to see one assertion pass and one fail.
+(let square (fn (x) (mul x x)))
+(assert-equal 9 (square 3))
+(assert-equal (quote (1 4)) (list (square 1) (square 3)))
+(quote done)
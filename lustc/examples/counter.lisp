@@ -0,0 +1,23 @@
+;; Two closures that share one counter. The counter is escaped so both
+;; closures need to see the same heap allocated box rather than their
+;; own copies of it.
+(let make-counter (fn ()
+		      (let count 0)
+		      (cons (fn () (set count (add1 count)))
+			    (fn () count))))
+
+(let a (make-counter))
+(let b (make-counter))
+
+(let a-inc (car a))
+(let a-get (cdr a))
+(let b-inc (car b))
+(let b-get (cdr b))
+
+;; The counter is modified after both closures were created.
+(a-inc)
+(a-inc)
+(b-inc)
+(a-inc)
+
+(cons (a-get) (b-get))
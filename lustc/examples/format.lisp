@@ -15,9 +15,6 @@
 	    (last args)))
 
 
-(let and (fn (a b)
-	      (if a (if b #t #f) #f)))
-
 (let len (fn (list)
 	     (if (eq list ())
 		 0
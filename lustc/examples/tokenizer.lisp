@@ -18,9 +18,6 @@
 (let do (fn (& args)
 	    (last args)))
 
-(let and (fn (a b)
-	     (if a (if b #t #f) #f)))
-
 (let list (fn (& args) args))
 
 (let list-starts-with (fn (list pred)
@@ -0,0 +1,218 @@
+//! Checks that calls to functions whose definitions are known at
+//! compile time pass the right number of arguments. A function's
+//! definition is known if it is bound with let and never reassigned
+//! with set. Calls to primitives are checked too so that every one
+//! with the wrong number of arguments can be reported at once
+//! instead of only the first that is emitted.
+
+use std::collections::HashMap;
+
+use crate::procedures::is_varadic_param;
+use crate::renamer::original_name;
+use crate::Expr;
+use crate::PreorderStatus;
+
+/// The number of arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Arity {
+    /// The number of non-varadic params.
+    required: usize,
+    /// Rather or not the function takes additional varadic arguments.
+    varadic: bool,
+}
+
+impl Arity {
+    fn accepts(&self, count: usize) -> bool {
+        if self.varadic {
+            count >= self.required
+        } else {
+            count == self.required
+        }
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.varadic {
+            write!(f, "at least {}", self.required)
+        } else {
+            write!(f, "{}", self.required)
+        }
+    }
+}
+
+/// Returns the arity of a function with the parameters PARAMS, or
+/// None if they aren't a valid signature. A varadic signature needs
+/// the & right before its last parameter. Compiling reports the ones
+/// that don't so they are left for it.
+fn signature_arity(params: &[&String]) -> Option<Arity> {
+    match params.iter().position(|p| is_varadic_param(p)) {
+        None => Some(Arity {
+            required: params.len(),
+            varadic: false,
+        }),
+        Some(i) if params.len() >= 2 && i == params.len() - 2 => Some(Arity {
+            required: i,
+            varadic: true,
+        }),
+        Some(_) => None,
+    }
+}
+
+/// Collects the arity of every function bound with let in the
+/// program. Variables that are assigned to with set are left out as
+/// their value can not be known.
+fn collect_arities(program: &[Expr]) -> HashMap<String, Arity> {
+    let mut arities = HashMap::new();
+    let mut assigned = Vec::new();
+
+    for e in program {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if let Some((name, binding)) = e.is_let() {
+                if let Some((params, _)) = binding.is_fndef() {
+                    if let Some(arity) = signature_arity(&params) {
+                        arities.insert(name.clone(), arity);
+                    }
+                }
+            } else if let Some((name, _)) = e.is_set() {
+                assigned.push(name.clone());
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    for name in assigned {
+        arities.remove(&name);
+    }
+    arities
+}
+
+/// Checks the number of arguments passed in every call to a function
+/// with a known definition. Needs to run after renaming so that each
+/// name refers to exactly one binding. As every binding is collected
+/// before any calls are checked calls that appear before a function's
+/// definition, like recursive calls, are checked as well.
+pub(crate) fn check_arities(program: &[Expr]) -> Result<(), String> {
+    let _t = crate::timer::timeit("arity checking pass");
+    match arity_errors(program).into_iter().next() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Returns every call in PROGRAM that `check_arities` would reject
+/// along with the index of the top level form it is in, in the order
+/// that they appear.
+pub(crate) fn arity_errors(program: &[Expr]) -> Vec<(usize, String)> {
+    let arities = collect_arities(program);
+    let mut errors = Vec::new();
+
+    for (form, e) in program.iter().enumerate() {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() || e.is_foreign_call().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if let Some((name, args)) = e.is_primcall() {
+                if let Err(e) = crate::primitives::check_primcall_arity(name, args.len()) {
+                    errors.push((form, e));
+                }
+            } else if let Some((Expr::Symbol(s), args)) = e.is_fncall() {
+                if let Some(arity) = arities.get(s) {
+                    if !arity.accepts(args.len()) {
+                        errors.push((
+                            form,
+                            format!(
+                                "function ({}) expects {} arguments but was called with {}",
+                                original_name(s),
+                                arity,
+                                args.len()
+                            ),
+                        ));
+                    }
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roundtrip_string;
+    use crate::Expr;
+
+    #[test]
+    fn wrong_arg_count() {
+        let source = r#"
+(let f (fn (a b) (add a b)))
+(f 1 2 3)
+"#;
+        assert_eq!(
+            roundtrip_string(source),
+            Err("function (f) expects 2 arguments but was called with 3".to_string())
+        );
+    }
+
+    #[test]
+    fn varadic_arg_count() {
+        let source = r#"
+(let f (fn (a & rest) rest))
+(f)
+"#;
+        assert_eq!(
+            roundtrip_string(source),
+            Err("function (f) expects at least 1 arguments but was called with 0".to_string())
+        );
+        let source = r#"
+(let f (fn (a & rest) rest))
+(f 1 2 3)
+"#;
+        assert!(roundtrip_string(source).is_ok());
+    }
+
+    #[test]
+    fn malformed_varadic_signature() {
+        // These are left for compiling to report rather than checked.
+        assert_eq!(
+            roundtrip_string("(let f (fn (&) 1)) (f)"),
+            Err("a varadic signature (one with the & symbol) must have at least one additional symbol to bind the varadic arguments to.".to_string())
+        );
+        assert_eq!(
+            roundtrip_string("(let f (fn (& a b) 1)) (f)"),
+            Err("varadic symbol (&) in non tail position".to_string())
+        );
+        let program = crate::parse_string("(let f (fn (&) 1)) (f)").unwrap();
+        let kinds: Vec<_> = crate::diagnostics::diagnose(&program)
+            .into_iter()
+            .map(|d| d.kind)
+            .collect();
+        assert!(!kinds.contains(&"arity"));
+    }
+
+    #[test]
+    fn recursive_arg_count() {
+        let source = r#"
+(let f (fn (n) (if (eq n 0) 0 (f n 1))))
+(f 10)
+"#;
+        assert_eq!(
+            roundtrip_string(source),
+            Err("function (f) expects 1 arguments but was called with 2".to_string())
+        );
+    }
+
+    #[test]
+    fn reassigned_not_checked() {
+        let source = r#"
+(let f (fn (a b) (add a b)))
+(set f (fn (a) a))
+(f 1)
+"#;
+        assert_eq!(roundtrip_string(source), Ok(Expr::Integer(1)));
+    }
+}
@@ -0,0 +1,255 @@
+//! Disassembles the machine code that the JIT generated. This is the
+//! code that actually runs, after register allocation and encoding,
+//! so it is a step beyond dumping the IR. There isn't a disassembler
+//! library among our dependencies so the code is handed to the
+//! system's objdump.
+//!
+//! Programs can disassemble their own functions too. `(disassemble
+//! name)` writes the listing of the function bound to the symbol NAME
+//! by a let, or of the function the JIT named NAME, the same way
+//! `--emit-asm` writes it. A name that no compiled function has raises
+//! a range error and anything other than a symbol raises a type error.
+//!
+//! ```lisp
+//! (let square (fn (x) (mul x x)))
+//! (disassemble (quote square)) ; writes "square (42 bytes at 0x...):" and the listing
+//! ```
+//!
+//! Running code can't see the JIT so `JIT::invoke` tells this module
+//! where the functions it has compiled are, per thread like captured
+//! output is, before it runs a program.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::process::Command;
+
+use cranelift::prelude::*;
+use cranelift_module::FuncId;
+
+use crate::compiler::{Context, JIT};
+use crate::conversions::{HEAP_TAG_MASK, SYMBOL_TAG};
+use crate::{Expr, Word};
+
+thread_local! {
+    /// The functions of the JIT whose program is running on this
+    /// thread. See `publish_functions`.
+    static RUNNING: RefCell<Vec<RunningFunction>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Where a function that a running program can disassemble lives.
+struct RunningFunction {
+    name: String,
+    binding: Option<String>,
+    address: usize,
+    size: usize,
+}
+
+/// A function that the JIT has compiled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledFunction {
+    pub name: String,
+    pub id: FuncId,
+    /// The number of bytes of machine code in the function.
+    pub size: u32,
+    /// The name the function is bound to by let, before renaming, if
+    /// it is bound to one.
+    pub binding: Option<String>,
+    /// The function's machine code before the addresses of the data
+    /// and functions it refers to were filled in. Unlike the code that
+    /// runs this is the same every time a program is compiled.
+    pub object_code: Vec<u8>,
+}
+
+/// Returns the machine code of the function that CONTEXT has just
+/// compiled before relocations were applied.
+pub(crate) fn object_code(context: &cranelift::codegen::Context) -> Vec<u8> {
+    context
+        .mach_compile_result
+        .as_ref()
+        .map(|r| r.buffer.data().to_vec())
+        .unwrap_or_default()
+}
+
+impl JIT {
+    /// Returns the machine code of the compiled function FUNCTION.
+    /// The function must have been finalized.
+    pub fn machine_code(&self, function: &CompiledFunction) -> &[u8] {
+        let ptr = self.module.get_finalized_function(function.id);
+        unsafe { std::slice::from_raw_parts(ptr, function.size as usize) }
+    }
+}
+
+/// Returns the name objdump uses for the architecture the JIT is
+/// generating code for.
+fn objdump_arch() -> Result<&'static str, String> {
+    if cfg!(target_arch = "x86_64") {
+        Ok("i386:x86-64")
+    } else if cfg!(target_arch = "aarch64") {
+        Ok("aarch64")
+    } else {
+        Err("disassembly isn't supported on this architecture".to_string())
+    }
+}
+
+/// Disassembles CODE which is loaded at ADDRESS.
+fn disassemble_code(name: &str, code: &[u8], address: usize) -> Result<String, String> {
+    let path = std::env::temp_dir().join(format!("lustc-{}-{}.bin", std::process::id(), name));
+    std::fs::File::create(&path)
+        .and_then(|mut f| f.write_all(code))
+        .map_err(|e| e.to_string())?;
+    let output = Command::new("objdump")
+        .args(["-D", "-b", "binary", "-m", objdump_arch()?])
+        .arg(format!("--adjust-vma={:#x}", address))
+        .arg(&path)
+        .output();
+    let _ = std::fs::remove_file(&path);
+    let output = output.map_err(|e| format!("failed to run objdump: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).to_string());
+    }
+    // Everything before the first instruction is objdump describing
+    // the file it was given.
+    let listing = String::from_utf8_lossy(&output.stdout);
+    Ok(listing
+        .lines()
+        .skip_while(|l| !l.ends_with(">:"))
+        .skip(1)
+        .map(|l| format!("{}\n", l))
+        .collect())
+}
+
+/// Returns the listing of the function named NAME which is loaded at
+/// ADDRESS, preceded by its name and where it lives.
+fn function_listing(name: &str, code: &[u8], address: usize) -> Result<String, String> {
+    Ok(format!(
+        "{} ({} bytes at {:#x}):\n{}",
+        name,
+        code.len(),
+        address,
+        disassemble_code(name, code, address)?
+    ))
+}
+
+/// Returns the disassembly of every function JIT has compiled. Each
+/// function's listing is preceded by its name and where it lives.
+pub fn disassemble(jit: &JIT) -> Result<String, String> {
+    let mut res = String::new();
+    for f in &jit.compiled_functions {
+        let code = jit.machine_code(f);
+        res.push_str(&function_listing(&f.name, code, code.as_ptr() as usize)?);
+        res.push('\n');
+    }
+    Ok(res)
+}
+
+/// Makes the functions JIT has compiled the ones that disassemble
+/// looks for on this thread. Called before JIT runs a program.
+pub(crate) fn publish_functions(jit: &JIT) {
+    let functions = jit
+        .compiled_functions
+        .iter()
+        .map(|f| RunningFunction {
+            name: f.name.clone(),
+            binding: f.binding.clone(),
+            address: jit.machine_code(f).as_ptr() as usize,
+            size: f.size as usize,
+        })
+        .collect();
+    RUNNING.with(|r| *r.borrow_mut() = functions);
+}
+
+/// Emits the code for `(disassemble NAME)` where NAME has already
+/// been evaluated.
+pub(crate) fn emit_disassemble(name: Value, ctx: &mut Context) -> Result<Value, String> {
+    crate::fatal::emit_check_tag(name, SYMBOL_TAG, HEAP_TAG_MASK, ctx)?;
+    let found = crate::foreign::emit_host_call("lustc_disassemble", &[name], ctx)?;
+    let found = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, found, Expr::Bool(true).immediate_rep());
+    crate::fatal::emit_check_range(found, ctx)?;
+    Ok(ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep()))
+}
+
+/// Implements (disassemble name). Writes the listing of the function
+/// and returns true, or returns false if no function has NAME. The
+/// function bound to NAME most recently wins if there is more than
+/// one. If the function can't be disassembled why is written instead.
+pub(crate) extern "C" fn lustc_disassemble(name: Word) -> Word {
+    let name = crate::symbols::symbol_name(name);
+    let listing = RUNNING.with(|r| {
+        r.borrow()
+            .iter()
+            .rev()
+            .find(|f| f.binding.as_deref() == Some(name) || f.name == name)
+            .map(|f| {
+                let code = unsafe { std::slice::from_raw_parts(f.address as *const u8, f.size) };
+                function_listing(name, code, f.address)
+                    .unwrap_or_else(|e| format!("can't disassemble {}: {}\n", name, e))
+            })
+    });
+    match listing {
+        Some(listing) => {
+            crate::output::write_output(&listing);
+            Expr::Bool(true).immediate_rep()
+        }
+        None => Expr::Bool(false).immediate_rep(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile_program;
+    use crate::parse_string;
+    use crate::test_util::error_kind;
+
+    #[test]
+    fn disassemble_trivial_program() {
+        let mut jit = JIT::default();
+        let mut program = parse_string("(let f (fn (x) x)) (f 1)").unwrap();
+        compile_program(&mut jit, &mut program).unwrap();
+
+        let entry = jit
+            .compiled_functions
+            .iter()
+            .find(|f| f.name == "lust_entry")
+            .unwrap();
+        assert!(entry.size > 0);
+        assert_eq!(jit.machine_code(entry).len(), entry.size as usize);
+
+        let asm = disassemble(&jit).unwrap();
+        assert!(asm.contains("lust_entry"));
+        assert!(asm.contains("__anon_fn_0"));
+        assert!(asm.lines().any(|l| l.contains("ret")));
+    }
+
+    #[test]
+    fn disassemble_from_lisp() {
+        // The listing is thousands of characters long so it is looked
+        // at in lisp rather than turned into a string.
+        let source = r#"
+(let square (fn (x) (mul x x)))
+(let res 1)
+(let out (with-output-to-string (set res (disassemble (quote square)))))
+(list (string-slice out 0 8) (lt 100 (string-length out)) res)
+"#;
+        let mut jit = JIT::default();
+        let mut program = parse_string(source).unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            crate::roundtrip_string("(list \"square (\" (eq 1 1) ())").unwrap()
+        );
+
+        for (source, kind) in [
+            ("(disassemble (quote nothing))", "range-error"),
+            ("(disassemble 1)", "type-error"),
+        ] {
+            assert_eq!(error_kind(source), kind);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Functions for building programs from Rust without going through
+//! the parser. The trees built here are the same ones that the parser
+//! produces for the equivalent source so they can be handed straight
+//! to `compile_program`.
+//!
+//! ```
+//! use lustc::{risp, Expr};
+//!
+//! let by_hand = Expr::list(vec![Expr::sym("+"), Expr::int(1), Expr::int(2)]);
+//! assert_eq!(risp! { (+ 1 2) }, by_hand);
+//! ```
+
+use crate::Expr;
+
+impl Expr {
+    /// Makes a list out of ITEMS. As in the parser the empty list is
+    /// nil.
+    pub fn list(items: Vec<Expr>) -> Expr {
+        if items.is_empty() {
+            Expr::Nil
+        } else {
+            Expr::List(items)
+        }
+    }
+
+    pub fn int(i: i64) -> Expr {
+        Expr::Integer(i)
+    }
+
+    pub fn sym(name: &str) -> Expr {
+        Expr::Symbol(name.to_string())
+    }
+
+    pub fn str(s: &str) -> Expr {
+        Expr::String(s.to_string())
+    }
+
+    pub fn nil() -> Expr {
+        Expr::Nil
+    }
+
+    /// Makes the expression `(quote E)`. The result is a complex
+    /// constant and ends up in the program's data.
+    pub fn quote(e: Expr) -> Expr {
+        Expr::List(vec![Expr::sym("quote"), e])
+    }
+}
+
+impl From<i64> for Expr {
+    fn from(i: i64) -> Self {
+        Expr::int(i)
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(s: &str) -> Self {
+        Expr::str(s)
+    }
+}
+
+/// Builds an `Expr` from lisp written inline in Rust source. Integer
+/// and string literals become integers and strings, identifiers and
+/// punctuation become symbols, and parenthesized groups become
+/// lists. Symbols that Rust can't tokenize as one token, like
+/// `data-one`, can't be written directly. A Rust expression that
+/// evaluates to an `Expr` can be spliced in with braces for those.
+///
+/// ```
+/// use lustc::{risp, Expr};
+///
+/// let name = Expr::sym("data-one");
+/// let e = risp! { (let {name} (quote (1 2 3))) };
+/// assert_eq!(e, lustc::parse_string("(let data-one (quote (1 2 3)))").unwrap()[0]);
+/// ```
+#[macro_export]
+macro_rules! risp {
+    (( $($inner:tt)* )) => {
+        $crate::Expr::list(vec![$($crate::risp!($inner)),*])
+    };
+    ({ $e:expr }) => {
+        $e
+    };
+    ($l:literal) => {
+        $crate::Expr::from($l)
+    };
+    ($s:tt) => {
+        $crate::Expr::sym(stringify!($s))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_program, JIT};
+    use crate::{parse_string, Expr};
+
+    fn roundtrip_exprs(program: &mut [Expr]) -> Expr {
+        let mut jit = JIT::default();
+        let id = compile_program(&mut jit, program).unwrap();
+        Expr::from_immediate(jit.invoke(id).unwrap())
+    }
+
+    #[test]
+    fn builder_matches_parser() {
+        let built = vec![
+            Expr::list(vec![
+                Expr::sym("let"),
+                Expr::sym("l"),
+                Expr::quote(Expr::list(vec![Expr::int(1), Expr::int(2), Expr::nil()])),
+            ]),
+            Expr::list(vec![
+                Expr::sym("cons"),
+                Expr::list(vec![Expr::sym("car"), Expr::sym("l")]),
+                Expr::str("hello"),
+            ]),
+        ];
+        let parsed = parse_string("(let l (quote (1 2 ()))) (cons (car l) \"hello\")").unwrap();
+        assert_eq!(built, parsed);
+        match &built[0] {
+            Expr::List(v) => assert!(v[2].is_complex_const().is_some()),
+            _ => panic!("expected a list"),
+        }
+
+        let mut built = built;
+        let mut parsed = parsed;
+        assert_eq!(roundtrip_exprs(&mut built), roundtrip_exprs(&mut parsed));
+    }
+
+    #[test]
+    fn macro_matches_parser() {
+        let name = Expr::sym("add-one");
+        let mut built = vec![
+            risp! { (let {name.clone()} (fn (n) (+ n 1))) },
+            risp! { (if (lt 1 2) ({name} 41) "no") },
+            risp! { (quote ()) },
+        ];
+        let mut parsed = parse_string(
+            "(let add-one (fn (n) (+ n 1))) (if (lt 1 2) (add-one 41) \"no\") (quote ())",
+        )
+        .unwrap();
+        assert_eq!(built, parsed);
+        assert_eq!(roundtrip_exprs(&mut built), Expr::Nil);
+        assert_eq!(roundtrip_exprs(&mut parsed), Expr::Nil);
+
+        assert_eq!(
+            risp! { (<= a b) },
+            Expr::list(vec![Expr::sym("<="), Expr::sym("a"), Expr::sym("b")])
+        );
+
+        let mut program = vec![risp! { (+ 1 (* 2 3)) }];
+        assert_eq!(roundtrip_exprs(&mut program), Expr::Integer(7));
+    }
+}
@@ -0,0 +1,117 @@
+//! Predicates that classify characters, for programs that read text
+//! a character at a time like tokenizers do.
+//!
+//! ```lisp
+//! (char-digit? (string-ref "a5" 1)) ; => true
+//! (char-alpha? (string-ref "a5" 1)) ; => false
+//! ```
+//!
+//! `(char-alpha? c)`, `(char-whitespace? c)`, `(char-upper? c)` and
+//! `(char-lower? c)` are true if C is alphabetic, whitespace, upper
+//! case or lower case as Unicode defines them, so they agree with how
+//! string-upcase and string-trim see characters and are true for
+//! letters like é and Ж as well as the ASCII ones. `(char-digit? c)` is
+//! only true for 0 through 9 so that the value of a digit is always
+//! `(sub (char->integer c) 48)`. Each of them raises a type error if C
+//! isn't a character.
+//!
+//! The predicates are host functions and are told which class to check
+//! for by the index of their name in `CHAR_PREDICATES`.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::{Expr, Word};
+
+/// The names of the character predicates.
+pub(crate) const CHAR_PREDICATES: [&str; 5] = [
+    "char-alpha?",
+    "char-digit?",
+    "char-whitespace?",
+    "char-upper?",
+    "char-lower?",
+];
+
+/// Determines if C is in the class checked for by the predicate NAME.
+pub(crate) fn char_is(name: &str, c: char) -> bool {
+    match name {
+        "char-alpha?" => c.is_alphabetic(),
+        "char-digit?" => c.is_ascii_digit(),
+        "char-whitespace?" => c.is_whitespace(),
+        "char-upper?" => c.is_uppercase(),
+        "char-lower?" => c.is_lowercase(),
+        _ => panic!("non character predicate in char_is: {}", name),
+    }
+}
+
+/// Emits the code for the character predicate NAME applied to C which
+/// has already been evaluated.
+pub(crate) fn emit_char_predicate(
+    name: &str,
+    c: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    crate::fatal::emit_check_char(c, ctx)?;
+    let class = CHAR_PREDICATES.iter().position(|p| *p == name).unwrap();
+    let class = ctx.builder.ins().iconst(ctx.word, class as i64);
+    crate::foreign::emit_host_call("lustc_char_is", &[c, class], ctx)
+}
+
+/// Implements the character predicates. C has already been checked to
+/// be a character and CLASS is the index of the predicate's name in
+/// CHAR_PREDICATES.
+pub(crate) extern "C" fn lustc_char_is(c: Word, class: Word) -> Word {
+    let is = match Expr::from_immediate(c) {
+        Expr::Char(c) => char_is(CHAR_PREDICATES[class as usize], c),
+        _ => false,
+    };
+    Expr::Bool(is).immediate_rep()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_string;
+    use crate::test_util::{check, error_kind};
+
+    #[test]
+    fn classes() {
+        // Each predicate applied to each character of the string, as a
+        // string of 1s and 0s.
+        let classify = |predicate: &str, s: &str| {
+            format!(
+                "(foldr (fn (c acc) (cons (if ({} c) 1 0) acc)) () \"{}\")",
+                predicate, s
+            )
+        };
+        let chars = "a5Z \té\n_Ж9ß";
+        let cases = [
+            ("char-alpha?", "(1 0 1 0 0 1 0 0 1 0 1)"),
+            ("char-digit?", "(0 1 0 0 0 0 0 0 0 1 0)"),
+            ("char-whitespace?", "(0 0 0 1 1 0 1 0 0 0 0)"),
+            ("char-upper?", "(0 0 1 0 0 0 0 0 1 0 0)"),
+            ("char-lower?", "(1 0 0 0 0 1 0 0 0 0 1)"),
+        ];
+        for (predicate, expected) in cases {
+            check(
+                &classify(predicate, chars),
+                &format!("(quote {})", expected),
+            );
+        }
+        check(
+            "(cons (char-digit? (integer->char 53)) (char-alpha? (integer->char 53)))",
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+        check(
+            "(let call (fn (f x) (f x))) (call char-upper? (string-ref \"aB\" 1))",
+            "(eq 1 1)",
+        );
+    }
+
+    #[test]
+    fn not_characters() {
+        assert_eq!(error_kind("(char-alpha? 5)"), "type-error");
+        assert!(
+            crate::interpreter::interpret(&parse_string("(char-digit? \"5\")").unwrap()).is_err()
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Compiles programs with a great many top level forms a group of
+//! forms at a time. The top level forms of a program are normally
+//! compiled into a single entry function and cranelift holds all of
+//! that function at once while compiling it, so a generated program
+//! with tens of thousands of forms needs memory in proportion to the
+//! whole program. When `CompileOptions::chunk_size` is set longer
+//! programs are split into chunks that are each compiled as a program
+//! of their own, followed by a small entry that runs the chunks in
+//! order.
+//!
+//! Chunks share their definitions the way REPL inputs do (see
+//! `globals.rs`) so a form can use anything defined by the forms
+//! before it no matter which chunk they are in. A function that uses
+//! a definition that comes after it is kept in the definition's chunk,
+//! so splitting a program never changes which programs compile.
+//!
+//! What splitting can change is assignment. A later chunk reads a
+//! variable from its slot, which holds the value the variable was
+//! defined with, while closures in the defining chunk share the
+//! variable itself and see it being set. So a program is never split
+//! between the definition of a variable that is set somewhere and the
+//! last form that uses it. Arities are checked
+//! within each chunk and calls between chunks are checked when they
+//! run.
+
+use std::collections::{HashMap, HashSet};
+
+use cranelift::prelude::*;
+use cranelift_module::{FuncId, Module};
+
+use crate::compiler::{compile_desugared, define_entry, Context, JIT};
+use crate::{fatal, Expr, PreorderStatus};
+
+/// Returns the end of each chunk PROGRAM is split into when chunks
+/// are to have at least SIZE forms. The last chunk may be shorter.
+fn chunk_ends(program: &[Expr], size: usize) -> Vec<usize> {
+    let mut defined = HashMap::new();
+    let mut first_use = HashMap::new();
+    let mut last_use = HashMap::new();
+    let mut assigned = HashSet::new();
+    for (form, e) in program.iter().enumerate() {
+        if let Some((name, _)) = e.is_let() {
+            defined.entry(name.clone()).or_insert(form);
+        }
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if let Some((name, _)) = e.is_set() {
+                assigned.insert(name.clone());
+            } else if let Expr::Symbol(name) = e {
+                first_use.entry(name.clone()).or_insert(form);
+                last_use.insert(name.clone(), form);
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    // joined[i] is true if forms i and i + 1 have to be in the same
+    // chunk.
+    let mut joined = vec![false; program.len()];
+    for name in assigned {
+        if let (Some(&first), Some(&last)) = (defined.get(&name), last_use.get(&name)) {
+            for j in &mut joined[first..last.max(first)] {
+                *j = true;
+            }
+        }
+    }
+
+    // A form that uses a definition that comes after it is compiled
+    // with the definition. See `forward.rs`.
+    for (name, &first) in &first_use {
+        if let Some(&defined) = defined.get(name) {
+            for j in &mut joined[first..defined.max(first)] {
+                *j = true;
+            }
+        }
+    }
+
+    let mut ends = Vec::new();
+    let mut start = 0;
+    for (form, joined) in joined.into_iter().enumerate() {
+        if form + 1 - start >= size && !joined {
+            ends.push(form + 1);
+            start = form + 1;
+        }
+    }
+    if start < program.len() {
+        ends.push(program.len());
+    }
+    ends
+}
+
+/// Compiles PROGRAM into JIT a chunk of about SIZE forms at a time and
+/// returns the id of the function that runs all of them.
+pub(crate) fn compile_in_chunks(
+    jit: &mut JIT,
+    program: &mut [Expr],
+    size: usize,
+) -> Result<FuncId, String> {
+    crate::desugar::desugar(program)?;
+
+    let persistent = jit.options.persistent;
+    jit.options.persistent = true;
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let res = chunk_ends(program, size)
+        .into_iter()
+        .try_for_each(|end| {
+            entries.push(compile_desugared(jit, &mut program[start..end], start)?);
+            start = end;
+            Ok::<_, String>(())
+        })
+        .and_then(|_| match entries.as_slice() {
+            [entry] => Ok(*entry),
+            _ => emit_chunk_entry(jit, &entries),
+        });
+    jit.options.persistent = persistent;
+    res
+}
+
+/// Emits an entry that calls each of ENTRIES in order and returns
+/// what the last one returned.
+fn emit_chunk_entry(jit: &mut JIT, entries: &[FuncId]) -> Result<FuncId, String> {
+    let _t = crate::timer::timeit("chunk entry compilation");
+
+    let word = jit.module.target_config().pointer_type();
+    jit.context.func.signature.returns.push(AbiParam::new(word));
+
+    let mut builder = FunctionBuilder::new(&mut jit.context.func, &mut jit.builder_context);
+    let entry_block = builder.create_block();
+    builder.switch_to_block(entry_block);
+
+    let mut ctx = Context::new(
+        builder,
+        &mut jit.module,
+        word,
+        HashMap::new(),
+        HashMap::new(),
+        Vec::new(),
+        jit.options.clone(),
+    );
+
+    let mut res = None;
+    for id in entries {
+        let local = ctx.module.declare_func_in_func(*id, ctx.builder.func);
+        let call = ctx.builder.ins().call(local, &[]);
+        res = Some(ctx.builder.inst_results(call)[0]);
+        if ctx.options.embedded {
+            fatal::emit_check_error_pending(&mut ctx)?;
+        }
+    }
+    ctx.builder
+        .ins()
+        .return_(&[res.ok_or("expected at least one chunk".to_string())?]);
+
+    ctx.builder.seal_all_blocks();
+    ctx.builder.finalize();
+
+    define_entry(jit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompileOptions;
+    use crate::parse_string;
+
+    fn run(source: &str, chunk_size: usize) -> Result<Expr, String> {
+        let mut jit = JIT::new(CompileOptions {
+            chunk_size: Some(chunk_size),
+            embedded: true,
+            ..Default::default()
+        });
+        let mut program = parse_string(source)?;
+        let id = crate::compiler::compile_program(&mut jit, &mut program)?;
+        jit.invoke(id)
+            .map(Expr::from_immediate)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn split_points() {
+        let program = parse_string("(let a 1) (let b 2) (set a 3) 4 5 6 (let c 7)").unwrap();
+        assert_eq!(chunk_ends(&program, 2), [3, 5, 7]);
+        assert_eq!(chunk_ends(&program, 1), [3, 4, 5, 6, 7]);
+        assert_eq!(chunk_ends(&program, 10), [7]);
+        let program = parse_string("(let a 1) (let f (fn () (set a 2))) (f) a 5").unwrap();
+        assert_eq!(chunk_ends(&program, 1), [4, 5]);
+    }
+
+    #[test]
+    fn chunked_programs() {
+        let source = r#"
+(let double (fn (x) (add x x)))
+(let xs (quote (1 2 3)))
+(let count 0)
+(let bump (fn () (set count (add1 count))))
+(bump)
+(let twice (fn (f x) (f (f x))))
+(bump)
+(cons (twice double (car xs)) count)
+"#;
+        for size in 1..4 {
+            assert_eq!(run(source, size), crate::roundtrip_string(source));
+        }
+        assert!(run("(let a 1) (let b 2) (add c 1) (let c 3)", 1).is_err());
+        assert!(run("(let a 1) (let b 2) (car a)", 1).is_err());
+    }
+
+    #[test]
+    fn many_forms() {
+        // Every hundredth form uses the definition a hundred forms
+        // before it, which is usually in an earlier chunk.
+        let mut source = "(let v0 0)\n".to_string();
+        for i in 1..50_000 {
+            if i % 100 == 0 {
+                source.push_str(&format!("(let v{} (add1 v{}))\n", i / 100, i / 100 - 1));
+            } else {
+                source.push_str(&format!("{}\n", i));
+            }
+        }
+        source.push_str("(let get (fn () v499))\n(get)\n");
+
+        let mut jit = JIT::new(CompileOptions {
+            chunk_size: Some(1000),
+            ..Default::default()
+        });
+        let mut program = parse_string(&source).unwrap();
+        let id = crate::compiler::compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            Expr::Integer(499)
+        );
+        // Cranelift holds one function at a time so the largest one
+        // bounds the memory compiling needs. Compiled all at once
+        // this program is a single function of about 200KB.
+        let largest = jit.compiled_functions.iter().map(|f| f.size).max();
+        assert!(largest.unwrap() < 64 * 1024, "{:?}", largest);
+    }
+}
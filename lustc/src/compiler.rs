@@ -9,27 +9,32 @@
 //!
 //! - [Mark Bell](https://hellopoetry.com/poem/1927377/give-us-a-clue/)
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use crate::arity;
+use crate::asm::CompiledFunction;
 use crate::conditional;
 use crate::conversions::{print_lustc_word, println_lustc_word};
 use crate::data;
 use crate::escape;
 use crate::fatal;
+use crate::fold;
 use crate::foreign;
-use crate::heap::define_alloc;
+use crate::heap::{self, define_alloc, Allocator, Arena, HeapStats};
 use crate::locals;
 use crate::primitives;
 use crate::procedures;
 use crate::renamer;
+use crate::sourcemap::{self, SourceMap};
+use crate::tail;
 use crate::Expr;
+use crate::Word;
 use cranelift::frontend::FunctionBuilder;
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
 use cranelift_module::DataContext;
-use cranelift_module::{Linkage, Module};
+use cranelift_module::{DataId, FuncId, Linkage, Module};
 use primitives::define_contiguous_to_list;
-use primitives::string_is_primitive;
 use procedures::emit_procedure;
 use procedures::LustFn;
 
@@ -49,6 +54,83 @@ pub struct JIT {
 
     // Stores information about data objects that the JIT owns.
     pub data_ctx: DataContext,
+
+    /// Options that change how programs are compiled.
+    pub options: CompileOptions,
+
+    /// The arena that values are allocated in if the JIT is using the
+    /// arena allocator. The arena is freed when the JIT is dropped.
+    pub arena: Option<Box<Arena>>,
+
+    /// Maps the code of the functions compiled by the JIT to the top
+    /// level forms that they came from.
+    pub source_map: SourceMap,
+
+    /// What the coverage counters of the programs the JIT has
+    /// compiled count. See `coverage.rs`.
+    pub coverage: crate::coverage::Coverage,
+
+    /// The entry functions and procedures the JIT has compiled. See
+    /// `asm.rs`.
+    pub compiled_functions: Vec<CompiledFunction>,
+
+    /// The number of programs, functions, and pieces of data the JIT
+    /// has compiled. Names are numbered from these so that the JIT
+    /// can compile more than one program.
+    programs: usize,
+    functions: usize,
+    data: usize,
+
+    /// The name of the next program's entry function if it has been
+    /// set by `set_entry_name`.
+    entry_name: Option<String>,
+    /// The entry functions the JIT has compiled by name.
+    entries: HashMap<String, FuncId>,
+
+    /// The variables that have slots. See `globals.rs`.
+    pub(crate) globals: HashSet<String>,
+
+    /// The number of times the module has been finalized.
+    pub(crate) finalizations: usize,
+    /// The data that has been defined with `data::create_data`.
+    pub(crate) defined_data: HashSet<DataId>,
+    /// The number of data objects declared before the last time the
+    /// module was finalized, all of which were defined.
+    checked_data: usize,
+}
+
+/// Options that change how the JIT compiles programs.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// When set runtime errors do not exit the process. Instead they
+    /// unwind back to the caller of `JIT::invoke` who is handed a
+    /// `RuntimeError`. This is meant for embedding the JIT in a larger
+    /// program and comes at the cost of a check after every function
+    /// call.
+    pub embedded: bool,
+    /// The allocator used for heap allocated values.
+    pub allocator: Allocator,
+    /// When set the top level definitions of a program are available
+    /// to the programs the JIT compiles after it, like they would be
+    /// in a REPL. See `globals.rs`.
+    pub persistent: bool,
+    /// When set checks that can be proven to always pass are left out
+    /// of the compiled program. See `guards.rs`.
+    pub optimize: bool,
+    /// When set programs with more top level forms than this are
+    /// compiled a group of about this many forms at a time. See
+    /// `chunks.rs`.
+    pub chunk_size: Option<usize>,
+    /// When set calls between functions that tail call each other
+    /// go through a trampoline so that mutual recursion runs in
+    /// constant stack space. See `tail.rs`.
+    pub trampoline: bool,
+    /// When set the program counts how many times each of its top
+    /// level forms and branches runs. See `coverage.rs`.
+    pub coverage: bool,
+    /// When set lists of one or two small integers are packed into a
+    /// word instead of being allocated. See `packed.rs`.
+    pub pack_lists: bool,
 }
 
 /// Manages the state needed for compilation of a function by lustc.
@@ -62,10 +144,21 @@ pub(crate) struct Context<'a> {
     // variables are in a "defined but not initialized state" and
     // closures care about this.
     pub letstack: Vec<String>,
+    pub options: CompileOptions,
+    // The block that a function's self tail calls jump to. Its params
+    // are the function's params. None outside of a function.
+    pub tail_call_block: Option<Block>,
 }
 
 impl Default for JIT {
     fn default() -> Self {
+        Self::new(CompileOptions::default())
+    }
+}
+
+impl JIT {
+    /// Makes a new JIT that compiles programs according to OPTIONS.
+    pub fn new(options: CompileOptions) -> Self {
         let mut flag_builder = settings::builder();
         // On at least AArch64, "colocated" calls use shorter-range relocations,
         // which might not reach all definitions; we can't handle that here, so
@@ -83,6 +176,117 @@ impl Default for JIT {
         builder.symbol("print_lustc_word", print_addr);
         let println_addr = println_lustc_word as *const u8;
         builder.symbol("println_lustc_word", println_addr);
+        builder.symbol("lustc_display", crate::output::lustc_display as *const u8);
+        builder.symbol("lustc_newline", crate::output::lustc_newline as *const u8);
+        builder.symbol("lustc_char_is", crate::chars::lustc_char_is as *const u8);
+        builder.symbol(
+            "lustc_disassemble",
+            crate::asm::lustc_disassemble as *const u8,
+        );
+        builder.symbol(
+            "lustc_flush_output",
+            crate::output::lustc_flush_output as *const u8,
+        );
+        builder.symbol(
+            "lustc_pending_call",
+            procedures::lustc_pending_call as *const u8,
+        );
+        builder.symbol(
+            "lustc_begin_capture",
+            crate::output::lustc_begin_capture as *const u8,
+        );
+        builder.symbol(
+            "lustc_end_capture",
+            crate::output::lustc_end_capture as *const u8,
+        );
+
+        builder.symbol(
+            "lustc_arena_grow",
+            crate::heap::lustc_arena_grow as *const u8,
+        );
+        builder.symbol("lustc_heap_stats", heap::lustc_heap_stats as *const u8);
+        builder.symbol(
+            "lustc_time_start",
+            crate::timer::lustc_time_start as *const u8,
+        );
+        builder.symbol("lustc_time_end", crate::timer::lustc_time_end as *const u8);
+
+        // Register the functions that give programs access to their
+        // environment.
+        builder.symbol(
+            "lustc_getenv",
+            crate::environment::lustc_getenv as *const u8,
+        );
+        builder.symbol(
+            "lustc_command_line_args",
+            crate::environment::lustc_command_line_args as *const u8,
+        );
+
+        // Register the list searching functions.
+        builder.symbol("lustc_equal", crate::lists::lustc_equal as *const u8);
+        builder.symbol("lustc_member", crate::lists::lustc_member as *const u8);
+        builder.symbol("lustc_assoc", crate::lists::lustc_assoc as *const u8);
+        builder.symbol("lustc_memq", crate::lists::lustc_memq as *const u8);
+        builder.symbol("lustc_assq", crate::lists::lustc_assq as *const u8);
+        builder.symbol("lustc_hash", crate::lists::lustc_hash as *const u8);
+        builder.symbol(
+            "lustc_type_of",
+            crate::conversions::lustc_type_of as *const u8,
+        );
+        builder.symbol("lustc_write", crate::serialize::lustc_write as *const u8);
+        builder.symbol(
+            "lustc_assert_equal",
+            crate::testing::lustc_assert_equal as *const u8,
+        );
+        builder.symbol("lustc_read", crate::serialize::lustc_read as *const u8);
+
+        // Register the string functions that the host implements.
+        builder.symbol(
+            "lustc_string_split",
+            crate::strings::lustc_string_split as *const u8,
+        );
+        builder.symbol(
+            "lustc_string_upcase",
+            crate::strings::lustc_string_upcase as *const u8,
+        );
+        builder.symbol(
+            "lustc_string_downcase",
+            crate::strings::lustc_string_downcase as *const u8,
+        );
+        builder.symbol(
+            "lustc_string_trim",
+            crate::strings::lustc_string_trim as *const u8,
+        );
+        builder.symbol(
+            "lustc_string_join",
+            crate::strings::lustc_string_join as *const u8,
+        );
+        builder.symbol(
+            "lustc_string_index",
+            crate::strings::lustc_string_index as *const u8,
+        );
+
+        // Register the functions used to raise errors in embedded
+        // mode.
+        builder.symbol("lustc_raise", fatal::lustc_raise as *const u8);
+        builder.symbol("lustc_print_error", fatal::lustc_print_error as *const u8);
+        builder.symbol(
+            "lustc_raise_internal",
+            fatal::lustc_raise_internal as *const u8,
+        );
+        builder.symbol(
+            "lustc_raise_condition",
+            fatal::lustc_raise_condition as *const u8,
+        );
+        builder.symbol(
+            "lustc_take_condition",
+            fatal::lustc_take_condition as *const u8,
+        );
+        builder.symbol(
+            "lustc_suspend_error",
+            fatal::lustc_suspend_error as *const u8,
+        );
+        builder.symbol("lustc_resume_error", fatal::lustc_resume_error as *const u8);
 
         let module = JITModule::new(builder);
         let mut jit = Self {
@@ -90,12 +294,114 @@ impl Default for JIT {
             context: module.make_context(),
             module,
             data_ctx: DataContext::new(),
+            arena: match options.allocator {
+                Allocator::Arena => Some(Box::new(Arena::new())),
+                Allocator::Malloc | Allocator::Custom(_) => None,
+            },
+            options,
+            source_map: SourceMap::default(),
+            coverage: Default::default(),
+            compiled_functions: Vec::new(),
+            programs: 0,
+            functions: 0,
+            data: 0,
+            entry_name: None,
+            entries: HashMap::new(),
+            globals: HashSet::new(),
+            finalizations: 0,
+            defined_data: HashSet::new(),
+            checked_data: 0,
         };
         define_alloc(&mut jit).unwrap();
         define_contiguous_to_list(&mut jit).unwrap();
         crate::fatal::emit_error_strings(&mut jit).unwrap();
+        crate::fatal::define_error_pending(&mut jit).unwrap();
+        heap::define_heap_stats(&mut jit).unwrap();
+        jit.finalize().unwrap();
         jit
     }
+
+    /// Finalizes everything defined since the module was last
+    /// finalized. Functions can't be called and data can't be read
+    /// until this has happened. References between functions and
+    /// data are resolved here so a batch of data and the functions
+    /// that use it can be finalized together, and the data can be
+    /// defined before or after the functions that use it.
+    ///
+    /// A function that uses data declares it, so by now every data
+    /// object that has been declared must have been defined too. The
+    /// module would otherwise look a missing one up in the host's
+    /// symbols and either panic or, if the host happens to have a
+    /// symbol with the same name, use that. This is an error instead
+    /// and nothing is finalized.
+    pub(crate) fn finalize(&mut self) -> Result<(), String> {
+        let declarations = self.module.declarations();
+        let missing = declarations
+            .get_data_objects()
+            .skip(self.checked_data)
+            .find(|(id, decl)| decl.linkage.is_definable() && !self.defined_data.contains(id));
+        if let Some((_, decl)) = missing {
+            return Err(format!(
+                "internal error: data ({}) is used but was never defined",
+                decl.name
+            ));
+        }
+        self.checked_data = declarations.get_data_objects().count();
+        self.finalizations += 1;
+        self.module.finalize_definitions();
+        Ok(())
+    }
+
+    /// Calls the function compiled by `compile_program`. If the JIT
+    /// is in embedded mode and the program raises an error the error
+    /// is returned. The JIT may be used again afterwards.
+    pub fn invoke(&mut self, id: FuncId) -> Result<Word, fatal::LustError> {
+        let code_ptr = self.module.get_finalized_function(id);
+        let code_fn = unsafe { std::mem::transmute::<_, fn() -> i64>(code_ptr) };
+
+        heap::reset_heap_stats(self);
+        crate::asm::publish_functions(self);
+
+        let res = {
+            let _t = crate::timer::timeit("program execution");
+            code_fn()
+        };
+
+        match fatal::take_raised_error() {
+            // Only programs that handle errors unwind outside of embedded
+            // mode. See `exceptions.rs`.
+            Some(e) if !self.options.embedded => {
+                println!("{}", e.message);
+                std::process::exit(e.code as i32)
+            }
+            Some(e) => {
+                fatal::clear_error_pending(self);
+                Err(e)
+            }
+            None => Ok(res),
+        }
+    }
+
+    /// Names the entry function of the next program the JIT compiles
+    /// NAME so that it can be found with `get_entry`. Without a name a
+    /// program's entry is named lust_entry followed by the number of
+    /// programs compiled before it, if there are any. Compiling a
+    /// program with a name that is already in use is an error.
+    pub fn set_entry_name(&mut self, name: &str) {
+        self.entry_name = Some(name.to_string());
+    }
+
+    /// Returns the entry function named NAME, which can be passed to
+    /// `invoke`, if the JIT has compiled one.
+    pub fn get_entry(&self, name: &str) -> Option<FuncId> {
+        self.entries.get(name).copied()
+    }
+
+    /// Returns the heap statistics of the program this JIT is
+    /// running or last ran.
+    pub fn heap_stats(&self) -> HeapStats {
+        heap::heap_stats(self)
+    }
 }
 
 impl<'a> Context<'a> {
@@ -106,6 +412,7 @@ impl<'a> Context<'a> {
         env: HashMap<String, Variable>,
         fnmap: HashMap<String, LustFn>,
         letstack: Vec<String>,
+        options: CompileOptions,
     ) -> Self {
         Self {
             builder,
@@ -114,6 +421,8 @@ impl<'a> Context<'a> {
             env,
             fnmap,
             letstack,
+            options,
+            tail_call_block: None,
         }
     }
 }
@@ -121,7 +430,10 @@ impl<'a> Context<'a> {
 /// Emits the code for an expression using the given builder.
 pub(crate) fn emit_expr(expr: &Expr, ctx: &mut Context) -> Result<Value, String> {
     Ok(match expr {
-        Expr::Integer(_) => ctx.builder.ins().iconst(ctx.word, expr.immediate_rep()),
+        Expr::Integer(_) => {
+            crate::desugar::check_integers(expr)?;
+            ctx.builder.ins().iconst(ctx.word, expr.immediate_rep())
+        }
         Expr::Char(_) => ctx.builder.ins().iconst(ctx.word, expr.immediate_rep()),
         Expr::Bool(_) => ctx.builder.ins().iconst(ctx.word, expr.immediate_rep()),
         Expr::Nil => ctx.builder.ins().iconst(ctx.word, expr.immediate_rep()),
@@ -133,12 +445,42 @@ pub(crate) fn emit_expr(expr: &Expr, ctx: &mut Context) -> Result<Value, String>
                 locals::emit_let(symbol, binding, ctx)?
             } else if let Some((symbol, binding)) = expr.is_set() {
                 locals::emit_set(symbol, binding, ctx)?
+            } else if let Some(switch) = expr.is_switch() {
+                conditional::emit_switch(&switch, ctx)?
             } else if let Some((cond, then, else_)) = expr.is_conditional() {
                 conditional::emit_conditional(cond, then, else_, ctx)?
+            } else if let Some((counter, arm)) = expr.is_coverage_count() {
+                crate::coverage::emit_coverage_count(counter, arm, ctx)?
+            } else if let Some((kind, message, data)) = expr.is_typed_error() {
+                crate::conditions::emit_typed_error(kind, message, data, ctx)?
             } else if let Some((message, exit_code)) = expr.is_error() {
                 fatal::emit_error(message, exit_code, ctx)?
+            } else if let Some(timed) = expr.is_time() {
+                crate::timer::emit_time(timed, ctx)?
+            } else if let Some((car, cdr)) = expr.is_stack_cons() {
+                crate::stack::emit_stack_cons(car, cdr, ctx)?
+            } else if let Some(vals) = expr.is_values() {
+                crate::values::emit_values(vals, ctx)?
+            } else if let Some(e) = expr.is_first_value() {
+                crate::values::emit_first_value(e, ctx)?
+            } else if let Some((producer, consumer)) = expr.is_call_with_values() {
+                crate::values::emit_call_with_values(producer, consumer, ctx)?
+            } else if let Some((body, handler)) = expr.is_try() {
+                crate::exceptions::emit_try(body, handler, ctx)?
+            } else if let Some((body, cleanup)) = expr.is_unwind_protect() {
+                crate::exceptions::emit_unwind_protect(body, cleanup, ctx)?
+            } else if let Some(body) = expr.is_with_output_to_string() {
+                crate::output::emit_with_output_to_string(body, ctx)?
             } else if let Some((name, args)) = expr.is_foreign_call() {
                 foreign::emit_foreign_call(&name, args, ctx)?
+            } else if let Some((is_car, pair)) = expr.is_unchecked_access() {
+                crate::guards::emit_unchecked_access(is_car, pair, ctx)?
+            } else if let Some(args) = expr.is_self_tail_call() {
+                procedures::emit_self_tail_call(args, ctx)?
+            } else if let Some((head, args)) = expr.is_bounce() {
+                procedures::emit_bounce(head, args, ctx)?
+            } else if let Some((head, args)) = expr.is_trampolined_call() {
+                procedures::emit_trampolined_call(head, args, ctx)?
             } else if let Some((head, args)) = expr.is_fncall() {
                 procedures::emit_fncall(head, args, ctx)?
             } else if v.len() == 0 {
@@ -154,38 +496,120 @@ pub(crate) fn emit_expr(expr: &Expr, ctx: &mut Context) -> Result<Value, String>
                 s
             ))
         }
+        Expr::Procedure => return Err("procedures can not be compiled".to_string()),
     })
 }
 
 pub fn roundtrip_program(program: &mut [Expr]) -> Result<Expr, String> {
     let mut jit = JIT::default();
+    let id = compile_program(&mut jit, program)?;
+    let res = jit.invoke(id).map_err(|e| e.to_string())?;
+    Ok(Expr::from_immediate(res))
+}
+
+/// Compiles PROGRAM into JIT and returns the id of the function that
+/// will run it when passed to `JIT::invoke`.
+pub fn compile_program(jit: &mut JIT, program: &mut [Expr]) -> Result<FuncId, String> {
+    if let Some(mut expanded) = crate::desugar::expand_records(program)? {
+        return compile_program(jit, &mut expanded);
+    }
+    if let Some(name) = &jit.entry_name {
+        if jit.module.get_name(name).is_some() {
+            let name = jit.entry_name.take().unwrap();
+            return Err(format!("the name {} is already in use", name));
+        }
+    }
+    // Errors need to unwind to be caught.
+    let embedded = jit.options.embedded;
+    jit.options.embedded |= crate::exceptions::handles_errors(program);
+    let res = match jit.options.chunk_size {
+        Some(size) if program.len() > size => crate::chunks::compile_in_chunks(jit, program, size),
+        _ => compile_unwinding(jit, program),
+    };
+    jit.options.embedded = embedded;
+    res
+}
+
+fn compile_unwinding(jit: &mut JIT, program: &mut [Expr]) -> Result<FuncId, String> {
+    // Rewrite shorthand forms like and and cond.
+    crate::desugar::desugar(program)?;
+    compile_desugared(jit, program, 0)
+}
+
+/// Compiles PROGRAM, which has already been desugared, into JIT.
+/// FIRST_FORM is the index of PROGRAM's first form in the source it
+/// came from.
+pub(crate) fn compile_desugared(
+    jit: &mut JIT,
+    program: &mut [Expr],
+    first_form: usize,
+) -> Result<FuncId, String> {
+    let definitions = if jit.options.persistent {
+        crate::globals::definitions(program)
+    } else {
+        Vec::new()
+    };
 
     // Rename symbols so that they are all unique.
-    renamer::make_names_unique(program)?;
+    renamer::make_names_unique_with_globals(program, &jit.globals)?;
+
+    // Give the definitions that are used before they are made slots
+    // to be used through.
+    let forward = crate::forward::resolve_forward_uses(program, jit.programs)?;
+
+    // Evaluate the expressions whose values are known at compile time.
+    fold::fold_constants(program);
+
+    // Check calls to functions with known definitions.
+    arity::check_arities(program)?;
+
+    // Turn calls functions make to themselves in tail position into
+    // loops.
+    tail::mark_self_tail_calls(program);
+    if jit.options.trampoline {
+        tail::mark_trampolined_calls(program, jit.options.persistent, &forward);
+    }
+
+    // Count how many times each form and branch runs. This comes after
+    // tail calls are marked so that counting doesn't change them.
+    let form_counters = if jit.options.coverage {
+        crate::coverage::instrument(program, first_form, jit)?
+    } else {
+        Vec::new()
+    };
+
+    // Only the first of the values an expression returns is used
+    // outside of tail position.
+    crate::values::take_first_values(program);
 
     // Collect primitives that are used as higher order functions.
     let higher_order_primitives = primitives::collect_higher_order_primitives(program)?;
     // Emit the primitive functions that are used in higher order contexts.
-    let primitive_fns = primitives::emit_primitives(&mut jit, higher_order_primitives)?;
+    let primitive_fns = primitives::emit_primitives(jit, higher_order_primitives)?;
 
-    // Initialize program data.
-    let data = data::collect_data(program);
-    // Replace it with references to its location in the JIT.
-    data::replace_data(program, &data);
+    // Initialize program data and replace it with references to its
+    // location in the JIT.
+    let data = data::extract_data(program, jit.data);
+    jit.data += data.len();
 
     {
         let _t = crate::timer::timeit("data creation");
         // Store the data in the JIT.
-        for d in data {
-            data::create_data(d, &mut jit)?;
-        }
+        data::create_data(data, jit)?;
+        crate::globals::define_slots(&definitions, jit)?;
+        crate::forward::define_slots(&forward, jit)?;
     }
+    data::check_data_uses(program, jit)?;
 
     // Transforms the program so that anonymous functions are lifted
     // to the top of the program and replaced with their anyonmous
     // names. There is some cool manuvering here that happens to make
     // sure that the bodies of the collected functions are updated.
-    let mut functions = procedures::collect_functions(program)?;
+    let mut functions = procedures::collect_functions(program, jit.functions)?;
+    jit.functions += functions.len();
+    for f in &mut functions {
+        f.form = f.form.map(|form| first_form + form);
+    }
     // Annotation needs to happen before replacement so that we can
     // traverse the body of nested functions for free variables that
     // outer functions need to caputre.
@@ -199,86 +623,145 @@ pub fn roundtrip_program(program: &mut [Expr]) -> Result<Expr, String> {
     // Annotate escaped variables in closures
     escape::annotate_escaped_variables(&mut functions, program)?;
 
+    // Move conses that never leave their function to the stack.
+    crate::stack::stack_allocate_conses(&mut functions);
+
+    if jit.options.optimize {
+        crate::guards::remove_guarded_checks(program, &mut functions);
+    }
+
+    // Functions are emitted in the order they were collected rather
+    // than the map's order so that compiling the same program twice
+    // gives the same output.
+    let order: Vec<String> = functions.iter().map(|f| f.name.clone()).collect();
+
     // Build a map from anonymous names to values
     let mut fnmap = procedures::build_fn_map(functions);
     // Extend the function map with the builtin functions
     fnmap.extend(primitive_fns.into_iter().map(|f| (f.name.clone(), f)));
 
+    let _codegen = crate::timer::timeit("codegen");
     {
         let _t = crate::timer::timeit("procedure compilation");
         // Emit all the non-primitive functions into the JIT.
-        for (_, f) in fnmap.iter().filter(|(name, _)| !string_is_primitive(name)) {
+        for f in order.iter().map(|name| &fnmap[name]) {
+            let _t = crate::timer::timeit(f.binding.clone().unwrap_or_else(|| f.name.clone()));
             emit_procedure(
-                &mut jit,
+                jit,
                 &f.name,
                 &f.params,
                 &f.body,
                 &f.varadic_symbol,
+                f.form,
                 &fnmap,
             )?;
         }
     }
 
-    let code_fn = {
-        let _t = crate::timer::timeit("lust_entry compilation");
+    let _t = crate::timer::timeit("lust_entry compilation");
 
-        let word = jit.module.target_config().pointer_type();
+    let word = jit.module.target_config().pointer_type();
 
-        // Signature for the function that we're compiling. This function
-        // takes no arguments and returns an integer.
-        jit.context.func.signature.returns.push(AbiParam::new(word));
+    // Signature for the function that we're compiling. This function
+    // takes no arguments and returns an integer.
+    jit.context.func.signature.returns.push(AbiParam::new(word));
 
-        // Create a new builder for building our function and create a new
-        // block to compile into.
-        let mut builder = FunctionBuilder::new(&mut jit.context.func, &mut jit.builder_context);
-        let entry_block = builder.create_block();
+    // Create a new builder for building our function and create a new
+    // block to compile into.
+    let mut builder = FunctionBuilder::new(&mut jit.context.func, &mut jit.builder_context);
+    let entry_block = builder.create_block();
 
-        // Give the paramaters that we set up earlier to this entry block.
-        builder.append_block_params_for_function_params(entry_block);
-        // Start putting code in the new block.
-        builder.switch_to_block(entry_block);
+    // Give the paramaters that we set up earlier to this entry block.
+    builder.append_block_params_for_function_params(entry_block);
+    // Start putting code in the new block.
+    builder.switch_to_block(entry_block);
 
-        let env = HashMap::new();
+    let env = HashMap::new();
+
+    let mut ctx = Context::new(
+        builder,
+        &mut jit.module,
+        word,
+        env,
+        fnmap,
+        Vec::new(),
+        jit.options.clone(),
+    );
 
-        let mut ctx = Context::new(builder, &mut jit.module, word, env, fnmap, Vec::new());
+    let vals = program
+        .iter()
+        .enumerate()
+        .map(|(form, e)| {
+            sourcemap::set_form(&mut ctx.builder, first_form + form);
+            if let Some(counter) = form_counters.get(form) {
+                crate::coverage::emit_count(*counter, &mut ctx)?;
+            }
+            let val = emit_expr(e, &mut ctx)?;
+            if ctx.options.persistent {
+                crate::globals::emit_store_definition(e, &mut ctx)?;
+            }
+            crate::forward::emit_store_definition(e, &forward, &mut ctx)?;
+            Ok::<_, String>(val)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-        let vals = program
-            .iter()
-            .map(|e| emit_expr(e, &mut ctx))
-            .collect::<Result<Vec<_>, _>>()?;
+    // Emit a return instruction to return the result. An empty
+    // program returns nil.
+    let res = match vals.last() {
+        Some(val) => *val,
+        None => ctx
+            .builder
+            .ins()
+            .iconst(ctx.word, Expr::Nil.immediate_rep()),
+    };
+    ctx.builder.ins().return_(&[res]);
 
-        // Emit a return instruction to return the result.
-        ctx.builder.ins().return_(&[*vals
-            .last()
-            .ok_or("expected at least one expression".to_string())?]);
+    // Clean up
+    ctx.builder.seal_all_blocks();
+    ctx.builder.finalize();
 
-        // Clean up
-        ctx.builder.seal_all_blocks();
-        ctx.builder.finalize();
+    define_entry(jit)
+}
 
-        let id = jit
-            .module
-            .declare_function("lust_entry", Linkage::Export, &jit.context.func.signature)
-            .map_err(|e| e.to_string())?;
+/// Defines the entry function that has been built in JIT's context
+/// and returns its id. Entries are named after the number of programs
+/// the JIT has compiled.
+pub(crate) fn define_entry(jit: &mut JIT) -> Result<FuncId, String> {
+    let name = match (jit.entry_name.take(), jit.programs) {
+        (Some(name), _) => name,
+        (None, 0) => "lust_entry".to_string(),
+        (None, n) => format!("lust_entry_{}", n),
+    };
+    jit.programs += 1;
 
-        jit.module
-            .define_function(id, &mut jit.context)
-            .map_err(|e| e.to_string())?;
+    let id = jit
+        .module
+        .declare_function(&name, Linkage::Export, &jit.context.func.signature)
+        .map_err(|e| e.to_string())?;
 
-        // If you want to dump the generated IR this is the way:
-        // println!("{}", jit.context.func.display(jit.module.isa()));
+    let compiled = jit
+        .module
+        .define_function(id, &mut jit.context)
+        .map_err(|e| e.to_string())?;
 
-        jit.module.clear_context(&mut jit.context);
+    jit.source_map.record(&name, &jit.context);
+    jit.entries.insert(name.clone(), id);
+    jit.compiled_functions.push(CompiledFunction {
+        name,
+        id,
+        size: compiled.size,
+        binding: None,
+        object_code: crate::asm::object_code(&jit.context),
+    });
 
-        jit.module.finalize_definitions();
+    // If you want to dump the generated IR this is the way:
+    // println!("{}", jit.context.func.display(jit.module.isa()));
 
-        let code_ptr = jit.module.get_finalized_function(id);
+    jit.module.clear_context(&mut jit.context);
 
-        unsafe { std::mem::transmute::<_, fn() -> i64>(code_ptr) }
-    };
+    jit.finalize()?;
 
-    let _t = crate::timer::timeit("program execution");
-    Ok(Expr::from_immediate(code_fn()))
+    Ok(id)
 }
 
 /// Compiles an expression and returns the result converted back into
@@ -320,6 +803,7 @@ pub fn roundtrip_expr(expr: Expr) -> Result<Expr, String> {
             env,
             HashMap::new(),
             Vec::new(),
+            jit.options.clone(),
         );
 
         // Compile the value and get the "output" of the instrution stored
@@ -347,7 +831,7 @@ pub fn roundtrip_expr(expr: Expr) -> Result<Expr, String> {
 
     jit.module.clear_context(&mut jit.context);
 
-    jit.module.finalize_definitions();
+    jit.finalize()?;
 
     let code_ptr = jit.module.get_finalized_function(id);
 
@@ -385,6 +869,7 @@ pub fn roundtrip_exprs(exprs: &[Expr]) -> Result<Expr, String> {
         env,
         HashMap::new(),
         Vec::new(),
+        jit.options.clone(),
     );
 
     let vals = exprs
@@ -392,10 +877,16 @@ pub fn roundtrip_exprs(exprs: &[Expr]) -> Result<Expr, String> {
         .map(|e| emit_expr(e, &mut ctx))
         .collect::<Result<Vec<_>, _>>()?;
 
-    // Emit a return instruction to return the result.
-    ctx.builder.ins().return_(&[*vals
-        .last()
-        .ok_or("expected at least one expression".to_string())?]);
+    // Emit a return instruction to return the result. An empty
+    // program returns nil.
+    let res = match vals.last() {
+        Some(val) => *val,
+        None => ctx
+            .builder
+            .ins()
+            .iconst(ctx.word, Expr::Nil.immediate_rep()),
+    };
+    ctx.builder.ins().return_(&[res]);
 
     // Clean up
     ctx.builder.seal_all_blocks();
@@ -415,7 +906,7 @@ pub fn roundtrip_exprs(exprs: &[Expr]) -> Result<Expr, String> {
 
     jit.module.clear_context(&mut jit.context);
 
-    jit.module.finalize_definitions();
+    jit.finalize()?;
 
     let code_ptr = jit.module.get_finalized_function(id);
 
@@ -423,3 +914,49 @@ pub fn roundtrip_exprs(exprs: &[Expr]) -> Result<Expr, String> {
 
     Ok(Expr::from_immediate(code_fn()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+
+    #[test]
+    fn entry_names() {
+        let mut jit = JIT::default();
+        for (name, source) in [
+            (
+                "first",
+                "(let f (fn (s) (cons s (quote (1 2))))) (f \"one\")",
+            ),
+            ("second", "(cons (quote (3 4)) \"two\")"),
+        ] {
+            jit.set_entry_name(name);
+            let mut program = parse_string(source).unwrap();
+            compile_program(&mut jit, &mut program).unwrap();
+        }
+        let mut program = parse_string("5").unwrap();
+        let third = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(jit.get_entry("lust_entry_2"), Some(third));
+
+        // Each entry finds its own data however it is named.
+        for (name, expected) in [
+            ("first", "(cons \"one\" (quote (1 2)))"),
+            ("second", "(cons (quote (3 4)) \"two\")"),
+        ] {
+            let id = jit.get_entry(name).unwrap();
+            let res = Expr::from_immediate(jit.invoke(id).unwrap());
+            assert_eq!(res, crate::roundtrip_string(expected).unwrap());
+        }
+        assert_eq!(jit.get_entry("third"), None);
+
+        jit.set_entry_name("first");
+        let mut program = parse_string("6").unwrap();
+        assert!(compile_program(&mut jit, &mut program).is_err());
+        let mut program = parse_string("7").unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            Expr::Integer(7)
+        );
+    }
+}
@@ -1,9 +1,35 @@
 use cranelift::prelude::*;
+use cranelift_codegen::ir::JumpTableData;
 
 use crate::compiler::emit_expr;
 use crate::compiler::Context;
+use crate::conversions::{FIXNUM_MASK, FIXNUM_SHIFT};
 use crate::Expr;
 
+/// The fewest cases an if chain needs before it is compiled as a
+/// jump table.
+const MIN_SWITCH_CASES: usize = 4;
+
+/// A chain of conditionals that compare the same variable to integer
+/// constants. For example:
+///
+/// ```lisp
+/// (if (eq x 0) a
+///     (if (eq x 1) b
+///         (if (eq x 2) c d)))
+/// ```
+#[derive(Debug)]
+pub(crate) struct Switch<'a> {
+    /// The variable being compared.
+    scrutinee: &'a str,
+    /// Every constant in the chain and the expression that is
+    /// evaluated if the variable is equal to it, in the order they
+    /// appear.
+    cases: Vec<(i64, &'a Expr)>,
+    /// The expression evaluated if no case matches.
+    default: &'a Expr,
+}
+
 impl Expr {
     pub fn is_conditional(&self) -> Option<(&Expr, &Expr, &Expr)> {
         match self {
@@ -21,6 +47,53 @@ impl Expr {
             _ => None,
         }
     }
+
+    /// If the expression is a comparison of a variable to an integer
+    /// constant returns the variable and the constant.
+    fn is_integer_comparison(&self) -> Option<(&str, i64)> {
+        match self.is_primcall() {
+            Some(("eq", [Expr::Symbol(s), Expr::Integer(i)]))
+            | Some(("eq", [Expr::Integer(i), Expr::Symbol(s)])) => Some((s, *i)),
+            _ => None,
+        }
+    }
+
+    /// Determines if the expression is a chain of conditionals that
+    /// would be better compiled as a jump table. That is the case if
+    /// every condition compares the same variable to an integer, there
+    /// are at least MIN_SWITCH_CASES of them, and the constants are
+    /// dense enough that at least half of the table's entries are
+    /// used.
+    pub(crate) fn is_switch(&self) -> Option<Switch<'_>> {
+        let mut cases = Vec::new();
+        let mut scrutinee = None;
+        let mut e = self;
+        while let Some((cond, then, else_)) = e.is_conditional() {
+            match cond.is_integer_comparison() {
+                Some((s, i)) if scrutinee.is_none() || scrutinee == Some(s) => {
+                    scrutinee = Some(s);
+                    cases.push((i, then));
+                    e = else_;
+                }
+                _ => break,
+            }
+        }
+
+        if cases.len() < MIN_SWITCH_CASES {
+            return None;
+        }
+        let min = cases.iter().map(|(i, _)| *i).min()?;
+        let max = cases.iter().map(|(i, _)| *i).max()?;
+        let range = (max as i128 - min as i128 + 1) as u128;
+        if range > 2 * cases.len() as u128 {
+            return None;
+        }
+        Some(Switch {
+            scrutinee: scrutinee?,
+            cases,
+            default: e,
+        })
+    }
 }
 
 pub(crate) fn emit_conditional(
@@ -75,6 +148,85 @@ pub(crate) fn emit_conditional(
     Ok(res)
 }
 
+/// Emits SWITCH as a jump table. The variable is offset by the
+/// smallest constant and used as an index into the table. Values that
+/// fall outside of the table, and values that aren't integers, go to
+/// the default case.
+pub(crate) fn emit_switch(switch: &Switch, ctx: &mut Context) -> Result<Value, String> {
+    let min = switch.cases.iter().map(|(i, _)| *i).min().unwrap();
+    let max = switch.cases.iter().map(|(i, _)| *i).max().unwrap();
+    let len = (max - min + 1) as usize;
+
+    let val = crate::locals::emit_var_access(switch.scrutinee, ctx)?;
+
+    let default_block = ctx.builder.create_block();
+    let check_block = ctx.builder.create_block();
+    let table_block = ctx.builder.create_block();
+    let merge_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(merge_block, ctx.word);
+
+    // Subtracting the tagged minimum leaves the tag bits alone so
+    // anything that isn't a fixnum still has some of them set.
+    let offset = ctx
+        .builder
+        .ins()
+        .iadd_imm(val, -Expr::Integer(min).immediate_rep());
+    let tag = ctx.builder.ins().band_imm(offset, FIXNUM_MASK);
+    ctx.builder.ins().brnz(tag, default_block, &[]);
+    ctx.builder.ins().jump(check_block, &[]);
+
+    // Values less than the minimum wrap around to large unsigned
+    // numbers so one comparison checks both ends of the table.
+    ctx.builder.switch_to_block(check_block);
+    ctx.builder.seal_block(check_block);
+    let index = ctx.builder.ins().ushr_imm(offset, FIXNUM_SHIFT);
+    let in_range = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::UnsignedLessThan, index, len as i64);
+    ctx.builder.ins().brz(in_range, default_block, &[]);
+    ctx.builder.ins().jump(table_block, &[]);
+
+    // Earlier cases take precedence over later ones with the same
+    // constant as they would in the chain.
+    let mut entries = vec![default_block; len];
+    let mut case_blocks = Vec::new();
+    for (i, body) in switch.cases.iter().rev() {
+        let block = ctx.builder.create_block();
+        entries[(i - min) as usize] = block;
+        case_blocks.push((block, *body));
+    }
+    case_blocks.retain(|(block, _)| entries.contains(block));
+    case_blocks.reverse();
+
+    let mut table = JumpTableData::with_capacity(len);
+    for block in entries {
+        table.push_entry(block);
+    }
+    let table = ctx.builder.create_jump_table(table);
+
+    ctx.builder.switch_to_block(table_block);
+    ctx.builder.seal_block(table_block);
+    let index = ctx.builder.ins().ireduce(types::I32, index);
+    ctx.builder.ins().br_table(index, default_block, table);
+
+    for (block, body) in case_blocks {
+        ctx.builder.switch_to_block(block);
+        ctx.builder.seal_block(block);
+        let res = emit_expr(body, ctx)?;
+        ctx.builder.ins().jump(merge_block, &[res]);
+    }
+
+    ctx.builder.switch_to_block(default_block);
+    ctx.builder.seal_block(default_block);
+    let res = emit_expr(switch.default, ctx)?;
+    ctx.builder.ins().jump(merge_block, &[res]);
+
+    ctx.builder.switch_to_block(merge_block);
+    ctx.builder.seal_block(merge_block);
+    Ok(ctx.builder.block_params(merge_block)[0])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,4 +271,89 @@ mod tests {
         let expected = Expr::Bool(true);
         test_evaluation(&ast, expected);
     }
+
+    /// Builds a function that dispatches on its argument with a chain
+    /// of ifs comparing it to each of CASES. A case for I evaluates to
+    /// I * 10 and the default case to 999.
+    fn dispatch_program(cases: &[i64]) -> String {
+        let chain = cases.iter().rev().fold("999".to_string(), |e, i| {
+            format!("(if (eq x {}) (mul {} 10) {})", i, i, e)
+        });
+        format!("(let dispatch (fn (x) {}))", chain)
+    }
+
+    /// Dispatches on every integer from -1 to 10 and on a character.
+    fn dispatch_results(cases: &[i64]) -> Expr {
+        let source = format!(
+            r#"
+{}
+(let results (fn (i) (if (eq i 11) () (cons (dispatch i) (results (add1 i))))))
+(cons (dispatch (integer->char 0)) (results (sub 1)))
+"#,
+            dispatch_program(cases)
+        );
+        crate::roundtrip_string(&source).unwrap()
+    }
+
+    fn expected_results(cases: &[i64]) -> Expr {
+        let mut expected = vec![999];
+        expected.extend((-1..=10).map(|i| if cases.contains(&i) { i * 10 } else { 999 }));
+        expected.into_iter().rev().fold(Expr::Nil, |tail, i| {
+            Expr::List(vec![Expr::Integer(i), tail])
+        })
+    }
+
+    fn switch_body(source: &str) -> Expr {
+        let program = crate::parse_string(source).unwrap();
+        match &program[0] {
+            Expr::List(v) => v[2].is_fndef().unwrap().1[0].clone(),
+            _ => panic!("expected a let"),
+        }
+    }
+
+    #[test]
+    fn switch_detection() {
+        let dense = switch_body(&dispatch_program(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]));
+        let switch = dense.is_switch().unwrap();
+        assert_eq!(switch.scrutinee, "x");
+        assert_eq!(switch.cases.len(), 10);
+        assert_eq!(switch.default, &Expr::Integer(999));
+
+        let sparse = switch_body(&dispatch_program(&[0, 100, 200, 300]));
+        assert!(sparse.is_switch().is_none());
+        let short = switch_body(&dispatch_program(&[0, 1, 2]));
+        assert!(short.is_switch().is_none());
+    }
+
+    #[test]
+    fn switch_dispatch() {
+        let dense = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(dispatch_results(&dense), expected_results(&dense));
+
+        // Gaps in the table go to the default case.
+        let gaps = [9, 7, 5, 3, 1, 0];
+        assert_eq!(dispatch_results(&gaps), expected_results(&gaps));
+
+        // Sparse chains are compiled as comparisons.
+        let sparse = [0, 5, 10];
+        assert_eq!(dispatch_results(&sparse), expected_results(&sparse));
+    }
+
+    #[test]
+    fn switch_duplicate_cases() {
+        let source = r#"
+(let f (fn (x) (if (eq x 1) 10 (if (eq 2 x) 20 (if (eq x 1) 30 (if (eq x 3) 40 (if (eq x 4) 50 0)))))))
+(cons (f 1) (cons (f 2) (cons (f 3) (f 5))))
+"#;
+        assert_eq!(
+            crate::roundtrip_string(source).unwrap(),
+            Expr::List(vec![
+                Expr::Integer(10),
+                Expr::List(vec![
+                    Expr::Integer(20),
+                    Expr::List(vec![Expr::Integer(40), Expr::Integer(0)])
+                ])
+            ])
+        );
+    }
 }
@@ -0,0 +1,271 @@
+//! Conditions are values that describe an error. A condition has a
+//! type, which is a symbol, a message, and a piece of data that can
+//! be anything. `(error type message data)` raises one:
+//!
+//! ```lisp
+//! (error (quote out-of-range) "index out of range" i)
+//! ```
+//!
+//! Errors raised with `(error message)` have the type `error` and
+//! errors raised by the runtime have a type that says what went wrong
+//! like `type-error` or `division-by-zero`.
+//!
+//! `(make-condition type message data)` makes a condition without
+//! raising it and `condition?`, `condition-type`, `condition-message`,
+//! and `condition-data` inspect one. Because types are interned
+//! symbols a handler can dispatch on them with eq.
+//!
+//! A condition lives on the heap and shares VALUES_TAG with tuples.
+//! It is four words: CONDITION_HEADER followed by the type, the
+//! message, and the data, so a collector can trace it by visiting
+//! its last three words.
+
+use cranelift::prelude::*;
+
+use crate::compiler::{emit_expr, Context};
+use crate::conversions::{CONDITION_HEADER, HEAP_PTR_MASK, HEAP_TAG_MASK, SYMBOL_TAG, VALUES_TAG};
+use crate::fatal;
+use crate::heap::emit_alloc;
+use crate::{Expr, Word};
+
+impl Expr {
+    /// Determines if the expression is an error expression that
+    /// raises a condition with a type and data and if it is returns
+    /// its type, message, and data arguments.
+    pub(crate) fn is_typed_error(&self) -> Option<(&Expr, &Expr, &Expr)> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "error" && v.len() == 4 {
+                    return Some((&v[1], &v[2], &v[3]));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns the type, message, and data of CONDITION.
+pub(crate) fn condition_fields(condition: Word) -> (Word, Word, Word) {
+    let ptr = (condition & HEAP_PTR_MASK) as *const Word;
+    unsafe { (*ptr.add(1), *ptr.add(2), *ptr.add(3)) }
+}
+
+/// Makes a condition from the host. KIND is the name of its type and
+/// MESSAGE and DATA are tagged values.
+pub(crate) fn new_condition(kind: &str, message: Word, data: Word) -> Word {
+    let condition = Box::new([
+        CONDITION_HEADER,
+        crate::symbols::intern(kind),
+        message,
+        data,
+    ]);
+    Box::into_raw(condition) as Word | VALUES_TAG
+}
+
+/// Returns the index of the word that the accessor NAME reads from
+/// a condition.
+pub(crate) fn accessor_field(name: &str) -> Option<usize> {
+    match name {
+        "condition-type" => Some(1),
+        "condition-message" => Some(2),
+        "condition-data" => Some(3),
+        _ => None,
+    }
+}
+
+/// Emits the code to make a condition. KIND must be a symbol.
+pub(crate) fn emit_make_condition(
+    kind: Value,
+    message: Value,
+    data: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_tag(kind, SYMBOL_TAG, HEAP_TAG_MASK, ctx)?;
+
+    let word_size = ctx.word.bytes() as i32;
+    let storage = emit_alloc((4 * word_size).into(), ctx)?;
+    let header = ctx.builder.ins().iconst(ctx.word, CONDITION_HEADER);
+    for (i, val) in [header, kind, message, data].iter().enumerate() {
+        ctx.builder
+            .ins()
+            .store(MemFlags::new(), *val, storage, i as i32 * word_size);
+    }
+    Ok(ctx.builder.ins().bor_imm(storage, VALUES_TAG))
+}
+
+/// Emits the code to check if VAL is a condition. The result is a
+/// word that is 1 if it is and 0 if it isn't.
+pub(crate) fn emit_is_condition(val: Value, ctx: &mut Context) -> Value {
+    emit_has_header(val, CONDITION_HEADER, ctx)
+}
+
+/// Emits the code to check if VAL is tagged with VALUES_TAG and
+/// points at HEADER. The result is a word that is 1 if it is and 0 if
+/// it isn't.
+pub(crate) fn emit_has_header(val: Value, header: Word, ctx: &mut Context) -> Value {
+    let header_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    // Only words with the values tag point at a header.
+    let tagged = crate::foreign::emit_is(val, VALUES_TAG, HEAP_TAG_MASK, ctx);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().brz(tagged, done_block, &[zero]);
+    ctx.builder.ins().jump(header_block, &[]);
+
+    ctx.builder.switch_to_block(header_block);
+    ctx.builder.seal_block(header_block);
+    let ptr = ctx.builder.ins().band_imm(val, HEAP_PTR_MASK);
+    let first = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+    let matches = ctx.builder.ins().icmp_imm(IntCC::Equal, first, header);
+    let matches = ctx.builder.ins().bint(ctx.word, matches);
+    ctx.builder.ins().jump(done_block, &[matches]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    ctx.builder.block_params(done_block)[0]
+}
+
+/// Emits the code to read word FIELD of the condition VAL raising a
+/// type error if VAL isn't a condition.
+pub(crate) fn emit_condition_field(
+    val: Value,
+    field: usize,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let is_condition = emit_is_condition(val, ctx);
+
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(is_condition, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_bad_arg_type".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+
+    let ptr = ctx.builder.ins().band_imm(val, HEAP_PTR_MASK);
+    Ok(ctx.builder.ins().load(
+        ctx.word,
+        MemFlags::new(),
+        ptr,
+        (field * ctx.word.bytes() as usize) as i32,
+    ))
+}
+
+/// Emits the code for `(error KIND MESSAGE DATA)`. Outside of
+/// embedded mode there is nobody to hand the condition to so the
+/// message is printed and the program exits like any other error.
+pub(crate) fn emit_typed_error(
+    kind: &Expr,
+    message: &Expr,
+    data: &Expr,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let exit_code = Expr::Integer(1);
+    if !ctx.options.embedded {
+        // Everything is evaluated in order before the message is
+        // printed.
+        emit_expr(kind, ctx)?;
+        let message = emit_expr(message, ctx)?;
+        emit_expr(data, ctx)?;
+        crate::foreign::emit_host_call("lustc_print_error", &[message], ctx)?;
+        let code = crate::foreign::emit_untag(&exit_code, ctx)?;
+        return crate::foreign::emit_untagged_foreign_call("exit", &[code], ctx);
+    }
+    let kind = emit_expr(kind, ctx)?;
+    let message = emit_expr(message, ctx)?;
+    let data = emit_expr(data, ctx)?;
+    let condition = emit_make_condition(kind, message, data, ctx)?;
+
+    let code = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, exit_code.immediate_rep());
+    let form = crate::sourcemap::emit_form(ctx);
+    crate::foreign::emit_host_call("lustc_raise_condition", &[condition, code, form], ctx)?;
+    fatal::emit_unwind(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fatal::LustError;
+    use crate::test_util::run_embedded;
+    use crate::{parse_string, roundtrip_string, Expr};
+
+    #[test]
+    fn accessors() {
+        let source = r#"
+(let c (make-condition (quote out-of-range) "too big" 10))
+(cons (condition? c)
+ (cons (condition? 1)
+  (cons (condition? (quote (1 2)))
+   (cons (eq (condition-type c) (quote out-of-range))
+    (cons (condition-message c) (condition-data c))))))
+"#;
+        let expected = roundtrip_string(
+            "(cons (eq 1 1) (cons (eq 1 2) (cons (eq 1 2) (cons (eq 1 1) (cons \"too big\" 10)))))",
+        )
+        .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        // Accessors can be used in higher order contexts.
+        let source = r#"
+(let get (fn (f c) (f c)))
+(get condition-data (make-condition (quote e) "m" (quote (1 2))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(quote (1 2))").unwrap()
+        );
+    }
+
+    #[test]
+    fn distinguish_types() {
+        let check = |n: &str| -> Result<Expr, LustError> {
+            let source = format!(
+                r#"
+(let check (fn (n)
+  (if (lt n 0) (error (quote too-small) "below range" n)
+    (if (gt n 9) (error (quote too-big) "above range" n) n))))
+(check {})
+"#,
+                n
+            );
+            run_embedded(&source)
+        };
+        assert_eq!(check("5"), Ok(Expr::Integer(5)));
+        let small = check("(sub 3)").unwrap_err();
+        let big = check("12").unwrap_err();
+        assert_eq!(
+            (small.kind.as_str(), small.message.as_str()),
+            ("too-small", "below range")
+        );
+        assert_eq!(
+            (big.kind.as_str(), big.message.as_str()),
+            ("too-big", "above range")
+        );
+
+        assert_eq!(run_embedded("(error \"x\")").unwrap_err().kind, "error");
+        assert_eq!(run_embedded("(car 1)").unwrap_err().kind, "type-error");
+        assert_eq!(
+            run_embedded("(error 1 \"bad\" 2)").unwrap_err().kind,
+            "type-error"
+        );
+    }
+}
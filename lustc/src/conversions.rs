@@ -16,6 +16,10 @@ pub(crate) static BOOL_TAG: Word = 0b0011111;
 
 pub(crate) static NIL_VALUE: Word = 0b00101111;
 
+/// What a trampolined tail call returns in place of a value. No value
+/// has this representation. See `tail.rs`.
+pub(crate) static BOUNCE_VALUE: Word = 0b00111111;
+
 /// Values on the heap use their last three bits (values 0..7) to
 /// store their type tag. The tag mask extracts that tag value.
 pub(crate) static HEAP_TAG_MASK: Word = 0b111;
@@ -26,9 +30,47 @@ pub(crate) static HEAP_PTR_MASK: Word = !HEAP_TAG_MASK;
 /// Tag for a cons object
 pub(crate) static PAIR_TAG: Word = 0b001;
 
+/// A list of one or two small integers packed into a word when
+/// `CompileOptions::pack_lists` is set. The tag is the last four bits
+/// of the word. See `packed.rs`.
+pub(crate) static PACKED_LIST_TAG: Word = 0b0111;
+pub(crate) static PACKED_LIST_MASK: Word = 0b1111;
+
 /// Tag for a closure object
 pub(crate) static CLOSURE_TAG: Word = 0b110;
 
+/// Tag for an interned symbol
+pub(crate) static SYMBOL_TAG: Word = 0b101;
+
+/// Tag for a vector. See `vectors.rs`.
+pub(crate) static VECTOR_TAG: Word = 0b010;
+
+/// A vector slice shares VECTOR_TAG with vectors and is told apart from
+/// them by its first word, which is VECTOR_SLICE_HEADER where a
+/// vector's is its length. See `vectors.rs`.
+pub(crate) static VECTOR_SLICE_HEADER: Word = -1;
+
+/// Tag for a tuple of multiple values
+pub(crate) static VALUES_TAG: Word = 0b011;
+
+/// Conditions share VALUES_TAG with tuples and are told apart from
+/// them by their first word, which is CONDITION_HEADER where a tuple's
+/// is its length. See `conditions.rs`.
+pub(crate) static CONDITION_HEADER: Word = -2;
+
+/// And so do priority queues. See `priority.rs`.
+pub(crate) static PRIORITY_QUEUE_HEADER: Word = -3;
+
+/// And string builders. See `stringbuilder.rs`.
+pub(crate) static STRING_BUILDER_HEADER: Word = -4;
+
+/// And records. See `records.rs`.
+pub(crate) static RECORD_HEADER: Word = -5;
+
+/// The smallest and largest integers that fit in a fixnum.
+pub(crate) static FIXNUM_MIN: Word = Word::MIN >> 2;
+pub(crate) static FIXNUM_MAX: Word = Word::MAX >> 2;
+
 pub fn word_is_char(what: Word) -> bool {
     what & CHAR_MASK == CHAR_TAG
 }
@@ -46,11 +88,61 @@ pub fn word_is_nil(what: Word) -> bool {
 }
 
 pub fn word_is_pair(what: Word) -> bool {
-    what & HEAP_TAG_MASK == PAIR_TAG
+    what & HEAP_TAG_MASK == PAIR_TAG || crate::packed::word_is_packed_list(what)
+}
+
+pub fn word_is_symbol(what: Word) -> bool {
+    what & HEAP_TAG_MASK == SYMBOL_TAG
+}
+
+pub fn word_is_vector(what: Word) -> bool {
+    what & HEAP_TAG_MASK == VECTOR_TAG
+}
+
+pub fn word_is_closure(what: Word) -> bool {
+    what & HEAP_TAG_MASK == CLOSURE_TAG
+}
+
+/// Returns the first word of the object that WHAT, which is tagged
+/// with VALUES_TAG, points to.
+fn values_header(what: Word) -> Word {
+    unsafe { *((what & HEAP_PTR_MASK) as *const Word) }
+}
+
+pub fn word_is_values(what: Word) -> bool {
+    what & HEAP_TAG_MASK == VALUES_TAG && values_header(what) >= 0
+}
+
+pub fn word_is_condition(what: Word) -> bool {
+    what & HEAP_TAG_MASK == VALUES_TAG && values_header(what) == CONDITION_HEADER
+}
+
+pub fn word_is_priority_queue(what: Word) -> bool {
+    what & HEAP_TAG_MASK == VALUES_TAG && values_header(what) == PRIORITY_QUEUE_HEADER
+}
+
+pub fn word_is_string_builder(what: Word) -> bool {
+    what & HEAP_TAG_MASK == VALUES_TAG && values_header(what) == STRING_BUILDER_HEADER
+}
+
+pub fn word_is_record(what: Word) -> bool {
+    what & HEAP_TAG_MASK == VALUES_TAG && values_header(what) == RECORD_HEADER
+}
+
+/// Returns true if I can be stored in a fixnum. Integers are always
+/// fixnums so programs with integers that can't are rejected.
+pub fn integer_fits_fixnum(i: Word) -> bool {
+    (FIXNUM_MIN..=FIXNUM_MAX).contains(&i)
+}
+
+/// Wraps I into the range of a fixnum the same way that arithmetic
+/// on fixnums wraps at runtime.
+pub(crate) fn wrap_fixnum(i: Word) -> Word {
+    (i << FIXNUM_SHIFT) >> FIXNUM_SHIFT
 }
 
 pub fn word_is_object(what: Word) -> bool {
-    word_is_pair(what)
+    what & HEAP_TAG_MASK == PAIR_TAG
 }
 
 pub fn word_is_immediate(what: Word) -> bool {
@@ -59,6 +151,13 @@ pub fn word_is_immediate(what: Word) -> bool {
         || word_is_bool(what)
         || word_is_nil(what)
         || word_is_pair(what)
+        || word_is_symbol(what)
+        || word_is_values(what)
+        || word_is_condition(what)
+        || word_is_priority_queue(what)
+        || word_is_string_builder(what)
+        || word_is_record(what)
+        || word_is_vector(what)
 }
 
 pub fn word_get_object_address(what: Word) -> UWord {
@@ -66,29 +165,45 @@ pub fn word_get_object_address(what: Word) -> UWord {
     (what & HEAP_PTR_MASK) as UWord
 }
 
+/// Returns the list of the elements of LIST. The pairs are made from
+/// the last one back rather than recursively so that long lists, like
+/// the strings that captured output becomes, don't run out of stack.
 pub fn list_to_immediate(list: &[Expr]) -> Word {
-    if let Some(e) = list.first() {
+    list.iter().rev().fold(NIL_VALUE, |rest, e| {
         let mut pair = Vec::with_capacity(2);
         pair.push(e.immediate_rep());
-        pair.push(list_to_immediate(&list[1..]));
+        pair.push(rest);
         let ptr_word = pair.as_mut_ptr() as Word;
         std::mem::forget(pair);
         ptr_word | PAIR_TAG
-    } else {
-        NIL_VALUE
+    })
+}
+
+/// Returns the car and cdr of the pair PAIR, which may be packed.
+pub(crate) fn pair_parts(pair: Word) -> (Word, Word) {
+    debug_assert!(word_is_pair(pair));
+    if crate::packed::word_is_packed_list(pair) {
+        return crate::packed::packed_parts(pair);
     }
+    let ptr = (pair & HEAP_PTR_MASK) as *const Word;
+    unsafe { (*ptr, *ptr.add(1)) }
 }
 
 pub fn list_from_immediate(ptr_word: Word) -> Expr {
-    debug_assert_eq!(ptr_word & HEAP_TAG_MASK, PAIR_TAG);
-    let ptr = (ptr_word & HEAP_PTR_MASK) as *mut Word;
-    let slice = unsafe { std::slice::from_raw_parts(ptr, 2) };
-    let first = Expr::from_immediate(slice[0]);
-    let rest = Expr::from_immediate(slice[1]);
+    let (car, cdr) = pair_parts(ptr_word);
+    let first = Expr::from_immediate(car);
+    let rest = Expr::from_immediate(cdr);
 
     Expr::List(vec![first, rest])
 }
 
+/// Returns the list of the values in ELEMENTS.
+fn list_from_elements(elements: &[Word]) -> Expr {
+    elements.iter().rev().fold(Expr::Nil, |cdr, car| {
+        Expr::List(vec![Expr::from_immediate(*car), cdr])
+    })
+}
+
 pub fn string_to_immediate(string: &str) -> Word {
     let chars = string.chars().map(|c| Expr::Char(c)).collect::<Vec<_>>();
     list_to_immediate(&chars)
@@ -96,7 +211,11 @@ pub fn string_to_immediate(string: &str) -> Word {
 
 impl Expr {
     pub fn is_immediate(&self) -> bool {
-        true
+        match self {
+            Expr::Integer(i) => integer_fits_fixnum(*i),
+            Expr::Procedure => false,
+            _ => true,
+        }
     }
 
     pub fn immediate_rep(&self) -> Word {
@@ -107,13 +226,18 @@ impl Expr {
             Expr::Bool(b) => ((*b as Word) << BOOL_SHIFT) | BOOL_TAG,
             Expr::Nil => NIL_VALUE,
             Expr::List(v) => list_to_immediate(v),
-            Expr::Symbol(_) => todo!("symbol immediates unsupported"),
+            Expr::Symbol(s) => crate::symbols::intern(s),
             Expr::String(s) => string_to_immediate(s),
+            // There is no closure to go back to.
+            Expr::Procedure => NIL_VALUE,
         }
     }
 
     pub fn from_immediate(what: Word) -> Expr {
-        debug_assert!(word_is_immediate(what), "expected immediate type");
+        debug_assert!(
+            word_is_immediate(what) || word_is_closure(what),
+            "expected immediate type"
+        );
         match () {
             _ if word_is_pair(what) => list_from_immediate(what),
             _ if word_is_int(what) => Expr::Integer(what >> FIXNUM_SHIFT),
@@ -124,26 +248,111 @@ impl Expr {
                 Expr::Bool(unsafe { std::mem::transmute_copy(&(what >> BOOL_SHIFT)) })
             }
             _ if word_is_nil(what) => Expr::Nil,
+            _ if word_is_symbol(what) => {
+                Expr::Symbol(crate::symbols::symbol_name(what).to_string())
+            }
+            // Conditions are seen as a list of their type, message,
+            // and data.
+            _ if word_is_condition(what) => {
+                let (kind, message, data) = crate::conditions::condition_fields(what);
+                Expr::List(vec![
+                    Expr::from_immediate(kind),
+                    Expr::List(vec![
+                        Expr::from_immediate(message),
+                        Expr::List(vec![Expr::from_immediate(data), Expr::Nil]),
+                    ]),
+                ])
+            }
+            // Priority queues are seen as a list of their elements in
+            // the order they are stored.
+            _ if word_is_priority_queue(what) => {
+                let (count, storage) = crate::priority::queue_elements(what);
+                let elements = unsafe { std::slice::from_raw_parts(storage.add(1), count) };
+                list_from_elements(elements)
+            }
+            // String builders are seen as the string they hold.
+            _ if word_is_string_builder(what) => {
+                let (count, storage) = crate::stringbuilder::builder_chars(what);
+                let elements = unsafe { std::slice::from_raw_parts(storage.add(1), count) };
+                list_from_elements(elements)
+            }
+            // Records are seen as a list of their type followed by
+            // their fields.
+            _ if word_is_record(what) => {
+                let (kind, fields) = crate::records::record_parts(what);
+                Expr::List(vec![
+                    Expr::from_immediate(kind),
+                    Expr::from_immediate(fields),
+                ])
+            }
+            // Vectors are seen as a list of their elements.
+            _ if word_is_vector(what) => {
+                let ptr = (what & HEAP_PTR_MASK) as *const Word;
+                let (ptr, len) = if unsafe { *ptr } == VECTOR_SLICE_HEADER {
+                    unsafe { (*ptr.add(1) as *const Word, *ptr.add(2) as usize) }
+                } else {
+                    (ptr, unsafe { *ptr } as usize)
+                };
+                let elements = unsafe { std::slice::from_raw_parts(ptr.add(1), len) };
+                list_from_elements(elements)
+            }
+            // A tuple of values is seen as its first value.
+            _ if word_is_values(what) => {
+                let ptr = (what & HEAP_PTR_MASK) as *const Word;
+                match unsafe { *ptr } {
+                    0 => Expr::Nil,
+                    _ => Expr::from_immediate(unsafe { *ptr.add(1) }),
+                }
+            }
+            _ if word_is_closure(what) => Expr::Procedure,
             _ => Expr::Nil,
         }
     }
 }
 
+/// Returns the name of the type of WHAT. The types are told apart in
+/// the same order as `Expr::from_immediate` tells them apart so that a
+/// new type needs adding to both. Strings are lists of characters so
+/// a non-empty one is a pair.
+pub(crate) fn type_name(what: Word) -> &'static str {
+    match () {
+        _ if word_is_pair(what) => "pair",
+        _ if word_is_int(what) => "integer",
+        _ if word_is_char(what) => "char",
+        _ if word_is_bool(what) => "bool",
+        _ if word_is_nil(what) => "nil",
+        _ if word_is_symbol(what) => "symbol",
+        _ if word_is_condition(what) => "condition",
+        _ if word_is_priority_queue(what) => "heap",
+        _ if word_is_string_builder(what) => "string-builder",
+        _ if word_is_record(what) => "record",
+        _ if word_is_vector(what) => "vector",
+        _ if word_is_values(what) => "values",
+        _ if word_is_closure(what) => "closure",
+        _ => "unknown",
+    }
+}
+
+/// Implements (type-of x).
+pub(crate) extern "C" fn lustc_type_of(word: Word) -> Word {
+    crate::symbols::intern(type_name(word))
+}
+
 pub extern "C" fn print_lustc_word(word: Word) -> Word {
     let expr = Expr::from_immediate(word);
-    print!("{}", expr);
+    crate::output::write_output(&expr.to_string());
     Expr::Nil.immediate_rep()
 }
 
 pub extern "C" fn println_lustc_word(word: Word) -> Word {
     let expr = Expr::from_immediate(word);
-    println!("{}", expr);
+    crate::output::write_output(&format!("{}\n", expr));
     Expr::Nil.immediate_rep()
 }
 
 /// Tries to convert E into a string. E is convertable into a string
 /// if it is a well formed list that contains only characters.
-fn try_stringify_list(e: &Expr) -> Option<String> {
+pub(crate) fn try_stringify_list(e: &Expr) -> Option<String> {
     let l = match e {
         Expr::List(l) => l,
         _ => return None,
@@ -165,38 +374,26 @@ fn try_stringify_list(e: &Expr) -> Option<String> {
     Some(format!("{}{}", c, r))
 }
 
-fn list_is_well_formed(l: &[Expr]) -> bool {
-    match &l[1] {
-        Expr::Nil => true,
-        Expr::List(l) => list_is_well_formed(&l),
-        _ => false,
-    }
-}
-
-fn stringify_well_formed_list(e: &Expr) -> String {
-    match e {
-        Expr::List(l) => match &l[1] {
-            Expr::Nil => format!("{}", l[0]),
-            _ => format!("{}, {}", l[0], stringify_well_formed_list(&l[1])),
-        },
-        _ => {
-            debug_assert!(false, "list is not well formed");
-            format!("{}", e)
+/// Writes the list whose first pair is L. Proper lists are written
+/// as (1 2 3). If the list ends in something other than nil the last
+/// item is written in dotted pair notation as in (1 2 . 3).
+fn write_list(f: &mut fmt::Formatter<'_>, l: &[Expr]) -> fmt::Result {
+    write!(f, "({}", l[0])?;
+    let mut rest = &l[1];
+    loop {
+        match rest {
+            Expr::Nil => break,
+            Expr::List(l) => {
+                write!(f, " {}", l[0])?;
+                rest = &l[1];
+            }
+            e => {
+                write!(f, " . {}", e)?;
+                break;
+            }
         }
     }
-}
-
-fn try_prettify_list(e: &Expr) -> Option<String> {
-    let l = match e {
-        Expr::List(l) => l,
-        _ => return None,
-    };
-
-    if !list_is_well_formed(&l) {
-        return None;
-    }
-
-    Some(format!("({})", stringify_well_formed_list(e)))
+    write!(f, ")")
 }
 
 impl fmt::Display for Expr {
@@ -208,14 +405,12 @@ impl fmt::Display for Expr {
             Expr::Nil => write!(f, "nil"),
             Expr::List(l) => match try_stringify_list(self) {
                 Some(s) => write!(f, "\"{}\"", s),
-                None => match try_prettify_list(self) {
-                    Some(s) => write!(f, "{}", s),
-                    None => write!(f, "({}, {})", l[0], l[1]),
-                },
+                None => write_list(f, l),
             },
             // sbcl capitalizes symbols when writing them out to stdout.
             Expr::Symbol(s) => write!(f, "{}", s.to_uppercase()),
             Expr::String(s) => write!(f, "{}", s),
+            Expr::Procedure => write!(f, "#<procedure>"),
         }
     }
 }
@@ -235,6 +430,54 @@ mod tests {
         }
     }
 
+    #[test]
+    fn roundtrip_large_int() {
+        for i in [FIXNUM_MAX, FIXNUM_MIN] {
+            assert_eq!(
+                Expr::from_immediate(Expr::Integer(i).immediate_rep()),
+                Expr::Integer(i)
+            );
+            test_roundtrip(Expr::Integer(i));
+        }
+        assert_eq!(
+            crate::roundtrip_string("(quote 2305843009213693951)"),
+            Ok(Expr::Integer(FIXNUM_MAX))
+        );
+        assert_eq!(
+            crate::roundtrip_string("(quote (-2305843009213693952))"),
+            Ok(Expr::List(vec![Expr::Integer(FIXNUM_MIN), Expr::Nil]))
+        );
+        assert_eq!(
+            crate::roundtrip_string("(sub -2305843009213693952 1)"),
+            Ok(Expr::Integer(FIXNUM_MAX))
+        );
+
+        // Integers that don't fit in a fixnum are rejected rather
+        // than wrapped.
+        for source in [
+            "2305843009213693952",
+            "(quote 2305843009213693952)",
+            "(quote (1 -2305843009213693953))",
+        ] {
+            assert!(matches!(
+                crate::parse_string(source).unwrap_err(),
+                crate::parser::ParseError::IntegerOutOfRange { .. }
+            ));
+        }
+        for e in [
+            Expr::Integer(FIXNUM_MAX + 1),
+            Expr::List(vec![
+                Expr::Symbol("quote".to_string()),
+                Expr::List(vec![Expr::Integer(FIXNUM_MAX + 1), Expr::Nil]),
+            ]),
+        ] {
+            assert_eq!(
+                crate::compiler::roundtrip_program(&mut [e]).unwrap_err(),
+                format!("the integer {} doesn't fit in a fixnum", FIXNUM_MAX + 1)
+            );
+        }
+    }
+
     #[test]
     fn roundtrip_bool() {
         test_roundtrip(Expr::Bool(false));
@@ -254,17 +497,40 @@ mod tests {
 
     #[test]
     fn list_well_formed() {
-        let good = vec![
+        let good = Expr::List(vec![
             Expr::Char('a'),
             Expr::List(vec![Expr::Integer(10), Expr::Nil]),
-        ];
-        let bad = vec![
+        ]);
+        let bad = Expr::List(vec![
             Expr::Char('a'),
             Expr::List(vec![Expr::Integer(10), Expr::Integer(11)]),
-        ];
+        ]);
 
-        assert!(list_is_well_formed(&good));
-        assert!(!list_is_well_formed(&bad));
+        assert_eq!(good.to_string(), "('a' 10)");
+        assert_eq!(bad.to_string(), "('a' 10 . 11)");
+    }
+
+    #[test]
+    fn display_lists() {
+        let pair = Expr::List(vec![Expr::Integer(1), Expr::Integer(2)]);
+        assert_eq!(pair.to_string(), "(1 . 2)");
+
+        let partial = Expr::List(vec![
+            Expr::Integer(1),
+            Expr::List(vec![Expr::Integer(2), Expr::Integer(3)]),
+        ]);
+        assert_eq!(partial.to_string(), "(1 2 . 3)");
+
+        let proper = Expr::List(vec![Expr::Integer(1), Expr::List(vec![pair, Expr::Nil])]);
+        assert_eq!(proper.to_string(), "(1 (1 . 2))");
+    }
+
+    #[test]
+    fn display_compiled_lists() {
+        let res = crate::roundtrip_string("(cons 1 (cons 2 3))").unwrap();
+        assert_eq!(res.to_string(), "(1 2 . 3)");
+        let res = crate::roundtrip_string("(cons \"a\" (cons (integer->char 98) ()))").unwrap();
+        assert_eq!(res.to_string(), "(\"a\" 'b')");
     }
 
     #[test]
@@ -289,4 +555,28 @@ mod tests {
             ])
         )
     }
+
+    #[test]
+    fn type_of() {
+        let source = r#"
+(let types (fn (l) (if (null? l) () (cons (type-of (car l)) (types (cdr l))))))
+(let big -2305843009213693952)
+(define-record point (x y))
+(let f type-of)
+(let xs (cons (make-point 1 2) (cons (make-condition (quote e) "m" 1) ())))
+(let xs (cons "ab" (cons types xs)))
+(let xs (cons () (cons (quote a) (cons (quote (1 2)) xs))))
+(let xs (cons 1 (cons big (cons (integer->char 97) (cons (eq 1 1) xs)))))
+(cons (types xs) (f car))
+"#;
+        let expected = crate::roundtrip_string(
+            "(cons (quote (integer integer char bool nil symbol pair pair closure record condition)) (quote closure))",
+        )
+        .unwrap();
+        assert_eq!(crate::roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&crate::parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+    }
 }
@@ -0,0 +1,263 @@
+//! Counts how many times each part of a program runs. When
+//! `CompileOptions::coverage` is set every top level form and both
+//! arms of every if get a counter that is incremented each time they
+//! run. After the program has run `JIT::coverage_counts` returns the
+//! counts, which makes branches that never ran easy to find.
+//!
+//! cond and the other forms that desugar into ifs are counted through
+//! the ifs they become and an if whose condition is known at compile
+//! time has already been folded away by the time the program is
+//! instrumented. Only the locations of top level forms are kept so an
+//! arm is known by the form it is in and the position of its if among
+//! the ifs in that form, first to last in the order they appear.
+//!
+//! Arms are instrumented by wrapping them with a counting form after
+//! self tail calls have been marked, so counting doesn't change which
+//! calls are tail calls or anything else about what the program does.
+//!
+//! ```lisp
+//! (if c a b) => (if c (__anon_coverage_count 0 a) (__anon_coverage_count 1 b))
+//! ```
+
+use cranelift::prelude::*;
+use cranelift_module::{Linkage, Module};
+
+use crate::compiler::{emit_expr, Context, JIT};
+use crate::data::LustData;
+use crate::location::Location;
+use crate::{Expr, PreorderStatus, Word};
+
+/// The name of the form that arms are wrapped with.
+const COVERAGE_COUNT: &str = "__anon_coverage_count";
+
+/// What a counter counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverageKind {
+    /// The top level form itself.
+    Form,
+    /// The true arm of the form's Nth if.
+    Then(usize),
+    /// The false arm of the form's Nth if.
+    Else(usize),
+}
+
+/// A part of a program that has a counter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoveragePoint {
+    /// The index of the top level form the point is in.
+    pub form: usize,
+    pub kind: CoverageKind,
+}
+
+/// The counters of the programs a JIT has compiled.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// What each counter counts. Counter N counts POINTS[N].
+    pub points: Vec<CoveragePoint>,
+}
+
+fn counter_name(counter: usize) -> String {
+    format!("__anon_data_coverage_{}", counter)
+}
+
+impl Expr {
+    /// Determines if the expression is a counted arm and if it is
+    /// returns its counter and the arm.
+    pub(crate) fn is_coverage_count(&self) -> Option<(usize, &Expr)> {
+        if let Expr::List(v) = self {
+            if let [Expr::Symbol(s), Expr::Integer(counter), arm] = v.as_slice() {
+                if s == COVERAGE_COUNT {
+                    return Some((*counter as usize, arm));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Wraps each arm of every if in PROGRAM with its counter and defines
+/// the counters of PROGRAM in JIT. FIRST_FORM is the index of
+/// PROGRAM's first form in the source it came from. Returns the
+/// counter of each of PROGRAM's forms.
+pub(crate) fn instrument(
+    program: &mut [Expr],
+    first_form: usize,
+    jit: &mut JIT,
+) -> Result<Vec<usize>, String> {
+    let _t = crate::timer::timeit("coverage instrumentation");
+    let points = &mut jit.coverage.points;
+    let first_counter = points.len();
+    let mut form_counters = Vec::with_capacity(program.len());
+    for (form, e) in program.iter_mut().enumerate() {
+        let form = first_form + form;
+        form_counters.push(points.len());
+        points.push(CoveragePoint {
+            form,
+            kind: CoverageKind::Form,
+        });
+        let mut ifs = 0;
+        e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if e.is_conditional().is_none() {
+                return PreorderStatus::Continue;
+            }
+            if let Expr::List(v) = e {
+                for (arm, kind) in [(2, CoverageKind::Then(ifs)), (3, CoverageKind::Else(ifs))] {
+                    let counter = Expr::Integer(points.len() as i64);
+                    points.push(CoveragePoint { form, kind });
+                    let body = std::mem::replace(&mut v[arm], Expr::Nil);
+                    v[arm] = Expr::List(vec![
+                        Expr::Symbol(COVERAGE_COUNT.to_string()),
+                        counter,
+                        body,
+                    ]);
+                }
+            }
+            ifs += 1;
+            PreorderStatus::Continue
+        });
+    }
+
+    let counters = (first_counter..jit.coverage.points.len())
+        .map(|counter| LustData {
+            name: counter_name(counter),
+            data: 0,
+            align: None,
+        })
+        .collect();
+    crate::data::create_data(counters, jit)?;
+    Ok(form_counters)
+}
+
+/// Emits the code to increment COUNTER.
+pub(crate) fn emit_count(counter: usize, ctx: &mut Context) -> Result<(), String> {
+    let sym = ctx
+        .module
+        .declare_data(&counter_name(counter), Linkage::Export, true, false)
+        .map_err(|e| e.to_string())?;
+    let local_id = ctx.module.declare_data_in_func(sym, ctx.builder.func);
+    let ptr = ctx.builder.ins().symbol_value(ctx.word, local_id);
+    let count = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+    let count = ctx.builder.ins().iadd_imm(count, 1);
+    ctx.builder.ins().store(MemFlags::new(), count, ptr, 0);
+    Ok(())
+}
+
+/// Emits the code for an arm wrapped with COUNTER.
+pub(crate) fn emit_coverage_count(
+    counter: usize,
+    arm: &Expr,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    emit_count(counter, ctx)?;
+    emit_expr(arm, ctx)
+}
+
+impl JIT {
+    /// Returns each coverage point of the programs the JIT has
+    /// compiled and the number of times it has run.
+    pub fn coverage_counts(&mut self) -> Vec<(CoveragePoint, u64)> {
+        let points = self.coverage.points.clone();
+        points
+            .into_iter()
+            .enumerate()
+            .map(|(counter, point)| {
+                let id = self
+                    .module
+                    .declare_data(&counter_name(counter), Linkage::Export, true, false)
+                    .unwrap();
+                let (ptr, _) = self.module.get_finalized_data(id);
+                (point, unsafe { *(ptr as *const Word) } as u64)
+            })
+            .collect()
+    }
+}
+
+/// Returns a report with a line for each of COUNTS giving the
+/// location of its form, what it counts, and how many times it ran.
+/// LOCATIONS are the locations of the program's top level forms as
+/// returned by `parse_string_with_locations`.
+pub fn coverage_report(counts: &[(CoveragePoint, u64)], locations: &[Location]) -> String {
+    let mut report = String::new();
+    for (point, count) in counts {
+        let at = match locations.get(point.form) {
+            Some(l) => format!("{}:{}", l.start.line, l.start.col),
+            None => format!("form {}", point.form),
+        };
+        let what = match point.kind {
+            CoverageKind::Form => "form".to_string(),
+            CoverageKind::Then(n) => format!("if {} then", n),
+            CoverageKind::Else(n) => format!("if {} else", n),
+        };
+        report.push_str(&format!("{} {} {}\n", at, what, count));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_program, CompileOptions};
+    use crate::parse_string_with_locations;
+
+    fn run(source: &str, coverage: bool) -> (Expr, JIT) {
+        let (mut program, _) = parse_string_with_locations(source).unwrap();
+        let mut jit = JIT::new(CompileOptions {
+            coverage,
+            ..Default::default()
+        });
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        let res = Expr::from_immediate(jit.invoke(id).unwrap());
+        (res, jit)
+    }
+
+    #[test]
+    fn counts_branches() {
+        let source = r#"
+(let sign (fn (n) (if (lt n 0) (quote negative) (quote positive))))
+(let count (fn (n acc) (if (eq n 0) acc (count (sub n 1) (cons (sign n) acc)))))
+(count 3 ())
+"#;
+        let (res, mut jit) = run(source, true);
+        assert_eq!(res, run(source, false).0);
+        let counts = jit.coverage_counts();
+        let count_of = |form, kind| {
+            counts
+                .iter()
+                .find(|(p, _)| *p == CoveragePoint { form, kind })
+                .unwrap()
+                .1
+        };
+        // sign is never called with a negative number.
+        assert_eq!(count_of(0, CoverageKind::Then(0)), 0);
+        assert_eq!(count_of(0, CoverageKind::Else(0)), 3);
+        assert_eq!(count_of(1, CoverageKind::Then(0)), 1);
+        assert_eq!(count_of(1, CoverageKind::Else(0)), 3);
+        for form in 0..3 {
+            assert_eq!(count_of(form, CoverageKind::Form), 1);
+        }
+
+        let (_, locations) = parse_string_with_locations(source).unwrap();
+        let report = coverage_report(&counts, &locations);
+        assert!(report.contains("1:0 if 0 then 0\n"), "{}", report);
+        assert!(report.contains("1:0 if 0 else 3\n"), "{}", report);
+    }
+
+    #[test]
+    fn tail_calls_stay_loops() {
+        // Instrumenting count's arms mustn't cost it its tail call.
+        let source = "(let count (fn (n) (if (eq n 0) 0 (count (sub n 1))))) (count 1000000)";
+        let (res, mut jit) = run(source, true);
+        assert_eq!(res, Expr::Integer(0));
+        let counts = jit.coverage_counts();
+        assert!(counts.contains(&(
+            CoveragePoint {
+                form: 0,
+                kind: CoverageKind::Else(0)
+            },
+            1000000
+        )));
+    }
+}
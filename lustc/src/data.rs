@@ -2,6 +2,8 @@
 //! time. Programs can then access them directly instead of needing to
 //! do any work themselves. Herein lies the code for that.
 
+use std::collections::HashMap;
+
 use crate::compiler::{Context, JIT};
 use crate::Expr;
 use crate::PreorderStatus;
@@ -14,12 +16,23 @@ impl Expr {
     /// A value is a complex constant if it appears inside of a quote
     /// expression. In that case we construct its value at compile time
     /// and store it in the programs data.
+    ///
+    /// Only the word for the value goes in the data section. The pairs
+    /// it points to are made on the host's heap by `immediate_rep` and
+    /// never freed, and a string inside of a quoted list is made there
+    /// as a list of characters like the rest of the datum is.
     pub fn is_complex_const(&self) -> Option<Word> {
+        self.complex_const_value().map(|e| e.immediate_rep())
+    }
+
+    /// Returns the expression whose value is stored in the program's
+    /// data if this is a complex constant.
+    fn complex_const_value(&self) -> Option<&Expr> {
         match self {
             Expr::List(v) => {
                 if let Some(Expr::Symbol(s)) = v.first() {
                     if s == "quote" && v.len() == 2 {
-                        Some(v[1].immediate_rep())
+                        Some(&v[1])
                     } else {
                         None
                     }
@@ -27,7 +40,7 @@ impl Expr {
                     None
                 }
             }
-            Expr::String(_) => Some(self.immediate_rep()),
+            Expr::String(_) => Some(self),
             _ => None,
         }
     }
@@ -43,47 +56,80 @@ pub struct LustData {
     /// Expr::to_immediate. Ownership of this data is given to the
     /// program.
     pub data: Word,
+    /// The alignment of the data in bytes. Must be a power of two. If
+    /// this is None the module's default alignment is used.
+    pub align: Option<u64>,
 }
 
-fn collect_data_w_count(program: &[Expr], count: &mut usize) -> Vec<LustData> {
-    let mut res = Vec::new();
+/// The constants that have been collected so far and their indexes.
+/// Constants whose value is a word that doesn't point at anything are
+/// found by that word, which compares in one instruction. The rest are
+/// found by hashing them so each is only compared in depth with the
+/// constants that hash the same rather than with every one before it.
+#[derive(Default)]
+struct Seen {
+    words: HashMap<Word, usize>,
+    constants: HashMap<Expr, usize>,
+}
 
-    for e in program {
-        e.preorder_traverse(&mut |e: &Expr| {
-            if let Some((_, args)) = e.is_foreign_call() {
-                res.extend(collect_data_w_count(args, count));
-                return PreorderStatus::Skip;
-            } else if let Some(repr) = e.is_complex_const() {
-                res.push(LustData {
-                    name: format!("__anon_data_{}", count),
-                    data: repr,
-                });
-                *count += 1;
-            }
-            PreorderStatus::Continue
-        });
+impl Seen {
+    fn len(&self) -> usize {
+        self.words.len() + self.constants.len()
     }
+}
 
-    res
+/// Returns the value of VALUE if it is a word that doesn't point at
+/// anything. Strings are left out as the empty one is the same word
+/// as nil.
+fn immediate_word(value: &Expr) -> Option<Word> {
+    match value {
+        Expr::Integer(_) | Expr::Char(_) | Expr::Bool(_) | Expr::Nil | Expr::Symbol(_) => {
+            Some(value.immediate_rep())
+        }
+        _ => None,
+    }
 }
 
-/// Collects all of the complex constants in the program and marshals
-/// them into a list.
-pub(crate) fn collect_data(program: &[Expr]) -> Vec<LustData> {
-    let _t = crate::timer::timeit("data collection pass");
-    let mut count = 0;
-    collect_data_w_count(program, &mut count)
+/// Returns the index of VALUE in SEEN, adding it if it hasn't been
+/// seen before.
+fn data_index(value: &Expr, seen: &mut Seen) -> (usize, bool) {
+    let next = seen.len();
+    let index = match immediate_word(value) {
+        Some(word) => *seen.words.entry(word).or_insert(next),
+        None => match seen.constants.get(value) {
+            Some(i) => *i,
+            None => {
+                seen.constants.insert(value.clone(), next);
+                next
+            }
+        },
+    };
+    (index, index == next)
 }
 
-fn replace_data_w_count(program: &mut [Expr], data: &[LustData], count: &mut usize) {
+fn extract_data_w_seen(
+    program: &mut [Expr],
+    first: usize,
+    seen: &mut Seen,
+    data: &mut Vec<LustData>,
+) {
     for e in program {
         e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            // The name of a foreign function is a string but it isn't
+            // data so only the arguments are visited.
             if let Some((_, args)) = e.is_foreign_call_mut() {
-                replace_data_w_count(args, data, count);
+                extract_data_w_seen(args, first, seen, data);
                 return PreorderStatus::Skip;
-            } else if let Some(_) = e.is_complex_const() {
-                *e = Expr::Symbol(data[*count].name.clone());
-                *count += 1;
+            } else if let Some(value) = e.complex_const_value() {
+                let (index, new) = data_index(value, seen);
+                if new {
+                    data.push(LustData {
+                        name: format!("__anon_data_{}", first + index),
+                        data: value.immediate_rep(),
+                        align: None,
+                    });
+                }
+                *e = Expr::Symbol(data[index].name.clone());
             }
             PreorderStatus::Continue
         });
@@ -104,9 +150,25 @@ pub(crate) fn emit_data_access(name: &str, ctx: &mut Context) -> Result<Value, S
         .load(ctx.word, MemFlags::new(), data_ptr, 0))
 }
 
-/// Replaces all of the complex constants in the program with a symbol
-/// that when looked up yields the data that it once represented. For
-/// example, the program:
+/// Emits the code to store VAL in the data named NAME.
+pub(crate) fn emit_data_store(name: &str, val: Value, ctx: &mut Context) -> Result<(), String> {
+    let sym = ctx
+        .module
+        .declare_data(name, cranelift_module::Linkage::Export, true, false)
+        .map_err(|e| e.to_string())?;
+    let local_id = ctx.module.declare_data_in_func(sym, ctx.builder.func);
+
+    let data_ptr = ctx.builder.ins().symbol_value(ctx.word, local_id);
+    ctx.builder.ins().store(MemFlags::new(), val, data_ptr, 0);
+    Ok(())
+}
+
+/// Collects all of the complex constants in the program and marshals
+/// them into a list. Constants that are equal share one entry so that
+/// each is only defined once no matter how many functions use it.
+///
+/// Each constant is replaced with a symbol that when looked up yields
+/// the data that it once represented. For example, the program:
 ///
 /// ```lisp
 /// (let a (quote (1 2 3)))
@@ -118,33 +180,73 @@ pub(crate) fn emit_data_access(name: &str, ctx: &mut Context) -> Result<Value, S
 /// (let a __anon_data_0)
 /// ```
 ///
-/// by this pass.
-pub(crate) fn replace_data(program: &mut [Expr], data: &[LustData]) {
-    let _t = crate::timer::timeit("data replacement pass");
-    let mut count = 0;
-    replace_data_w_count(program, data, &mut count);
+/// by this pass. Collection and replacement happen in the same walk
+/// over the program so a use can't end up naming some other constant.
+/// Data is numbered from FIRST so that it doesn't clash with the data
+/// of programs compiled earlier.
+pub(crate) fn extract_data(program: &mut [Expr], first: usize) -> Vec<LustData> {
+    let _t = crate::timer::timeit("data extraction pass");
+    let mut data = Vec::new();
+    extract_data_w_seen(program, first, &mut Seen::default(), &mut data);
+    data
 }
 
-/// Gives ownership of DATA to JIT and assocaites its name with its
-/// value internally.
-pub(crate) fn create_data(data: LustData, jit: &mut JIT) -> Result<(), String> {
-    let contents = Box::new(data.data.to_ne_bytes());
-    jit.data_ctx.define(contents);
-    let id = jit
-        .module
-        .declare_data(&data.name, cranelift_module::Linkage::Export, true, false)
-        .map_err(|e| e.to_string())?;
+/// Checks that every name of a piece of data that PROGRAM uses names
+/// data that has been created in JIT. Uses of data are only looked up
+/// when the program is linked so a name that `extract_data` got wrong
+/// would otherwise be a confusing link error or a read of the wrong
+/// memory.
+pub(crate) fn check_data_uses(program: &[Expr], jit: &JIT) -> Result<(), String> {
+    let mut res = Ok(());
+    for e in program {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if let Expr::Symbol(s) = e {
+                let defined = matches!(
+                    jit.module.get_name(s),
+                    Some(cranelift_module::FuncOrDataId::Data(_))
+                );
+                if s.starts_with("__anon_data_") && !defined && res.is_ok() {
+                    res = Err(format!(
+                        "internal error: use of data ({}) which was never created",
+                        s
+                    ));
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+    res
+}
 
-    jit.module
-        .define_data(id, &jit.data_ctx)
-        .map_err(|e| e.to_string())?;
+/// Gives ownership of every entry in DATA to JIT and associates each
+/// name with its value internally. The data can't be read until
+/// `JIT::finalize` has been called. Functions that use the data can be
+/// defined before that so a program's data and functions are finalized
+/// together.
+///
+/// Every entry is defined with the JIT's one data context. It is
+/// cleared before each entry rather than after so that each
+/// definition only ever sees its own contents, even if defining the
+/// one before it failed partway through.
+pub(crate) fn create_data(data: Vec<LustData>, jit: &mut JIT) -> Result<(), String> {
+    for d in data {
+        jit.data_ctx.clear();
+        jit.data_ctx.define(Box::new(d.data.to_ne_bytes()));
+        if let Some(align) = d.align {
+            jit.data_ctx.set_align(align);
+        }
+        let id = jit
+            .module
+            .declare_data(&d.name, cranelift_module::Linkage::Export, true, false)
+            .map_err(|e| e.to_string())?;
 
+        jit.module
+            .define_data(id, &jit.data_ctx)
+            .map_err(|e| e.to_string())?;
+        jit.defined_data.insert(id);
+    }
     jit.data_ctx.clear();
 
-    // NOTE: this is only safe to do so long as data processing comes
-    // before function processing.
-    jit.module.finalize_definitions();
-
     Ok(())
 }
 
@@ -155,6 +257,113 @@ mod tests {
     use crate::roundtrip_file;
     use crate::roundtrip_string;
 
+    #[test]
+    fn deterministic_output() {
+        let source = r#"
+(let xs (quote (1 (2 "three") four)))
+(let f (fn (a b) (fn (c) (cons (cons a b) (cons c xs)))))
+(let g (fn (n) (if (eq n 0) (quote done) (g (sub n 1)))))
+((f 1 (g 3)) (quote (5 6)))
+"#;
+        let compile = || {
+            let mut program = parse_string(source).unwrap();
+            let mut copy = program.clone();
+            crate::desugar::desugar(&mut copy).unwrap();
+            crate::renamer::make_names_unique(&mut copy).unwrap();
+            crate::fold::fold_constants(&mut copy);
+            let names: Vec<_> = extract_data(&mut copy, 0)
+                .into_iter()
+                .map(|d| d.name)
+                .collect();
+
+            let mut jit = JIT::default();
+            crate::compiler::compile_program(&mut jit, &mut program).unwrap();
+            (names, jit.compiled_functions.clone())
+        };
+        let first = compile();
+        assert_eq!(first.0, ["__anon_data_0", "__anon_data_1", "__anon_data_2"]);
+        for _ in 0..4 {
+            assert_eq!(compile(), first);
+        }
+    }
+
+    #[test]
+    fn many_constants_one_finalize() {
+        let cars = (0..100)
+            .map(|i| format!("(car (quote ({} {})))", i, i + 1))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut program = parse_string(&format!("(add {})", cars)).unwrap();
+
+        let mut jit = JIT::default();
+        let before = jit.finalizations;
+        let id = crate::compiler::compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(jit.finalizations - before, 1);
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            Expr::Integer(4950)
+        );
+    }
+
+    #[test]
+    fn many_data_entries_batched() {
+        // Returning the constants from functions keeps them from
+        // being folded away so each one needs its own entry.
+        let n = 50;
+        let functions = (0..n)
+            .map(|i| format!("(let f{} (fn () (quote ({} x{}))))\n", i, i, i))
+            .collect::<String>();
+        let cars = (0..n)
+            .map(|i| format!(" (car (f{}))", i))
+            .collect::<String>();
+        let source = format!(
+            "{}(cons (add{}) (cons (car (cdr (f0))) (car (cdr (f{})))))",
+            functions,
+            cars,
+            n - 1
+        );
+        let mut program = parse_string(&source).unwrap();
+        let mut copy = program.clone();
+        assert_eq!(extract_data(&mut copy, 0).len(), n);
+
+        let mut jit = JIT::default();
+        let before = jit.finalizations;
+        let id = crate::compiler::compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(jit.finalizations - before, 1);
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            roundtrip_string(&format!(
+                "(cons {} (cons (quote x0) (quote x{})))",
+                n * (n - 1) / 2,
+                n - 1
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn many_near_identical_constants() {
+        // Every constant differs from the others only in its last
+        // element and each appears twice.
+        let n = 2000;
+        let prefix = (0..50).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        let constants = (0..2 * n)
+            .map(|i| format!("(quote ({} {}))", prefix, i % n))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut program = parse_string(&constants).unwrap();
+        // Constants that are the same word, and ones that are equal
+        // as values but not as constants, are told apart correctly.
+        program.extend(parse_string("(quote a) (quote a) (quote 1) \"\" (quote ())").unwrap());
+
+        let data = extract_data(&mut program, 0);
+        assert_eq!(data.len(), n + 4);
+        assert_eq!(program[0], program[n]);
+        assert_ne!(program[0], program[n - 1]);
+        assert_eq!(program[2 * n], program[2 * n + 1]);
+        assert_ne!(program[2 * n + 3], program[2 * n + 4]);
+    }
+
     #[test]
     fn test_data_collection() {
         let source = r#"
@@ -162,9 +371,9 @@ mod tests {
 
 (if 1 (quote (1 2)) (quote (2 3)))
 "#;
-        let exprs = parse_string(source).unwrap();
+        let mut exprs = parse_string(source).unwrap();
 
-        let data = collect_data(&exprs);
+        let data = extract_data(&mut exprs, 0);
 
         assert_eq!(data.len(), 3);
 
@@ -177,6 +386,24 @@ mod tests {
         )
     }
 
+    #[test]
+    fn unknown_data_names() {
+        let mut jit = JIT::default();
+        let mut program = parse_string("(let a (quote (1 2))) (cons a (quote (3)))").unwrap();
+        let mut data = extract_data(&mut program, 0);
+        data[1].name = "__anon_data_stale".to_string();
+        create_data(data, &mut jit).unwrap();
+        assert_eq!(
+            check_data_uses(&program, &jit),
+            Err("internal error: use of data (__anon_data_1) which was never created".to_string())
+        );
+        // Names that match the data that was created pass.
+        let mut program = parse_string("(let a (quote (1 2))) (cons a (quote (3)))").unwrap();
+        let data = extract_data(&mut program, 2);
+        create_data(data, &mut jit).unwrap();
+        assert_eq!(check_data_uses(&program, &jit), Ok(()));
+    }
+
     #[test]
     fn test_data() {
         let expected_source = r#"
@@ -186,4 +413,155 @@ mod tests {
         let res = roundtrip_file("examples/data.lisp").unwrap();
         assert_eq!(expected, res)
     }
+
+    #[test]
+    fn test_shared_data() {
+        let source = r#"
+(let a (fn () (quote (1 2 3))))
+(let b (fn () (quote (1 2 3))))
+(let s (fn () "hello"))
+(let t (fn () (quote "hello")))
+(cons (eq (a) (b)) (eq (s) (t)))
+"#;
+        let mut exprs = parse_string(source).unwrap();
+        let data = extract_data(&mut exprs, 0);
+        assert_eq!(data.len(), 2);
+
+        let res = roundtrip_string(source).unwrap();
+        assert_eq!(res, Expr::List(vec![Expr::Bool(true), Expr::Bool(true)]));
+    }
+
+    #[test]
+    fn nested_strings() {
+        // The whole datum is one piece of data and the strings in it
+        // are lists of characters inside of it.
+        let mut program = parse_string(r#"(quote ("a" "b"))"#).unwrap();
+        let data = extract_data(&mut program, 0);
+        assert_eq!(data.len(), 1);
+        let strings = Expr::from_immediate(data[0].data);
+        assert_eq!(strings.to_string(), r#"("a" "b")"#);
+        assert_eq!(roundtrip_string(r#"(quote ("a" "b"))"#).unwrap(), strings);
+
+        // The strings are the same as the literals they were read from
+        // once the program is running.
+        let source = r#"
+(let f (fn () (quote ("a" ("bc" "d")))))
+(list (equal (car (f)) "a")
+      (equal (car (car (cdr (f)))) "bc")
+      (string-append (car (f)) (car (cdr (car (cdr (f)))))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string(r#"(list (eq 1 1) (eq 1 1) "ad")"#).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_foreign_call_data() {
+        let source = r#"
+(let a (quote (1 2)))
+(foreign-call "f" (quote (3)) "s" (foreign-call "g" (quote (1 2)) (quote 4)))
+(cons "s" (quote (3)))
+"#;
+        let mut exprs = parse_string(source).unwrap();
+        let data = extract_data(&mut exprs, 0);
+
+        let expected = r#"
+(let a __anon_data_0)
+(foreign-call "f" __anon_data_1 __anon_data_2 (foreign-call "g" __anon_data_0 __anon_data_3))
+(cons __anon_data_2 __anon_data_1)
+"#;
+        assert_eq!(exprs, parse_string(expected).unwrap());
+
+        let values = ["(1 2)", "(3)", "\"s\"", "4"]
+            .iter()
+            .map(|v| Expr::from_immediate(parse_string(v).unwrap()[0].immediate_rep()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            data.iter()
+                .map(|d| Expr::from_immediate(d.data))
+                .collect::<Vec<_>>(),
+            values
+        );
+    }
+
+    #[test]
+    fn data_defined_after_use() {
+        // Emitting a function that uses data declares the data, like
+        // this does, which can happen before the data is defined.
+        let declare = |jit: &mut JIT, name: &str| {
+            jit.module
+                .declare_data(name, cranelift_module::Linkage::Export, true, false)
+                .unwrap()
+        };
+        let define = |jit: &mut JIT, name: &str, i: i64| {
+            let data = LustData {
+                name: name.to_string(),
+                data: Expr::Integer(i).immediate_rep(),
+                align: None,
+            };
+            create_data(vec![data], jit).unwrap();
+        };
+        let read = |jit: &mut JIT, id| {
+            let (ptr, _) = jit.module.get_finalized_data(id);
+            Expr::from_immediate(unsafe { *(ptr as *const Word) })
+        };
+
+        let mut jit = JIT::default();
+        let id = declare(&mut jit, "late");
+        define(&mut jit, "late", 7);
+        jit.finalize().unwrap();
+        assert_eq!(read(&mut jit, id), Expr::Integer(7));
+
+        // Finalizing with data that was used and never defined is an
+        // error rather than a lookup of the name in the host.
+        let id = declare(&mut jit, "missing");
+        let finalizations = jit.finalizations;
+        assert_eq!(
+            jit.finalize(),
+            Err("internal error: data (missing) is used but was never defined".to_string())
+        );
+        assert_eq!(jit.finalizations, finalizations);
+        define(&mut jit, "missing", 8);
+        jit.finalize().unwrap();
+        assert_eq!(read(&mut jit, id), Expr::Integer(8));
+    }
+
+    #[test]
+    fn test_aligned_data() {
+        let mut jit = JIT::default();
+        // Interleave unaligned entries so that the aligned ones would
+        // land on an 8 byte boundary some of the time if the alignment
+        // was ignored.
+        for i in 0..4 {
+            let unaligned = LustData {
+                name: format!("unaligned_{}", i),
+                data: Expr::Integer(i).immediate_rep(),
+                align: None,
+            };
+            let aligned = LustData {
+                name: format!("aligned_{}", i),
+                data: Expr::Integer(i).immediate_rep(),
+                align: Some(16),
+            };
+            create_data(vec![unaligned, aligned], &mut jit).unwrap();
+            jit.finalize().unwrap();
+
+            let id = jit
+                .module
+                .declare_data(
+                    &format!("aligned_{}", i),
+                    cranelift_module::Linkage::Export,
+                    true,
+                    false,
+                )
+                .unwrap();
+            let (ptr, _) = jit.module.get_finalized_data(id);
+            assert_eq!(ptr as usize % 16, 0);
+            assert_eq!(
+                Expr::from_immediate(unsafe { *(ptr as *const Word) }),
+                Expr::Integer(i)
+            );
+        }
+    }
 }
@@ -0,0 +1,1191 @@
+//! Rewrites forms that are shorthand for other forms into the forms
+//! they stand for so that the rest of the compiler doesn't need to
+//! know about them. This runs before renaming.
+//!
+//! `and` and `or` return the first of their arguments that decides
+//! their result like they do in other lisps. An argument is false if
+//! it is nil or false.
+//!
+//! ```lisp
+//! (and a b) => (if (not (let t a)) t b)
+//! (or a b)  => (if (not (let t a)) b t)
+//! ```
+//!
+//! The calls that desugaring makes go to the builtins even where the
+//! program binds a variable with the same name, so an and inside of a
+//! function with a parameter named not still works. They are written
+//! with `builtin` and the renamer resolves them (see `renamer.rs`).
+//! The forms themselves can't be bound by the program: `(let and ...)`
+//! is an error rather than a variable that calls to and would ignore.
+//!
+//! `cond` tests its clauses the same way that `if` tests its
+//! condition and evaluates to nil if no clause matches.
+//!
+//! ```lisp
+//! (cond ((eq x 1) a) ((eq x 2) b) (else c)) => (if (eq x 1) a (if (eq x 2) b c))
+//! ```
+//!
+//! An `if` without an else branch evaluates to nil when its condition
+//! isn't true, so the rest of the compiler only sees ifs with both.
+//!
+//! ```lisp
+//! (if (eq x 1) a) => (if (eq x 1) a ())
+//! ```
+//!
+//! The composed accessors `caar` through `cddddr` are nested calls to
+//! the builtin `car` and `cdr`, applied from the last letter to the
+//! first.
+//!
+//! ```lisp
+//! (caddr l) => (car (cdr (cdr l)))
+//! ```
+//!
+//! `compose` evaluates each of its functions once and makes a closure
+//! that applies them from right to left.
+//!
+//! ```lisp
+//! (compose f g) => ((fn (f0 f1) (fn (x) (f0 (f1 x)))) f g)
+//! ```
+//!
+//! `if-let` evaluates an expression once and, if it isn't false or
+//! nil, evaluates its then branch with the value bound to a name. The
+//! name isn't bound in the else branch. `when-let` is the same without
+//! an else branch and with a body of any number of expressions.
+//!
+//! ```lisp
+//! (if-let (x e) a b)   => (if (not (let t e)) b ((fn (x) a) t))
+//! (when-let (x e) a b) => (if (not (let t e)) () ((fn (x) a b) t))
+//! ```
+//!
+//! The name is bound by a function rather than a let because a let
+//! stays in scope for the rest of the function it is in. This means
+//! that the body isn't in tail position.
+//!
+//! A comparison with more than two arguments compares each argument
+//! to the one after it and is true if every comparison is. Every
+//! argument is evaluated once from left to right before any of them
+//! are compared and the comparisons stop at the first one that fails.
+//!
+//! ```lisp
+//! (lt a b c) => ((fn (x0 x1 x2) (and (lt x0 x1) (lt x1 x2))) a b c)
+//! ```
+//!
+//! A function body may start with internal defines. Each binds a name
+//! for the rest of the body like a let does, and every one of them is
+//! in scope in all of their values so that they can call each other.
+//! A name used by an earlier define is bound to nil first and then set
+//! where it is defined, the same way mutually recursive functions are
+//! written by hand. A define anywhere else is an error.
+//!
+//! ```lisp
+//! (fn (n)                           (fn (n)
+//!   (define (ev? n) ... (od? n))      (let od? ())
+//!   (define (od? n) ... (ev? n))  =>  (let ev? (fn (n) ... (od? n)))
+//!   (ev? n))                          (set od? (fn (n) ... (ev? n)))
+//!                                     (ev? n))
+//! ```
+//!
+//! `(dotimes (i n) body...)` evaluates N once and then runs BODY with
+//! I bound to each integer from zero up to but not including N,
+//! returning nil. An N of zero or less runs BODY zero times. The loop
+//! is a function that calls itself in tail position so it is compiled
+//! into a jump back to the top of the function with I as its
+//! parameter.
+//!
+//! ```lisp
+//! (dotimes (i n) body...) => ((fn (end)
+//!                               (let loop (fn (i)
+//!                                 body...
+//!                                 (if (lt (add i 1) end) (loop (add i 1)) ())))
+//!                               (if (lt 0 end) (loop 0) ()))
+//!                             n)
+//! ```
+//!
+//! `(letrec* ((name value)...) body...)` binds every name for the
+//! values and the body and evaluates the values from left to right,
+//! giving each name its value as soon as it has been evaluated. A value
+//! can use the values of the names before it and any function can
+//! call any of the names. A name used before its value has been
+//! evaluated is nil. The names are bound like internal defines are,
+//! inside of a function so that they are only in scope in the letrec*.
+//!
+//! ```lisp
+//! (letrec* ((a 1) (b (add a 1))) b) => ((fn () (let a 1) (let b (add a 1)) b))
+//! ```
+//!
+//! `(for (x list) body...)` evaluates BODY with X bound to each
+//! element of LIST from first to last and returns the list of the
+//! values. With more than one generator, as in `(for ((x xs) (y ys))
+//! body...)`, each generator runs through all of its elements for
+//! each element of the one before it, so the result has an element
+//! for each combination in that order and YS can use X. `(when pred)`
+//! clauses before the body leave out the combinations they are false
+//! for.
+//!
+//! ```lisp
+//! (for (x (quote (1 2 3))) (mul x x))                 ; => (1 4 9)
+//! (for ((x (quote (1 2))) (y (quote (a b)))) (cons x y)) ; => ((1 . a) (1 . b) (2 . a) (2 . b))
+//! ```
+//!
+//! A for is a reduce for each generator that conses the values onto
+//! the front of a list, which ends up backwards, and one more that
+//! turns it around.
+//!
+//! ```lisp
+//! (for (x xs) (when p) body) => (reduce (fn (r e) (cons e r)) ()
+//!                                       (reduce (fn (r x) (if p (cons body r) r)) () xs))
+//! ```
+//!
+//! Each top level `define-record` is expanded into the definitions it
+//! stands for before anything else, see `records.rs`. A define-record
+//! anywhere else is an error.
+//!
+//! `try`, `unwind-protect`, `catch` and `throw` are desugared too, see
+//! `exceptions.rs`, `make-parameter` and `parameterize`, see
+//! `parameters.rs`, `quasiquote`, see `quasiquote.rs`, `let-values`,
+//! see `values.rs`, and `with-output-to-string`, see `output.rs`.
+//!
+//! The last argument of an `and` or `or` and the body of every `cond`
+//! clause end up in the same position as the form they came from, so
+//! a call there is still a tail call. So does the then branch of an
+//! if without an else.
+
+use crate::Expr;
+
+/// Names the variable that holds the value of an argument to and or
+/// or while it is being tested. Names starting with __anon_ are
+/// reserved for the compiler so this won't capture one of the
+/// program's variables.
+pub(crate) fn temporary(count: &mut usize) -> Expr {
+    *count += 1;
+    Expr::Symbol(format!("__anon_tmp_{}", *count - 1))
+}
+
+/// The prefix of the names that `builtin` makes.
+pub(crate) const BUILTIN_PREFIX: &str = "__anon_builtin_";
+
+/// Refers to the builtin NAME from code that desugaring makes. Like
+/// the temporaries the name is reserved for the compiler so it can't
+/// be captured by one of the program's variables.
+pub(crate) fn builtin(name: &str) -> Expr {
+    Expr::Symbol(format!("{}{}", BUILTIN_PREFIX, name))
+}
+
+fn sym(s: &str) -> Expr {
+    Expr::Symbol(s.to_string())
+}
+
+fn desugar_and_or(name: &str, args: &[Expr], count: &mut usize) -> Expr {
+    match args {
+        [] => Expr::Bool(name == "and"),
+        [last] => last.clone(),
+        [first, rest @ ..] => {
+            let tmp = temporary(count);
+            let rest = desugar_and_or(name, rest, count);
+            let test = Expr::List(vec![
+                builtin("not"),
+                Expr::List(vec![sym("let"), tmp.clone(), first.clone()]),
+            ]);
+            let (then, else_) = if name == "and" {
+                (tmp, rest)
+            } else {
+                (rest, tmp)
+            };
+            Expr::List(vec![sym("if"), test, then, else_])
+        }
+    }
+}
+
+fn desugar_cond(clauses: &[Expr]) -> Result<Expr, String> {
+    let (clause, rest) = match clauses.split_first() {
+        Some(c) => c,
+        None => return Ok(Expr::Nil),
+    };
+    match clause {
+        Expr::List(v) if v.len() == 2 => {
+            if v[0] == sym("else") {
+                if rest.is_empty() {
+                    Ok(v[1].clone())
+                } else {
+                    Err("cond has clauses after its else clause".to_string())
+                }
+            } else {
+                Ok(Expr::List(vec![
+                    sym("if"),
+                    v[0].clone(),
+                    v[1].clone(),
+                    desugar_cond(rest)?,
+                ]))
+            }
+        }
+        _ => Err(format!(
+            "cond clause ({:?}) should be a test and an expression",
+            clause
+        )),
+    }
+}
+
+fn desugar_if_let(name: &str, args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (binding, rest) = match args.split_first() {
+        Some((Expr::List(binding), rest)) if binding.len() == 2 => (binding, rest),
+        _ => {
+            return Err(format!(
+                "{} expects a binding like (name value) and got ({:?})",
+                name,
+                args.first()
+            ))
+        }
+    };
+    let var = match &binding[0] {
+        Expr::Symbol(_) => binding[0].clone(),
+        _ => {
+            return Err(format!(
+                "{} binding name ({:?}) should be a symbol",
+                name, binding[0]
+            ))
+        }
+    };
+    let (body, else_) = match (name, rest) {
+        ("if-let", [_, else_]) => (&rest[..1], else_.clone()),
+        ("if-let", _) => {
+            return Err(format!(
+                "if-let expects a then and an else branch and got {} expressions",
+                rest.len()
+            ))
+        }
+        (_, []) => return Err("when-let expects at least one body expression".to_string()),
+        (_, body) => (body, Expr::Nil),
+    };
+    let tmp = temporary(count);
+    let test = Expr::List(vec![
+        builtin("not"),
+        Expr::List(vec![sym("let"), tmp.clone(), binding[1].clone()]),
+    ]);
+    let closure = vec![sym("fn"), Expr::List(vec![var])]
+        .into_iter()
+        .chain(body.iter().cloned())
+        .collect();
+    let then = Expr::List(vec![Expr::List(closure), tmp]);
+    Ok(Expr::List(vec![sym("if"), test, else_, then]))
+}
+
+/// Returns true if NAME is a comparison that can be chained.
+fn is_chainable_comparison(name: &str) -> bool {
+    let name = crate::primitives::primitive_alias(name).unwrap_or(name);
+    matches!(name, "lt" | "gt" | "eq")
+}
+
+fn desugar_chained_comparison(name: &str, args: &[Expr], count: &mut usize) -> Expr {
+    // Like compose the arguments are evaluated outside of the
+    // function so its parameter names can't capture anything.
+    let params = (0..args.len())
+        .map(|i| Expr::Symbol(format!("x{}", i)))
+        .collect::<Vec<_>>();
+    let comparisons = params
+        .windows(2)
+        .map(|w| Expr::List(vec![sym(name), w[0].clone(), w[1].clone()]))
+        .collect::<Vec<_>>();
+    let body = desugar_and_or("and", &comparisons, count);
+    let compare = Expr::List(vec![sym("fn"), Expr::List(params), body]);
+    Expr::List(
+        std::iter::once(compare)
+            .chain(args.iter().cloned())
+            .collect(),
+    )
+}
+
+fn desugar_dotimes(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (var, n, body) = match args.split_first() {
+        Some((Expr::List(binding), body)) => match binding.as_slice() {
+            [var @ Expr::Symbol(_), n] => (var, n, body),
+            _ => {
+                return Err(format!(
+                    "dotimes binding ({:?}) should be a name and a count",
+                    binding
+                ))
+            }
+        },
+        _ => return Err("dotimes expects a binding like (name count)".to_string()),
+    };
+    let end = temporary(count);
+    let looper = temporary(count);
+    let next = Expr::List(vec![builtin("add"), var.clone(), Expr::Integer(1)]);
+    let again = Expr::List(vec![
+        sym("if"),
+        Expr::List(vec![builtin("lt"), next.clone(), end.clone()]),
+        Expr::List(vec![looper.clone(), next]),
+        Expr::Nil,
+    ]);
+    let step = vec![sym("fn"), Expr::List(vec![var.clone()])]
+        .into_iter()
+        .chain(body.iter().cloned())
+        .chain(std::iter::once(again))
+        .collect();
+    let start = Expr::List(vec![
+        sym("if"),
+        Expr::List(vec![builtin("lt"), Expr::Integer(0), end.clone()]),
+        Expr::List(vec![looper.clone(), Expr::Integer(0)]),
+        Expr::Nil,
+    ]);
+    let run = Expr::List(vec![
+        sym("fn"),
+        Expr::List(vec![end]),
+        Expr::List(vec![sym("let"), looper, Expr::List(step)]),
+        start,
+    ]);
+    Ok(Expr::List(vec![run, n.clone()]))
+}
+
+/// If E is an internal define returns the name it binds and its
+/// value.
+fn internal_define(e: &Expr) -> Result<Option<(Expr, Expr)>, String> {
+    let v = match e {
+        Expr::List(v) if v[0] == sym("define") => v,
+        _ => return Ok(None),
+    };
+    match &v[1..] {
+        [name @ Expr::Symbol(_), value] => Ok(Some((name.clone(), value.clone()))),
+        [Expr::List(signature), body @ ..] if !body.is_empty() => match signature.split_first() {
+            Some((name @ Expr::Symbol(_), params)) => {
+                // An empty parameter list is nil rather than an empty
+                // list.
+                let params = if params.is_empty() {
+                    Expr::Nil
+                } else {
+                    Expr::List(params.to_vec())
+                };
+                let value = vec![sym("fn"), params]
+                    .into_iter()
+                    .chain(body.iter().cloned())
+                    .collect();
+                Ok(Some((name.clone(), Expr::List(value))))
+            }
+            _ => Err(format!("define name ({:?}) should be a symbol", signature)),
+        },
+        _ => Err("define expects a name and a value or a signature and a body".to_string()),
+    }
+}
+
+/// Rewrites the internal defines at the start of BODY, the body of a
+/// function, into lets and sets.
+fn desugar_internal_defines(body: &mut Vec<Expr>) -> Result<(), String> {
+    let mut defines = Vec::new();
+    while let Some(define) = body.get(defines.len()).map(internal_define).transpose()? {
+        match define {
+            Some(define) => defines.push(define),
+            None => break,
+        }
+    }
+    if defines.is_empty() {
+        return Ok(());
+    }
+    if defines.len() == body.len() {
+        return Err("a function body needs an expression after its defines".to_string());
+    }
+    let rest = body.split_off(defines.len());
+    *body = bind_in_order(defines, false);
+    body.extend(rest);
+    Ok(())
+}
+
+/// Returns the lets and sets that give each name in BINDINGS its value
+/// in order. A name is bound before any of the values if the value of
+/// an earlier binding uses it, or its own value does and OWN_VALUE is
+/// set. It is bound to nil and then set where its value is evaluated.
+fn bind_in_order(bindings: Vec<(Expr, Expr)>, own_value: bool) -> Vec<Expr> {
+    let mut forward = Vec::new();
+    for (i, (name, _)) in bindings.iter().enumerate() {
+        let before = if own_value { i + 1 } else { i };
+        let used = bindings[..before].iter().any(|(_, value)| {
+            let mut found = false;
+            value.preorder_traverse(&mut |e: &Expr| {
+                if e.is_quote().is_some() {
+                    return crate::PreorderStatus::Skip;
+                }
+                found |= e == name;
+                crate::PreorderStatus::Continue
+            });
+            found
+        });
+        forward.push(used);
+    }
+
+    let mut res = Vec::new();
+    for ((name, _), &forward) in bindings.iter().zip(&forward) {
+        if forward {
+            res.push(Expr::List(vec![sym("let"), name.clone(), Expr::Nil]));
+        }
+    }
+    for ((name, value), &forward) in bindings.into_iter().zip(&forward) {
+        let head = if forward { "set" } else { "let" };
+        res.push(Expr::List(vec![sym(head), name, value]));
+    }
+    res
+}
+
+fn desugar_letrec_star(args: &[Expr]) -> Result<Expr, String> {
+    let (bindings, body) = match args.split_first() {
+        Some((bindings @ (Expr::List(_) | Expr::Nil), body)) if !body.is_empty() => {
+            (bindings, body)
+        }
+        _ => return Err("letrec* expects a list of bindings and a body".to_string()),
+    };
+    let bindings = match bindings {
+        Expr::List(v) => v
+            .iter()
+            .map(|binding| match binding {
+                Expr::List(b) if b.len() == 2 && matches!(b[0], Expr::Symbol(_)) => {
+                    Ok((b[0].clone(), b[1].clone()))
+                }
+                _ => Err(format!(
+                    "letrec* binding ({:?}) should be a name and a value",
+                    binding
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => Vec::new(),
+    };
+    // The names are bound in a function so that they are only in scope
+    // in the letrec*.
+    let scope = vec![sym("fn"), Expr::Nil]
+        .into_iter()
+        .chain(bind_in_order(bindings, true))
+        .chain(body.iter().cloned())
+        .collect();
+    Ok(Expr::List(vec![Expr::List(scope)]))
+}
+
+/// Returns the arguments of E if it is a when clause of a for.
+fn when_clause(e: &Expr) -> Option<&[Expr]> {
+    match e {
+        Expr::List(v) if v.first() == Some(&sym("when")) => Some(v),
+        _ => None,
+    }
+}
+
+fn desugar_for(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (generators, rest) = match args.split_first() {
+        Some((Expr::List(g), rest)) if matches!(g.first(), Some(Expr::Symbol(_))) => {
+            (vec![Expr::List(g.clone())], rest)
+        }
+        Some((Expr::List(g), rest)) => (g.clone(), rest),
+        _ => return Err("for expects a generator like (name list) and a body".to_string()),
+    };
+    let generators = generators
+        .iter()
+        .map(|g| match g {
+            Expr::List(g) if g.len() == 2 && matches!(g[0], Expr::Symbol(_)) => {
+                Ok((g[0].clone(), g[1].clone()))
+            }
+            _ => Err(format!(
+                "for generator ({:?}) should be a name and a list",
+                g
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let filters = rest.iter().take_while(|e| when_clause(e).is_some()).count();
+    let (filters, body) = rest.split_at(filters);
+    if body.is_empty() {
+        return Err("for expects a body".to_string());
+    }
+    let filters = filters
+        .iter()
+        .map(|e| match when_clause(e).unwrap() {
+            [_, pred] => Ok(pred.clone()),
+            _ => Err("for expects when clauses like (when pred)".to_string()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let value = match body {
+        [value] => value.clone(),
+        _ => Expr::List(vec![Expr::List(
+            vec![sym("fn"), Expr::Nil]
+                .into_iter()
+                .chain(body.iter().cloned())
+                .collect(),
+        )]),
+    };
+    // The innermost generator conses the value onto the list the ones
+    // outside it are building and each generator outside of it passes
+    // that list through.
+    let res = temporary(count);
+    let mut step = Expr::List(vec![builtin("cons"), value, res.clone()]);
+    for pred in filters.into_iter().rev() {
+        step = Expr::List(vec![sym("if"), pred, step, res.clone()]);
+    }
+    for (i, (var, list)) in generators.into_iter().enumerate().rev() {
+        let f = Expr::List(vec![sym("fn"), Expr::List(vec![res.clone(), var]), step]);
+        // Only the outermost generator starts from the empty list.
+        let start = if i == 0 { Expr::Nil } else { res.clone() };
+        step = Expr::List(vec![builtin("reduce"), f, start, list]);
+    }
+    let e = temporary(count);
+    let reverse = Expr::List(vec![
+        sym("fn"),
+        Expr::List(vec![res.clone(), e.clone()]),
+        Expr::List(vec![builtin("cons"), e, res]),
+    ]);
+    Ok(Expr::List(vec![
+        builtin("reduce"),
+        reverse,
+        Expr::Nil,
+        step,
+    ]))
+}
+
+/// If NAME is one of the composed accessors like cadr returns the
+/// letters between its c and r.
+fn accessor_path(name: &str) -> Option<&str> {
+    let path = name.strip_prefix('c')?.strip_suffix('r')?;
+    if (2..=4).contains(&path.len()) && path.chars().all(|c| c == 'a' || c == 'd') {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Returns true if NAME is one of the composed accessors.
+pub(crate) fn is_list_accessor(name: &str) -> bool {
+    accessor_path(name).is_some()
+}
+
+fn desugar_accessor(name: &str, path: &str, args: &[Expr]) -> Result<Expr, String> {
+    match args {
+        [arg] => Ok(path.chars().rev().fold(arg.clone(), |inner, c| {
+            let op = if c == 'a' { "car" } else { "cdr" };
+            Expr::List(vec![builtin(op), inner])
+        })),
+        _ => Err(format!("{} expected 1 args and got {}", name, args.len())),
+    }
+}
+
+/// Returns true if NAME is a special form or a form that desugaring
+/// rewrites. Programs can't bind these names because calls to them
+/// would still mean the form rather than the program's variable.
+pub(crate) fn is_special_form(name: &str) -> bool {
+    (crate::primitives::string_is_builtin(name) && !crate::primitives::string_is_primitive(name))
+        || matches!(
+            name,
+            "if-let"
+                | "when-let"
+                | "dotimes"
+                | "letrec*"
+                | "for"
+                | "let-values"
+                | "throw"
+                | "make-parameter"
+                | "parameterize"
+                | "define"
+                | "quasiquote"
+                | "define-record"
+        )
+}
+
+/// Returns an error if E binds the name of a special form.
+fn check_binding(e: &Expr) -> Result<(), String> {
+    let names = match (e.is_let(), e.is_fndef()) {
+        (Some((name, _)), _) => vec![name],
+        (_, Some((params, _))) => params,
+        _ => return Ok(()),
+    };
+    match names.into_iter().find(|name| is_special_form(name)) {
+        Some(name) => Err(format!("can not bind the special form {}", name)),
+        None => Ok(()),
+    }
+}
+
+fn desugar_compose(fns: &[Expr]) -> Expr {
+    // The functions are evaluated outside of the closure so its
+    // parameter names can't capture anything they refer to.
+    let params = (0..fns.len())
+        .map(|i| Expr::Symbol(format!("f{}", i)))
+        .collect::<Vec<_>>();
+    let body = params
+        .iter()
+        .rev()
+        .fold(sym("x"), |arg, f| Expr::List(vec![f.clone(), arg]));
+    let closure = Expr::List(vec![sym("fn"), Expr::List(vec![sym("x")]), body]);
+    let maker = Expr::List(vec![sym("fn"), Expr::List(params), closure]);
+    Expr::List(std::iter::once(maker).chain(fns.iter().cloned()).collect())
+}
+
+/// Returns an error if E contains an integer that doesn't fit in a
+/// fixnum. The parser rejects them so this only finds ones in
+/// programs that were put together some other way.
+pub(crate) fn check_integers(e: &Expr) -> Result<(), String> {
+    match e {
+        Expr::Integer(i) if !crate::conversions::integer_fits_fixnum(*i) => {
+            Err(format!("the integer {} doesn't fit in a fixnum", i))
+        }
+        Expr::List(v) => v.iter().try_for_each(check_integers),
+        _ => Ok(()),
+    }
+}
+
+fn desugar_expr(e: &mut Expr, count: &mut usize) -> Result<(), String> {
+    // Quoted data is left alone.
+    if let Some(data) = e.is_quote() {
+        return check_integers(data);
+    }
+    if let Expr::Integer(_) = e {
+        return check_integers(e);
+    }
+    check_binding(e)?;
+    // Internal defines are rewritten before the body is desugared so
+    // that any define left over is one in the wrong place.
+    if e.is_fndef().is_some() {
+        if let Expr::List(v) = e {
+            let mut body = v.split_off(2);
+            desugar_internal_defines(&mut body)?;
+            v.extend(body);
+        }
+    }
+    // try is desugared before its arguments so that its catch clause
+    // isn't mistaken for a catch expression.
+    if let Expr::List(v) = e {
+        if v.first() == Some(&sym("try")) {
+            *e = crate::exceptions::desugar_try(&v[1..])?;
+            if let Expr::List(v) = e {
+                for e in v[1..].iter_mut() {
+                    desugar_expr(e, count)?;
+                }
+            }
+            return Ok(());
+        }
+    }
+    // A quasiquote's template is data so only the expressions it
+    // unquotes are desugared, once it has been turned into the calls
+    // that build it.
+    if let Expr::List(v) = e {
+        if v.first() == Some(&sym("quasiquote")) {
+            *e = crate::quasiquote::desugar_quasiquote(&v[1..])?;
+            return desugar_expr(e, count);
+        }
+    }
+    if let Expr::List(v) = e {
+        for e in v.iter_mut() {
+            desugar_expr(e, count)?;
+        }
+        let replacement = match v.first() {
+            Some(Expr::Symbol(s)) if s == "and" || s == "or" => {
+                Some(desugar_and_or(&s.clone(), &v[1..], count))
+            }
+            Some(Expr::Symbol(s)) if s == "cond" => Some(desugar_cond(&v[1..])?),
+            Some(Expr::Symbol(s)) if s == "if" && v.len() == 3 => Some(Expr::List(vec![
+                sym("if"),
+                v[1].clone(),
+                v[2].clone(),
+                Expr::Nil,
+            ])),
+            Some(Expr::Symbol(s)) if s == "if-let" || s == "when-let" => {
+                Some(desugar_if_let(&s.clone(), &v[1..], count)?)
+            }
+            Some(Expr::Symbol(s)) if is_list_accessor(s) => {
+                Some(desugar_accessor(s, accessor_path(s).unwrap(), &v[1..])?)
+            }
+            Some(Expr::Symbol(s)) if is_chainable_comparison(s) && v.len() > 3 => {
+                Some(desugar_chained_comparison(&s.clone(), &v[1..], count))
+            }
+            Some(Expr::Symbol(s)) if s == "dotimes" => Some(desugar_dotimes(&v[1..], count)?),
+            Some(Expr::Symbol(s)) if s == "letrec*" => Some(desugar_letrec_star(&v[1..])?),
+            Some(Expr::Symbol(s)) if s == "for" => Some(desugar_for(&v[1..], count)?),
+            Some(Expr::Symbol(s)) if s == "let-values" => {
+                Some(crate::values::desugar_let_values(&v[1..], count)?)
+            }
+            Some(Expr::Symbol(s)) if s == "catch" => {
+                Some(crate::exceptions::desugar_catch(&v[1..], count)?)
+            }
+            Some(Expr::Symbol(s)) if s == "throw" => {
+                Some(crate::exceptions::desugar_throw(&v[1..], count)?)
+            }
+            Some(Expr::Symbol(s)) if s == "unwind-protect" => {
+                Some(crate::exceptions::desugar_unwind_protect(&v[1..])?)
+            }
+            Some(Expr::Symbol(s)) if s == "make-parameter" => {
+                Some(crate::parameters::desugar_make_parameter(&v[1..], count)?)
+            }
+            Some(Expr::Symbol(s)) if s == "parameterize" => {
+                Some(crate::parameters::desugar_parameterize(&v[1..], count)?)
+            }
+            Some(Expr::Symbol(s)) if s == "with-output-to-string" => {
+                Some(crate::output::desugar_with_output_to_string(&v[1..]))
+            }
+            Some(Expr::Symbol(s)) if s == "define" => {
+                return Err("define is only allowed at the start of a function body".to_string())
+            }
+            Some(Expr::Symbol(s)) if s == "define-record" => {
+                return Err("define-record is only allowed at the top level".to_string())
+            }
+            Some(Expr::Symbol(s)) if s == "compose" => {
+                if v.len() < 2 {
+                    return Err("compose expects at least one function".to_string());
+                }
+                Some(desugar_compose(&v[1..]))
+            }
+            _ => None,
+        };
+        if let Some(replacement) = replacement {
+            *e = replacement;
+        }
+    }
+    Ok(())
+}
+
+/// Expands the top level `define-record` forms in PROGRAM. Returns
+/// None if it has none. Programs that are parsed have them expanded
+/// as they are read so that each definition has the location of its
+/// form, this is for the ones that were put together some other way.
+pub(crate) fn expand_records(program: &[Expr]) -> Result<Option<Vec<Expr>>, String> {
+    let mut expanded = None;
+    for (form, e) in program.iter().enumerate() {
+        if let Some(definitions) = crate::records::expand_define_record(e)? {
+            expanded
+                .get_or_insert_with(|| program[..form].to_vec())
+                .extend(definitions);
+        } else if let Some(expanded) = &mut expanded {
+            expanded.push(e.clone());
+        }
+    }
+    Ok(expanded)
+}
+
+/// Desugars every form in PROGRAM.
+pub(crate) fn desugar(program: &mut [Expr]) -> Result<(), String> {
+    let _t = crate::timer::timeit("desugaring pass");
+    let mut count = 0;
+    for e in program {
+        desugar_expr(e, &mut count)?;
+    }
+    Ok(())
+}
+
+/// Like `desugar` but carries on after a form with an error. Returns
+/// the index of each form that couldn't be desugared along with its
+/// error. Those forms are left partly desugared.
+pub(crate) fn desugar_each(program: &mut [Expr]) -> Vec<(usize, String)> {
+    let mut count = 0;
+    program
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(form, e)| desugar_expr(e, &mut count).err().map(|e| (form, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::check;
+    use crate::{parse_string, roundtrip_string};
+
+    fn desugared(source: &str) -> Vec<Expr> {
+        let mut program = parse_string(source).unwrap();
+        desugar(&mut program).unwrap();
+        program
+    }
+
+    #[test]
+    fn desugar_forms() {
+        assert_eq!(
+            desugared("(and a b)"),
+            parse_string("(if (__anon_builtin_not (let __anon_tmp_0 a)) __anon_tmp_0 b)").unwrap()
+        );
+        assert_eq!(
+            desugared("(or a b)"),
+            parse_string("(if (__anon_builtin_not (let __anon_tmp_0 a)) b __anon_tmp_0)").unwrap()
+        );
+        assert_eq!(
+            desugared("(cond ((eq x 1) a) (else b))"),
+            parse_string("(if (eq x 1) a b)").unwrap()
+        );
+        assert_eq!(
+            desugared("(cond ((eq x 1) a))"),
+            parse_string("(if (eq x 1) a ())").unwrap()
+        );
+        assert_eq!(
+            desugared("(if (eq x 1) a)"),
+            parse_string("(if (eq x 1) a ())").unwrap()
+        );
+        assert_eq!(
+            desugared("(quote (and a b))"),
+            parse_string("(quote (and a b))").unwrap()
+        );
+    }
+
+    #[test]
+    fn and_or_cond() {
+        let source = r#"
+(let classify (fn (n)
+  (cond ((lt n 0) (quote negative))
+        ((eq n 0) (quote zero))
+        (else (quote positive)))))
+(cons (and 1 2 3)
+ (cons (and 1 () 3)
+  (cons (or () 2 3)
+   (cons (or () ())
+    (cons (and)
+     (cons (or)
+      (cons (classify (sub 5)) (cons (classify 0) (classify 5)))))))))
+"#;
+        let expected = roundtrip_string(
+            "(cons 3 (cons () (cons 2 (cons () (cons (eq 1 1) (cons (eq 1 2) (cons (quote negative) (cons (quote zero) (quote positive)))))))))",
+        )
+        .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn if_without_else() {
+        check("(if (eq 1 1) 2)", "2");
+        check("(if (eq 1 2) 2)", "()");
+        check("(if 0 1)", "()");
+        // The then branch is still a tail call so this doesn't run out
+        // of stack. The interpreter doesn't have tail calls so it is
+        // only compiled.
+        assert_eq!(
+            roundtrip_string("(let count (fn (n) (if (lt 0 n) (count (sub n 1))))) (count 100000)")
+                .unwrap(),
+            Expr::Nil
+        );
+    }
+
+    #[test]
+    fn and_or_with_not_in_scope() {
+        // The not that and and or test with is the builtin one and
+        // not the parameter.
+        let source = r#"
+(let f (fn (not) (cons (and 1 2) (cons (or () 2) (lt 1 2 3)))))
+(f (fn (x) (eq 1 1)))
+"#;
+        check(source, "(cons 2 (cons 2 (eq 1 1)))");
+        check("(let g (fn (not) (and () 2))) (g (fn (x) x))", "()");
+    }
+
+    #[test]
+    fn and_or_evaluate_once() {
+        let source = r#"
+(let count 0)
+(let bump (fn () (set count (add1 count)) count))
+(cons (and (bump) (bump)) (cons (or (bump) 10) count))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(cons 2 (cons 3 3))").unwrap()
+        );
+    }
+
+    #[test]
+    fn identity_and_compose() {
+        let source = r#"
+(let inc (fn (n) (add1 n)))
+(let double (fn (n) (add n n)))
+(let id identity)
+(cons (identity 5)
+ (cons ((compose inc inc) 0)
+  (cons ((compose double inc) 3)
+   (cons ((compose inc double) 3)
+    (cons ((compose inc) 1) (id (quote (1 2))))))))
+"#;
+        let expected =
+            roundtrip_string("(cons 5 (cons 2 (cons 8 (cons 7 (cons 2 (quote (1 2)))))))").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        // Each function is evaluated once when the composition is
+        // made.
+        let source = r#"
+(let count 0)
+(let get-inc (fn () (set count (add1 count)) (fn (n) (add1 n))))
+(let f (compose (get-inc) (get-inc)))
+(cons (f (f 0)) count)
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(cons 4 2)").unwrap()
+        );
+        assert!(roundtrip_string("(compose)").is_err());
+    }
+
+    #[test]
+    fn list_accessors() {
+        assert_eq!(
+            desugared("(caddr l)"),
+            parse_string("(__anon_builtin_car (__anon_builtin_cdr (__anon_builtin_cdr l)))")
+                .unwrap()
+        );
+        assert_eq!(
+            desugared("(cdar l)"),
+            parse_string("(__anon_builtin_cdr (__anon_builtin_car l))").unwrap()
+        );
+        let source = r#"
+(let l (quote ((1 2) 3 4 5)))
+(cons (cadr (quote (1 2 3)))
+ (cons (caddr (quote (1 2 3)))
+  (cons (caar l)
+   (cons (cdar l)
+    (cons (cadddr l) (cddr l))))))
+"#;
+        let expected = roundtrip_string("(quote (2 3 1 (2) 5 4 5))").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+        // The accessors use the builtin car and cdr.
+        check("(let f (fn (car cdr) (caddr (list 1 2 3)))) (f 1 2)", "3");
+        assert!(roundtrip_string("(cadr)").is_err());
+        // Not accessors.
+        assert!(accessor_path("cr").is_none());
+        assert!(accessor_path("car").is_none());
+        assert!(accessor_path("caddddr").is_none());
+        assert!(accessor_path("cabr").is_none());
+    }
+
+    #[test]
+    fn if_let() {
+        let source = r#"
+(let count 0)
+(let lookup (fn (x) (set count (add1 count)) (if (eq x 1) 10 ())))
+(let get (fn (k) (if-let (v (lookup k)) (add v 1) (quote missing))))
+(let x 5)
+(let when (when-let (x (lookup 1)) (set count (add count x)) (sub x 1)))
+(cons (get 1) (cons (get 2) (cons when (cons (when-let (y (lookup 2)) y) (cons x count)))))
+"#;
+        let expected =
+            roundtrip_string("(cons 11 (cons (quote missing) (cons 9 (cons () (cons 5 14)))))")
+                .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        // The value is tested with the builtin not.
+        check(
+            "(let f (fn (not) (cons (if-let (v 1) v 2) (when-let (v 3) v)))) (f (fn (x) x))",
+            "(cons 1 3)",
+        );
+
+        // The name isn't bound in the else branch.
+        assert!(roundtrip_string("(if-let (v ()) 1 v)").is_err());
+        assert!(roundtrip_string("(if-let (v 1) v)").is_err());
+        assert!(roundtrip_string("(when-let v 1)").is_err());
+    }
+
+    #[test]
+    fn internal_defines() {
+        assert_eq!(
+            desugared("(fn (n) (define (ev? n) (od? n)) (define (od? n) (ev? n)) (define k 1) (ev? k))"),
+            parse_string(
+                "(fn (n) (let od? ()) (let ev? (fn (n) (od? n))) (set od? (fn (n) (ev? n))) (let k 1) (ev? k))"
+            )
+            .unwrap()
+        );
+
+        let source = r#"
+(let parity (fn (n)
+  (define (ev? n) (if (eq n 0) (quote even) (od? (sub n 1))))
+  (define (od? n) (if (eq n 0) (quote odd) (ev? (sub n 1))))
+  (define start n)
+  (ev? start)))
+(let counter (fn ()
+  (define count 0)
+  (define (bump) (set count (add1 count)) count)
+  (bump)
+  (bump)))
+(cons (parity 10) (cons (parity 7) (counter)))
+"#;
+        let expected = roundtrip_string("(cons (quote even) (cons (quote odd) 2))").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        // Defines have to come before everything else in the body.
+        assert!(roundtrip_string("(let f (fn () 1 (define x 2) x)) (f)").is_err());
+        assert!(roundtrip_string("(define x 1)").is_err());
+        assert!(roundtrip_string("(let f (fn () (define x 1))) (f)").is_err());
+        assert!(roundtrip_string("(let f (fn () (define (1) 1) 2)) (f)").is_err());
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        let source = r#"
+(let count 0)
+(let bump (fn (x) (set count (add1 count)) x))
+(cons (< 1 2 3)
+      (cons (lt 1 3 2)
+            (cons (> (bump 3) (bump 1) (bump 2) (bump 0))
+                  (cons (eq 2 2 2) (cons (neq 1 2) (cons (neq 1 1) count))))))
+"#;
+        let expected = roundtrip_string(
+            "(cons (eq 1 1) (cons (eq 1 2) (cons (eq 1 2) (cons (eq 1 1) (cons (eq 1 1) (cons (eq 1 2) 4))))))",
+        )
+        .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            roundtrip_string("(let f neq) (cons (f 1 2) (f (quote a) (quote a)))").unwrap(),
+            roundtrip_string("(cons (eq 1 1) (eq 1 2))").unwrap()
+        );
+    }
+
+    #[test]
+    fn dotimes() {
+        let source = r#"
+(let sum 0)
+(let seen ())
+(let res (dotimes (i 4) (set sum (add sum i)) (set seen (cons i seen))))
+(cons res (cons sum seen))
+"#;
+        let expected = roundtrip_string("(cons () (cons 6 (quote (3 2 1 0))))").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        // The count is evaluated once and a count that isn't more than
+        // zero runs the body zero times.
+        let source = r#"
+(let runs 0)
+(let evals 0)
+(let count (fn (n) (set evals (add1 evals)) n))
+(dotimes (i (count 0)) (set runs (add1 runs)))
+(dotimes (i (count -3)) (set runs (add1 runs)))
+(dotimes (i (count 2)) (set runs (add runs (add1 i))))
+(cons runs evals)
+"#;
+        let expected = roundtrip_string("(cons 3 3)").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        // The loop runs in constant stack space.
+        assert_eq!(
+            roundtrip_string("(let n 0) (dotimes (i 1000000) (set n (add n 1))) n").unwrap(),
+            Expr::Integer(1000000)
+        );
+        // The loop counts with the builtin add and lt even where they
+        // are bound to something else.
+        let source = r#"
+(let f (fn (add lt) (let n 0) (dotimes (i 3) (set n (add n 1))) n))
+(f sub (fn (a b) 1))
+"#;
+        check(source, "-3");
+        assert!(roundtrip_string("(dotimes i 3)").is_err());
+        assert!(roundtrip_string("(dotimes (1 3) 1)").is_err());
+    }
+
+    #[test]
+    fn letrec_star() {
+        let source = r#"
+(let log ())
+(let note (fn (x) (set log (cons x log)) x))
+(letrec* ((a (note 1))
+          (b (note (add a 1)))
+          (ev? (fn (n) (if (eq n 0) (eq 1 1) (od? (sub n 1)))))
+          (od? (fn (n) (if (eq n 0) (eq 1 2) (ev? (sub n 1)))))
+          (c (note (mul b 10)))
+          (early (note d))
+          (d 5))
+  (cons (cons a (cons b (cons c (cons early (cons d ()))))) (cons (ev? b) log)))
+"#;
+        // Each value is evaluated after the ones before it and sees
+        // their values. d is nil until its value has been evaluated.
+        let expected =
+            roundtrip_string("(cons (quote (1 2 20 () 5)) (cons (eq 1 1) (quote (() 20 2 1))))")
+                .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            roundtrip_string("(letrec* ((x (cons 1 x))) x)").unwrap(),
+            roundtrip_string("(cons 1 ())").unwrap()
+        );
+        assert_eq!(
+            roundtrip_string("(letrec* () 3)").unwrap(),
+            Expr::Integer(3)
+        );
+
+        // The names are only in scope in the letrec*.
+        assert!(roundtrip_string("(letrec* ((x 1)) x) x").is_err());
+        assert!(roundtrip_string("(letrec* ((x 1)))").is_err());
+        assert!(roundtrip_string("(letrec* ((1 2)) 3)").is_err());
+    }
+
+    #[test]
+    fn for_comprehensions() {
+        check("(for (x (list 1 2 3)) (mul x x))", "(quote (1 4 9))");
+        check("(for (x ()) x)", "()");
+        check(
+            "(for (x (list 1 2 3 4 5)) (when (lt 1 x)) (when (lt x 5)) (add x 10))",
+            "(quote (12 13 14))",
+        );
+        // Later generators run through all of their elements for each
+        // element of the ones before them and can use their names.
+        check(
+            "(for ((x (list 1 2)) (y (list x 3))) (cons x (cons y ())))",
+            "(quote ((1 1) (1 3) (2 2) (2 3)))",
+        );
+        // Bodies are evaluated from left to right.
+        check(
+            r#"
+(let log ())
+(let res (for ((x (list 1 2)) (y (list 3 4))) (set log (cons (cons x (cons y ())) log)) (add x y)))
+(cons res log)
+"#,
+            "(quote ((4 5 5 6) (2 4) (2 3) (1 4) (1 3)))",
+        );
+        // A for builds its list with the builtin reduce and cons.
+        check(
+            "(let f (fn (reduce cons) (for (x (list 1 2)) (add x 1)))) (f 1 2)",
+            "(quote (2 3))",
+        );
+        assert!(roundtrip_string("(for (x) x)").is_err());
+        assert!(roundtrip_string("(for (x (list 1)))").is_err());
+        assert!(roundtrip_string("(for ((1 (list 1))) 1)").is_err());
+    }
+
+    #[test]
+    fn bad_cond() {
+        assert!(roundtrip_string("(cond ((eq 1 1)))").is_err());
+        assert!(roundtrip_string("(cond (else 1) ((eq 1 1) 2))").is_err());
+        assert!(roundtrip_string("(let f and)").is_err());
+    }
+
+    #[test]
+    fn bind_special_form() {
+        let error = |source: &str| desugar(&mut parse_string(source).unwrap()).unwrap_err();
+        assert_eq!(
+            error("(let and (fn (a b) 42)) (and 1 2)"),
+            "can not bind the special form and"
+        );
+        assert_eq!(
+            error("(let f (fn (x cond) x))"),
+            "can not bind the special form cond"
+        );
+        assert_eq!(
+            error("(let f (fn () (define dotimes 1) 2))"),
+            "can not bind the special form dotimes"
+        );
+        assert_eq!(error("(let set 10)"), "can not bind the special form set");
+        assert!(crate::interpreter::interpret(&parse_string("(let or 1)").unwrap()).is_err());
+        // Builtin functions can still be shadowed.
+        check("(let car (fn (l) 42)) (car (quote (1 2)))", "42");
+    }
+}
@@ -0,0 +1,349 @@
+//! Every problem with a program found in one pass. Compiling stops at
+//! the first error, so a program with several errors would have to
+//! be fixed and compiled again once for each of them. The checks here
+//! run over a copy of the program before it is compiled and carry on
+//! past an error wherever the forms after it can still be checked.
+//!
+//! Each top level form is desugared on its own, so a malformed form
+//! doesn't hide the forms after it. A form that can't be desugared is
+//! checked as if it were `(let name ())` when it defines NAME, and as
+//! nil otherwise, so that the forms that use what it defines aren't
+//! reported as well. Every undefined variable, every use of a
+//! definition before the definition runs, and every call to a known
+//! function or a primitive with the wrong number of arguments is
+//! reported, along with the warnings for unused definitions and
+//! shadowed builtins. An undefined variable keeps its name so the
+//! checks after renaming pass over it.
+//!
+//! Diagnostics point at the top level form they were found in, which
+//! can be turned into a place in the source with the locations that
+//! `parse_string_with_locations` returns.
+//!
+//! ```text
+//! error: function (f) expects 1 arguments but was called with 2 (in the form at 3:0)
+//! error: function (g) expects 2 arguments but was called with 0 (in the form at 4:0)
+//! ```
+
+use std::collections::HashSet;
+
+use cranelift_module::FuncId;
+
+use crate::compiler::{compile_program, JIT};
+use crate::location::Location;
+use crate::Expr;
+
+/// How bad a diagnostic is. A program with an error isn't compiled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Something wrong with a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What found the problem, one of "syntax", "name",
+    /// "forward-use", "arity", "compile", "unused-definition" and
+    /// "shadowed-builtin".
+    pub kind: &'static str,
+    pub message: String,
+    /// The index of the top level form the problem is in, if it is
+    /// in one.
+    pub form: Option<usize>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+impl Diagnostic {
+    fn error(kind: &'static str, message: String, form: Option<usize>) -> Self {
+        Self {
+            severity: Severity::Error,
+            kind,
+            message,
+            form,
+        }
+    }
+
+    /// Makes a diagnostic out of one of the warnings that the rest of
+    /// the compiler reports, which write themselves with the
+    /// "warning: " in front.
+    fn warning(kind: &'static str, warning: impl std::fmt::Display, form: usize) -> Self {
+        let message = warning.to_string();
+        Self {
+            severity: Severity::Warning,
+            kind,
+            message: message
+                .strip_prefix("warning: ")
+                .unwrap_or(&message)
+                .to_string(),
+            form: Some(form),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Returns the location in the source of the form the diagnostic
+    /// is in. LOCATIONS are the locations of the program's top level
+    /// forms as returned by `parse_string_with_locations`.
+    pub fn location<'a>(&self, locations: &'a [Location]) -> Option<&'a Location> {
+        self.form.and_then(|form| locations.get(form))
+    }
+
+    /// Returns the diagnostic the way the command line shows it, with
+    /// where its form starts if LOCATIONS has it.
+    pub fn describe(&self, locations: &[Location]) -> String {
+        match self.location(locations) {
+            Some(loc) => format!(
+                "{} (in the form at {}:{})",
+                self, loc.start.line, loc.start.col
+            ),
+            None => self.to_string(),
+        }
+    }
+}
+
+/// Returns every diagnostic for PROGRAM ordered by the form they are
+/// in. PROGRAM is left alone.
+pub fn diagnose(program: &[Expr]) -> Vec<Diagnostic> {
+    diagnose_with_globals(program, &HashSet::new())
+}
+
+/// Like `diagnose` for a program that can use the definitions GLOBALS
+/// of the programs compiled before it. See `globals.rs`.
+fn diagnose_with_globals(program: &[Expr], globals: &HashSet<String>) -> Vec<Diagnostic> {
+    let expanded = match crate::desugar::expand_records(program) {
+        Ok(expanded) => expanded,
+        Err(e) => return vec![Diagnostic::error("syntax", e, None)],
+    };
+    let program = expanded.as_deref().unwrap_or(program);
+    let mut diagnostics = Vec::new();
+
+    let mut desugared = program.to_vec();
+    let mut checked = program.to_vec();
+    for (form, e) in crate::desugar::desugar_each(&mut desugared) {
+        diagnostics.push(Diagnostic::error("syntax", e, Some(form)));
+        let placeholder = match program[form].is_let() {
+            Some((name, _)) => Expr::List(vec![
+                Expr::Symbol("let".to_string()),
+                Expr::Symbol(name.clone()),
+                Expr::Nil,
+            ]),
+            None => Expr::Nil,
+        };
+        desugared[form] = placeholder.clone();
+        checked[form] = placeholder;
+    }
+
+    // The rest of the checks follow the order that compiling does
+    // them in so that they see the program the same way.
+    for (form, e) in crate::renamer::rename_each_form(&mut desugared, globals) {
+        diagnostics.push(Diagnostic::error("name", e, Some(form)));
+    }
+    for (form, e) in crate::forward::forward_use_errors(&mut desugared) {
+        diagnostics.push(Diagnostic::error("forward-use", e, Some(form)));
+    }
+    crate::fold::fold_constants(&mut desugared);
+    for (form, e) in crate::arity::arity_errors(&desugared) {
+        diagnostics.push(Diagnostic::error("arity", e, Some(form)));
+    }
+
+    // The forms that couldn't be desugared have been replaced so these
+    // can't fail.
+    for unused in crate::unused::find_unused_definitions(&checked).unwrap_or_default() {
+        let form = unused.form;
+        diagnostics.push(Diagnostic::warning("unused-definition", unused, form));
+    }
+    for shadow in crate::shadow::find_shadowed_builtins(&checked).unwrap_or_default() {
+        let form = shadow.form;
+        diagnostics.push(Diagnostic::warning("shadowed-builtin", shadow, form));
+    }
+
+    diagnostics.sort_by_key(|d| d.form);
+    diagnostics
+}
+
+/// Compiles PROGRAM into JIT like `compile_program` does if it has no
+/// errors. Returns the id of the program's entry and its warnings, or
+/// every diagnostic if it has errors. An error that stops the program
+/// from being compiled after it has been checked is the last
+/// diagnostic.
+pub fn compile_with_diagnostics(
+    jit: &mut JIT,
+    program: &mut [Expr],
+) -> Result<(FuncId, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let mut diagnostics = diagnose_with_globals(program, &jit.globals);
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(diagnostics);
+    }
+    match compile_program(jit, program) {
+        Ok(id) => Ok((id, diagnostics)),
+        Err(e) => {
+            diagnostics.push(Diagnostic::error("compile", e, None));
+            Err(diagnostics)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_string, parse_string_with_locations};
+
+    fn errors(source: &str) -> Vec<(&'static str, String, Option<usize>)> {
+        diagnose(&parse_string(source).unwrap())
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .map(|d| (d.kind, d.message, d.form))
+            .collect()
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let source = r#"
+(let f (fn (x) (add x 1)))
+(let g (fn (a b) (f a b)))
+(let h (fn () (g)))
+(h)
+"#;
+        assert_eq!(
+            errors(source),
+            vec![
+                (
+                    "arity",
+                    "function (f) expects 1 arguments but was called with 2".to_string(),
+                    Some(1)
+                ),
+                (
+                    "arity",
+                    "function (g) expects 2 arguments but was called with 0".to_string(),
+                    Some(2)
+                ),
+            ]
+        );
+
+        // A malformed form doesn't stop the forms after it from being
+        // checked, and the forms that use what it defines aren't
+        // reported.
+        let source = r#"
+(let a (dotimes))
+(let b (add c 1))
+(let c a)
+(let f (fn (x) x))
+(f 1 2)
+"#;
+        assert_eq!(
+            errors(source),
+            vec![
+                (
+                    "syntax",
+                    "dotimes expects a binding like (name count)".to_string(),
+                    Some(0)
+                ),
+                (
+                    "forward-use",
+                    "(c) is used before it is defined".to_string(),
+                    Some(1)
+                ),
+                (
+                    "arity",
+                    "function (f) expects 1 arguments but was called with 2".to_string(),
+                    Some(4)
+                ),
+            ]
+        );
+        assert!(errors("(let f (fn (x) x)) (f 1)").is_empty());
+    }
+
+    #[test]
+    fn errors_in_separate_functions() {
+        // An undefined variable doesn't stop the rest of the program
+        // from being renamed.
+        assert_eq!(
+            errors("(let g (fn () (nope))) (let k (fn () (nope2) (nope2)))"),
+            vec![
+                ("name", "undefined variable (nope)".to_string(), Some(0)),
+                ("name", "undefined variable (nope2)".to_string(), Some(1)),
+            ]
+        );
+        assert_eq!(
+            errors("(let f (fn () (car 1 2))) (let g (fn () (cdr 1 2 3) (add1 x)))"),
+            vec![
+                (
+                    "arity",
+                    "car expected 1 args and got 2".to_string(),
+                    Some(0)
+                ),
+                ("name", "undefined variable (x)".to_string(), Some(1)),
+                (
+                    "arity",
+                    "cdr expected 1 args and got 3".to_string(),
+                    Some(1)
+                ),
+            ]
+        );
+        assert_eq!(
+            errors("(sub) (iota) (make-vector)"),
+            vec![
+                (
+                    "arity",
+                    "sub expected at least 1 args and got 0".to_string(),
+                    Some(0)
+                ),
+                (
+                    "arity",
+                    "iota expected between 1 and 3 args and got 0".to_string(),
+                    Some(1)
+                ),
+                (
+                    "arity",
+                    "make-vector expected 1 or 2 args and got 0".to_string(),
+                    Some(2)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn warnings_and_locations() {
+        let source = "(let unused 1)\n(let f (fn (x) x))\n(f 1 2)";
+        let (program, locations) = parse_string_with_locations(source).unwrap();
+        let described: Vec<_> = diagnose(&program)
+            .iter()
+            .map(|d| d.describe(&locations))
+            .collect();
+        assert_eq!(
+            described,
+            vec![
+                "warning: (unused) is defined but never used (in the form at 0:0)",
+                "error: function (f) expects 1 arguments but was called with 2 (in the form at 2:0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn compiling() {
+        let mut jit = JIT::default();
+        let mut program = parse_string("(let unused 1) (let f (fn (x) x)) (f 2)").unwrap();
+        let (id, warnings) = compile_with_diagnostics(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            Expr::Integer(2)
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, "unused-definition");
+
+        let mut program = parse_string("(let f (fn (x) x)) (f) (f 1 2)").unwrap();
+        let diagnostics = compile_with_diagnostics(&mut jit, &mut program).unwrap_err();
+        assert_eq!(diagnostics.iter().filter(|d| d.is_error()).count(), 2);
+    }
+}
@@ -0,0 +1,83 @@
+//! Access to the environment that a program is running in. Programs
+//! can read environment variables and the arguments that they were
+//! started with.
+
+use std::sync::Mutex;
+
+use crate::conversions::{self, string_to_immediate, try_stringify_list};
+use crate::{Expr, Word};
+
+/// The arguments returned by (command-line-args).
+static PROGRAM_ARGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Sets the arguments that programs will see when they call
+/// (command-line-args).
+pub fn set_program_args(args: Vec<String>) {
+    *PROGRAM_ARGS.lock().unwrap() = args;
+}
+
+/// Returns the arguments programs see when they call
+/// (command-line-args).
+pub(crate) fn program_args() -> Vec<String> {
+    PROGRAM_ARGS.lock().unwrap().clone()
+}
+
+/// Returns the value of the environment variable NAME if it is set
+/// and is valid unicode.
+pub(crate) fn getenv(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Implements (getenv name). Returns the value of the environment
+/// variable as a string or nil if it is unset.
+pub extern "C" fn lustc_getenv(name: Word) -> Word {
+    let name = match conversions::word_is_pair(name) {
+        true => try_stringify_list(&Expr::from_immediate(name)),
+        false => None,
+    };
+    match name.and_then(|n| getenv(&n)) {
+        Some(val) => string_to_immediate(&val),
+        None => Expr::Nil.immediate_rep(),
+    }
+}
+
+/// Implements (command-line-args). Returns the program's arguments as
+/// a list of strings.
+pub extern "C" fn lustc_command_line_args() -> Word {
+    let args = program_args()
+        .into_iter()
+        .map(Expr::String)
+        .collect::<Vec<_>>();
+    conversions::list_to_immediate(&args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roundtrip_string;
+
+    #[test]
+    fn getenv() {
+        std::env::set_var("LUSTC_TEST_GETENV", "a=b=c ünïcødé 🚨");
+        assert_eq!(
+            roundtrip_string("(getenv \"LUSTC_TEST_GETENV\")")
+                .unwrap()
+                .to_string(),
+            "\"a=b=c ünïcødé 🚨\""
+        );
+        assert_eq!(
+            roundtrip_string("(getenv \"LUSTC_TEST_GETENV_UNSET\")"),
+            Ok(Expr::Nil)
+        );
+    }
+
+    #[test]
+    fn command_line_args() {
+        set_program_args(vec!["one".to_string(), "tw=o".to_string()]);
+        assert_eq!(
+            roundtrip_string("(command-line-args)").unwrap().to_string(),
+            "(\"one\" \"tw=o\")"
+        );
+        set_program_args(vec![]);
+    }
+}
@@ -3,6 +3,7 @@
 //! Errors to
 
 use crate::location::Location;
+use crate::parser::ParseError;
 use crate::reader;
 use crate::tokenizer::{Token, TokenType};
 use colored::*;
@@ -11,6 +12,8 @@ use colored::*;
 pub(crate) struct Error {
     pub(crate) loc: Location,
     pub(crate) what: String,
+    /// What kind of error this is.
+    pub(crate) kind: ParseError,
     suggestion: Option<Suggestion>,
 }
 
@@ -168,17 +171,19 @@ impl Printable for Suggestion {
 }
 
 impl Error {
-    pub(crate) fn on_tok(what: &str, token: &Token) -> Self {
+    pub(crate) fn on_tok(what: &str, token: &Token, kind: ParseError) -> Self {
         Self {
             loc: token.loc.clone(),
             what: what.to_string(),
+            kind,
             suggestion: Suggestion::on_tok(token),
         }
     }
-    pub(crate) fn at_loc(what: &str, loc: &Location) -> Self {
+    pub(crate) fn at_loc(what: &str, loc: &Location, kind: ParseError) -> Self {
         Self {
             loc: loc.clone(),
             what: what.to_string(),
+            kind,
             suggestion: None,
         }
     }
@@ -68,4 +68,11 @@ mod tests {
         let actual = roundtrip_file("examples/set.lisp").unwrap();
         assert_eq!(actual, expected)
     }
+
+    #[test]
+    fn test_shared_counter() {
+        let expected = Expr::List(vec![Expr::Integer(3), Expr::Integer(1)]);
+        let actual = roundtrip_file("examples/counter.lisp").unwrap();
+        assert_eq!(actual, expected)
+    }
 }
@@ -0,0 +1,574 @@
+//! Handling errors. `(try body (catch e handler))` evaluates BODY and
+//! if an error is raised while doing so evaluates HANDLER with E bound
+//! to the error's condition (see `conditions.rs`) instead.
+//!
+//! ```lisp
+//! (try (car 1) (catch e (condition-type e))) ; => type-error
+//! ```
+//!
+//! Desugaring turns the body and the handler into closures so that
+//! the rest of the compiler doesn't need to know that try binds a
+//! variable. The handler raises throws (see below) again so that they
+//! reach the catch they are meant for.
+//!
+//! ```lisp
+//! (try body (catch e handler)) => (try (fn () body)
+//!                                      (fn (e) (if (eq (condition-type e) (quote throw))
+//!                                                  (error (condition-type e)
+//!                                                         (condition-message e)
+//!                                                         (condition-data e))
+//!                                                  handler)))
+//! ```
+//!
+//! Errors are handled using the same mechanism as embedded mode. When
+//! an error is raised the error pending word is set and functions
+//! return to their callers until one of them is a try, which clears
+//! the word and calls its handler. Because the body is a function of
+//! its own the innermost try is always the first to see the error.
+//! Programs that use try are compiled as if they were in embedded
+//! mode so that errors unwind rather than exiting. If an error isn't
+//! handled `JIT::invoke` exits the same way that the program would
+//! have had it not used try.
+//!
+//! `(unwind-protect body cleanup)` evaluates BODY and then CLEANUP,
+//! even if BODY raised an error, and returns the value of BODY. If
+//! BODY raised the error keeps unwinding once CLEANUP is done so
+//! cleanups run from the innermost out before the error reaches a
+//! try. If CLEANUP raises an error of its own that error replaces the
+//! one that was unwinding. It is desugared the same way as try.
+//!
+//! ```lisp
+//! (unwind-protect body cleanup) => (unwind-protect (fn () body) (fn () cleanup))
+//! ```
+//!
+//! `(catch tag body...)` evaluates TAG and then BODY and `(throw tag
+//! value)` exits to the nearest catch around it whose tag is eq to
+//! TAG, which returns VALUE. Catches with other tags are passed by.
+//! A throw is an error whose condition has the type throw and the tag
+//! and the value as its data, so it unwinds through unwind-protect
+//! cleanups the same way and a catch is a try that makes the throw
+//! its result if the tags match and raises the condition again
+//! otherwise. A throw with no catch for its tag is an error saying
+//! so. A try between a throw and its catch passes the throw on. The
+//! calls in these expansions go to the builtins even if the program
+//! binds the same names (see `desugar::builtin`).
+//!
+//! ```lisp
+//! (catch tag body...) => ((fn (t)
+//!                           (try (fn () body...)
+//!                                (fn (c) (if (eq (condition-type c) (quote throw))
+//!                                            (if (eq (car (condition-data c)) t)
+//!                                                (cdr (condition-data c))
+//!                                                reraise)
+//!                                            reraise))))
+//!                         tag)
+//!
+//! where reraise is (error (condition-type c)
+//!                         (condition-message c)
+//!                         (condition-data c))
+//!
+//! (throw tag value) => ((fn (t v)
+//!                         (error (quote throw)
+//!                                (string-append "uncaught throw to tag " (write t))
+//!                                (cons t v)))
+//!                       tag value)
+//! ```
+//!
+//! Unwinding doesn't hold on to anything other than the condition so
+//! everything the body allocated before the error is unreachable once
+//! the handler runs.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::desugar::{builtin, temporary};
+use crate::fatal::{self, emit_check_callable};
+use crate::heap::emit_alloc;
+use crate::procedures::{emit_closure_call, emit_unchecked_closure_call};
+use crate::{Expr, PreorderStatus};
+
+/// The type of the condition that throw raises.
+const THROW: &str = "throw";
+
+impl Expr {
+    /// If the expression is a desugared try expression returns its
+    /// body and handler closures.
+    pub(crate) fn is_try(&self) -> Option<(&Expr, &Expr)> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "try" && v.len() == 3 {
+                    return Some((&v[1], &v[2]));
+                }
+            }
+        }
+        None
+    }
+
+    /// If the expression is a desugared unwind-protect expression
+    /// returns its body and cleanup closures.
+    pub(crate) fn is_unwind_protect(&self) -> Option<(&Expr, &Expr)> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "unwind-protect" && v.len() == 3 {
+                    return Some((&v[1], &v[2]));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns true if PROGRAM contains a try, unwind-protect, catch or
+/// parameterize expression.
+pub(crate) fn handles_errors(program: &[Expr]) -> bool {
+    let mut found = false;
+    for e in program {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if let Expr::List(v) = e {
+                if let Some(Expr::Symbol(s)) = v.first() {
+                    found |=
+                        s == "try" || s == "unwind-protect" || s == "catch" || s == "parameterize";
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+    found
+}
+
+pub(crate) fn closure(params: Expr, body: &[Expr]) -> Expr {
+    Expr::List(
+        vec![Expr::Symbol("fn".to_string()), params]
+            .into_iter()
+            .chain(body.iter().cloned())
+            .collect(),
+    )
+}
+
+/// Desugars `(try BODY... (catch E HANDLER...))`. ARGS are the
+/// arguments to try.
+pub(crate) fn desugar_try(args: &[Expr]) -> Result<Expr, String> {
+    let (catch, body) = match args.split_last() {
+        Some(split) if !split.1.is_empty() => split,
+        _ => return Err("try expects a body and a catch clause".to_string()),
+    };
+    let (var, handler) = match catch {
+        Expr::List(v) if v.len() >= 3 && v[0] == Expr::Symbol("catch".to_string()) => match &v[1] {
+            Expr::Symbol(_) => (v[1].clone(), &v[2..]),
+            _ => return Err(format!("catch expected a variable and got ({:?})", v[1])),
+        },
+        _ => {
+            return Err(format!(
+                "try expects its last argument to be a catch clause and got ({:?})",
+                catch
+            ))
+        }
+    };
+    // Throws are passed on to the catch they are meant for.
+    let handler = match handler {
+        [handler] => handler.clone(),
+        _ => Expr::List(vec![closure(Expr::Nil, handler)]),
+    };
+    let handler = if_throw(&var, reraise(&var), handler);
+    Ok(Expr::List(vec![
+        Expr::Symbol("try".to_string()),
+        closure(Expr::Nil, body),
+        closure(Expr::List(vec![var]), &[handler]),
+    ]))
+}
+
+/// Desugars `(unwind-protect BODY CLEANUP...)`. ARGS are the
+/// arguments to unwind-protect.
+pub(crate) fn desugar_unwind_protect(args: &[Expr]) -> Result<Expr, String> {
+    match args.split_first() {
+        Some((body, cleanup)) if !cleanup.is_empty() => Ok(Expr::List(vec![
+            Expr::Symbol("unwind-protect".to_string()),
+            closure(Expr::Nil, std::slice::from_ref(body)),
+            closure(Expr::Nil, cleanup),
+        ])),
+        _ => Err("unwind-protect expects a body and a cleanup".to_string()),
+    }
+}
+
+fn call(f: &str, args: Vec<Expr>) -> Expr {
+    Expr::List(
+        std::iter::once(Expr::Symbol(f.to_string()))
+            .chain(args)
+            .collect(),
+    )
+}
+
+/// Calls the builtin F in a way that the program's variables can't
+/// capture (see `desugar::builtin`).
+fn call_builtin(f: &str, args: Vec<Expr>) -> Expr {
+    Expr::List(std::iter::once(builtin(f)).chain(args).collect())
+}
+
+/// `(if (eq (condition-type C) (quote throw)) THEN ELSE)`.
+fn if_throw(c: &Expr, then: Expr, else_: Expr) -> Expr {
+    call(
+        "if",
+        vec![
+            call_builtin(
+                "eq",
+                vec![
+                    call_builtin("condition-type", vec![c.clone()]),
+                    call("quote", vec![Expr::Symbol(THROW.to_string())]),
+                ],
+            ),
+            then,
+            else_,
+        ],
+    )
+}
+
+/// `(error (condition-type C) (condition-message C) (condition-data C))`
+/// which raises the condition C again.
+fn reraise(c: &Expr) -> Expr {
+    let field = |name: &str| call_builtin(name, vec![c.clone()]);
+    call(
+        "error",
+        vec![
+            field("condition-type"),
+            field("condition-message"),
+            field("condition-data"),
+        ],
+    )
+}
+
+/// Desugars `(catch TAG BODY...)`. ARGS are the arguments to catch.
+pub(crate) fn desugar_catch(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (tag, body) = match args.split_first() {
+        Some(split) if !split.1.is_empty() => split,
+        _ => return Err("catch expects a tag and a body".to_string()),
+    };
+    let t = temporary(count);
+    let c = temporary(count);
+    let data = || call_builtin("condition-data", vec![c.clone()]);
+    let handler = if_throw(
+        &c,
+        call(
+            "if",
+            vec![
+                call_builtin("eq", vec![call_builtin("car", vec![data()]), t.clone()]),
+                call_builtin("cdr", vec![data()]),
+                reraise(&c),
+            ],
+        ),
+        reraise(&c),
+    );
+    let try_ = call(
+        "try",
+        vec![
+            closure(Expr::Nil, body),
+            closure(Expr::List(vec![c]), &[handler]),
+        ],
+    );
+    Ok(Expr::List(vec![
+        closure(Expr::List(vec![t]), &[try_]),
+        tag.clone(),
+    ]))
+}
+
+/// Desugars `(throw TAG VALUE)`. ARGS are the arguments to throw.
+pub(crate) fn desugar_throw(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (tag, value) = match args {
+        [tag, value] => (tag, value),
+        _ => return Err("throw expects a tag and a value".to_string()),
+    };
+    let t = temporary(count);
+    let v = temporary(count);
+    let message = call_builtin(
+        "string-append",
+        vec![
+            Expr::String("uncaught throw to tag ".to_string()),
+            call_builtin("write", vec![t.clone()]),
+        ],
+    );
+    let error = call(
+        "error",
+        vec![
+            call("quote", vec![Expr::Symbol(THROW.to_string())]),
+            message,
+            call_builtin("cons", vec![t.clone(), v.clone()]),
+        ],
+    );
+    Ok(Expr::List(vec![
+        closure(Expr::List(vec![t, v]), &[error]),
+        tag.clone(),
+        value.clone(),
+    ]))
+}
+
+/// Emits the code for a desugared try expression.
+pub(crate) fn emit_try(body: &Expr, handler: &Expr, ctx: &mut Context) -> Result<Value, String> {
+    let body = emit_check_callable(body, ctx)?;
+    let handler = emit_check_callable(handler, ctx)?;
+
+    let no_args = emit_alloc(0, ctx)?;
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    let res = emit_unchecked_closure_call(body, zero, no_args, ctx)?;
+
+    let handler_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let pending = crate::data::emit_data_access(fatal::ERROR_PENDING, ctx)?;
+    ctx.builder.ins().brnz(pending, handler_block, &[]);
+    ctx.builder.ins().jump(done_block, &[res]);
+
+    ctx.builder.switch_to_block(handler_block);
+    ctx.builder.seal_block(handler_block);
+    fatal::emit_set_error_pending(0, ctx)?;
+    let condition = crate::foreign::emit_host_call("lustc_take_condition", &[], ctx)?;
+    let argloc = emit_alloc(ctx.word.bytes().into(), ctx)?;
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), condition, argloc, 0);
+    let one = ctx.builder.ins().iconst(ctx.word, 1);
+    // Errors raised by the handler go to the next try out.
+    let handled = emit_closure_call(handler, one, argloc, ctx)?;
+    ctx.builder.ins().jump(done_block, &[handled]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+/// Emits the code for a desugared unwind-protect expression.
+pub(crate) fn emit_unwind_protect(
+    body: &Expr,
+    cleanup: &Expr,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let body = emit_check_callable(body, ctx)?;
+    let cleanup = emit_check_callable(cleanup, ctx)?;
+
+    let no_args = emit_alloc(0, ctx)?;
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    let res = emit_unchecked_closure_call(body, zero, no_args, ctx)?;
+
+    let error_block = ctx.builder.create_block();
+    let normal_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let pending = crate::data::emit_data_access(fatal::ERROR_PENDING, ctx)?;
+    ctx.builder.ins().brnz(pending, error_block, &[]);
+    ctx.builder.ins().jump(normal_block, &[]);
+
+    ctx.builder.switch_to_block(normal_block);
+    ctx.builder.seal_block(normal_block);
+    emit_closure_call(cleanup, zero, no_args, ctx)?;
+    ctx.builder.ins().jump(done_block, &[res]);
+
+    // The error is put aside while the cleanup runs so that trys in
+    // the cleanup don't see it and is raised again afterwards.
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+    fatal::emit_set_error_pending(0, ctx)?;
+    crate::foreign::emit_host_call("lustc_suspend_error", &[], ctx)?;
+    emit_unchecked_closure_call(cleanup, zero, no_args, ctx)?;
+    crate::foreign::emit_host_call("lustc_resume_error", &[], ctx)?;
+    let unreachable = fatal::emit_unwind(ctx)?;
+    ctx.builder.ins().jump(done_block, &[unreachable]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind, run_embedded};
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn try_catch() {
+        check("(try (error \"x\") (catch e 42))", "42");
+        check("(try (add 1 2) (catch e 42))", "3");
+        check(
+            "(try (error (quote oops) \"x\" 7) (catch e (cons (condition-type e) (condition-data e))))",
+            "(cons (quote oops) 7)",
+        );
+        check(
+            "(try (error \"no\") (catch e (condition-message e)))",
+            "\"no\"",
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let source = r#"
+(let f (fn (n) (if (eq n 0) (car n) (add 1 (f (sub n 1))))))
+(try (f 10) (catch e (condition-type e)))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(quote type-error)").unwrap()
+        );
+        assert_eq!(
+            roundtrip_string("(try (div 1 0) (catch e (condition-message e)))").unwrap(),
+            roundtrip_string("\"fatal error: division by zero in div\"").unwrap()
+        );
+    }
+
+    #[test]
+    fn nested() {
+        // The innermost try handles the error and errors raised by
+        // a handler go to the try around it.
+        check(
+            "(try (add 1 (try (error \"inner\") (catch e 1))) (catch e 100))",
+            "2",
+        );
+        check(
+            "(try (try (error \"inner\") (catch e (error \"outer\"))) (catch e (condition-message e)))",
+            "\"outer\"",
+        );
+        // Execution continues normally after an error is handled.
+        check(
+            "(let x (try (error \"a\") (catch e 1))) (let y (try (car 1) (catch e 2))) (add x y)",
+            "3",
+        );
+    }
+
+    #[test]
+    fn unhandled_in_embedded_mode() {
+        let err =
+            run_embedded("(try 1 (catch e 2)) (error (quote late) \"after try\" 0)").unwrap_err();
+        assert_eq!(
+            (err.kind.as_str(), err.message.as_str()),
+            ("late", "after try")
+        );
+    }
+
+    #[test]
+    fn cleanup_runs_once() {
+        // Cleanups run on both paths and the innermost runs first.
+        let source = r#"
+(let log ())
+(let note (fn (n) (set log (cons n log))))
+(let r (try
+         (unwind-protect
+           (unwind-protect (error "x") (note 1))
+           (note 2))
+         (catch e (condition-message e))))
+(let s (unwind-protect (add 2 3) (note 3)))
+(cons log (cons r s))
+"#;
+        check(source, "(cons (quote (3 2 1)) (cons \"x\" 5))");
+    }
+
+    #[test]
+    fn cleanup_errors() {
+        // An error raised by the cleanup replaces the one unwinding
+        // and trys in a cleanup don't catch the error that is.
+        check(
+            "(try (unwind-protect (error \"body\") (error \"cleanup\")) (catch e (condition-message e)))",
+            "\"cleanup\"",
+        );
+        check(
+            "(try (unwind-protect (error \"body\") (try 1 (catch e 2))) (catch e (condition-message e)))",
+            "\"body\"",
+        );
+
+        assert_eq!(
+            error_kind("(unwind-protect (error (quote first) \"body\" 0) (add 1 2))"),
+            "first"
+        );
+    }
+
+    #[test]
+    fn catch_and_throw() {
+        check("(catch (quote done) 1 2)", "2");
+        check("(catch (quote done) (add 1 (throw (quote done) 41)))", "41");
+        // Each throw goes to the nearest catch with its tag.
+        check(
+            "(catch (quote outer) (cons 1 (catch (quote inner) (throw (quote outer) 2))))",
+            "2",
+        );
+        check(
+            "(catch (quote outer) (cons 1 (catch (quote inner) (throw (quote inner) 2))))",
+            "(cons 1 2)",
+        );
+        check(
+            "(catch (quote a) (catch (quote b) (catch (quote a) (throw (quote a) 1)) (throw (quote b) 2)))",
+            "2",
+        );
+        // Cleanups run on the way out.
+        check(
+            "(let log ()) (catch 7 (unwind-protect (throw 7 1) (set log (cons 2 log)))) log",
+            "(quote (2))",
+        );
+    }
+
+    #[test]
+    fn throw_through_try() {
+        // A try passes a throw on to its catch.
+        check(
+            "(catch (quote done) (try (throw (quote done) 5) (catch e 99)))",
+            "5",
+        );
+        check(
+            "(catch (quote done) (cons 1 (try (car 1) (catch e (throw (quote done) 2)))))",
+            "2",
+        );
+        // Even one that doesn't have a catch.
+        assert_eq!(
+            run_embedded("(try (throw (quote missing) 5) (catch e 99))")
+                .unwrap_err()
+                .kind,
+            "throw"
+        );
+        // The handler is all of the expressions after the variable.
+        check(
+            "(let seen ()) (cons (try (car 1) (catch e (set seen 1) (condition-type e))) seen)",
+            "(cons (quote type-error) 1)",
+        );
+    }
+
+    #[test]
+    fn throw_with_builtins_in_scope() {
+        check(
+            "(let eq (fn (a b) ())) (let cons 1) (let car 2) (let cdr 3) (let write 4)
+             (catch (quote a) (catch (quote b) (throw (quote a) 7)))",
+            "7",
+        );
+    }
+
+    #[test]
+    fn throw_out_of_loop() {
+        let source = r#"
+(let seen ())
+(let found (catch (quote found)
+  (catch (quote skip)
+    (dotimes (i 10)
+      (set seen (cons i seen))
+      (if (eq i 4) (throw (quote found) (mul i 10)) ())))
+  (quote none)))
+(cons found seen)
+"#;
+        check(source, "(cons 40 (quote (4 3 2 1 0)))");
+    }
+
+    #[test]
+    fn uncaught_throw() {
+        let err = run_embedded("(catch (quote other) (throw (quote missing) 1))").unwrap_err();
+        assert_eq!(
+            (err.kind.as_str(), err.message.as_str()),
+            ("throw", "uncaught throw to tag missing")
+        );
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string("(throw (quote missing) 1)").unwrap())
+                .unwrap_err(),
+            "uncaught throw to tag missing"
+        );
+        assert_eq!(
+            crate::test_util::run_cli("(display 1) (newline) (throw (quote missing) 1)"),
+            ("1\nuncaught throw to tag missing\n".to_string(), Some(1))
+        );
+    }
+}
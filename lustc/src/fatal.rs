@@ -1,58 +1,447 @@
 use crate::{
     compiler::{self, Context, JIT},
     conversions,
-    data::LustData,
-    foreign, Expr, Word,
+    data::{self, LustData},
+    foreign, sourcemap, Expr, Word,
 };
 use cranelift::prelude::*;
+use cranelift_module::{Linkage, Module};
+
+/// The exit code used by error expressions that do not provide one.
+static DEFAULT_EXIT_CODE: Expr = Expr::Integer(1);
+
+/// Messages for the errors raised by the runtime itself. The first
+/// element of each entry is the name of the data that holds the
+/// message, the third is the type of the condition raised for it, and
+/// the last is the trap code that tells the host why it was raised.
+/// This is the only place that trap codes and messages are tied
+/// together.
+static ERROR_STRINGS: [(&str, &str, &str, TrapCode); 9] = [
+    (
+        "__anon_data_bad_call_type",
+        "fatal error: non-closure object in head position of list",
+        "bad-call",
+        TrapCode::BadSignature,
+    ),
+    (
+        "__anon_data_bad_arg_type",
+        "fatal error: runtime type missmatch",
+        "type-error",
+        TrapCode::User(TYPE_ERROR_TRAP),
+    ),
+    (
+        "__anon_data_bad_arg_count",
+        "fatal error: wrong number of arguments in function call",
+        "arity-error",
+        TrapCode::User(ARITY_ERROR_TRAP),
+    ),
+    (
+        "__anon_data_div_by_zero_div",
+        "fatal error: division by zero in div",
+        "division-by-zero",
+        TrapCode::IntegerDivisionByZero,
+    ),
+    (
+        "__anon_data_div_by_zero_mod",
+        "fatal error: division by zero in mod",
+        "division-by-zero",
+        TrapCode::IntegerDivisionByZero,
+    ),
+    (
+        "__anon_data_div_by_zero_rem",
+        "fatal error: division by zero in rem",
+        "division-by-zero",
+        TrapCode::IntegerDivisionByZero,
+    ),
+    (
+        "__anon_data_out_of_range",
+        "fatal error: index out of range",
+        "range-error",
+        TrapCode::HeapOutOfBounds,
+    ),
+    (
+        "__anon_data_negative_exponent",
+        "fatal error: expt expects a non-negative exponent",
+        "range-error",
+        TrapCode::HeapOutOfBounds,
+    ),
+    (
+        "__anon_data_bad_read",
+        "fatal error: read expects a string holding one datum",
+        "read-error",
+        TrapCode::User(READ_ERROR_TRAP),
+    ),
+];
+
+/// The user trap code of runtime type errors.
+pub const TYPE_ERROR_TRAP: u16 = 1;
+
+/// The user trap code of calls with the wrong number of arguments.
+pub const ARITY_ERROR_TRAP: u16 = 2;
+
+/// The user trap code of strings that read can't parse.
+pub const READ_ERROR_TRAP: u16 = 3;
+
+/// The type of the condition raised by an error expression that
+/// doesn't give one.
+pub(crate) const DEFAULT_ERROR_TYPE: &str = "error";
+
+/// Returns the message of the runtime error whose message is stored
+/// in the data named NAME.
+pub(crate) fn internal_error_message(name: &str) -> &'static str {
+    ERROR_STRINGS
+        .iter()
+        .find(|(n, _, _, _)| *n == name)
+        .map(|(_, msg, _, _)| *msg)
+        .unwrap()
+}
+
+/// Returns the type of the condition raised for the runtime error
+/// with MESSAGE or the default type if MESSAGE isn't one of theirs.
+pub(crate) fn internal_error_type(message: &str) -> &'static str {
+    ERROR_STRINGS
+        .iter()
+        .find(|(_, msg, _, _)| *msg == message)
+        .map_or(DEFAULT_ERROR_TYPE, |(_, _, kind, _)| *kind)
+}
+
+/// Returns the message of the runtime errors with the trap code TRAP
+/// or None if the runtime never raises it. The division primitives
+/// share a trap code and the message is the one for div.
+pub fn trap_message(trap: TrapCode) -> Option<&'static str> {
+    ERROR_STRINGS
+        .iter()
+        .find(|(_, _, _, t)| *t == trap)
+        .map(|(_, msg, _, _)| *msg)
+}
+
+/// Name of the data word that is set while an error raised in
+/// embedded mode is unwinding.
+pub(crate) const ERROR_PENDING: &str = "__anon_data_error_pending";
 
 impl Expr {
     // Determines if an expression is an error expression and returns
-    // its messsage and return code arguments.
+    // its messsage and return code arguments. If no return code is
+    // provided a default one is used.
     pub fn is_error(&self) -> Option<(&Expr, &Expr)> {
         if let Expr::List(v) = self {
             if let Some(Expr::Symbol(s)) = v.first() {
                 if s == "error" && v.len() == 3 {
                     return Some((&v[1], &v[2]));
                 }
+                if s == "error" && v.len() == 2 {
+                    return Some((&v[1], &DEFAULT_EXIT_CODE));
+                }
             }
         }
         None
     }
 }
 
+/// An error raised by a program running in embedded mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LustError {
+    /// The error's message.
+    pub message: String,
+    /// The exit code the program would have exited with had it not
+    /// been running in embedded mode.
+    pub code: i64,
+    /// The name of the type symbol of the condition that was raised.
+    /// See `conditions.rs`.
+    pub kind: String,
+    /// The trap code of the error if the runtime raised it and None
+    /// if the program did. Unlike the message this is meant to be
+    /// matched on. See `trap_message`.
+    pub trap: Option<TrapCode>,
+    /// The index of the top level form whose code raised the error,
+    /// from the same tags as the JIT's source map. An error raised in
+    /// a function names the form that defined the function rather
+    /// than the one that called it. This is None for code that isn't
+    /// part of a form, like a primitive that was passed as a value.
+    /// `parse_string_with_locations` gives the form's place in the
+    /// source.
+    pub form: Option<usize>,
+}
+
+impl std::fmt::Display for LustError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (exit code {})", self.message, self.code)
+    }
+}
+
+thread_local! {
+    /// The error raised by the program currently running on this
+    /// thread, if any.
+    static RAISED_ERROR: std::cell::RefCell<Option<LustError>> = const { std::cell::RefCell::new(None) };
+
+    /// The condition describing the raised error. This is what a
+    /// `try` handler is passed.
+    static RAISED_CONDITION: std::cell::Cell<Word> = const { std::cell::Cell::new(0) };
+
+    /// Errors that were put aside while an unwind-protect's cleanup
+    /// runs. The innermost cleanup's is last.
+    static SUSPENDED_ERRORS: std::cell::RefCell<Vec<(LustError, Word)>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+fn raise(
+    message: String,
+    code: Word,
+    kind: &str,
+    trap: Option<TrapCode>,
+    condition: Word,
+    form: Word,
+) {
+    let code = match Expr::from_immediate(code) {
+        Expr::Integer(i) => i,
+        _ => 1,
+    };
+    RAISED_ERROR.with(|e| {
+        *e.borrow_mut() = Some(LustError {
+            message,
+            code,
+            kind: kind.to_string(),
+            trap,
+            form: if form < 0 { None } else { Some(form as usize) },
+        })
+    });
+    RAISED_CONDITION.with(|c| c.set(condition));
+}
+
+/// Converts the tagged message of an error into a string.
+pub(crate) fn message_to_string(message: Word) -> String {
+    let message = Expr::from_immediate(message);
+    conversions::try_stringify_list(&message).unwrap_or(message.to_string())
+}
+
+/// Records an error raised by an error expression. MESSAGE and CODE
+/// are the tagged values of the expression's arguments and FORM is
+/// the untagged index of the top level form or -1.
+pub extern "C" fn lustc_raise(message: Word, code: Word, form: Word) -> Word {
+    let condition =
+        crate::conditions::new_condition(DEFAULT_ERROR_TYPE, message, Expr::Nil.immediate_rep());
+    raise(
+        message_to_string(message),
+        code,
+        DEFAULT_ERROR_TYPE,
+        None,
+        condition,
+        form,
+    );
+    Expr::Nil.immediate_rep()
+}
+
+/// Records one of the errors raised by the runtime. INDEX is the
+/// index of the error's message in ERROR_STRINGS and FORM is as for
+/// `lustc_raise`.
+pub extern "C" fn lustc_raise_internal(index: Word, code: Word, form: Word) -> Word {
+    let (_, message, kind, trap) = ERROR_STRINGS[index as usize];
+    let condition = crate::conditions::new_condition(
+        kind,
+        Expr::String(message.to_string()).immediate_rep(),
+        Expr::Nil.immediate_rep(),
+    );
+    raise(message.to_string(), code, kind, Some(trap), condition, form);
+    Expr::Nil.immediate_rep()
+}
+
+/// Records an error raised with a condition. CONDITION is the tagged
+/// condition, CODE the exit code and FORM is as for `lustc_raise`.
+pub extern "C" fn lustc_raise_condition(condition: Word, code: Word, form: Word) -> Word {
+    let (kind, message, _) = crate::conditions::condition_fields(condition);
+    raise(
+        message_to_string(message),
+        code,
+        crate::symbols::symbol_name(kind),
+        None,
+        condition,
+        form,
+    );
+    Expr::Nil.immediate_rep()
+}
+
+/// Prints the message of an error raised outside of embedded mode
+/// just before the program exits. MESSAGE is the tagged value of the
+/// error's message.
+pub extern "C" fn lustc_print_error(message: Word) -> Word {
+    crate::output::flush_output();
+    println!("{}", message_to_string(message));
+    Expr::Nil.immediate_rep()
+}
+
+/// Handles the error that is unwinding for a `try` expression. The
+/// error is forgotten so that it doesn't reach the host and its
+/// condition is returned.
+pub extern "C" fn lustc_take_condition() -> Word {
+    take_raised_error();
+    RAISED_CONDITION.with(|c| c.get())
+}
+
+/// Puts the error that is unwinding aside so that an unwind-protect
+/// can run its cleanup.
+pub extern "C" fn lustc_suspend_error() -> Word {
+    let error = take_raised_error().unwrap();
+    let condition = RAISED_CONDITION.with(|c| c.get());
+    SUSPENDED_ERRORS.with(|s| s.borrow_mut().push((error, condition)));
+    Expr::Nil.immediate_rep()
+}
+
+/// Raises the error put aside by the last call to
+/// `lustc_suspend_error` again unless the cleanup raised one of its
+/// own, in which case the cleanup's error wins.
+pub extern "C" fn lustc_resume_error() -> Word {
+    let (error, condition) = SUSPENDED_ERRORS.with(|s| s.borrow_mut().pop()).unwrap();
+    RAISED_ERROR.with(|e| {
+        let mut e = e.borrow_mut();
+        if e.is_none() {
+            *e = Some(error);
+            RAISED_CONDITION.with(|c| c.set(condition));
+        }
+    });
+    Expr::Nil.immediate_rep()
+}
+
+/// Takes the error raised by the last program run on this thread if
+/// there was one.
+pub(crate) fn take_raised_error() -> Option<LustError> {
+    RAISED_ERROR.with(|e| e.borrow_mut().take())
+}
+
 pub(crate) fn emit_error_strings(jit: &mut JIT) -> Result<(), String> {
-    let error_strings = [
-        (
-            "__anon_data_bad_call_type",
-            "fatal error: non-closure object in head position of list",
-        ),
-        (
-            "__anon_data_bad_arg_type",
-            "fatal error: runtime type missmatch",
-        ),
-        (
-            "__anon_data_bad_arg_count",
-            "fatal error: wrong number of arguments in function call",
-        ),
-    ];
-    let error_data = error_strings
+    let error_data = ERROR_STRINGS
         .iter()
-        .map(|(name, msg)| -> Result<LustData, std::ffi::NulError> {
-            Ok(LustData {
-                name: name.to_string(),
-                // bit of a hack but we tag these as pairs so that
-                // they register as heap allocated values elsewhere.
-                data: std::ffi::CString::new(*msg)?.into_raw() as Word | conversions::PAIR_TAG,
-            })
-        })
+        .map(
+            |(name, msg, _, _)| -> Result<LustData, std::ffi::NulError> {
+                Ok(LustData {
+                    name: name.to_string(),
+                    // bit of a hack but we tag these as pairs so that
+                    // they register as heap allocated values elsewhere.
+                    data: std::ffi::CString::new(*msg)?.into_raw() as Word | conversions::PAIR_TAG,
+                    align: None,
+                })
+            },
+        )
         .collect::<Result<Vec<LustData>, _>>()
         .map_err(|e| e.to_string())?;
 
-    error_data
-        .into_iter()
-        .map(|d| crate::data::create_data(d, jit))
-        .collect()
+    crate::data::create_data(error_data, jit)
+}
+
+/// Defines the word that is set while an error is unwinding in
+/// embedded mode.
+pub(crate) fn define_error_pending(jit: &mut JIT) -> Result<(), String> {
+    crate::data::create_data(
+        vec![LustData {
+            name: ERROR_PENDING.to_string(),
+            data: 0,
+            align: None,
+        }],
+        jit,
+    )
+}
+
+/// Clears the error pending word so that JIT can run programs again
+/// after one of its programs raised an error.
+pub(crate) fn clear_error_pending(jit: &mut JIT) {
+    let id = jit
+        .module
+        .declare_data(ERROR_PENDING, Linkage::Export, true, false)
+        .unwrap();
+    let (ptr, _) = jit.module.get_finalized_data(id);
+    unsafe { *(ptr as *mut Word) = 0 };
+}
+
+/// Emits the code to return from the current function, leaving the
+/// value returned to the caller unspecified, if an error is unwinding
+/// in embedded mode.
+pub(crate) fn emit_check_error_pending(ctx: &mut Context) -> Result<(), String> {
+    let pending = data::emit_data_access(ERROR_PENDING, ctx)?;
+
+    let unwind_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brnz(pending, unwind_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(unwind_block);
+    ctx.builder.seal_block(unwind_block);
+
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+    ctx.builder.ins().return_(&[nil]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+
+    Ok(())
+}
+
+/// Emits the code to raise an error in embedded mode. The error is
+/// recorded, the error pending word is set, and the current function
+/// returns. Every caller returns in turn once it sees that the error
+/// pending word is set until control makes its way back to the host.
+fn emit_raise(message: &Expr, exit_code: &Expr, ctx: &mut Context) -> Result<Value, String> {
+    let internal = match message {
+        Expr::Symbol(s) => ERROR_STRINGS.iter().position(|(name, _, _, _)| name == s),
+        _ => None,
+    };
+    match internal {
+        Some(index) => {
+            let index = ctx.builder.ins().iconst(ctx.word, index as i64);
+            let code = compiler::emit_expr(exit_code, ctx)?;
+            let form = sourcemap::emit_form(ctx);
+            foreign::emit_host_call("lustc_raise_internal", &[index, code, form], ctx)?;
+        }
+        None => {
+            let message = compiler::emit_expr(message, ctx)?;
+            let code = compiler::emit_expr(exit_code, ctx)?;
+            let form = sourcemap::emit_form(ctx);
+            foreign::emit_host_call("lustc_raise", &[message, code, form], ctx)?;
+        }
+    }
+
+    emit_unwind(ctx)
+}
+
+/// Emits the code to start unwinding once an error has been recorded
+/// in embedded mode. Returns a value for the unreachable code after
+/// the unwind to use.
+pub(crate) fn emit_unwind(ctx: &mut Context) -> Result<Value, String> {
+    emit_set_error_pending(1, ctx)?;
+
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+    ctx.builder.ins().return_(&[nil]);
+
+    // Code emitted after the error is unreachable but callers expect
+    // to be able to keep emitting code so we give them a block to
+    // put it in.
+    let unreachable_block = ctx.builder.create_block();
+    ctx.builder.switch_to_block(unreachable_block);
+    ctx.builder.seal_block(unreachable_block);
+
+    Ok(ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep()))
+}
+
+/// Emits the code to set the error pending word to PENDING.
+pub(crate) fn emit_set_error_pending(pending: Word, ctx: &mut Context) -> Result<(), String> {
+    let sym = ctx
+        .module
+        .declare_data(ERROR_PENDING, Linkage::Export, true, false)
+        .map_err(|e| e.to_string())?;
+    let local_id = ctx.module.declare_data_in_func(sym, ctx.builder.func);
+    let pending_ptr = ctx.builder.ins().symbol_value(ctx.word, local_id);
+    let pending = ctx.builder.ins().iconst(ctx.word, pending);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), pending, pending_ptr, 0);
+    Ok(())
 }
 
 pub(crate) fn emit_error(
@@ -60,7 +449,22 @@ pub(crate) fn emit_error(
     exit_code: &Expr,
     ctx: &mut Context,
 ) -> Result<Value, String> {
-    foreign::emit_foreign_call("puts", &[message.clone()], ctx)?;
+    if ctx.options.embedded {
+        return emit_raise(message, exit_code, ctx);
+    }
+    let internal = match message {
+        Expr::Symbol(s) => ERROR_STRINGS.iter().any(|(name, _, _, _)| name == s),
+        _ => false,
+    };
+    if internal {
+        foreign::emit_host_call("lustc_flush_output", &[], ctx)?;
+        foreign::emit_foreign_call("puts", &[message.clone()], ctx)?;
+    } else {
+        // The message of an error expression is a string of the
+        // program's own which puts can't print.
+        let message = compiler::emit_expr(message, ctx)?;
+        foreign::emit_host_call("lustc_print_error", &[message], ctx)?;
+    }
     foreign::emit_foreign_call("exit", &[exit_code.clone()], ctx)
 }
 
@@ -96,6 +500,145 @@ pub(crate) fn emit_check_tag(
     Ok(())
 }
 
+/// Emits the code to raise a type error unless COND is true.
+pub(crate) fn emit_check_type(cond: Value, ctx: &mut Context) -> Result<(), String> {
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(cond, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    emit_error(
+        &Expr::Symbol("__anon_data_bad_arg_type".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+    Ok(())
+}
+
+/// Emits the code to raise a range error unless COND is true.
+pub(crate) fn emit_check_range(cond: Value, ctx: &mut Context) -> Result<(), String> {
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(cond, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    emit_error(
+        &Expr::Symbol("__anon_data_out_of_range".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+    Ok(())
+}
+
+/// Emits the code to raise a read error unless COND is true.
+pub(crate) fn emit_check_readable(cond: Value, ctx: &mut Context) -> Result<(), String> {
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(cond, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    emit_error(
+        &Expr::Symbol("__anon_data_bad_read".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+    Ok(())
+}
+
+/// Emits the code to check that the fixnum INDEX is at least zero and
+/// less than LENGTH, which is an untagged count.
+pub(crate) fn emit_check_index(
+    index: Value,
+    length: Value,
+    ctx: &mut Context,
+) -> Result<(), String> {
+    // Negative indexes are huge when compared unsigned.
+    let index = ctx.builder.ins().sshr_imm(index, conversions::FIXNUM_SHIFT);
+    let in_range = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::UnsignedLessThan, index, length);
+
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(in_range, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    emit_error(
+        &Expr::Symbol("__anon_data_out_of_range".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+
+    Ok(())
+}
+
+/// Emits the code to check that the divisor QUERY of the division
+/// primitive NAME is not zero.
+pub(crate) fn emit_check_nonzero(
+    query: Value,
+    name: &str,
+    ctx: &mut Context,
+) -> Result<(), String> {
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(query, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    emit_error(
+        &Expr::Symbol(format!("__anon_data_div_by_zero_{}", name)),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+
+    Ok(())
+}
+
 pub(crate) fn emit_check_int(query: Value, ctx: &mut Context) -> Result<(), String> {
     emit_check_tag(
         query,
@@ -109,11 +652,11 @@ pub(crate) fn emit_check_char(query: Value, ctx: &mut Context) -> Result<(), Str
     emit_check_tag(query, conversions::CHAR_TAG, conversions::CHAR_MASK, ctx)
 }
 
-pub(crate) fn emit_check_bool(query: Value, ctx: &mut Context) -> Result<(), String> {
-    emit_check_tag(query, conversions::BOOL_TAG, conversions::BOOL_MASK, ctx)
-}
-
 pub(crate) fn emit_check_pair(query: Value, ctx: &mut Context) -> Result<(), String> {
+    if ctx.options.pack_lists {
+        let is_pair = crate::vectors::emit_is_pair(query, ctx);
+        return emit_check_type(is_pair, ctx);
+    }
     emit_check_tag(
         query,
         conversions::PAIR_TAG,
@@ -124,6 +667,12 @@ pub(crate) fn emit_check_pair(query: Value, ctx: &mut Context) -> Result<(), Str
 
 pub(crate) fn emit_check_callable(query: &Expr, ctx: &mut Context) -> Result<Value, String> {
     let closure_ptr = compiler::emit_expr(query, ctx)?;
+    emit_check_closure(closure_ptr, ctx)?;
+    Ok(closure_ptr)
+}
+
+/// Emits the code to check that CLOSURE_PTR is a closure.
+pub(crate) fn emit_check_closure(closure_ptr: Value, ctx: &mut Context) -> Result<(), String> {
     let tag = ctx
         .builder
         .ins()
@@ -155,7 +704,7 @@ pub(crate) fn emit_check_callable(query: &Expr, ctx: &mut Context) -> Result<Val
     ctx.builder.switch_to_block(ok_block);
     ctx.builder.seal_block(ok_block);
 
-    Ok(closure_ptr)
+    Ok(())
 }
 
 pub(crate) fn emit_check_arg_count(
@@ -175,7 +724,27 @@ pub(crate) fn emit_check_arg_count(
             .ins()
             .icmp_imm(IntCC::Equal, actual, expected as i64)
     };
+    emit_check_arg_count_holds(cond, ctx)
+}
 
+/// Emits the code to check that a primitive with optional arguments
+/// was called with at most MAX of them. The least it can be called
+/// with is checked like the regular parameters of a varadic function.
+pub(crate) fn emit_check_max_arg_count(
+    max: usize,
+    actual: Value,
+    ctx: &mut Context,
+) -> Result<(), String> {
+    let cond = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::UnsignedLessThanOrEqual, actual, max as i64);
+    emit_check_arg_count_holds(cond, ctx)
+}
+
+/// Emits the code to raise a bad argument count error unless COND is
+/// nonzero.
+fn emit_check_arg_count_holds(cond: Value, ctx: &mut Context) -> Result<(), String> {
     let error_block = ctx.builder.create_block();
     let ok_block = ctx.builder.create_block();
 
@@ -200,3 +769,109 @@ pub(crate) fn emit_check_arg_count(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_program, CompileOptions};
+    use crate::parse_string;
+    use crate::test_util::run_embedded;
+
+    #[test]
+    fn embedded_error() {
+        assert_eq!(
+            run_embedded("(error \"x\")"),
+            Err(LustError {
+                message: "x".to_string(),
+                code: 1,
+                kind: "error".to_string(),
+                trap: None,
+                form: Some(0),
+            })
+        );
+    }
+
+    #[test]
+    fn embedded_error_unwinds() {
+        let source = r#"
+(let f (fn (n) (if (eq n 0) (error "bottom" 3) (add (f (sub n 1)) 1))))
+(f 10)
+"#;
+        assert_eq!(
+            run_embedded(source),
+            Err(LustError {
+                message: "bottom".to_string(),
+                code: 3,
+                kind: "error".to_string(),
+                trap: None,
+                form: Some(0),
+            })
+        );
+    }
+
+    #[test]
+    fn embedded_runtime_error() {
+        let err = run_embedded("(add 1 (quote (1 2)))").unwrap_err();
+        assert_eq!(err.message, "fatal error: runtime type missmatch");
+        assert_eq!(err.code, -1);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        for (source, op, form) in [
+            ("(/ 1 0)", "div", Some(0)),
+            ("(div 10 2 0)", "div", Some(0)),
+            ("(div 0)", "div", Some(0)),
+            ("(mod 5 0)", "mod", Some(0)),
+            ("(rem 5 0)", "rem", Some(0)),
+            ("(let d (fn (f) (f 5 0))) (d mod)", "mod", None),
+        ] {
+            assert_eq!(
+                run_embedded(source),
+                Err(LustError {
+                    message: format!("fatal error: division by zero in {}", op),
+                    code: -1,
+                    kind: "division-by-zero".to_string(),
+                    trap: Some(TrapCode::IntegerDivisionByZero),
+                    form,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn trap_codes() {
+        let trap = |source: &str| run_embedded(source).unwrap_err().trap;
+        let division = trap("(div 1 0)");
+        let range = trap("(vector-ref (list->vector (quote (1 2))) 2)");
+        assert_eq!(division, Some(TrapCode::IntegerDivisionByZero));
+        assert_eq!(range, Some(TrapCode::HeapOutOfBounds));
+        assert_ne!(division, range);
+
+        assert_eq!(trap("(car 1)"), Some(TrapCode::User(TYPE_ERROR_TRAP)));
+        assert_eq!(trap("(error \"x\")"), None);
+        assert_eq!(
+            trap_message(range.unwrap()),
+            Some("fatal error: index out of range")
+        );
+        assert_eq!(trap_message(TrapCode::StackOverflow), None);
+    }
+
+    #[test]
+    fn embedded_reuse_after_error() {
+        let source = r#"
+(let f (fn (n) (if (eq n 0) (error "zero" 2) n)))
+(f 0)
+"#;
+        let mut jit = JIT::new(CompileOptions {
+            embedded: true,
+            ..Default::default()
+        });
+        let mut program = parse_string(source).unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert!(jit.invoke(id).is_err());
+        assert!(jit.invoke(id).is_err());
+
+        assert_eq!(run_embedded("(add 1 2)"), Ok(Expr::Integer(3)));
+    }
+}
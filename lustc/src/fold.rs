@@ -0,0 +1,891 @@
+//! Constant folding. Primitive calls whose arguments are all known at
+//! compile time are evaluated here and replaced with their results so
+//! that no code needs to be emitted for them. Variables bound with
+//! let to a value known at compile time are replaced with that value
+//! so that expressions using them can fold as well.
+//!
+//! The list primitives length, null?, pair?, car and cdr fold when
+//! their argument is a quoted list or a string. car and cdr of the
+//! empty list are left for the runtime so that they still raise an
+//! error.
+//!
+//! string-append, string-length and string-ref fold when their
+//! arguments are string literals, so `(string-append "a" "b")` becomes
+//! the literal "ab" and takes its place in the program's data instead
+//! of being built at runtime. A string-ref outside of its string is
+//! left alone for the same reason as car of the empty list.
+//!
+//! Only integers fold. There are no rationals or floats, so expt with
+//! a negative exponent is left for the runtime to raise an error
+//! about, and sqrt, sin, cos, log and exp wait on floats to exist at
+//! all (see docs/floats.md).
+//!
+//! A conditional whose condition folds to a known value is replaced
+//! with the branch it would take. The branch may then fold in turn, so
+//! `(if (null? (quote ())) (if (pair? (quote (1))) 1 2) 3)` becomes 1.
+//!
+//! Arithmetic wraps around at the width of a fixnum at runtime and
+//! folding wraps in the same way, so a call gives the same result
+//! whether or not it was folded. Calls that the runtime would raise an
+//! error for, like dividing by zero, are never folded. There is no
+//! checked arithmetic mode. If one is added, calls that overflow must
+//! be left for the runtime to check too.
+
+use std::collections::HashMap;
+
+use crate::conversions::{wrap_fixnum, FIXNUM_MAX, FIXNUM_MIN};
+use crate::Expr;
+use crate::PreorderStatus;
+
+impl Expr {
+    /// Determines if the expression is a quote expression and if it
+    /// is returns the expression being quoted.
+    pub(crate) fn is_quote(&self) -> Option<&Expr> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "quote" && v.len() == 2 {
+                    return Some(&v[1]);
+                }
+            }
+        }
+        None
+    }
+
+    /// Determines if the value of the expression is known at compile
+    /// time without needing to be stored in the program's data.
+    fn is_literal(&self) -> bool {
+        matches!(
+            self,
+            Expr::Integer(_) | Expr::Char(_) | Expr::Bool(_) | Expr::Nil
+        )
+    }
+
+    /// If the expression is a value known at compile time returns
+    /// rather or not it is falsey. Nil and false are the only falsey
+    /// values.
+    fn literal_is_falsey(&self) -> Option<bool> {
+        match self {
+            Expr::Bool(b) => Some(!b),
+            Expr::Nil => Some(true),
+            Expr::String(_) => Some(false),
+            e if e.is_literal() => Some(false),
+            e => e.is_quote().map(|q| *q == Expr::Nil),
+        }
+    }
+
+    /// If the expression is a list known at compile time returns its
+    /// elements. Strings are lists of characters.
+    fn constant_list_elements(&self) -> Option<Vec<Expr>> {
+        match self {
+            Expr::Nil => Some(Vec::new()),
+            Expr::String(s) => Some(s.chars().map(Expr::Char).collect()),
+            e => match e.is_quote()? {
+                Expr::Nil => Some(Vec::new()),
+                Expr::List(v) => Some(v.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Returns an expression that evaluates to the constant E. Literals
+/// evaluate to themselves and everything else is quoted.
+fn quote_constant(e: Expr) -> Expr {
+    match e {
+        Expr::Nil => Expr::Nil,
+        Expr::List(v) if v.is_empty() => Expr::Nil,
+        e if e.is_literal() => e,
+        e => Expr::List(vec![Expr::Symbol("quote".to_string()), e]),
+    }
+}
+
+/// Folds a call to the list primitive NAME on a list whose elements
+/// are known at compile time after ARG has been checked to be one.
+fn fold_list_primcall(name: &str, arg: &Expr) -> Option<Expr> {
+    let elements = arg.constant_list_elements()?;
+    match name {
+        "length" => Some(Expr::Integer(elements.len() as i64)),
+        "null?" => Some(Expr::Bool(elements.is_empty())),
+        "pair?" => Some(Expr::Bool(!elements.is_empty())),
+        "atom?" => Some(Expr::Bool(elements.is_empty())),
+        "car" => elements.into_iter().next().map(quote_constant),
+        "cdr" if elements.is_empty() => None,
+        "cdr" => Some(match arg {
+            Expr::String(s) => match s.chars().skip(1).collect::<String>() {
+                rest if rest.is_empty() => Expr::Nil,
+                rest => Expr::String(rest),
+            },
+            _ => quote_constant(Expr::List(elements[1..].to_vec())),
+        }),
+        _ => None,
+    }
+}
+
+/// If E is a string literal returns its characters. The empty string
+/// is nil.
+fn constant_string(e: &Expr) -> Option<&str> {
+    match e {
+        Expr::String(s) => Some(s),
+        Expr::Nil => Some(""),
+        _ => None,
+    }
+}
+
+/// Folds a call to the string primitive NAME with ARGS if they are
+/// string literals.
+fn fold_string_primcall(name: &str, args: &[Expr]) -> Option<Expr> {
+    match (name, args) {
+        ("string-append", [a, b]) => {
+            let s = constant_string(a)?.to_string() + constant_string(b)?;
+            Some(if s.is_empty() {
+                Expr::Nil
+            } else {
+                Expr::String(s)
+            })
+        }
+        ("string-length", [s]) => Some(Expr::Integer(constant_string(s)?.chars().count() as i64)),
+        ("string-ref", [s, Expr::Integer(i)]) if *i >= 0 => {
+            constant_string(s)?.chars().nth(*i as usize).map(Expr::Char)
+        }
+        _ => None,
+    }
+}
+
+/// Tries to evaluate a call to the primitive NAME at compile time. On
+/// success returns the result of the call.
+fn fold_primcall(name: &str, args: &[Expr]) -> Option<Expr> {
+    match (name, args) {
+        ("not", [arg]) => arg.literal_is_falsey().map(Expr::Bool),
+        ("add" | "sub" | "mul" | "div" | "min" | "max", args) => fold_arithmetic(name, args),
+        ("zero?", [arg]) if arg.is_literal() => Some(Expr::Bool(*arg == Expr::Integer(0))),
+        ("positive?" | "negative?" | "even?" | "odd?", [Expr::Integer(i)]) => {
+            Some(Expr::Bool(numeric_predicate(name, *i)))
+        }
+        ("abs", [Expr::Integer(i)]) => Some(Expr::Integer(wrap_fixnum(i.wrapping_abs()))),
+        ("mod" | "rem", [Expr::Integer(l), Expr::Integer(r)]) => {
+            fold_remainder(name, *l, *r).map(Expr::Integer)
+        }
+        ("expt", [Expr::Integer(b), Expr::Integer(e)]) => fold_expt(*b, *e).map(Expr::Integer),
+        ("null?" | "pair?" | "atom?", [arg]) if arg.is_literal() => Some(Expr::Bool(match name {
+            "null?" => *arg == Expr::Nil,
+            "pair?" => false,
+            _ => true,
+        })),
+        ("length" | "null?" | "pair?" | "atom?" | "car" | "cdr", [arg]) => {
+            fold_list_primcall(name, arg)
+        }
+        ("string-append" | "string-length" | "string-ref", args) => {
+            fold_string_primcall(name, args)
+        }
+        _ => None,
+    }
+}
+
+/// Evaluates the numeric predicate NAME on the integer I.
+pub(crate) fn numeric_predicate(name: &str, i: i64) -> bool {
+    match name {
+        "positive?" => i > 0,
+        "negative?" => i < 0,
+        "even?" => i % 2 == 0,
+        "odd?" => i % 2 != 0,
+        _ => panic!("non numeric predicate in numeric_predicate: {}", name),
+    }
+}
+
+/// Combines two integers at compile time. Returns None if the result
+/// ought to be computed at runtime instead.
+type FoldOp = fn(i64, i64) -> Option<i64>;
+
+/// Folds a call to an arithmetic primitive. These follow the same
+/// rules as at runtime: (add) is 0, (mul) is 1, (sub x) is -x, (div x)
+/// is 1 / x, and everything else folds left.
+pub(crate) fn fold_arithmetic(name: &str, args: &[Expr]) -> Option<Expr> {
+    let args = args
+        .iter()
+        .map(|a| match a {
+            Expr::Integer(i) => Some(*i),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let (min_args, identity, op): (usize, i64, FoldOp) = match name {
+        "add" => (0, 0, |l, r| Some(l.wrapping_add(r))),
+        "sub" => (1, 0, |l, r| Some(l.wrapping_sub(r))),
+        "mul" => (0, 1, |l, r| Some(l.wrapping_mul(r))),
+        // Division by zero is left for the runtime to deal with.
+        "div" => (1, 1, |l, r| l.checked_div(r)),
+        "min" => (1, FIXNUM_MAX, |l, r| Some(l.min(r))),
+        "max" => (1, FIXNUM_MIN, |l, r| Some(l.max(r))),
+        _ => return None,
+    };
+
+    if args.len() < min_args {
+        return None;
+    }
+    let (accum, rest) = if args.len() > min_args {
+        (args[0], &args[1..])
+    } else {
+        (identity, &args[..])
+    };
+    rest.iter()
+        .try_fold(accum, |accum, arg| op(accum, *arg))
+        .map(|i| Expr::Integer(wrap_fixnum(i)))
+}
+
+/// Folds the constant expressions in E. Returns rather or not
+/// anything was folded.
+fn fold_expr(e: &mut Expr) -> bool {
+    // Quoted expressions are data and foreign calls have string
+    // arguments that will be marshaled later so we leave both alone.
+    // Quoting a literal is the same as not quoting it at all. The
+    // literal doesn't need a place in the program's data.
+    if let Some(quoted) = e.is_quote() {
+        if quoted.is_literal() {
+            *e = quoted.clone();
+            return true;
+        }
+        return false;
+    }
+    if e.is_foreign_call().is_some() {
+        return false;
+    }
+    let mut changed = false;
+    if let Expr::List(v) = e {
+        // Fold the arguments first so that nested constant
+        // expressions fold all the way up.
+        for e in v.iter_mut() {
+            changed |= fold_expr(e);
+        }
+    }
+    if let Some((name, args)) = e.is_primcall() {
+        if let Some(res) = fold_primcall(name, args) {
+            *e = res;
+            return true;
+        }
+    }
+    // A conditional whose condition is known only evaluates one of
+    // its branches so the other one can go. Like at runtime only true
+    // takes the then branch, every other value takes the else branch.
+    if let Some((cond, then, else_)) = e.is_conditional() {
+        if cond.literal_is_falsey().is_some() {
+            *e = if *cond == Expr::Bool(true) {
+                then.clone()
+            } else {
+                else_.clone()
+            };
+            return true;
+        }
+    }
+    changed
+}
+
+/// Collects the variables in the program that are bound with let to
+/// a literal and never assigned to with set. Every use of one of them
+/// can be replaced with its value.
+fn collect_constant_bindings(program: &[Expr]) -> HashMap<String, Expr> {
+    let mut constants = HashMap::new();
+    let mut assigned = Vec::new();
+
+    for e in program {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() || e.is_foreign_call().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if let Some((name, value)) = e.is_let() {
+                if value.is_literal() {
+                    constants.insert(name.clone(), value.clone());
+                }
+            } else if let Some((name, _)) = e.is_set() {
+                assigned.push(name.clone());
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    for name in assigned {
+        constants.remove(&name);
+    }
+    constants
+}
+
+/// Replaces the uses of the variables in CONSTANTS with their
+/// values. Returns rather or not anything was replaced.
+fn propagate_constants(e: &mut Expr, constants: &HashMap<String, Expr>) -> bool {
+    if e.is_quote().is_some() || e.is_foreign_call().is_some() {
+        return false;
+    }
+    // The name being bound by a let is not a use of it.
+    let skip = if e.is_let().is_some() { 2 } else { 0 };
+    match e {
+        Expr::Symbol(s) => match constants.get(s) {
+            Some(value) => {
+                *e = value.clone();
+                true
+            }
+            None => false,
+        },
+        Expr::List(v) => {
+            let mut changed = false;
+            for e in v.iter_mut().skip(skip) {
+                changed |= propagate_constants(e, constants);
+            }
+            changed
+        }
+        _ => false,
+    }
+}
+
+/// Folds all of the constant expressions in the program. This pass
+/// needs to run after renaming so that every symbol left in a
+/// primitive's name position actually refers to that primitive and
+/// so that a variable being shadowed doesn't cause the shadowing
+/// variable to be replaced with the outer one's value.
+pub(crate) fn fold_constants(program: &mut [Expr]) {
+    let _t = crate::timer::timeit("constant folding pass");
+    // Propagating a constant can make the binding of another variable
+    // constant, and folding a condition can prune the branch that kept
+    // an expression from folding, so keep going until nothing changes.
+    // This terminates as every fold replaces an expression with a
+    // smaller one and every propagation replaces a variable with a
+    // literal, so each round either shrinks the program or leaves it
+    // with fewer variables or changes nothing.
+    loop {
+        let mut changed = false;
+        for e in program.iter_mut() {
+            changed |= fold_expr(e);
+        }
+        let constants = collect_constant_bindings(program);
+        for e in program.iter_mut() {
+            changed |= propagate_constants(e, &constants);
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// Folds a call to mod or rem. The result of mod has the sign of its
+/// divisor and the result of rem has the sign of its dividend. Like
+/// with div, division by zero is left for the runtime.
+pub(crate) fn fold_remainder(name: &str, left: i64, right: i64) -> Option<i64> {
+    let rem = left.checked_rem(right)?;
+    match name {
+        "mod" if rem != 0 && (rem < 0) != (right < 0) => Some(rem + right),
+        _ => Some(rem),
+    }
+}
+
+/// Raises BASE to the power of EXP wrapping around at the width of a
+/// fixnum like expt does at runtime. A negative EXP is left for the
+/// runtime to raise an error about since the result would be a
+/// rational or a float and neither exists yet.
+pub(crate) fn fold_expt(base: i64, exp: i64) -> Option<i64> {
+    if exp < 0 {
+        return None;
+    }
+    let (mut base, mut exp, mut result) = (base, exp, 1i64);
+    while exp != 0 {
+        if exp & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
+    }
+    Some(wrap_fixnum(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+    use crate::roundtrip_string;
+    use crate::test_util::run_embedded;
+
+    fn folded(source: &str) -> Vec<Expr> {
+        let mut exprs = parse_string(source).unwrap();
+        fold_constants(&mut exprs);
+        exprs
+    }
+
+    /// Builds the list of integers that ends in its last item rather
+    /// than nil, i.e. what nested calls to cons produce.
+    fn dotted(items: &[i64]) -> Expr {
+        let (last, rest) = items.split_last().unwrap();
+        rest.iter().rev().fold(Expr::Integer(*last), |tail, i| {
+            Expr::List(vec![Expr::Integer(*i), tail])
+        })
+    }
+
+    #[test]
+    fn overflow_matches_runtime() {
+        let max = FIXNUM_MAX.to_string();
+        let min = format!("(sub (sub 0 {}) 1)", max);
+        let cases = [
+            ("add", vec![max.as_str(), "1"]),
+            ("add", vec![max.as_str(), max.as_str()]),
+            ("sub", vec![min.as_str(), "1"]),
+            ("sub", vec![min.as_str()]),
+            ("mul", vec![max.as_str(), "3"]),
+            ("mul", vec!["1073741824", "1073741824"]),
+            ("mul", vec!["1073741824", "1073741824", "4"]),
+            ("mul", vec![min.as_str(), "(sub 0 1)"]),
+            ("div", vec![min.as_str(), "(sub 0 1)"]),
+            ("abs", vec![min.as_str()]),
+            ("expt", vec!["2", "61"]),
+            ("expt", vec!["3", "40"]),
+        ];
+        for (name, args) in cases {
+            let folded_source = format!("({} {})", name, args.join(" "));
+            // Passing the arguments through a function keeps them from
+            // being folded.
+            let unfolded_source = format!(
+                "(let id (fn (x) x)) ({} {})",
+                name,
+                args.iter()
+                    .map(|a| format!("(id {})", a))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+            let constant = match folded(&folded_source).as_slice() {
+                [e @ Expr::Integer(_)] => e.clone(),
+                e => panic!("{} didn't fold: {:?}", folded_source, e),
+            };
+            assert_eq!(
+                roundtrip_string(&unfolded_source).unwrap(),
+                constant,
+                "{}",
+                folded_source
+            );
+            assert_eq!(
+                crate::interpreter::interpret(&parse_string(&unfolded_source).unwrap()).unwrap(),
+                constant,
+                "{}",
+                folded_source
+            );
+        }
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(&format!("(add1 {})", max)).unwrap())
+                .unwrap(),
+            roundtrip_string(&format!("(let id (fn (x) x)) (add1 (id {}))", max)).unwrap()
+        );
+    }
+
+    #[test]
+    fn not() {
+        assert_eq!(roundtrip_string("(not ())").unwrap(), Expr::Bool(true));
+        assert_eq!(roundtrip_string("(not 5)").unwrap(), Expr::Bool(false));
+        assert_eq!(
+            roundtrip_string("(if (not (eq 1 2)) 10 20)").unwrap(),
+            Expr::Integer(10)
+        );
+    }
+
+    #[test]
+    fn not_runtime() {
+        let source = r#"
+(let f (fn (x) (not x)))
+(cons (f ()) (cons (f 5) (cons (f (eq 1 2)) (f (eq 1 1)))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![
+                Expr::Bool(true),
+                Expr::List(vec![
+                    Expr::Bool(false),
+                    Expr::List(vec![Expr::Bool(true), Expr::Bool(false)])
+                ])
+            ])
+        );
+    }
+
+    #[test]
+    fn not_not() {
+        let source = r#"
+(let id (fn (x) x))
+(cons (not (not (id 5))) (not (not (id ()))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![Expr::Bool(true), Expr::Bool(false)])
+        );
+    }
+
+    #[test]
+    fn arithmetic_identities() {
+        assert_eq!(roundtrip_string("(add)").unwrap(), Expr::Integer(0));
+        assert_eq!(roundtrip_string("(mul)").unwrap(), Expr::Integer(1));
+        assert_eq!(roundtrip_string("(add 5)").unwrap(), Expr::Integer(5));
+        assert_eq!(roundtrip_string("(mul 5)").unwrap(), Expr::Integer(5));
+        assert_eq!(roundtrip_string("(sub 5)").unwrap(), Expr::Integer(-5));
+        assert_eq!(roundtrip_string("(div 5)").unwrap(), Expr::Integer(0));
+        assert_eq!(roundtrip_string("(div 1)").unwrap(), Expr::Integer(1));
+        assert_eq!(roundtrip_string("(- 10 1 2)").unwrap(), Expr::Integer(7));
+        assert_eq!(roundtrip_string("(/ 100 5 2)").unwrap(), Expr::Integer(10));
+        assert_eq!(roundtrip_string("(+ 1 2 3 4)").unwrap(), Expr::Integer(10));
+        assert_eq!(roundtrip_string("(* 1 2 3 4)").unwrap(), Expr::Integer(24));
+        assert!(roundtrip_string("(sub)").is_err());
+        assert!(roundtrip_string("(div)").is_err());
+    }
+
+    #[test]
+    fn arithmetic_runtime() {
+        let source = r#"
+(let id (fn (x) x))
+(let ten (id 10))
+(cons (+) (cons (*) (cons (- ten) (cons (/ (id 2)) (cons (- ten 1 2) (cons (/ ten 3 2) (* ten ten 2)))))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            dotted(&[0, 1, -10, 0, 7, 1, 200])
+        );
+    }
+
+    #[test]
+    fn arithmetic_higher_order() {
+        let source = r#"
+(let apply3 (fn (f a b c) (f a b c)))
+(let apply1 (fn (f a) (f a)))
+(let apply0 (fn (f) (f)))
+(cons (apply3 - 10 1 2)
+      (cons (apply3 / 100 5 2)
+            (cons (apply3 + 1 2 3)
+                  (cons (apply1 - 4)
+                        (cons (apply0 *) (apply0 +))))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            dotted(&[7, 10, 6, -4, 1, 0])
+        );
+    }
+
+    #[test]
+    fn min_max_abs() {
+        let source = r#"
+(let id (fn (x) x))
+(let apply1 (fn (f a) (f a)))
+(let apply3 (fn (f a b c) (f a b c)))
+(cons (max (id 3) 1 2)
+      (cons (min (id 4))
+            (cons (abs (id -5))
+                  (cons (abs 7)
+                        (cons (apply3 min 3 (id -1) 2)
+                              (cons (apply1 max 9) (apply1 abs -2)))))))
+"#;
+        let expected = dotted(&[3, 4, 5, 7, -1, 9, 2]);
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+        assert!(roundtrip_string("(max)").is_err());
+        assert!(roundtrip_string("(abs 1 2)").is_err());
+    }
+
+    #[test]
+    fn numeric_predicates() {
+        let source = r#"
+(let id (fn (x) x))
+(let apply1 (fn (f a) (f a)))
+(cons (even? (id 4))
+      (cons (odd? (id 4))
+            (cons (zero? (id 0))
+                  (cons (odd? (id -3))
+                        (cons (positive? (id 0))
+                              (cons (negative? -1) (apply1 even? -2)))))))
+"#;
+        let expected = roundtrip_string(
+            "(cons (eq 1 1) (cons (eq 1 2) (cons (eq 1 1) (cons (eq 1 1) (cons (eq 1 2) (cons (eq 1 1) (eq 1 1)))))))",
+        )
+        .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+        assert_eq!(folded("(even? 4)"), vec![Expr::Bool(true)]);
+        assert_eq!(folded("(odd? 4)"), vec![Expr::Bool(false)]);
+        assert_eq!(folded("(zero? 0)"), vec![Expr::Bool(true)]);
+        assert_eq!(folded("(zero? ())"), vec![Expr::Bool(false)]);
+        assert!(
+            crate::interpreter::interpret(&parse_string("(even? (quote a))").unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        assert_eq!(folded("(add)"), vec![Expr::Integer(0)]);
+        assert_eq!(folded("(mul)"), vec![Expr::Integer(1)]);
+        assert_eq!(folded("(sub 3)"), vec![Expr::Integer(-3)]);
+        assert_eq!(folded("(div 2)"), vec![Expr::Integer(0)]);
+        assert_eq!(folded("(sub 10 1 2)"), vec![Expr::Integer(7)]);
+        assert_eq!(folded("(add 1 (mul 2 3))"), vec![Expr::Integer(7)]);
+        assert_eq!(folded("(div 1 0)"), parse_string("(div 1 0)").unwrap());
+        assert_eq!(folded("(sub)"), parse_string("(sub)").unwrap());
+        assert_eq!(folded("(max 3 1 2)"), vec![Expr::Integer(3)]);
+        assert_eq!(folded("(min 3 1 2)"), vec![Expr::Integer(1)]);
+        assert_eq!(folded("(abs (sub 5))"), vec![Expr::Integer(5)]);
+        assert_eq!(folded("(max)"), parse_string("(max)").unwrap());
+    }
+
+    #[test]
+    fn remainders() {
+        let source = r#"
+(let id (fn (x) x))
+(cons (mod (id (sub 7)) 2) (cons (rem (id (sub 7)) 2) (cons (mod 7 (sub 2)) (rem 7 (sub 2)))))
+"#;
+        assert_eq!(roundtrip_string(source).unwrap(), dotted(&[1, -1, -1, 1]));
+        assert_eq!(folded("(mod (sub 7) 2)"), vec![Expr::Integer(1)]);
+        assert_eq!(folded("(rem (sub 7) 2)"), vec![Expr::Integer(-1)]);
+        assert_eq!(folded("(mod 6 3)"), vec![Expr::Integer(0)]);
+    }
+
+    #[test]
+    fn expt() {
+        let source = r#"
+(let id (fn (x) x))
+(let apply2 (fn (f a b) (f a b)))
+(cons (expt (id 2) 10) (cons (expt (id -3) 3) (cons (expt (id 7) 0) (apply2 expt 10 (id 18)))))
+"#;
+        let expected = dotted(&[1024, -27, 1, 1_000_000_000_000_000_000]);
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+        assert_eq!(folded("(expt 2 10)"), vec![Expr::Integer(1024)]);
+        assert_eq!(folded("(expt 0 0)"), vec![Expr::Integer(1)]);
+        // Like mul, expt wraps around at the width of a fixnum.
+        assert_eq!(
+            folded("(expt 2 61)"),
+            vec![Expr::Integer(crate::conversions::FIXNUM_MIN)]
+        );
+        assert_eq!(
+            roundtrip_string("(let id (fn (x) x)) (expt (id 2) 61)").unwrap(),
+            Expr::Integer(crate::conversions::FIXNUM_MIN)
+        );
+
+        let source = "(let id (fn (x) x)) (expt 2 (id -1))";
+        let error = run_embedded(source).unwrap_err();
+        assert_eq!(error.kind, "range-error");
+        assert_eq!(
+            error.message,
+            "fatal error: expt expects a non-negative exponent"
+        );
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap_err(),
+            "fatal error: expt expects a non-negative exponent"
+        );
+        assert_eq!(folded("(expt 2 -1)"), parse_string("(expt 2 -1)").unwrap());
+    }
+
+    #[test]
+    fn division_by_zero_not_folded() {
+        for source in ["(div 1 0)", "(div 0)", "(mod 5 0)", "(rem 5 0)"] {
+            assert_eq!(folded(source), parse_string(source).unwrap());
+        }
+    }
+
+    #[test]
+    fn fold_not() {
+        assert_eq!(folded("(not ())"), vec![Expr::Bool(true)]);
+        assert_eq!(folded("(not 5)"), vec![Expr::Bool(false)]);
+        assert_eq!(folded("(not (not \"hi\"))"), vec![Expr::Bool(true)]);
+        assert_eq!(folded("(not (quote ()))"), vec![Expr::Bool(true)]);
+        assert_eq!(
+            folded("(not (eq 1 2))"),
+            parse_string("(not (eq 1 2))").unwrap()
+        );
+    }
+
+    #[test]
+    fn propagate_let_constants() {
+        assert_eq!(
+            folded("(let x 2) (mul x x)"),
+            parse_string("(let x 2) 4").unwrap()
+        );
+        assert_eq!(
+            folded("(let f (fn () (let a 2) (let b (add a 1)) (mul a b)))"),
+            parse_string("(let f (fn () (let a 2) (let b 3) 6))").unwrap()
+        );
+        assert_eq!(
+            roundtrip_string("(let x 2) (let y (mul x 3)) (add x y)").unwrap(),
+            Expr::Integer(8)
+        );
+    }
+
+    #[test]
+    fn mutated_let_not_propagated() {
+        let source = "(let x 2) (set x 3) (mul x x)";
+        assert_eq!(folded(source), parse_string(source).unwrap());
+        assert_eq!(roundtrip_string(source).unwrap(), Expr::Integer(9));
+
+        let source = r#"
+(let x 2)
+(let bump (fn () (set x (add x 1))))
+(bump)
+(mul x x)
+"#;
+        assert_eq!(roundtrip_string(source).unwrap(), Expr::Integer(9));
+    }
+
+    #[test]
+    fn shadowed_let_not_propagated() {
+        let source = r#"
+(let x 2)
+(let f (fn (x) (mul x x)))
+(let g (fn () (let x 5) (add x 1)))
+(cons (f 3) (cons (g) x))
+"#;
+        assert_eq!(roundtrip_string(source).unwrap(), dotted(&[9, 6, 2]));
+    }
+
+    #[test]
+    fn fold_quoted_literals() {
+        assert_eq!(
+            folded("(quote 5) (quote ())"),
+            vec![Expr::Integer(5), Expr::Nil]
+        );
+        assert_eq!(folded("(quote foo)"), parse_string("(quote foo)").unwrap());
+        assert!(crate::data::extract_data(&mut folded("(quote 5) (quote ())"), 0).is_empty());
+    }
+
+    #[test]
+    fn fold_list_primitives() {
+        for (source, expected) in [
+            ("(length (quote (1 2 3)))", "3"),
+            ("(length (quote ()))", "0"),
+            ("(length \"hi\")", "2"),
+            ("(null? (quote ()))", "(eq 1 1)"),
+            ("(null? (quote (1)))", "(eq 1 2)"),
+            ("(null? 5)", "(eq 1 2)"),
+            ("(pair? (quote (1 2)))", "(eq 1 1)"),
+            ("(pair? \"\")", "(eq 1 2)"),
+            ("(car (quote (1 2)))", "1"),
+            ("(car (cdr (quote (1 2))))", "2"),
+            ("(cdr (quote (1)))", "()"),
+            ("(car \"hi\")", "(integer->char 104)"),
+            ("(length (cdr (quote (a b c))))", "2"),
+        ] {
+            let mut program = folded(source);
+            assert!(program[0].is_literal(), "{}", source);
+            assert!(crate::data::extract_data(&mut program, 0).is_empty());
+            let expected = roundtrip_string(expected).unwrap();
+            assert_eq!(roundtrip_string(source).unwrap(), expected);
+            assert_eq!(
+                crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(
+            folded("(cdr (quote (1 a)))"),
+            parse_string("(quote (a))").unwrap()
+        );
+        assert_eq!(
+            folded("(car (quote (a)))"),
+            parse_string("(quote a)").unwrap()
+        );
+        // Taking apart the empty list is an error at runtime.
+        for source in ["(car (quote ()))", "(cdr \"\")"] {
+            assert!(folded(source)[0].is_primcall().is_some(), "{}", source);
+            assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn fold_string_primitives() {
+        assert_eq!(
+            folded("(string-append \"a\" \"b\")"),
+            vec![Expr::String("ab".to_string())]
+        );
+        assert_eq!(
+            folded("(string-length (string-append \"héllo\" \"\"))"),
+            vec![Expr::Integer(5)]
+        );
+        assert_eq!(folded("(string-append \"\" \"\")"), vec![Expr::Nil]);
+        for (source, expected) in [
+            ("(string-append \"a\" \"b\")", "\"ab\""),
+            ("(string-append \"\" \"b\")", "\"b\""),
+            (
+                "(string-append (string-append \"a\" \"é\") \"c\")",
+                "\"aéc\"",
+            ),
+            ("(string-length \"héllo\")", "5"),
+            ("(string-ref \"héllo\" 2)", "(integer->char 108)"),
+        ] {
+            assert!(folded(source)[0].is_primcall().is_none(), "{}", source);
+            let expected_value = roundtrip_string(expected).unwrap();
+            assert_eq!(roundtrip_string(source).unwrap(), expected_value);
+            assert_eq!(
+                crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+                expected_value
+            );
+        }
+        // Out of range and non string arguments are left for the
+        // runtime.
+        for source in [
+            "(string-ref \"ab\" 2)",
+            "(string-append \"a\" 1)",
+            "(string-length 1)",
+        ] {
+            assert!(folded(source)[0].is_primcall().is_some(), "{}", source);
+        }
+
+        // The folded string is a constant so making it allocates
+        // nothing.
+        let source = r#"
+(let before (heap-stats))
+(let s (string-append "a" "b"))
+(let after (heap-stats))
+(cons s (sub (car after) (car before)))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(cons \"ab\" 0)").unwrap()
+        );
+    }
+
+    #[test]
+    fn fold_at_fixnum_width() {
+        // Folded arithmetic wraps like fixnums do at runtime.
+        assert_eq!(
+            folded("(add 2305843009213693951 1)"),
+            vec![Expr::Integer(crate::conversions::FIXNUM_MIN)]
+        );
+    }
+
+    #[test]
+    fn prune_known_conditions() {
+        assert_eq!(
+            folded("(if (null? (quote ())) 1 2)"),
+            vec![Expr::Integer(1)]
+        );
+        assert_eq!(
+            folded("(if (pair? ()) 1 (quote x))"),
+            parse_string("(quote x)").unwrap()
+        );
+        // Only true takes the then branch, the same as at runtime.
+        assert_eq!(folded("(if 0 1 2)"), vec![Expr::Integer(2)]);
+        assert_eq!(roundtrip_string("(if 0 1 2)").unwrap(), Expr::Integer(2));
+        // The innermost condition is only known once N has been
+        // propagated, which takes a second round of folding.
+        let source = r#"
+(let n 2)
+(if (null? (quote ()))
+    (if (pair? (quote (1)))
+        (if (zero? (sub (length (quote (a b))) n)) (add n 1) (car 1))
+        2)
+    3)
+"#;
+        assert_eq!(
+            folded(source),
+            vec![
+                parse_string("(let n 2)").unwrap().remove(0),
+                Expr::Integer(3)
+            ]
+        );
+        assert_eq!(roundtrip_string(source).unwrap(), Expr::Integer(3));
+        // Conditions that aren't known are left alone.
+        let source = "(let id (fn (x) x)) (if (id 1) 1 2)";
+        assert_eq!(folded(source), parse_string(source).unwrap());
+    }
+}
@@ -41,6 +41,20 @@ pub(crate) fn emit_foreign_call(
     name: &str,
     args: &[Expr],
     ctx: &mut Context,
+) -> Result<Value, String> {
+    let args = args
+        .iter()
+        .map(|e| emit_untag(e, ctx))
+        .collect::<Result<Vec<_>, String>>()?;
+    emit_untagged_foreign_call(name, &args, ctx)
+}
+
+/// Emits the code to call the foreign function NAME with ARGS which
+/// have already been evaluated and untagged.
+pub(crate) fn emit_untagged_foreign_call(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
 ) -> Result<Value, String> {
     let mut sig = ctx.module.make_signature();
 
@@ -58,12 +72,7 @@ pub(crate) fn emit_foreign_call(
         .module
         .declare_func_in_func(callee, &mut ctx.builder.func);
 
-    let args = args
-        .iter()
-        .map(|e| emit_untag(e, ctx))
-        .collect::<Result<Vec<_>, String>>()?;
-
-    let call = ctx.builder.ins().call(local_callee, &args);
+    let call = ctx.builder.ins().call(local_callee, args);
     let res = ctx.builder.inst_results(call)[0];
 
     // For now we just assume that all foreign functions are going to
@@ -75,6 +84,30 @@ pub(crate) fn emit_foreign_call(
     Ok(res)
 }
 
+/// Emits a call to the function NAME provided by the host. Unlike
+/// foreign functions host functions know about Lust's value
+/// representation so ARGS and the return value are passed as is.
+pub(crate) fn emit_host_call(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let mut sig = ctx.module.make_signature();
+    for _ in args {
+        sig.params.push(AbiParam::new(ctx.word));
+    }
+    sig.returns.push(AbiParam::new(ctx.word));
+
+    let callee = ctx
+        .module
+        .declare_function(name, cranelift_module::Linkage::Import, &sig)
+        .map_err(|e| e.to_string())?;
+    let local_callee = ctx.module.declare_func_in_func(callee, ctx.builder.func);
+
+    let call = ctx.builder.ins().call(local_callee, args);
+    Ok(ctx.builder.inst_results(call)[0])
+}
+
 /// Emits the code to store VAL is the type represented by TAG using
 /// MASK and returning the result.
 pub(crate) fn emit_is(val: Value, tag: Word, mask: Word, ctx: &mut Context) -> Value {
@@ -0,0 +1,273 @@
+//! Top level definitions used by the forms before them. A function
+//! can call a function that is defined after it as long as it isn't
+//! called until the definition has run:
+//!
+//! ```lisp
+//! (let ev? (fn (n) (or (eq n 0) (od? (sub n 1)))))
+//! (let od? (fn (n) (and (not (eq n 0)) (ev? (sub n 1)))))
+//! (ev? 10)
+//! ```
+//!
+//! The renamer gives od? its name before it reaches ev? so both refer
+//! to the same variable, but when ev? is made od? doesn't exist yet
+//! and ev? has nothing to close over. So a definition like od? also
+//! gets a slot in the JIT's data, like the definitions of a persistent
+//! program do (see `globals.rs`). The definition stores its value in
+//! the slot and the forms before it read the variable from there.
+//!
+//! With `CompileOptions::trampoline` set a call through a slot is
+//! treated as a call to its definition, so ev? and od? above still run
+//! in constant stack space (see `tail.rs`).
+//!
+//! Only a function can use a definition that comes after it. Anywhere
+//! else the use would run before the definition, so it is an error,
+//! and so is setting the variable before it is defined.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::Context;
+use crate::data::{self, LustData};
+use crate::renamer::original_name;
+use crate::Expr;
+
+/// Replaces the uses of the definitions that come after them in FORM,
+/// the FORMth form of its program, with the definitions' slots.
+/// DEFINED maps each definition to the form that makes it.
+fn replace_forward_uses(
+    e: &mut Expr,
+    form: usize,
+    in_fn: bool,
+    defined: &HashMap<String, usize>,
+    slots: &mut HashMap<String, String>,
+    program: usize,
+) -> Result<(), String> {
+    if e.is_quote().is_some() {
+        return Ok(());
+    }
+    let later = |name: &str| matches!(defined.get(name), Some(&d) if d > form);
+    if let Some((name, _)) = e.is_set() {
+        if later(name) {
+            return Err(format!(
+                "({}) is set before it is defined",
+                original_name(name)
+            ));
+        }
+    }
+    let in_fn = in_fn || e.is_fndef().is_some();
+    match e {
+        Expr::Symbol(name) if later(name) => {
+            if !in_fn {
+                return Err(format!(
+                    "({}) is used before it is defined",
+                    original_name(name)
+                ));
+            }
+            let slot = slots.entry(name.clone()).or_insert_with(|| {
+                crate::globals::slot_name(&format!("forward_{}_{}", program, name))
+            });
+            *name = slot.clone();
+        }
+        Expr::List(v) => {
+            for e in v {
+                replace_forward_uses(e, form, in_fn, defined, slots, program)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Finds the uses of top level definitions in PROGRAM that come
+/// before the definition and replaces them with the definition's
+/// slot. Returns each of those definitions and the name of its slot.
+/// PROGRAM's names must already be unique and INDEX is the number of
+/// programs the JIT compiled before it, which keeps the slots of
+/// different programs apart.
+pub(crate) fn resolve_forward_uses(
+    program: &mut [Expr],
+    index: usize,
+) -> Result<HashMap<String, String>, String> {
+    resolve_forms(program, index).map_err(|(_, e)| e)
+}
+
+/// Like `resolve_forward_uses` but returns the index of the form an
+/// error is in along with the error.
+fn resolve_forms(
+    program: &mut [Expr],
+    index: usize,
+) -> Result<HashMap<String, String>, (usize, String)> {
+    let (slots, errors) = resolve_each_form(program, index);
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(slots),
+    }
+}
+
+/// Like `resolve_forms` but carries on after a form with an error so
+/// that the first error in each form is returned.
+fn resolve_each_form(
+    program: &mut [Expr],
+    index: usize,
+) -> (HashMap<String, String>, Vec<(usize, String)>) {
+    let mut defined = HashMap::new();
+    for (form, e) in program.iter().enumerate() {
+        if let Some((name, _)) = e.is_let() {
+            defined.entry(name.clone()).or_insert(form);
+        }
+    }
+    let mut slots = HashMap::new();
+    let mut errors = Vec::new();
+    for (form, e) in program.iter_mut().enumerate() {
+        if let Err(e) = replace_forward_uses(e, form, false, &defined, &mut slots, index) {
+            errors.push((form, e));
+        }
+    }
+    (slots, errors)
+}
+
+/// Returns the first use of a definition before it is made in each
+/// form of PROGRAM that has one, along with the index of the form.
+/// PROGRAM's names must already be unique.
+pub(crate) fn forward_use_errors(program: &mut [Expr]) -> Vec<(usize, String)> {
+    resolve_each_form(program, 0).1
+}
+
+/// A variable that is used where it isn't defined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndefinedVariable {
+    /// What is wrong with the use.
+    pub message: String,
+    /// The index of the top level form that contains the use.
+    pub form: usize,
+}
+
+impl std::fmt::Display for UndefinedVariable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Returns the first use of a variable in PROGRAM that isn't defined
+/// where it is used, if there is one. Compiling PROGRAM fails with the
+/// same error but this also says which form the use is in.
+pub fn find_undefined_variable(program: &[Expr]) -> Result<Option<UndefinedVariable>, String> {
+    let mut program = program.to_vec();
+    crate::desugar::desugar(&mut program)?;
+    let res = crate::renamer::rename_forms(&mut program, &HashSet::new())
+        .and_then(|_| resolve_forms(&mut program, 0));
+    Ok(res
+        .err()
+        .map(|(form, message)| UndefinedVariable { message, form }))
+}
+
+/// Makes the SLOTS returned by `resolve_forward_uses` in JIT.
+pub(crate) fn define_slots(
+    slots: &HashMap<String, String>,
+    jit: &mut crate::compiler::JIT,
+) -> Result<(), String> {
+    let slots = slots
+        .values()
+        .map(|name| LustData {
+            name: name.clone(),
+            data: Expr::Nil.immediate_rep(),
+            align: None,
+        })
+        .collect();
+    data::create_data(slots, jit)
+}
+
+/// If FORM is the definition of one of SLOTS emits the code to store
+/// the value it bound in its slot.
+pub(crate) fn emit_store_definition(
+    form: &Expr,
+    slots: &HashMap<String, String>,
+    ctx: &mut Context,
+) -> Result<(), String> {
+    if let Some((name, _)) = form.is_let() {
+        // Escaped variables get an e_ in front of the name the
+        // renamer gave them.
+        if let Some(slot) = slots.get(name.strip_prefix("e_").unwrap_or(name)) {
+            let value = crate::locals::emit_var_access(name, ctx)?;
+            data::emit_data_store(slot, value, ctx)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_program, CompileOptions, JIT};
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn forward_references() {
+        let source = r#"
+(let ev? (fn (n) (or (eq n 0) (od? (sub n 1)))))
+(let od? (fn (n) (and (not (eq n 0)) (ev? (sub n 1)))))
+(let main (fn () (count 10)))
+(let count (fn (n) (if (eq n 0) (ev? 11) (count (sub n 1)))))
+(main)
+"#;
+        assert_eq!(roundtrip_string(source), Ok(Expr::Bool(false)));
+        assert_eq!(
+            roundtrip_string(source),
+            crate::interpreter::interpret(&parse_string(source).unwrap())
+        );
+        // A use before a redefinition is still a use of the
+        // definition before it.
+        let source = "(let a 1) (let f (fn () a)) (let a 2) (f)";
+        assert_eq!(roundtrip_string(source), Ok(Expr::Integer(1)));
+        let source = "(let f (fn () (g))) (let g (fn () 3)) (f)";
+        assert_eq!(roundtrip_string(source), Ok(Expr::Integer(3)));
+    }
+
+    #[test]
+    fn misspelled_variables() {
+        let source = "(let count (fn (n) (if (eq n 0) 0 (cuont (sub n 1))))) (count 3)";
+        assert_eq!(
+            roundtrip_string(source),
+            Err("undefined variable (cuont)".to_string())
+        );
+        assert_eq!(
+            roundtrip_string("(let a (g)) (let g (fn () 1))"),
+            Err("(g) is used before it is defined".to_string())
+        );
+        assert_eq!(
+            roundtrip_string("(let f (fn () (set g 1))) (let g 2)"),
+            Err("(g) is set before it is defined".to_string())
+        );
+    }
+
+    #[test]
+    fn locations() {
+        let program = parse_string("(let f (fn () 1))\n(let g (fn () (h)))\n(f)").unwrap();
+        assert_eq!(
+            find_undefined_variable(&program),
+            Ok(Some(UndefinedVariable {
+                message: "undefined variable (h)".to_string(),
+                form: 1,
+            }))
+        );
+        let program = parse_string("(let f (fn () (h)))\n(let h (fn () 1))\n(f)").unwrap();
+        assert_eq!(find_undefined_variable(&program), Ok(None));
+    }
+
+    #[test]
+    fn called_before_defined() {
+        let mut jit = JIT::new(CompileOptions {
+            embedded: true,
+            ..Default::default()
+        });
+        let mut program = parse_string("(let f (fn () (g))) (f) (let g (fn () 1))").unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(jit.invoke(id).unwrap_err().kind, "bad-call");
+        // Later programs have slots of their own.
+        let mut program = parse_string("(let f (fn () (g))) (let g (fn () 1)) (f)").unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            Expr::Integer(1)
+        );
+    }
+}
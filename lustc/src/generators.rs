@@ -0,0 +1,297 @@
+//! Primitives that build new lists.
+//!
+//! `(make-list n x)` is a list of N copies of X and `(iota n)` is the
+//! list of the first N integers starting at zero. `(iota n start)`
+//! and `(iota n start step)` start at START and go up by STEP, which
+//! is one if it isn't given.
+//!
+//! ```lisp
+//! (make-list 3 0)  ; => (0 0 0)
+//! (iota 4)         ; => (0 1 2 3)
+//! (iota 3 10 -2)   ; => (10 8 6)
+//! ```
+//!
+//! Both build their list back to front in a loop rather than by
+//! recursing so that long lists only use the heap. A negative N is a
+//! range error and an N of zero gives the empty list.
+
+use cranelift::prelude::*;
+
+use crate::compiler::{emit_expr, Context};
+use crate::conversions::{FIXNUM_SHIFT, PAIR_TAG};
+use crate::fatal;
+use crate::heap::emit_alloc;
+use crate::Expr;
+
+/// The names of the generator primitives.
+pub(crate) const GENERATOR_PRIMITIVES: [&str; 2] = ["make-list", "iota"];
+
+/// Returns the smallest and largest number of arguments that the
+/// generator primitive NAME takes.
+pub(crate) fn generator_primitive_arity(name: &str) -> (usize, usize) {
+    match name {
+        "make-list" => (2, 2),
+        "iota" => (1, 3),
+        _ => panic!(
+            "non generator primitive in generator_primitive_arity: {}",
+            name
+        ),
+    }
+}
+
+/// Emits the code for the generator primitive NAME applied to ARGS
+/// which have already been evaluated. ARGS has an argument for each
+/// of the primitive's optional arguments, the ones that weren't given
+/// being their defaults.
+pub(crate) fn emit_generator_primitive(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    match name {
+        // Every copy is the same so the step between them is zero,
+        // which works whatever kind of value X is.
+        "make-list" => {
+            let zero = ctx.builder.ins().iconst(ctx.word, 0);
+            emit_build_list(args[0], args[1], zero, ctx)
+        }
+        "iota" => {
+            fatal::emit_check_int(args[1], ctx)?;
+            fatal::emit_check_int(args[2], ctx)?;
+            emit_build_list(args[0], args[1], args[2], ctx)
+        }
+        _ => panic!(
+            "non generator primitive in emit_generator_primitive: {}",
+            name
+        ),
+    }
+}
+
+/// Emits the code for a call to the generator primitive NAME with
+/// ARGS. This is kept out of `emit_primcall` so that its stack frame
+/// stays small as it recurses through nested calls.
+pub(crate) fn emit_generator_primcall(
+    name: &str,
+    args: &[Expr],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let (min, max) = generator_primitive_arity(name);
+    if args.len() < min || args.len() > max {
+        return Err(format!(
+            "{} expected between {} and {} args and got {}",
+            name,
+            min,
+            max,
+            args.len()
+        ));
+    }
+    let args = (0..max)
+        .map(|i| match args.get(i) {
+            Some(a) => emit_expr(a, ctx),
+            None => emit_expr(&default_arg(name, i), ctx),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    emit_generator_primitive(name, &args, ctx)
+}
+
+/// Returns the default value of the optional argument INDEX of the
+/// generator primitive NAME.
+pub(crate) fn default_arg(name: &str, index: usize) -> Expr {
+    match (name, index) {
+        ("iota", 1) => Expr::Integer(0),
+        ("iota", 2) => Expr::Integer(1),
+        _ => panic!("no default for argument {} of {}", index, name),
+    }
+}
+
+/// Emits the code that loads the arguments of the higher order
+/// version of the generator primitive NAME which was called with ARGC
+/// arguments stored at ARGLOC. Optional arguments that weren't passed
+/// are their defaults.
+pub(crate) fn emit_load_args(
+    name: &str,
+    argc: Value,
+    argloc: Value,
+    ctx: &mut Context,
+) -> Result<Vec<Value>, String> {
+    let (min, max) = generator_primitive_arity(name);
+    fatal::emit_check_arg_count(min, argc, ctx, true)?;
+
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+    let too_many = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::UnsignedGreaterThan, argc, max as i64);
+    ctx.builder.ins().brnz(too_many, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_bad_arg_count".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+    let word_size = ctx.word.bytes() as i32;
+    let mut args = Vec::with_capacity(max);
+    for i in 0..max {
+        if i < min {
+            let arg =
+                ctx.builder
+                    .ins()
+                    .load(ctx.word, MemFlags::new(), argloc, i as i32 * word_size);
+            args.push(arg);
+            continue;
+        }
+        // Only load the argument if it was passed as nothing past
+        // the last one is ours to read.
+        let load_block = ctx.builder.create_block();
+        let join_block = ctx.builder.create_block();
+        ctx.builder.append_block_param(join_block, ctx.word);
+        let default = emit_expr(&default_arg(name, i), ctx)?;
+        let passed = ctx
+            .builder
+            .ins()
+            .icmp_imm(IntCC::UnsignedGreaterThan, argc, i as i64);
+        ctx.builder.ins().brz(passed, join_block, &[default]);
+        ctx.builder.ins().jump(load_block, &[]);
+
+        ctx.builder.switch_to_block(load_block);
+        ctx.builder.seal_block(load_block);
+        let arg = ctx
+            .builder
+            .ins()
+            .load(ctx.word, MemFlags::new(), argloc, i as i32 * word_size);
+        ctx.builder.ins().jump(join_block, &[arg]);
+
+        ctx.builder.switch_to_block(join_block);
+        ctx.builder.seal_block(join_block);
+        args.push(ctx.builder.block_params(join_block)[0]);
+    }
+    Ok(args)
+}
+
+/// Emits the code for a list of the fixnum N values where the first
+/// is FIRST and each one after it is STEP more than the one before.
+fn emit_build_list(
+    n: Value,
+    first: Value,
+    step: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_int(n, ctx)?;
+    let n = ctx.builder.ins().sshr_imm(n, FIXNUM_SHIFT);
+
+    let negative_block = ctx.builder.create_block();
+    let start_block = ctx.builder.create_block();
+    let negative = ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, n, 0);
+    ctx.builder.ins().brnz(negative, negative_block, &[]);
+    ctx.builder.ins().jump(start_block, &[]);
+
+    ctx.builder.switch_to_block(negative_block);
+    ctx.builder.seal_block(negative_block);
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_out_of_range".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+    ctx.builder.ins().jump(start_block, &[]);
+
+    ctx.builder.switch_to_block(start_block);
+    ctx.builder.seal_block(start_block);
+    // The list is built from its last value back.
+    let count = ctx.builder.ins().iadd_imm(n, -1);
+    let offset = ctx.builder.ins().imul(count, step);
+    let last = ctx.builder.ins().iadd(first, offset);
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+
+    let build_block = ctx.builder.create_block();
+    let build_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    for _ in 0..3 {
+        ctx.builder.append_block_param(build_block, ctx.word);
+    }
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.ins().jump(build_block, &[n, last, nil]);
+
+    ctx.builder.switch_to_block(build_block);
+    let left = ctx.builder.block_params(build_block)[0];
+    let value = ctx.builder.block_params(build_block)[1];
+    let list = ctx.builder.block_params(build_block)[2];
+    ctx.builder.ins().brz(left, done_block, &[list]);
+    ctx.builder.ins().jump(build_body, &[]);
+
+    ctx.builder.switch_to_block(build_body);
+    ctx.builder.seal_block(build_body);
+    let word_size = ctx.word.bytes() as i32;
+    let pair = emit_alloc((2 * word_size).into(), ctx)?;
+    ctx.builder.ins().store(MemFlags::new(), value, pair, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), list, pair, word_size);
+    let list = ctx.builder.ins().bor_imm(pair, PAIR_TAG);
+    let value = ctx.builder.ins().isub(value, step);
+    let left = ctx.builder.ins().iadd_imm(left, -1);
+    ctx.builder.ins().jump(build_block, &[left, value, list]);
+    ctx.builder.seal_block(build_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind};
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn generators() {
+        check("(make-list 3 0)", "(quote (0 0 0))");
+        check("(iota 4)", "(quote (0 1 2 3))");
+        check("(iota 3 10 -2)", "(quote (10 8 6))");
+        check("(iota 3 5)", "(quote (5 6 7))");
+        check("(make-list 0 1)", "()");
+        check("(iota 0)", "()");
+        check(
+            "(make-list 2 (quote (a)))",
+            "(cons (quote (a)) (cons (quote (a)) ()))",
+        );
+        check(
+            "(let f (fn (g) (cons (g 2 1) (g 3 1)))) (cons (f iota) (f make-list))",
+            "(cons (cons (quote (1 2)) (quote (1 2 3))) (cons (quote (1 1)) (quote (1 1 1))))",
+        );
+        check("(let f (fn (g) (g 2))) (f iota)", "(quote (0 1))");
+        check("(let f (fn (g) (g 3 4 5))) (f iota)", "(quote (4 9 14))");
+        // Long lists don't need any stack. The interpreter's lists
+        // are freed recursively so this is only checked compiled.
+        assert_eq!(
+            roundtrip_string("(length (iota 1000000 5))").unwrap(),
+            crate::Expr::Integer(1000000)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for (source, kind) in [
+            ("(make-list -1 0)", "range-error"),
+            ("(iota -1)", "range-error"),
+            ("(iota (quote a))", "type-error"),
+            ("(iota 2 0 (quote a))", "type-error"),
+            ("(let f (fn (g) (g 1 2 3 4))) (f iota)", "arity-error"),
+        ] {
+            assert_eq!(error_kind(source), kind, "{}", source);
+            assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        }
+        assert!(roundtrip_string("(iota 1 2 3 4)").is_err());
+        assert!(roundtrip_string("(make-list 1)").is_err());
+    }
+}
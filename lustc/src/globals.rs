@@ -0,0 +1,126 @@
+//! Top level definitions that outlive the program that made them.
+//! When `CompileOptions::persistent` is set every top level let also
+//! stores its value in a slot in the JIT's data, and later programs
+//! compiled by the same JIT read the variables that they don't define
+//! themselves from those slots. This is what a REPL needs: each input
+//! is compiled as a program of its own but can use what earlier
+//! inputs defined.
+//!
+//! Redefining a variable in a later input updates its slot so inputs
+//! after that see the new value. Functions compiled before the
+//! redefinition keep the binding they closed over like they would
+//! had the inputs been one program.
+//!
+//! Symbols don't need anything special. The intern table in
+//! `symbols.rs` is shared by the whole process so a symbol is eq to
+//! every other symbol with its name no matter which input interned
+//! it.
+
+use crate::compiler::{Context, JIT};
+use crate::data::{self, LustData};
+use crate::renamer::original_name;
+use crate::Expr;
+
+/// The prefix of the names of the slots that hold definitions.
+const SLOT_PREFIX: &str = "__anon_global_";
+
+/// Returns the name of the slot that holds the definition of NAME.
+pub(crate) fn slot_name(name: &str) -> String {
+    format!("{}{}", SLOT_PREFIX, name)
+}
+
+/// Returns true if NAME is the name of a slot.
+pub(crate) fn is_slot(name: &str) -> bool {
+    name.starts_with(SLOT_PREFIX)
+}
+
+/// Returns the names defined by the top level lets in PROGRAM.
+pub(crate) fn definitions(program: &[Expr]) -> Vec<String> {
+    program
+        .iter()
+        .filter_map(|e| e.is_let())
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Makes slots in JIT for the NAMES that don't have one yet.
+pub(crate) fn define_slots(names: &[String], jit: &mut JIT) -> Result<(), String> {
+    let slots = names
+        .iter()
+        .filter(|name| jit.globals.insert(name.to_string()))
+        .map(|name| LustData {
+            name: slot_name(name),
+            data: Expr::Nil.immediate_rep(),
+            align: None,
+        })
+        .collect();
+    data::create_data(slots, jit)
+}
+
+/// If FORM is a top level let emits the code to store the value it
+/// bound in its variable's slot.
+pub(crate) fn emit_store_definition(form: &Expr, ctx: &mut Context) -> Result<(), String> {
+    if let Some((name, _)) = form.is_let() {
+        // Escaped variables get an e_ in front of the name the
+        // renamer gave them.
+        let renamed = name.strip_prefix("e_").unwrap_or(name);
+        let value = crate::locals::emit_var_access(name, ctx)?;
+        data::emit_data_store(&slot_name(original_name(renamed)), value, ctx)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_program, CompileOptions, JIT};
+    use crate::{parse_string, roundtrip_string, Expr};
+
+    fn run(jit: &mut JIT, source: &str) -> Result<Expr, String> {
+        let mut program = parse_string(source)?;
+        let id = compile_program(jit, &mut program)?;
+        Ok(Expr::from_immediate(jit.invoke(id).unwrap()))
+    }
+
+    #[test]
+    fn repl_inputs() {
+        let mut jit = JIT::new(CompileOptions {
+            persistent: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            run(
+                &mut jit,
+                "(let fruit (quote apple)) (let double (fn (x) (add x x))) (double 2)"
+            ),
+            Ok(Expr::Integer(4))
+        );
+        assert_eq!(
+            run(&mut jit, "(cons (double 21) (eq fruit (quote apple)))"),
+            roundtrip_string("(cons 42 (eq 1 1))")
+        );
+
+        // Later inputs see redefinitions and assignments.
+        run(
+            &mut jit,
+            "(let double (fn (x) (mul x 3))) (set fruit (quote pear))",
+        )
+        .unwrap();
+        assert_eq!(
+            run(&mut jit, "(cons (double 2) (eq fruit (quote pear)))"),
+            roundtrip_string("(cons 6 (eq 1 1))")
+        );
+    }
+
+    #[test]
+    fn not_persistent() {
+        // Without the option a JIT can still compile several
+        // programs but they don't share definitions.
+        let mut jit = JIT::default();
+        assert_eq!(run(&mut jit, "(let a 1) (add a 1)"), Ok(Expr::Integer(2)));
+        assert_eq!(
+            run(&mut jit, "(let f (fn (x) (car x))) (f (quote (3)))"),
+            Ok(Expr::Integer(3))
+        );
+        assert!(run(&mut jit, "a").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Removes the type check from car and cdr when the compiler can
+//! prove that their argument is a pair.
+//!
+//! ```lisp
+//! (let first (fn (l) (if (pair? l) (car l) ())))
+//! ```
+//!
+//! The then branch of that if only runs when l is a pair so checking
+//! it again in car is wasted work. This pass walks each function
+//! keeping track of which variables are known to be pairs in each
+//! branch of the conditionals it goes through and rewrites car and
+//! cdr of one of those variables into a version that skips the check.
+//! `and` is desugared into an if so `(and (pair? l) (car l))` is
+//! guarded too.
+//!
+//! Only `pair?` proves that a variable is a pair. A variable that
+//! isn't nil could be anything else so `(if (null? l) () (car l))`
+//! keeps its check. Variables that are assigned to with set are never
+//! known to be pairs since they could change between the guard and
+//! the access, and each function is analyzed on its own so a guard
+//! outside of a closure doesn't count inside of it. The pass only
+//! runs when `CompileOptions::optimize` is set.
+
+use std::collections::HashSet;
+
+use cranelift::prelude::*;
+
+use crate::compiler::{emit_expr, Context};
+use crate::procedures::LustFn;
+use crate::vectors::{emit_car, emit_cdr};
+use crate::Expr;
+use crate::PreorderStatus;
+
+/// The names that car and cdr are renamed to once their argument is
+/// known to be a pair.
+const UNCHECKED_CAR: &str = "__anon_unchecked_car";
+const UNCHECKED_CDR: &str = "__anon_unchecked_cdr";
+
+impl Expr {
+    /// If the expression is a car or cdr whose check has been removed
+    /// returns true if it is a car and the expression for the pair.
+    pub(crate) fn is_unchecked_access(&self) -> Option<(bool, &Expr)> {
+        match self {
+            Expr::List(v) if v.len() == 2 => match &v[0] {
+                Expr::Symbol(s) if s == UNCHECKED_CAR => Some((true, &v[1])),
+                Expr::Symbol(s) if s == UNCHECKED_CDR => Some((false, &v[1])),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Returns the variables that are pairs if COND is true, or if it is
+/// false when WHEN is false.
+fn known_pairs(cond: &Expr, when: bool) -> Vec<&String> {
+    match cond {
+        Expr::List(v) => match v.as_slice() {
+            // The desugared and binds its test to a temporary.
+            [Expr::Symbol(s), Expr::Symbol(_), value] if s == "let" => known_pairs(value, when),
+            [Expr::Symbol(s), inner] if s == "not" => known_pairs(inner, !when),
+            [Expr::Symbol(s), Expr::Symbol(x)] if s == "pair?" && when => vec![x],
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn mark_expr(e: &mut Expr, pairs: &HashSet<String>, assigned: &HashSet<String>) {
+    if e.is_quote().is_some() {
+        return;
+    }
+    if let Some((cond, _, _)) = e.is_conditional() {
+        let then_pairs = known_pairs(cond, true)
+            .into_iter()
+            .filter(|x| !assigned.contains(*x))
+            .cloned()
+            .collect::<Vec<_>>();
+        let else_pairs = known_pairs(cond, false)
+            .into_iter()
+            .filter(|x| !assigned.contains(*x))
+            .cloned()
+            .collect::<Vec<_>>();
+        if let Expr::List(v) = e {
+            mark_expr(&mut v[1], pairs, assigned);
+            let mut then = pairs.clone();
+            then.extend(then_pairs);
+            mark_expr(&mut v[2], &then, assigned);
+            let mut else_ = pairs.clone();
+            else_.extend(else_pairs);
+            mark_expr(&mut v[3], &else_, assigned);
+        }
+        return;
+    }
+    if let Expr::List(v) = e {
+        if let [Expr::Symbol(f), Expr::Symbol(x)] = v.as_mut_slice() {
+            if pairs.contains(x.as_str()) {
+                if f == "car" {
+                    *f = UNCHECKED_CAR.to_string();
+                } else if f == "cdr" {
+                    *f = UNCHECKED_CDR.to_string();
+                }
+            }
+        }
+        for e in v.iter_mut() {
+            mark_expr(e, pairs, assigned);
+        }
+    }
+}
+
+/// Removes the checks from the car and cdr calls in PROGRAM and
+/// FUNCTIONS whose arguments are known to be pairs. Needs to run
+/// after renaming so that a guard on a variable can't be confused
+/// with another variable of the same name, and after functions have
+/// been collected so that each one is analyzed on its own.
+pub(crate) fn remove_guarded_checks(program: &mut [Expr], functions: &mut [LustFn]) {
+    let _t = crate::timer::timeit("guarded access pass");
+    let mut assigned = HashSet::new();
+    let bodies = program
+        .iter()
+        .chain(functions.iter().flat_map(|f| f.body.iter()));
+    for e in bodies {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if let Some((name, _)) = e.is_set() {
+                assigned.insert(name.clone());
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    let bodies = program
+        .iter_mut()
+        .chain(functions.iter_mut().flat_map(|f| f.body.iter_mut()));
+    for e in bodies {
+        mark_expr(e, &HashSet::new(), &assigned);
+    }
+}
+
+/// Emits the code for the car, if IS_CAR, or cdr of PAIR without
+/// checking that it is a pair.
+pub(crate) fn emit_unchecked_access(
+    is_car: bool,
+    pair: &Expr,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let pair = emit_expr(pair, ctx)?;
+    Ok(if is_car {
+        emit_car(pair, ctx)
+    } else {
+        emit_cdr(pair, ctx)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_program, CompileOptions, JIT};
+    use crate::parse_string;
+
+    fn marked(source: &str) -> Vec<Expr> {
+        let mut program = parse_string(source).unwrap();
+        crate::desugar::desugar(&mut program).unwrap();
+        // The variables aren't defined so the errors are ignored.
+        crate::renamer::rename_each_form(&mut program, &Default::default());
+        remove_guarded_checks(&mut program, &mut []);
+        program
+    }
+
+    fn unchecked(e: &Expr) -> usize {
+        let mut count = 0;
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_unchecked_access().is_some() {
+                count += 1;
+            }
+            PreorderStatus::Continue
+        });
+        count
+    }
+
+    #[test]
+    fn guarded_access() {
+        let count = |source: &str| marked(source).iter().map(unchecked).sum::<usize>();
+        assert_eq!(count("(if (pair? l) (car l) (cdr l))"), 1);
+        assert_eq!(count("(if (not (pair? l)) (car l) (cdr l))"), 1);
+        assert_eq!(count("(and (pair? l) (pair? m) (cons (car l) (cdr m)))"), 2);
+        assert_eq!(count("(if (null? l) () (car l))"), 0);
+        assert_eq!(count("(if (pair? l) (car m) (car l))"), 0);
+        assert_eq!(count("(if (pair? l) (car l) ()) (set l 1)"), 0);
+        assert_eq!(count("(if (pair? l) (quote (car l)) ())"), 0);
+    }
+
+    #[test]
+    fn removes_checks() {
+        let source = r#"
+(let first (fn (l) (if (pair? l) (car l) (quote none))))
+(cons (first (quote (1 2))) (first 3))
+"#;
+        let compile = |optimize| {
+            let mut jit = JIT::new(CompileOptions {
+                optimize,
+                ..Default::default()
+            });
+            let mut program = parse_string(source).unwrap();
+            let id = compile_program(&mut jit, &mut program).unwrap();
+            let res = Expr::from_immediate(jit.invoke(id).unwrap());
+            let first = jit
+                .compiled_functions
+                .iter()
+                .find(|f| f.name.starts_with("__anon_fn"))
+                .unwrap()
+                .size;
+            (res, first)
+        };
+        let (checked, checked_size) = compile(false);
+        let (optimized, optimized_size) = compile(true);
+        assert_eq!(checked, optimized);
+        assert!(optimized_size < checked_size);
+    }
+}
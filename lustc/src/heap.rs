@@ -3,14 +3,197 @@
 //! Some things truly never die
 
 use cranelift::frontend::FunctionBuilder;
-use cranelift::prelude::AbiParam;
-use cranelift::prelude::InstBuilder;
-use cranelift::prelude::Value;
+use cranelift::prelude::*;
+use cranelift_jit::JITModule;
 use cranelift_module::Module;
 
 use crate::compiler::JIT;
+use crate::data::LustData;
+use crate::Expr;
+use crate::Word;
 
-// Emits an 'alloc' function which when called makes a call to malloc.
+/// The allocator that heap allocated values are placed in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Allocator {
+    /// Every allocation is a call to malloc.
+    #[default]
+    Malloc,
+    /// Allocations are bumps of a pointer into an arena owned by the
+    /// JIT. Nothing is freed until the JIT is dropped. This is the
+    /// fastest option for programs that run once and exit.
+    Arena,
+    /// Allocations are handed to an allocator supplied by the program
+    /// embedding the JIT.
+    Custom(CustomAllocator),
+}
+
+/// An allocator supplied by an embedder. Every allocation the program
+/// makes, conses, strings, closures, and so on, calls ALLOC with DATA,
+/// the number of bytes needed, and the alignment they need. The
+/// address returned must be a multiple of the alignment, which is
+/// always at least a word so that the low bits are free for tags.
+/// Nothing allocated is ever freed by the JIT.
+#[derive(Debug, Clone, Copy)]
+pub struct CustomAllocator {
+    pub alloc: extern "C" fn(data: *mut std::ffi::c_void, size: usize, align: usize) -> *mut u8,
+    /// Passed to every call to ALLOC.
+    pub data: *mut std::ffi::c_void,
+}
+
+impl PartialEq for CustomAllocator {
+    fn eq(&self, other: &Self) -> bool {
+        self.alloc as usize == other.alloc as usize && self.data == other.data
+    }
+}
+
+/// The size of the arena's first chunk in bytes. Every chunk after
+/// that is twice as large as the one before it.
+const ARENA_CHUNK_SIZE: usize = 1 << 16;
+
+/// A bump allocator. Allocation is done in the code emitted by
+/// `define_alloc` which moves NEXT along until it hits END and then
+/// asks `lustc_arena_grow` for a new chunk.
+#[repr(C)]
+pub struct Arena {
+    /// The address of the next free byte in the current chunk.
+    next: Word,
+    /// The address one past the end of the current chunk.
+    end: Word,
+    /// Every chunk that has been allocated. Chunks are made of words
+    /// so that the addresses handed out are aligned enough to be
+    /// tagged.
+    chunks: Vec<Box<[Word]>>,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Self {
+            next: 0,
+            end: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Returns the number of bytes that the arena has reserved from
+    /// the system.
+    pub fn reserved(&self) -> usize {
+        self.chunks
+            .iter()
+            .map(|c| c.len() * std::mem::size_of::<Word>())
+            .sum()
+    }
+
+    /// Adds a chunk large enough to hold SIZE bytes and allocates SIZE
+    /// bytes from it.
+    fn grow(&mut self, size: usize) -> Word {
+        let previous = self.chunks.last().map_or(ARENA_CHUNK_SIZE / 2, |c| {
+            c.len() * std::mem::size_of::<Word>()
+        });
+        let bytes = (previous * 2).max(size);
+        let words = bytes.div_ceil(std::mem::size_of::<Word>());
+        let mut chunk = vec![0; words].into_boxed_slice();
+
+        let start = chunk.as_mut_ptr() as Word;
+        self.next = start + size as Word;
+        self.end = start + (words * std::mem::size_of::<Word>()) as Word;
+        self.chunks.push(chunk);
+        start
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Called by `alloc` when the current chunk of ARENA does not have
+/// room for SIZE more bytes.
+pub(crate) extern "C" fn lustc_arena_grow(arena: *mut Arena, size: Word) -> Word {
+    let arena = unsafe { &mut *arena };
+    arena.grow(size as usize)
+}
+
+/// The word in the program's data that counts the bytes that `alloc`
+/// has handed out since the JIT started running the current program.
+pub(crate) const HEAP_ALLOCATED: &str = "__anon_data_heap_allocated";
+
+/// Figures about the heap of the program a JIT is running or last
+/// ran. All of the figures are reset when `JIT::invoke` starts a
+/// program.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HeapStats {
+    /// The number of bytes that have been allocated.
+    pub allocated: usize,
+    /// The number of bytes that are still in use. Nothing is ever
+    /// freed so this is the same as allocated.
+    pub live: usize,
+    /// The number of times the garbage collector has run. There isn't
+    /// a garbage collector so this is always zero.
+    pub collections: usize,
+}
+
+impl HeapStats {
+    fn from_allocated(allocated: usize) -> Self {
+        Self {
+            allocated,
+            live: allocated,
+            collections: 0,
+        }
+    }
+
+    /// Returns the list (allocated live collections).
+    fn to_immediate(self) -> Word {
+        crate::conversions::list_to_immediate(&[
+            Expr::Integer(self.allocated as i64),
+            Expr::Integer(self.live as i64),
+            Expr::Integer(self.collections as i64),
+        ])
+    }
+}
+
+/// Defines the word that `alloc` counts allocated bytes in.
+pub(crate) fn define_heap_stats(jit: &mut JIT) -> Result<(), String> {
+    crate::data::create_data(
+        vec![LustData {
+            name: HEAP_ALLOCATED.to_string(),
+            data: 0,
+            align: None,
+        }],
+        jit,
+    )
+}
+
+/// Returns the address of the word that `alloc` counts allocated
+/// bytes in.
+fn heap_allocated_ptr(jit: &JIT) -> *mut Word {
+    match jit.module.get_name(HEAP_ALLOCATED) {
+        Some(cranelift_module::FuncOrDataId::Data(id)) => {
+            jit.module.get_finalized_data(id).0 as *mut Word
+        }
+        _ => panic!("{} is not defined", HEAP_ALLOCATED),
+    }
+}
+
+/// Returns the heap statistics for the program JIT is running or
+/// last ran.
+pub(crate) fn heap_stats(jit: &JIT) -> HeapStats {
+    HeapStats::from_allocated(unsafe { *heap_allocated_ptr(jit) } as usize)
+}
+
+/// Resets the heap statistics before JIT runs a new program.
+pub(crate) fn reset_heap_stats(jit: &mut JIT) {
+    unsafe { *heap_allocated_ptr(jit) = 0 };
+}
+
+/// Called by `(heap-stats)` with the number of bytes allocated so
+/// far. Returns the statistics as a list.
+pub(crate) extern "C" fn lustc_heap_stats(allocated: Word) -> Word {
+    HeapStats::from_allocated(allocated as usize).to_immediate()
+}
+
+// Emits an 'alloc' function which when called allocates memory using
+// the JIT's allocator.
 pub fn define_alloc(jit: &mut JIT) -> Result<(), String> {
     let _t = crate::timer::timeit("emit alloc");
     let word = jit.module.target_config().pointer_type();
@@ -27,24 +210,65 @@ pub fn define_alloc(jit: &mut JIT) -> Result<(), String> {
     builder.append_block_params_for_function_params(entry_block);
     builder.switch_to_block(entry_block);
 
-    // Make a call to malloc:
-    let mut sig = jit.module.make_signature();
-
-    sig.params.push(AbiParam::new(word));
-    sig.returns.push(AbiParam::new(word));
+    let size = builder.block_params(entry_block)[0];
 
-    let callee = jit
+    // Count the allocation.
+    let counter = jit
         .module
-        .declare_function("malloc", cranelift_module::Linkage::Import, &sig)
+        .declare_data(
+            HEAP_ALLOCATED,
+            cranelift_module::Linkage::Export,
+            true,
+            false,
+        )
         .map_err(|e| e.to_string())?;
+    let counter = jit.module.declare_data_in_func(counter, builder.func);
+    let counter = builder.ins().symbol_value(word, counter);
+    let allocated = builder.ins().load(word, MemFlags::new(), counter, 0);
+    let allocated = builder.ins().iadd(allocated, size);
+    builder.ins().store(MemFlags::new(), allocated, counter, 0);
 
-    let local_callee = jit.module.declare_func_in_func(callee, &mut builder.func);
+    let res = match (&mut jit.arena, jit.options.allocator) {
+        (_, Allocator::Custom(custom)) => {
+            let mut sig = jit.module.make_signature();
+            for _ in 0..3 {
+                sig.params.push(AbiParam::new(word));
+            }
+            sig.returns.push(AbiParam::new(word));
+            let sig = builder.import_signature(sig);
 
-    let size = builder.block_params(entry_block)[0];
-    let args = vec![size];
+            let alloc = builder.ins().iconst(word, custom.alloc as usize as Word);
+            let data = builder.ins().iconst(word, custom.data as Word);
+            let align = builder.ins().iconst(word, word.bytes() as Word);
+            let call = builder
+                .ins()
+                .call_indirect(sig, alloc, &[data, size, align]);
+            builder.inst_results(call)[0]
+        }
+        (Some(arena), _) => {
+            let arena = arena.as_mut() as *mut Arena as Word;
+            emit_arena_alloc(arena, size, word, &mut builder, &mut jit.module)?
+        }
+        (None, _) => {
+            // Make a call to malloc:
+            let mut sig = jit.module.make_signature();
 
-    let call = builder.ins().call(local_callee, &args);
-    let res = builder.inst_results(call)[0];
+            sig.params.push(AbiParam::new(word));
+            sig.returns.push(AbiParam::new(word));
+
+            let callee = jit
+                .module
+                .declare_function("malloc", cranelift_module::Linkage::Import, &sig)
+                .map_err(|e| e.to_string())?;
+
+            let local_callee = jit.module.declare_func_in_func(callee, &mut builder.func);
+
+            let args = vec![size];
+
+            let call = builder.ins().call(local_callee, &args);
+            builder.inst_results(call)[0]
+        }
+    };
 
     builder.ins().return_(&[res]);
 
@@ -69,7 +293,82 @@ pub fn define_alloc(jit: &mut JIT) -> Result<(), String> {
     Ok(())
 }
 
+/// Emits the code to allocate SIZE bytes from the arena at address
+/// ARENA. In the common case this is just bumping a pointer.
+fn emit_arena_alloc(
+    arena: Word,
+    size: Value,
+    word: Type,
+    builder: &mut FunctionBuilder,
+    module: &mut JITModule,
+) -> Result<Value, String> {
+    let word_bytes = word.bytes() as Word;
+    let next_offset = 0;
+    let end_offset = word.bytes() as i32;
+
+    // Round the size up to a multiple of the word size so that every
+    // address handed out can be tagged.
+    let size = builder.ins().iadd_imm(size, word_bytes - 1);
+    let size = builder.ins().band_imm(size, !(word_bytes - 1));
+
+    let arena = builder.ins().iconst(word, arena);
+    let next = builder
+        .ins()
+        .load(word, MemFlags::new(), arena, next_offset);
+    let end = builder.ins().load(word, MemFlags::new(), arena, end_offset);
+    let new_next = builder.ins().iadd(next, size);
+
+    let bump_block = builder.create_block();
+    let grow_block = builder.create_block();
+    let return_block = builder.create_block();
+    builder.append_block_param(return_block, word);
+
+    let fits = builder
+        .ins()
+        .icmp(IntCC::UnsignedLessThanOrEqual, new_next, end);
+    builder.ins().brz(fits, grow_block, &[]);
+    builder.ins().jump(bump_block, &[]);
+
+    builder.switch_to_block(bump_block);
+    builder.seal_block(bump_block);
+    builder
+        .ins()
+        .store(MemFlags::new(), new_next, arena, next_offset);
+    builder.ins().jump(return_block, &[next]);
+
+    builder.switch_to_block(grow_block);
+    builder.seal_block(grow_block);
+
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(word));
+    sig.params.push(AbiParam::new(word));
+    sig.returns.push(AbiParam::new(word));
+
+    let callee = module
+        .declare_function("lustc_arena_grow", cranelift_module::Linkage::Import, &sig)
+        .map_err(|e| e.to_string())?;
+    let local_callee = module.declare_func_in_func(callee, builder.func);
+
+    let call = builder.ins().call(local_callee, &[arena, size]);
+    let res = builder.inst_results(call)[0];
+    builder.ins().jump(return_block, &[res]);
+
+    builder.switch_to_block(return_block);
+    builder.seal_block(return_block);
+    Ok(builder.block_params(return_block)[0])
+}
+
 pub(crate) fn emit_alloc(size: i64, ctx: &mut crate::compiler::Context) -> Result<Value, String> {
+    let size = ctx.builder.ins().iconst(ctx.word, size);
+    emit_alloc_value(size, ctx)
+}
+
+/// Emits the code to allocate SIZE bytes where SIZE is only known at
+/// runtime.
+pub(crate) fn emit_alloc_value(
+    size: Value,
+    ctx: &mut crate::compiler::Context,
+) -> Result<Value, String> {
     let word = ctx.module.target_config().pointer_type();
 
     let mut sig = ctx.module.make_signature();
@@ -86,7 +385,6 @@ pub(crate) fn emit_alloc(size: i64, ctx: &mut crate::compiler::Context) -> Resul
         .module
         .declare_func_in_func(callee, &mut ctx.builder.func);
 
-    let size = ctx.builder.ins().iconst(word, size);
     let args = vec![size];
 
     let call = ctx.builder.ins().call(local_callee, &args);
@@ -94,3 +392,167 @@ pub(crate) fn emit_alloc(size: i64, ctx: &mut crate::compiler::Context) -> Resul
 
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_program, CompileOptions};
+    use crate::{parse_string, Expr};
+
+    /// Builds and sums a list of N numbers. Every cons is an
+    /// allocation.
+    fn list_program(n: usize) -> String {
+        format!(
+            r#"
+(let build (fn (n acc) (if (eq n 0) acc (build (sub n 1) (cons n acc)))))
+(let sum (fn (l) (if (null? l) 0 (add (car l) (sum (cdr l))))))
+(sum (build {} ()))
+"#,
+            n
+        )
+    }
+
+    fn run_with(allocator: Allocator, source: &str) -> (Expr, JIT) {
+        let mut jit = JIT::new(CompileOptions {
+            allocator,
+            ..Default::default()
+        });
+        let mut program = parse_string(source).unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        let res = Expr::from_immediate(jit.invoke(id).unwrap());
+        (res, jit)
+    }
+
+    /// Counts the allocations made through it in the usize that DATA
+    /// points to.
+    extern "C" fn counting_alloc(
+        data: *mut std::ffi::c_void,
+        size: usize,
+        align: usize,
+    ) -> *mut u8 {
+        unsafe {
+            *(data as *mut usize) += 1;
+            std::alloc::alloc(std::alloc::Layout::from_size_align(size.max(1), align).unwrap())
+        }
+    }
+
+    #[test]
+    fn custom_allocator() {
+        let mut count = 0usize;
+        let allocator = Allocator::Custom(CustomAllocator {
+            alloc: counting_alloc,
+            data: &mut count as *mut usize as *mut std::ffi::c_void,
+        });
+        let (res, jit) = run_with(allocator, "(cons 1 (cons 2 (cons 3 ())))");
+        assert_eq!(res, crate::roundtrip_string("(quote (1 2 3))").unwrap());
+        assert_eq!(count, 3);
+        assert_eq!(
+            jit.heap_stats().allocated,
+            3 * 2 * std::mem::size_of::<Word>()
+        );
+
+        count = 0;
+        let (res, _) = run_with(allocator, &list_program(100));
+        assert_eq!(res, Expr::Integer(100 * 101 / 2));
+        assert!(count >= 100);
+    }
+
+    #[test]
+    fn arena_grows() {
+        // Each cons is two words so this needs several chunks.
+        let (res, jit) = run_with(Allocator::Arena, &list_program(10000));
+        assert_eq!(res, Expr::Integer(10000 * 10001 / 2));
+        let arena = jit.arena.as_ref().unwrap();
+        assert!(arena.chunks.len() > 1);
+        assert!(arena.reserved() >= 10000 * 2 * std::mem::size_of::<Word>());
+    }
+
+    #[test]
+    fn arena_large_allocation() {
+        let mut arena = Arena::new();
+        let small = arena.grow(8);
+        let large = arena.grow(ARENA_CHUNK_SIZE * 8);
+        assert_ne!(small, large);
+        assert_eq!(large % std::mem::size_of::<Word>() as Word, 0);
+        assert!(arena.end - large >= (ARENA_CHUNK_SIZE * 8) as Word);
+    }
+
+    #[test]
+    fn allocators_agree() {
+        // Enough conses that the arena needs more than its first chunk.
+        let n = 5000;
+        let source = format!(
+            "{}\n(cons (sum (build {} ())) (car (heap-stats)))",
+            list_program(0),
+            n
+        );
+        let cell = 2 * std::mem::size_of::<Word>() as i64;
+        let results = [Allocator::Malloc, Allocator::Arena].map(|allocator| {
+            let (res, _) = run_with(allocator, &source);
+            res
+        });
+        assert_eq!(results[0], results[1]);
+        // Every cons takes a cell and the calls that build the list
+        // take less than another one each.
+        match &results[0] {
+            Expr::List(v) => match v.as_slice() {
+                [sum, Expr::Integer(allocated)] => {
+                    assert_eq!(*sum, Expr::Integer(n * (n + 1) / 2));
+                    assert!((n * cell..2 * n * cell).contains(allocated));
+                }
+                _ => panic!("{:?}", v),
+            },
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn heap_stats() {
+        // Calls allocate space for their arguments so the conses are
+        // written out rather than built by a function.
+        let n = 100;
+        let list = (1..=n).fold("()".to_string(), |l, i| format!("(cons {} {})", i, l));
+        let source = format!(
+            r#"
+(let before (heap-stats))
+(let l {})
+(let after (heap-stats))
+(cons (sub (car after) (car before))
+      (cons (sub (car (cdr after)) (car after))
+            (car (cdr (cdr after)))))
+"#,
+            list
+        );
+        let cell = 2 * std::mem::size_of::<Word>() as i64;
+
+        for allocator in [Allocator::Malloc, Allocator::Arena] {
+            let (res, jit) = run_with(allocator, &source);
+            // The allocated bytes grow by one cell per cons, live
+            // is the same as allocated, and nothing was collected.
+            assert_eq!(
+                res,
+                Expr::List(vec![
+                    Expr::Integer(n * cell),
+                    Expr::List(vec![Expr::Integer(0), Expr::Integer(0)])
+                ])
+            );
+
+            let stats = jit.heap_stats();
+            assert_eq!(stats.allocated, stats.live);
+            assert!(stats.allocated as i64 >= n * cell);
+            assert_eq!(stats.collections, 0);
+        }
+    }
+
+    #[test]
+    fn heap_stats_reset() {
+        let mut jit = JIT::default();
+        let mut program = parse_string("(cons 1 (cons 2 ()))").unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        jit.invoke(id).unwrap();
+        let first = jit.heap_stats();
+        jit.invoke(id).unwrap();
+        assert_eq!(jit.heap_stats(), first);
+        assert!(first.allocated > 0);
+    }
+}
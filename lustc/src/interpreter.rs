@@ -0,0 +1,1427 @@
+//! A tree walking interpreter for Lust programs. It implements the
+//! same semantics as the compiler without going anywhere near
+//! Cranelift which makes it useful as a reference when the compiled
+//! version of a program misbehaves.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::conversions::try_stringify_list;
+use crate::fatal::{internal_error_message, internal_error_type, DEFAULT_ERROR_TYPE};
+use crate::output::{begin_capture, display_string, end_capture, write_output};
+use crate::primitives::string_is_primitive;
+use crate::procedures::is_varadic_param;
+use crate::Expr;
+
+/// A value that the interpreter can work with. Pairs and closures are
+/// reference counted so that eq can compare them by identity like the
+/// compiled version does.
+#[derive(Clone)]
+enum Value<'a> {
+    Integer(i64),
+    Char(char),
+    Bool(bool),
+    Nil,
+    /// Symbols are interned in compiled code so comparing them by
+    /// name behaves the same way.
+    Symbol(Rc<str>),
+    Pair(Rc<(Value<'a>, Value<'a>)>),
+    Closure(Rc<Closure<'a>>),
+    Primitive(&'a str),
+    /// The values returned by `(values ...)`.
+    Values(Rc<Vec<Value<'a>>>),
+    /// A condition's type, message, and data.
+    Condition(Rc<(Value<'a>, Value<'a>, Value<'a>)>),
+    Vector(Rc<Elements<'a>>),
+    Queue(Rc<Queue<'a>>),
+    /// The characters added to a string builder. See `stringbuilder.rs`.
+    Builder(Rc<RefCell<Vec<char>>>),
+    /// A record's type and the vector of its fields. See `records.rs`.
+    Record(Rc<(Value<'a>, Value<'a>)>),
+}
+
+/// A priority queue kept as a binary heap. See `priority.rs`.
+struct Queue<'a> {
+    less: Value<'a>,
+    elements: RefCell<Vec<Value<'a>>>,
+}
+
+/// The elements of a vector. A slice of a vector shares the storage of
+/// the vector it was made from and sees LEN elements of it starting at
+/// START.
+struct Elements<'a> {
+    storage: Rc<RefCell<Vec<Value<'a>>>>,
+    start: usize,
+    len: usize,
+}
+
+impl<'a> Elements<'a> {
+    /// Returns a new vector of ELEMENTS.
+    fn vector(elements: Vec<Value<'a>>) -> Value<'a> {
+        Value::Vector(Rc::new(Self {
+            len: elements.len(),
+            storage: Rc::new(RefCell::new(elements)),
+            start: 0,
+        }))
+    }
+
+    fn to_vec(&self) -> Vec<Value<'a>> {
+        self.storage.borrow()[self.start..self.start + self.len].to_vec()
+    }
+
+    fn get(&self, i: usize) -> Option<Value<'a>> {
+        if i < self.len {
+            Some(self.storage.borrow()[self.start + i].clone())
+        } else {
+            None
+        }
+    }
+
+    fn swap(&self, i: usize, j: usize) {
+        self.storage
+            .borrow_mut()
+            .swap(self.start + i, self.start + j)
+    }
+
+    fn set(&self, i: usize, x: &Value<'a>) {
+        self.storage.borrow_mut()[self.start + i] = x.clone()
+    }
+
+    fn fill(&self, x: &Value<'a>) {
+        self.storage.borrow_mut()[self.start..self.start + self.len]
+            .iter_mut()
+            .for_each(|e| *e = x.clone());
+    }
+
+    /// Returns the slice of the elements from START up to END or None
+    /// if they aren't in range.
+    fn slice(&self, start: i64, end: i64) -> Option<Value<'a>> {
+        if start < 0 || start > end || end as usize > self.len {
+            return None;
+        }
+        Some(Value::Vector(Rc::new(Self {
+            storage: self.storage.clone(),
+            start: self.start + start as usize,
+            len: (end - start) as usize,
+        })))
+    }
+}
+
+/// A function and the scope that it was defined in.
+struct Closure<'a> {
+    params: Vec<&'a String>,
+    body: &'a [Expr],
+    scope: Rc<Scope<'a>>,
+}
+
+/// The variables bound in a function call or at the top level of the
+/// program. As the program has been renamed every name is unique so
+/// there is no need to worry about shadowing.
+struct Scope<'a> {
+    vars: RefCell<HashMap<&'a str, Value<'a>>>,
+    parent: Option<Rc<Scope<'a>>>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(parent: Option<Rc<Scope<'a>>>) -> Rc<Self> {
+        Rc::new(Self {
+            vars: RefCell::new(HashMap::new()),
+            parent,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<Value<'a>> {
+        match self.vars.borrow().get(name) {
+            Some(v) => Some(v.clone()),
+            None => self.parent.as_ref().and_then(|p| p.get(name)),
+        }
+    }
+
+    fn set(&self, name: &str, val: Value<'a>) -> bool {
+        match self.vars.borrow_mut().get_mut(name) {
+            Some(v) => {
+                *v = val;
+                true
+            }
+            None => self.parent.as_ref().is_some_and(|p| p.set(name, val)),
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    /// Converts the value into the expression that the compiled
+    /// program would have returned for it.
+    fn to_expr(&self) -> Expr {
+        match self {
+            Value::Integer(i) => Expr::Integer(*i),
+            Value::Char(c) => Expr::Char(*c),
+            Value::Bool(b) => Expr::Bool(*b),
+            Value::Nil => Expr::Nil,
+            Value::Symbol(s) => Expr::Symbol(s.to_string()),
+            Value::Pair(p) => Expr::List(vec![p.0.to_expr(), p.1.to_expr()]),
+            Value::Closure(_) | Value::Primitive(_) => Expr::Procedure,
+            Value::Values(v) => v.first().map_or(Expr::Nil, |v| v.to_expr()),
+            Value::Condition(c) => Expr::List(vec![
+                c.0.to_expr(),
+                Expr::List(vec![
+                    c.1.to_expr(),
+                    Expr::List(vec![c.2.to_expr(), Expr::Nil]),
+                ]),
+            ]),
+            Value::Queue(q) => q
+                .elements
+                .borrow()
+                .iter()
+                .rev()
+                .fold(Expr::Nil, |cdr, car| Expr::List(vec![car.to_expr(), cdr])),
+            Value::Builder(b) => b.borrow().iter().rev().fold(Expr::Nil, |cdr, car| {
+                Expr::List(vec![Expr::Char(*car), cdr])
+            }),
+            Value::Vector(v) => v
+                .to_vec()
+                .iter()
+                .rev()
+                .fold(Expr::Nil, |cdr, car| Expr::List(vec![car.to_expr(), cdr])),
+            Value::Record(r) => Expr::List(vec![r.0.to_expr(), r.1.to_expr()]),
+        }
+    }
+
+    fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    fn cons(car: Value<'a>, cdr: Value<'a>) -> Self {
+        Value::Pair(Rc::new((car, cdr)))
+    }
+
+    fn from_list(items: impl DoubleEndedIterator<Item = Value<'a>>) -> Self {
+        items
+            .rev()
+            .fold(Value::Nil, |cdr, car| Value::cons(car, cdr))
+    }
+
+    /// Converts a piece of quoted data or a string into a value.
+    fn from_data(e: &Expr) -> Result<Self, String> {
+        Ok(match e {
+            Expr::Integer(i) => Value::Integer(*i),
+            Expr::Char(c) => Value::Char(*c),
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Nil => Value::Nil,
+            Expr::List(v) => Value::from_list(
+                v.iter()
+                    .map(Value::from_data)
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter(),
+            ),
+            Expr::String(s) => Value::from_list(s.chars().map(Value::Char)),
+            Expr::Symbol(s) => Value::Symbol(s.as_str().into()),
+            Expr::Procedure => return Err("procedures can not be quoted".to_string()),
+        })
+    }
+}
+
+fn type_error<T>() -> Result<T, String> {
+    Err(internal_error_message("__anon_data_bad_arg_type").to_string())
+}
+
+fn check_arg_count(args: &[Value], expected: usize) -> Result<(), String> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(internal_error_message("__anon_data_bad_arg_count").to_string())
+    }
+}
+
+fn expect_int(v: &Value) -> Result<i64, String> {
+    match v {
+        Value::Integer(i) => Ok(*i),
+        _ => type_error(),
+    }
+}
+
+struct Interpreter<'a> {
+    /// The values of quoted data and strings keyed on the address of
+    /// the expression that they came from. Quoted data must evaluate
+    /// to the same object every time it is evaluated.
+    data: HashMap<*const Expr, Value<'a>>,
+    /// The condition of the error that is being raised if it was
+    /// raised by an error expression.
+    raised: Option<Value<'a>>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn eval_body(&mut self, body: &'a [Expr], scope: &Rc<Scope<'a>>) -> Result<Value<'a>, String> {
+        let mut res = Err("expected at least one expression".to_string());
+        for e in body {
+            res = Ok(self.eval(e, scope)?);
+        }
+        res
+    }
+
+    fn eval_data(&mut self, e: &'a Expr, data: &Expr) -> Result<Value<'a>, String> {
+        if let Some(v) = self.data.get(&(e as *const Expr)) {
+            return Ok(v.clone());
+        }
+        let v = Value::from_data(data)?;
+        self.data.insert(e as *const Expr, v.clone());
+        Ok(v)
+    }
+
+    /// Evaluates E outside of tail position where only its first
+    /// value is seen.
+    fn eval_one(&mut self, e: &'a Expr, scope: &Rc<Scope<'a>>) -> Result<Value<'a>, String> {
+        Ok(match self.eval(e, scope)? {
+            Value::Values(v) => v.first().cloned().unwrap_or(Value::Nil),
+            v => v,
+        })
+    }
+
+    fn eval(&mut self, e: &'a Expr, scope: &Rc<Scope<'a>>) -> Result<Value<'a>, String> {
+        Ok(match e {
+            Expr::Integer(i) => Value::Integer(*i),
+            Expr::Char(c) => Value::Char(*c),
+            Expr::Bool(b) => Value::Bool(*b),
+            Expr::Nil => Value::Nil,
+            Expr::String(_) => self.eval_data(e, e)?,
+            Expr::Procedure => return Err("procedures can not be evaluated".to_string()),
+            Expr::Symbol(s) => match scope.get(s) {
+                Some(v) => v,
+                None if string_is_primitive(s) => Value::Primitive(s),
+                None => return Err(format!("use of undeclared variable ({})", s)),
+            },
+            Expr::List(_) => {
+                if let Some(data) = e.is_quote() {
+                    self.eval_data(e, data)?
+                } else if let Some((name, args)) = e.is_primcall() {
+                    let args = args
+                        .iter()
+                        .map(|a| self.eval_one(a, scope))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    self.apply_builtin(name, args)?
+                } else if let Some((name, binding)) = e.is_let() {
+                    let val = self.eval_one(binding, scope)?;
+                    scope.vars.borrow_mut().insert(name, val.clone());
+                    val
+                } else if let Some((name, binding)) = e.is_set() {
+                    let val = self.eval_one(binding, scope)?;
+                    if !scope.set(name, val.clone()) {
+                        return Err(format!(
+                            "use of undeclared variable ({}) in set expression",
+                            name
+                        ));
+                    }
+                    val
+                } else if let Some((cond, then, else_)) = e.is_conditional() {
+                    // Like in compiled code only true takes the then
+                    // branch.
+                    match self.eval_one(cond, scope)? {
+                        Value::Bool(true) => self.eval(then, scope)?,
+                        _ => self.eval(else_, scope)?,
+                    }
+                } else if let Some((params, body)) = e.is_fndef() {
+                    Value::Closure(Rc::new(Closure {
+                        params,
+                        body,
+                        scope: scope.clone(),
+                    }))
+                } else if let Some((kind, message, data)) = e.is_typed_error() {
+                    let kind = self.eval(kind, scope)?;
+                    if !matches!(kind, Value::Symbol(_)) {
+                        return type_error();
+                    }
+                    let message = self.eval(message, scope)?;
+                    let data = self.eval(data, scope)?;
+                    let text = message.to_expr();
+                    self.raised = Some(Value::Condition(Rc::new((kind, message, data))));
+                    return Err(try_stringify_list(&text).unwrap_or(text.to_string()));
+                } else if let Some((message, _)) = e.is_error() {
+                    let message = self.eval(message, scope)?;
+                    let text = message.to_expr();
+                    self.raised = Some(Value::Condition(Rc::new((
+                        Value::Symbol(DEFAULT_ERROR_TYPE.into()),
+                        message,
+                        Value::Nil,
+                    ))));
+                    return Err(try_stringify_list(&text).unwrap_or(text.to_string()));
+                } else if let Some(timed) = e.is_time() {
+                    let start = std::time::Instant::now();
+                    let val = self.eval(timed, scope)?;
+                    crate::timer::write_time(start.elapsed(), &mut std::io::stderr());
+                    val
+                } else if let Some(vals) = e.is_values() {
+                    let mut vals = vals
+                        .iter()
+                        .map(|a| self.eval_one(a, scope))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    match vals.len() {
+                        1 => vals.pop().unwrap(),
+                        _ => Value::Values(Rc::new(vals)),
+                    }
+                } else if let Some((producer, consumer)) = e.is_call_with_values() {
+                    let producer = self.eval(producer, scope)?;
+                    let consumer = self.eval(consumer, scope)?;
+                    let args = match self.apply(producer, Vec::new())? {
+                        Value::Values(v) => v.to_vec(),
+                        v => vec![v],
+                    };
+                    self.apply(consumer, args)?
+                } else if let Some((body, handler)) = e.is_try() {
+                    let body = self.eval(body, scope)?;
+                    let handler = self.eval(handler, scope)?;
+                    match self.apply(body, Vec::new()) {
+                        Ok(v) => v,
+                        // Errors raised by the interpreter itself don't
+                        // come with a condition.
+                        Err(message) => {
+                            let condition = self.raised.take().unwrap_or_else(|| {
+                                Value::Condition(Rc::new((
+                                    Value::Symbol(internal_error_type(&message).into()),
+                                    Value::from_list(message.chars().map(Value::Char)),
+                                    Value::Nil,
+                                )))
+                            });
+                            self.apply(handler, vec![condition])?
+                        }
+                    }
+                } else if let Some((body, cleanup)) = e.is_unwind_protect() {
+                    let body = self.eval(body, scope)?;
+                    let cleanup = self.eval(cleanup, scope)?;
+                    let res = self.apply(body, Vec::new());
+                    let raised = self.raised.take();
+                    self.apply(cleanup, Vec::new())?;
+                    self.raised = raised;
+                    res?
+                } else if let Some(body) = e.is_with_output_to_string() {
+                    let body = self.eval(body, scope)?;
+                    begin_capture();
+                    let res = self.apply(body, Vec::new());
+                    let output = end_capture();
+                    res?;
+                    Value::from_list(output.chars().map(Value::Char))
+                } else if e.is_foreign_call().is_some() {
+                    return Err("foreign calls are not supported by the interpreter".to_string());
+                } else if let Some((head, args)) = e.is_fncall() {
+                    let f = self.eval_one(head, scope)?;
+                    let args = args
+                        .iter()
+                        .map(|a| self.eval_one(a, scope))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    self.apply(f, args)?
+                } else {
+                    return Err(format!("illegal function application {:?}", e));
+                }
+            }
+        })
+    }
+
+    /// Applies the primitive NAME to ARGS. Primitives that call
+    /// functions need the interpreter and are handled here.
+    fn apply_builtin(&mut self, name: &str, args: Vec<Value<'a>>) -> Result<Value<'a>, String> {
+        match name {
+            "sort" => {
+                check_arg_count(&args, 2)?;
+                let mut args = args.into_iter();
+                let (list, less) = (args.next().unwrap(), args.next().unwrap());
+                if !matches!(less, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                let items = match apply_primitive("list->vector", vec![list])? {
+                    Value::Vector(v) => v.to_vec(),
+                    _ => unreachable!(),
+                };
+                let sorted = self.merge_sort(items, &less)?;
+                Ok(Value::from_list(sorted.into_iter()))
+            }
+            "foldr" => {
+                check_arg_count(&args, 3)?;
+                let mut args = args.into_iter();
+                let (f, init, list) = (
+                    args.next().unwrap(),
+                    args.next().unwrap(),
+                    args.next().unwrap(),
+                );
+                if !matches!(f, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                list_elements(list)?
+                    .into_iter()
+                    .rev()
+                    .try_fold(init, |acc, x| self.apply(f.clone(), vec![x, acc]))
+            }
+            "reduce" => {
+                check_arg_count(&args, 3)?;
+                let mut args = args.into_iter();
+                let (f, init, list) = (
+                    args.next().unwrap(),
+                    args.next().unwrap(),
+                    args.next().unwrap(),
+                );
+                if !matches!(f, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                list_elements(list)?
+                    .into_iter()
+                    .try_fold(init, |acc, x| self.apply(f.clone(), vec![acc, x]))
+            }
+            "vector-sort!" => {
+                check_arg_count(&args, 2)?;
+                let elements = match &args[0] {
+                    Value::Vector(v) => v.clone(),
+                    _ => return type_error(),
+                };
+                if !matches!(args[1], Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                self.heap_sort(&elements, &args[1])?;
+                Ok(args[0].clone())
+            }
+            "vector-map" => {
+                check_arg_count(&args, 2)?;
+                let mut args = args.into_iter();
+                let (f, vector) = (args.next().unwrap(), args.next().unwrap());
+                if !matches!(f, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                let elements = match vector {
+                    Value::Vector(v) => v.to_vec(),
+                    _ => return type_error(),
+                };
+                let results = elements
+                    .into_iter()
+                    .map(|x| self.apply(f.clone(), vec![x]))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Elements::vector(results))
+            }
+            "vector-for-each" | "vector-for-each-indexed" => {
+                check_arg_count(&args, 2)?;
+                let mut args = args.into_iter();
+                let (f, vector) = (args.next().unwrap(), args.next().unwrap());
+                if !matches!(f, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                let elements = match vector {
+                    Value::Vector(v) => v.to_vec(),
+                    _ => return type_error(),
+                };
+                for (i, x) in elements.into_iter().enumerate() {
+                    let args = if name == "vector-for-each" {
+                        vec![x]
+                    } else {
+                        vec![Value::Integer(i as i64), x]
+                    };
+                    self.apply(f.clone(), args)?;
+                }
+                Ok(Value::Nil)
+            }
+            "apply" => {
+                check_arg_count(&args, 2)?;
+                let mut args = args.into_iter();
+                let (f, list) = (args.next().unwrap(), args.next().unwrap());
+                if !matches!(f, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                self.apply(f, list_elements(list)?)
+            }
+            "heap-push" => {
+                check_arg_count(&args, 2)?;
+                let queue = match &args[0] {
+                    Value::Queue(q) => q.clone(),
+                    _ => return type_error(),
+                };
+                let mut i = {
+                    let mut elements = queue.elements.borrow_mut();
+                    elements.push(args[1].clone());
+                    elements.len() - 1
+                };
+                while i > 0 {
+                    let parent = (i - 1) / 2;
+                    if !self.queue_before(&queue, i, parent)? {
+                        break;
+                    }
+                    queue.elements.borrow_mut().swap(i, parent);
+                    i = parent;
+                }
+                Ok(args[0].clone())
+            }
+            "heap-pop" => {
+                check_arg_count(&args, 1)?;
+                let queue = match &args[0] {
+                    Value::Queue(q) => q.clone(),
+                    _ => return type_error(),
+                };
+                let (top, count) = {
+                    let mut elements = queue.elements.borrow_mut();
+                    if elements.is_empty() {
+                        return Err(internal_error_message("__anon_data_out_of_range").to_string());
+                    }
+                    (elements.swap_remove(0), elements.len())
+                };
+                let mut i = 0;
+                while 2 * i + 1 < count {
+                    let left = 2 * i + 1;
+                    let right = left + 1;
+                    let child = if right < count && self.queue_before(&queue, right, left)? {
+                        right
+                    } else {
+                        left
+                    };
+                    if !self.queue_before(&queue, child, i)? {
+                        break;
+                    }
+                    queue.elements.borrow_mut().swap(i, child);
+                    i = child;
+                }
+                Ok(top)
+            }
+            "for-each" => {
+                check_arg_count(&args, 2)?;
+                let mut args = args.into_iter();
+                let (f, list) = (args.next().unwrap(), args.next().unwrap());
+                if !matches!(f, Value::Closure(_) | Value::Primitive(_)) {
+                    return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+                }
+                for x in list_elements(list)? {
+                    self.apply(f.clone(), vec![x])?;
+                }
+                Ok(Value::Nil)
+            }
+            _ => apply_primitive(name, args),
+        }
+    }
+
+    /// Determines if element I of QUEUE belongs before element J.
+    fn queue_before(&mut self, queue: &Queue<'a>, i: usize, j: usize) -> Result<bool, String> {
+        let (x, y) = {
+            let elements = queue.elements.borrow();
+            (elements[i].clone(), elements[j].clone())
+        };
+        Ok(!self.apply(queue.less.clone(), vec![x, y])?.is_falsey())
+    }
+
+    /// Sorts ITEMS with the comparator LESS keeping equal items in
+    /// the order they were in.
+    fn merge_sort(
+        &mut self,
+        mut items: Vec<Value<'a>>,
+        less: &Value<'a>,
+    ) -> Result<Vec<Value<'a>>, String> {
+        if items.len() <= 1 {
+            return Ok(items);
+        }
+        let right = items.split_off(items.len() / 2);
+        let mut left = self.merge_sort(items, less)?.into_iter().peekable();
+        let mut right = self.merge_sort(right, less)?.into_iter().peekable();
+        let mut res = Vec::new();
+        while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+            let right_first = self.apply(less.clone(), vec![r.clone(), l.clone()])?;
+            if right_first.is_falsey() {
+                res.push(left.next().unwrap());
+            } else {
+                res.push(right.next().unwrap());
+            }
+        }
+        res.extend(left);
+        res.extend(right);
+        Ok(res)
+    }
+
+    /// Sorts ELEMENTS in place with the comparator LESS by making the
+    /// same calls to it that the compiled heapsort in `sort.rs` does.
+    fn heap_sort(&mut self, elements: &Elements<'a>, less: &Value<'a>) -> Result<(), String> {
+        for i in (0..elements.len / 2).rev() {
+            self.sift_down(elements, i, elements.len, less)?;
+        }
+        for end in (1..elements.len).rev() {
+            elements.swap(0, end);
+            self.sift_down(elements, 0, end, less)?;
+        }
+        Ok(())
+    }
+
+    fn sift_down(
+        &mut self,
+        elements: &Elements<'a>,
+        mut i: usize,
+        end: usize,
+        less: &Value<'a>,
+    ) -> Result<(), String> {
+        let mut before = |i: usize, j: usize| {
+            let args = vec![elements.get(i).unwrap(), elements.get(j).unwrap()];
+            Ok::<_, String>(!self.apply(less.clone(), args)?.is_falsey())
+        };
+        while 2 * i + 1 < end {
+            let left = 2 * i + 1;
+            let right = left + 1;
+            let child = if right < end && before(left, right)? {
+                right
+            } else {
+                left
+            };
+            if !before(i, child)? {
+                break;
+            }
+            elements.swap(i, child);
+            i = child;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, f: Value<'a>, mut args: Vec<Value<'a>>) -> Result<Value<'a>, String> {
+        let closure = match f {
+            Value::Closure(c) => c,
+            Value::Primitive(name) => return self.apply_builtin(name, args),
+            _ => {
+                return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+            }
+        };
+
+        let varadic = closure.params.iter().any(|p| is_varadic_param(p));
+        let required = if varadic {
+            closure.params.len() - 2
+        } else {
+            closure.params.len()
+        };
+        if args.len() < required || (!varadic && args.len() != required) {
+            return Err(internal_error_message("__anon_data_bad_arg_count").to_string());
+        }
+
+        let scope = Scope::new(Some(closure.scope.clone()));
+        {
+            let mut vars = scope.vars.borrow_mut();
+            let rest = args.split_off(required);
+            for (param, arg) in closure.params.iter().zip(args) {
+                vars.insert(param, arg);
+            }
+            if varadic {
+                vars.insert(
+                    closure.params[required + 1],
+                    Value::from_list(rest.into_iter()),
+                );
+            }
+        }
+        self.eval_body(closure.body, &scope)
+    }
+}
+
+fn values_eq<'a>(left: &Value<'a>, right: &Value<'a>) -> bool {
+    match (left, right) {
+        (Value::Integer(l), Value::Integer(r)) => l == r,
+        (Value::Char(l), Value::Char(r)) => l == r,
+        (Value::Bool(l), Value::Bool(r)) => l == r,
+        (Value::Nil, Value::Nil) => true,
+        (Value::Symbol(l), Value::Symbol(r)) => l == r,
+        (Value::Pair(l), Value::Pair(r)) => Rc::ptr_eq(l, r),
+        (Value::Closure(l), Value::Closure(r)) => Rc::ptr_eq(l, r),
+        (Value::Vector(l), Value::Vector(r)) => Rc::ptr_eq(l, r),
+        (Value::Queue(l), Value::Queue(r)) => Rc::ptr_eq(l, r),
+        (Value::Builder(l), Value::Builder(r)) => Rc::ptr_eq(l, r),
+        (Value::Record(l), Value::Record(r)) => Rc::ptr_eq(l, r),
+        _ => false,
+    }
+}
+
+/// Deep equality. See `lists.rs`.
+fn values_equal<'a>(left: &Value<'a>, right: &Value<'a>) -> bool {
+    let mut worklist = vec![(left.clone(), right.clone())];
+    while let Some((left, right)) = worklist.pop() {
+        if values_eq(&left, &right) {
+            continue;
+        }
+        match (&left, &right) {
+            (Value::Pair(l), Value::Pair(r)) => {
+                worklist.push((l.1.clone(), r.1.clone()));
+                worklist.push((l.0.clone(), r.0.clone()));
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Returns the elements of the proper list LIST.
+fn list_elements(list: Value) -> Result<Vec<Value>, String> {
+    let mut elements = Vec::new();
+    let mut list = list;
+    loop {
+        let next = match &list {
+            Value::Pair(p) => {
+                elements.push(p.0.clone());
+                p.1.clone()
+            }
+            Value::Nil => break,
+            _ => return type_error(),
+        };
+        list = next;
+    }
+    Ok(elements)
+}
+
+/// Hashes VAL so that values that are `equal` hash the same. See
+/// `lists.rs`. The hashes aren't the same as the compiled version's
+/// but they agree about which values share a hash.
+fn hash_value(val: &Value) -> i64 {
+    use crate::lists::{finish_hash, mix_hash, HASH_SEED};
+    use std::hash::{Hash, Hasher};
+
+    let mut h = HASH_SEED;
+    let mut worklist = vec![val.clone()];
+    while let Some(val) = worklist.pop() {
+        let x = match &val {
+            Value::Integer(i) => *i as u64,
+            Value::Char(c) => Expr::Char(*c).immediate_rep() as u64,
+            Value::Bool(b) => Expr::Bool(*b).immediate_rep() as u64,
+            Value::Nil => Expr::Nil.immediate_rep() as u64,
+            Value::Symbol(s) => {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                s.hash(&mut hasher);
+                hasher.finish()
+            }
+            Value::Pair(p) => {
+                worklist.push(p.1.clone());
+                worklist.push(p.0.clone());
+                1
+            }
+            Value::Closure(c) => Rc::as_ptr(c) as usize as u64,
+            Value::Primitive(p) => p.as_ptr() as usize as u64,
+            Value::Values(v) => Rc::as_ptr(v) as usize as u64,
+            Value::Condition(c) => Rc::as_ptr(c) as usize as u64,
+            Value::Vector(v) => Rc::as_ptr(v) as usize as u64,
+            Value::Queue(q) => Rc::as_ptr(q) as usize as u64,
+            Value::Builder(b) => Rc::as_ptr(b) as usize as u64,
+            Value::Record(r) => Rc::as_ptr(r) as usize as u64,
+        };
+        h = mix_hash(h, x);
+    }
+    finish_hash(h)
+}
+
+fn apply_primitive<'a>(name: &str, args: Vec<Value<'a>>) -> Result<Value<'a>, String> {
+    Ok(match name {
+        "add" | "sub" | "mul" | "div" | "min" | "max" => {
+            let args = args
+                .iter()
+                .map(|a| expect_int(a).map(Expr::Integer))
+                .collect::<Result<Vec<_>, _>>()?;
+            match crate::fold::fold_arithmetic(name, &args) {
+                Some(Expr::Integer(i)) => Value::Integer(i),
+                _ if args.is_empty() => {
+                    return Err(internal_error_message("__anon_data_bad_arg_count").to_string())
+                }
+                _ => return Err(internal_error_message("__anon_data_div_by_zero_div").to_string()),
+            }
+        }
+        "mod" | "rem" => {
+            check_arg_count(&args, 2)?;
+            let (left, right) = (expect_int(&args[0])?, expect_int(&args[1])?);
+            match crate::fold::fold_remainder(name, left, right) {
+                Some(i) => Value::Integer(i),
+                None => {
+                    return Err(internal_error_message(&format!(
+                        "__anon_data_div_by_zero_{}",
+                        name
+                    ))
+                    .to_string())
+                }
+            }
+        }
+        "expt" => {
+            check_arg_count(&args, 2)?;
+            let (base, exp) = (expect_int(&args[0])?, expect_int(&args[1])?);
+            match crate::fold::fold_expt(base, exp) {
+                Some(i) => Value::Integer(i),
+                None => {
+                    return Err(internal_error_message("__anon_data_negative_exponent").to_string())
+                }
+            }
+        }
+        "newline" => {
+            check_arg_count(&args, 0)?;
+            write_output("\n");
+            Value::Nil
+        }
+        "flush-output" => {
+            check_arg_count(&args, 0)?;
+            crate::output::flush_output();
+            Value::Nil
+        }
+        "command-line-args" => {
+            check_arg_count(&args, 0)?;
+            Value::from_list(
+                crate::environment::program_args()
+                    .iter()
+                    .map(|a| Value::from_list(a.chars().map(Value::Char))),
+            )
+        }
+        // The interpreter allocates with Rust so there is nothing to
+        // report.
+        "heap-stats" => return Err("heap-stats is not supported by the interpreter".to_string()),
+        // Nothing is compiled so there is no machine code to show.
+        "disassemble" => return Err("disassemble is not supported by the interpreter".to_string()),
+        "make-condition" => {
+            check_arg_count(&args, 3)?;
+            let mut args = args.into_iter();
+            let (kind, message, data) = (
+                args.next().unwrap(),
+                args.next().unwrap(),
+                args.next().unwrap(),
+            );
+            if !matches!(kind, Value::Symbol(_)) {
+                return type_error();
+            }
+            Value::Condition(Rc::new((kind, message, data)))
+        }
+        "list" => Value::from_list(args.into_iter()),
+        "make-heap" => {
+            check_arg_count(&args, 1)?;
+            let less = args.into_iter().next().unwrap();
+            if !matches!(less, Value::Closure(_) | Value::Primitive(_)) {
+                return Err(internal_error_message("__anon_data_bad_call_type").to_string());
+            }
+            Value::Queue(Rc::new(Queue {
+                less,
+                elements: RefCell::new(Vec::new()),
+            }))
+        }
+        "make-string-builder" => {
+            check_arg_count(&args, 0)?;
+            Value::Builder(Rc::new(RefCell::new(Vec::new())))
+        }
+        "sb-append!" => {
+            check_arg_count(&args, 2)?;
+            let builder = match &args[0] {
+                Value::Builder(b) => b.clone(),
+                _ => return type_error(),
+            };
+            let mut rest = args[1].clone();
+            loop {
+                rest = match rest {
+                    Value::Pair(p) => match p.0 {
+                        Value::Char(c) => {
+                            builder.borrow_mut().push(c);
+                            p.1.clone()
+                        }
+                        _ => return type_error(),
+                    },
+                    Value::Nil => break,
+                    _ => return type_error(),
+                };
+            }
+            args[0].clone()
+        }
+        "make-vector" => {
+            if args.len() != 1 {
+                check_arg_count(&args, 2)?;
+            }
+            let n = expect_int(&args[0])?;
+            if n < 0 {
+                return Err(internal_error_message("__anon_data_out_of_range").to_string());
+            }
+            let fill = args.get(1).cloned().unwrap_or(Value::Nil);
+            Elements::vector(vec![fill; n as usize])
+        }
+        "vector-set!" => {
+            check_arg_count(&args, 3)?;
+            let i = expect_int(&args[1])?;
+            match &args[0] {
+                Value::Vector(v) if i >= 0 && (i as usize) < v.len => v.set(i as usize, &args[2]),
+                Value::Vector(_) => {
+                    return Err(internal_error_message("__anon_data_out_of_range").to_string())
+                }
+                _ => return type_error(),
+            }
+            args[0].clone()
+        }
+        "vector-fill!" => {
+            check_arg_count(&args, 2)?;
+            match &args[0] {
+                Value::Vector(v) => v.fill(&args[1]),
+                _ => return type_error(),
+            }
+            args[0].clone()
+        }
+        "vector-ref" => {
+            check_arg_count(&args, 2)?;
+            let i = expect_int(&args[1])?;
+            match &args[0] {
+                Value::Vector(v) => match v.get(i as usize).filter(|_| i >= 0) {
+                    Some(x) => x,
+                    None => {
+                        return Err(internal_error_message("__anon_data_out_of_range").to_string())
+                    }
+                },
+                _ => return type_error(),
+            }
+        }
+        "string-slice" => {
+            check_arg_count(&args, 3)?;
+            let mut args = args.into_iter();
+            let (s, start, end) = (
+                args.next().unwrap(),
+                args.next().unwrap(),
+                args.next().unwrap(),
+            );
+            let length = Value::Integer(expect_int(&end)? - expect_int(&start)?);
+            let rest = apply_primitive("list-tail", vec![s, start])?;
+            match apply_primitive("list-tail", vec![rest.clone(), length.clone()])? {
+                Value::Nil => rest,
+                _ => apply_primitive("take", vec![rest, length])?,
+            }
+        }
+        "vector-slice" => {
+            check_arg_count(&args, 3)?;
+            let (start, end) = (expect_int(&args[1])?, expect_int(&args[2])?);
+            match &args[0] {
+                Value::Vector(v) => match v.slice(start, end) {
+                    Some(slice) => slice,
+                    None => {
+                        return Err(internal_error_message("__anon_data_out_of_range").to_string())
+                    }
+                },
+                _ => return type_error(),
+            }
+        }
+        "make-record" => {
+            check_arg_count(&args, 2)?;
+            if !matches!(args[0], Value::Symbol(_)) || !matches!(args[1], Value::Vector(_)) {
+                return type_error();
+            }
+            let mut args = args.into_iter();
+            Value::Record(Rc::new((args.next().unwrap(), args.next().unwrap())))
+        }
+        "record-ref" => {
+            check_arg_count(&args, 3)?;
+            let i = expect_int(&args[2])?;
+            match &args[0] {
+                Value::Record(r) if values_eq(&r.0, &args[1]) => match &r.1 {
+                    Value::Vector(v) => match v.get(i as usize).filter(|_| i >= 0) {
+                        Some(x) => x,
+                        None => {
+                            return Err(
+                                internal_error_message("__anon_data_out_of_range").to_string()
+                            )
+                        }
+                    },
+                    _ => return type_error(),
+                },
+                _ => return type_error(),
+            }
+        }
+        "list-tail" | "take" => {
+            check_arg_count(&args, 2)?;
+            let n = expect_int(&args[1])?;
+            if n < 0 {
+                return Err(internal_error_message("__anon_data_out_of_range").to_string());
+            }
+            let mut rest = args[0].clone();
+            let mut taken = Vec::new();
+            for _ in 0..n {
+                let next = match &rest {
+                    Value::Pair(p) => {
+                        taken.push(p.0.clone());
+                        p.1.clone()
+                    }
+                    Value::Nil => {
+                        return Err(internal_error_message("__anon_data_out_of_range").to_string())
+                    }
+                    _ => return type_error(),
+                };
+                rest = next;
+            }
+            if name == "take" {
+                Value::from_list(taken.into_iter())
+            } else {
+                rest
+            }
+        }
+        "make-list" | "iota" => {
+            let (min, max) = crate::generators::generator_primitive_arity(name);
+            if args.len() < min || args.len() > max {
+                return Err(internal_error_message("__anon_data_bad_arg_count").to_string());
+            }
+            let n = expect_int(&args[0])?;
+            if n < 0 {
+                return Err(internal_error_message("__anon_data_out_of_range").to_string());
+            }
+            if name == "make-list" {
+                Value::from_list((0..n).map(|_| args[1].clone()))
+            } else {
+                let start = args.get(1).map(expect_int).transpose()?.unwrap_or(0);
+                let step = args.get(2).map(expect_int).transpose()?.unwrap_or(1);
+                Value::from_list(
+                    (0..n).map(|i| Value::Integer(start.wrapping_add(i.wrapping_mul(step)))),
+                )
+            }
+        }
+        "string-ref" => {
+            check_arg_count(&args, 2)?;
+            let i = expect_int(&args[1])?;
+            let chars = list_elements(args[0].clone())?;
+            if !chars.iter().all(|c| matches!(c, Value::Char(_))) {
+                return type_error();
+            }
+            if i >= 0 && (i as usize) < chars.len() {
+                chars[i as usize].clone()
+            } else {
+                return Err(internal_error_message("__anon_data_out_of_range").to_string());
+            }
+        }
+        "string-split" => {
+            check_arg_count(&args, 2)?;
+            match crate::strings::string_split(&args[0].to_expr(), &args[1].to_expr()) {
+                Some(pieces) => Value::from_list(
+                    pieces
+                        .iter()
+                        .map(|p| Value::from_list(p.chars().map(Value::Char))),
+                ),
+                None => return type_error(),
+            }
+        }
+        "string-join" => {
+            check_arg_count(&args, 2)?;
+            match crate::strings::string_join(&args[0].to_expr(), &args[1].to_expr()) {
+                Some(s) => Value::from_list(s.chars().map(Value::Char)),
+                None => return type_error(),
+            }
+        }
+        "assert-equal" => {
+            check_arg_count(&args, 2)?;
+            let equal = values_equal(&args[0], &args[1]);
+            crate::testing::record_assertion(&args[0].to_expr(), &args[1].to_expr(), equal);
+            Value::Bool(equal)
+        }
+        "string-index" | "string-contains" => {
+            check_arg_count(&args, 2)?;
+            match crate::strings::string_index(&args[0].to_expr(), &args[1].to_expr()) {
+                Some(i) if name == "string-contains" => Value::Bool(i.is_some()),
+                Some(Some(i)) => Value::Integer(i as i64),
+                Some(None) => Value::Nil,
+                None => return type_error(),
+            }
+        }
+        "string-upcase" | "string-downcase" | "string-trim" => {
+            check_arg_count(&args, 1)?;
+            match crate::strings::string_transform(name, &args[0].to_expr()) {
+                Some(s) => Value::from_list(s.chars().map(Value::Char)),
+                None => return type_error(),
+            }
+        }
+        "string-append" => {
+            check_arg_count(&args, 2)?;
+            let mut args = args.into_iter();
+            let (a, b) = (args.next().unwrap(), args.next().unwrap());
+            list_elements(a)?
+                .into_iter()
+                .rev()
+                .fold(b, |cdr, car| Value::cons(car, cdr))
+        }
+        "eq" | "neq" | "equal" | "member" | "memq" | "assoc" | "assq" | "lt" | "gt" | "cons" => {
+            check_arg_count(&args, 2)?;
+            let mut args = args.into_iter();
+            let (left, right) = (args.next().unwrap(), args.next().unwrap());
+            match name {
+                "eq" => Value::Bool(values_eq(&left, &right)),
+                "neq" => Value::Bool(!values_eq(&left, &right)),
+                "equal" => Value::Bool(values_equal(&left, &right)),
+                "member" | "memq" | "assoc" | "assq" => {
+                    let same = if name == "member" || name == "assoc" {
+                        values_equal
+                    } else {
+                        values_eq
+                    };
+                    let by_key = name == "assoc" || name == "assq";
+                    let mut list = right;
+                    loop {
+                        let next = match &list {
+                            Value::Pair(p) => match &p.0 {
+                                Value::Pair(entry) if by_key && same(&left, &entry.0) => {
+                                    break p.0.clone()
+                                }
+                                x if !by_key && same(&left, x) => break list,
+                                _ => p.1.clone(),
+                            },
+                            _ => break Value::Nil,
+                        };
+                        list = next;
+                    }
+                }
+                "lt" => Value::Bool(expect_int(&left)? < expect_int(&right)?),
+                "gt" => Value::Bool(expect_int(&left)? > expect_int(&right)?),
+                _ => Value::cons(left, right),
+            }
+        }
+        _ => {
+            check_arg_count(&args, 1)?;
+            let arg = args.into_iter().next().unwrap();
+            match name {
+                "add1" => Value::Integer(crate::conversions::wrap_fixnum(
+                    expect_int(&arg)?.wrapping_add(1),
+                )),
+                "abs" => Value::Integer(crate::conversions::wrap_fixnum(
+                    expect_int(&arg)?.wrapping_abs(),
+                )),
+                "identity" => arg,
+                "print" => {
+                    write_output(&arg.to_expr().to_string());
+                    Value::Nil
+                }
+                "println" => {
+                    write_output(&format!("{}\n", arg.to_expr()));
+                    Value::Nil
+                }
+                "display" => {
+                    write_output(&display_string(&arg.to_expr()));
+                    Value::Nil
+                }
+                "integer->char" => match std::char::from_u32(expect_int(&arg)? as u32) {
+                    Some(c) => Value::Char(c),
+                    None => return type_error(),
+                },
+                name if crate::chars::CHAR_PREDICATES.contains(&name) => match arg {
+                    Value::Char(c) => Value::Bool(crate::chars::char_is(name, c)),
+                    _ => return type_error(),
+                },
+                "char->integer" => match arg {
+                    Value::Char(c) => Value::Integer(c as i64),
+                    _ => return type_error(),
+                },
+                "getenv" => match try_stringify_list(&arg.to_expr())
+                    .and_then(|name| crate::environment::getenv(&name))
+                {
+                    Some(val) => Value::from_list(val.chars().map(Value::Char)),
+                    None => Value::Nil,
+                },
+                "null?" => Value::Bool(matches!(arg, Value::Nil)),
+                "zero?" => Value::Bool(matches!(arg, Value::Integer(0))),
+                "vector?" => Value::Bool(matches!(arg, Value::Vector(_))),
+                "vector-length" => match arg {
+                    Value::Vector(v) => Value::Integer(v.len as i64),
+                    _ => return type_error(),
+                },
+                "vector->list" => match arg {
+                    Value::Vector(v) => Value::from_list(v.to_vec().into_iter()),
+                    _ => return type_error(),
+                },
+                "list->vector" => Elements::vector(list_elements(arg)?),
+                "heap-peek" => match arg {
+                    Value::Queue(q) => match q.elements.borrow().first() {
+                        Some(x) => x.clone(),
+                        None => {
+                            return Err(
+                                internal_error_message("__anon_data_out_of_range").to_string()
+                            )
+                        }
+                    },
+                    _ => return type_error(),
+                },
+                "sb->string" => match arg {
+                    Value::Builder(b) => {
+                        Value::from_list(b.borrow().iter().map(|c| Value::Char(*c)))
+                    }
+                    _ => return type_error(),
+                },
+                "heap-size" => match arg {
+                    Value::Queue(q) => Value::Integer(q.elements.borrow().len() as i64),
+                    _ => return type_error(),
+                },
+                "vector-copy" => match arg {
+                    Value::Vector(v) => Elements::vector(v.to_vec()),
+                    _ => return type_error(),
+                },
+                "hash" => Value::Integer(hash_value(&arg)),
+                "type-of" => Value::Symbol(
+                    match arg {
+                        Value::Integer(_) => "integer",
+                        Value::Char(_) => "char",
+                        Value::Bool(_) => "bool",
+                        Value::Nil => "nil",
+                        Value::Symbol(_) => "symbol",
+                        Value::Pair(_) => "pair",
+                        Value::Closure(_) | Value::Primitive(_) => "closure",
+                        Value::Values(_) => "values",
+                        Value::Condition(_) => "condition",
+                        Value::Vector(_) => "vector",
+                        Value::Queue(_) => "heap",
+                        Value::Builder(_) => "string-builder",
+                        Value::Record(_) => "record",
+                    }
+                    .into(),
+                ),
+                "write" => Value::from_list(
+                    crate::serialize::write_string(&arg.to_expr())
+                        .chars()
+                        .map(Value::Char),
+                ),
+                "read" => match crate::strings::expr_to_string(&arg.to_expr()) {
+                    Some(s) => match crate::serialize::read_string(&s) {
+                        Some(e) => Value::from_data(&e)?,
+                        None => {
+                            return Err(internal_error_message("__anon_data_bad_read").to_string())
+                        }
+                    },
+                    None => return type_error(),
+                },
+                "length" => Value::Integer(list_elements(arg)?.len() as i64),
+                "last" => {
+                    let mut pair = match arg {
+                        Value::Pair(p) => p,
+                        Value::Nil => {
+                            return Err(
+                                internal_error_message("__anon_data_out_of_range").to_string()
+                            )
+                        }
+                        _ => return type_error(),
+                    };
+                    while let Value::Pair(next) = &pair.1 {
+                        let next = next.clone();
+                        pair = next;
+                    }
+                    pair.0.clone()
+                }
+                "list-copy" => {
+                    let mut elements = Vec::new();
+                    let mut rest = arg;
+                    while let Value::Pair(p) = rest {
+                        elements.push(p.0.clone());
+                        rest = p.1.clone();
+                    }
+                    elements
+                        .into_iter()
+                        .rev()
+                        .fold(rest, |cdr, car| Value::cons(car, cdr))
+                }
+                "string-length" => {
+                    let chars = list_elements(arg)?;
+                    if !chars.iter().all(|c| matches!(c, Value::Char(_))) {
+                        return type_error();
+                    }
+                    Value::Integer(chars.len() as i64)
+                }
+                "positive?" | "negative?" | "even?" | "odd?" => {
+                    Value::Bool(crate::fold::numeric_predicate(name, expect_int(&arg)?))
+                }
+                "not" => Value::Bool(arg.is_falsey()),
+                "boolean?" => Value::Bool(matches!(arg, Value::Bool(_))),
+                "integer?" => Value::Bool(matches!(arg, Value::Integer(_))),
+                "pair?" => Value::Bool(matches!(arg, Value::Pair(_))),
+                "atom?" => Value::Bool(!matches!(arg, Value::Pair(_))),
+                "closure?" => Value::Bool(matches!(arg, Value::Closure(_) | Value::Primitive(_))),
+                "car" => match arg {
+                    Value::Pair(p) => p.0.clone(),
+                    _ => return type_error(),
+                },
+                "condition?" => Value::Bool(matches!(arg, Value::Condition(_))),
+                "record-type" => match arg {
+                    Value::Record(r) => r.0.clone(),
+                    _ => Value::Nil,
+                },
+                "condition-type" | "condition-message" | "condition-data" => match arg {
+                    Value::Condition(c) => match name {
+                        "condition-type" => c.0.clone(),
+                        "condition-message" => c.1.clone(),
+                        _ => c.2.clone(),
+                    },
+                    _ => return type_error(),
+                },
+                "cdr" => match arg {
+                    Value::Pair(p) => p.1.clone(),
+                    _ => return type_error(),
+                },
+                _ => return Err(format!("interpreter does not support primitive ({})", name)),
+            }
+        }
+    })
+}
+
+/// Interprets PROGRAM and returns the value of its last expression.
+/// Runtime errors that would cause the compiled version of the
+/// program to exit are returned as errors.
+pub fn interpret(program: &[Expr]) -> Result<Expr, String> {
+    let _t = crate::timer::timeit("interpretation");
+    let mut program = crate::desugar::expand_records(program)?.unwrap_or_else(|| program.to_vec());
+    crate::desugar::desugar(&mut program)?;
+    crate::renamer::make_names_unique(&mut program)?;
+
+    let mut interpreter = Interpreter {
+        data: HashMap::new(),
+        raised: None,
+    };
+    // An empty program returns nil like a compiled one does.
+    if program.is_empty() {
+        return Ok(Expr::Nil);
+    }
+    let scope = Scope::new(None);
+    let res = interpreter.eval_body(&program, &scope)?;
+    Ok(res.to_expr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+    use crate::roundtrip_string;
+
+    fn interpret_string(source: &str) -> Result<Expr, String> {
+        interpret(&parse_string(source)?)
+    }
+
+    /// Checks that the interpreter and the compiler agree on the
+    /// result of running SOURCE.
+    fn assert_agree(source: &str) {
+        assert_eq!(interpret_string(source), roundtrip_string(source))
+    }
+
+    #[test]
+    fn agrees_with_compiler() {
+        assert_agree("(add 1 (mul 2 3))");
+        assert_agree("(let foo 1) (cons (eq (quote foo) (quote foo)) (quote (foo 5)))");
+        assert_agree("(- 10 1 2)");
+        assert_agree("(cons (mod (- 7) 2) (rem (- 7) 2))");
+        assert_agree("(if (lt 1 2) (quote (1 2)) 3)");
+        assert_agree("(if () 1 2)");
+        assert_agree("(cons (not ()) (cons #\\a \"hi\"))");
+        assert_agree(
+            r#"
+(let count 0)
+(let inc (fn () (set count (add1 count))))
+(inc)
+(inc)
+count
+"#,
+        );
+        assert_agree(
+            r#"
+(let list (fn (& items) items))
+(let map (fn (f l) (if (null? l) () (cons (f (car l)) (map f (cdr l))))))
+(map add1 (list 1 2 3))
+"#,
+        );
+    }
+
+    #[test]
+    fn agrees_with_compiler_on_files() {
+        for file in [
+            "examples/data.lisp",
+            "examples/data-eq.lisp",
+            "examples/closure.lisp",
+            "examples/primitives.lisp",
+            "examples/set.lisp",
+            "examples/counter.lisp",
+        ] {
+            let source = std::fs::read_to_string(file).unwrap();
+            assert_agree(&source);
+        }
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(
+            interpret_string("(add 1 (quote (1)))"),
+            Err("fatal error: runtime type missmatch".to_string())
+        );
+        assert_eq!(
+            interpret_string("(error \"oops\" 42)"),
+            Err("oops".to_string())
+        );
+    }
+}
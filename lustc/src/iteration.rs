@@ -0,0 +1,256 @@
+//! Primitives that call a function on every element of a list.
+//!
+//! `(foldr f init list)` combines the elements of LIST with F from
+//! the right, so `(foldr f init (quote (1 2)))` is `(f 1 (f 2 init))`,
+//! `(reduce f init list)` combines them from the left, so
+//! `(reduce f init (quote (1 2)))` is `(f (f init 1) 2)`, and
+//! `(for-each f list)` calls F on each element of LIST from first to
+//! last for its side effects and returns nil.
+//!
+//! ```lisp
+//! (foldr cons () (quote (1 2 3)))                       ; => (1 2 3)
+//! (reduce sub 10 (quote (1 2 3)))                       ; => 4
+//! (for-each (fn (x) (set sum (add sum x))) (quote (1 2))) ; => ()
+//! ```
+//!
+//! A right fold has to start at the end of the list. Rather than
+//! recursing down the list, which would need a stack frame for each
+//! element, foldr copies the list into a vector and walks that
+//! backwards. Both call F like any other closure so errors it raises
+//! unwind through them.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::HEAP_PTR_MASK;
+use crate::procedures::emit_closure_call;
+use crate::vectors::{
+    emit_check_nil, emit_element_address, emit_is_pair, emit_list_to_vector, emit_pair_parts,
+};
+use crate::Expr;
+
+/// Emits the code for `(foldr f init list)` where every argument has
+/// already been evaluated.
+pub(crate) fn emit_foldr(
+    f: Value,
+    init: Value,
+    list: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    crate::fatal::emit_check_closure(f, ctx)?;
+
+    let vector = emit_list_to_vector(list, ctx)?;
+    let ptr = ctx.builder.ins().band_imm(vector, HEAP_PTR_MASK);
+    let length = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+
+    let word_size = ctx.word.bytes();
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * word_size,
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+    let argc = ctx.builder.ins().iconst(ctx.word, 2);
+
+    let fold_block = ctx.builder.create_block();
+    let fold_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(fold_block, ctx.word);
+    ctx.builder.append_block_param(fold_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.ins().jump(fold_block, &[length, init]);
+
+    ctx.builder.switch_to_block(fold_block);
+    let i = ctx.builder.block_params(fold_block)[0];
+    let acc = ctx.builder.block_params(fold_block)[1];
+    ctx.builder.ins().brz(i, done_block, &[acc]);
+    ctx.builder.ins().jump(fold_body, &[]);
+
+    ctx.builder.switch_to_block(fold_body);
+    ctx.builder.seal_block(fold_body);
+    let i = ctx.builder.ins().iadd_imm(i, -1);
+    let address = emit_element_address(ptr, i, ctx);
+    let element = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), address, 0);
+    ctx.builder.ins().store(MemFlags::new(), element, argloc, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), acc, argloc, word_size as i32);
+    let acc = emit_closure_call(f, argc, argloc, ctx)?;
+    ctx.builder.ins().jump(fold_block, &[i, acc]);
+    ctx.builder.seal_block(fold_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+/// Emits the code for `(reduce f init list)` where every argument has
+/// already been evaluated.
+pub(crate) fn emit_reduce(
+    f: Value,
+    init: Value,
+    list: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    crate::fatal::emit_check_closure(f, ctx)?;
+
+    let word_size = ctx.word.bytes();
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * word_size,
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+    let argc = ctx.builder.ins().iconst(ctx.word, 2);
+
+    let walk_block = ctx.builder.create_block();
+    let walk_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.ins().jump(walk_block, &[list, init]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let acc = ctx.builder.block_params(walk_block)[1];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, done_block, &[]);
+    ctx.builder.ins().jump(walk_body, &[]);
+
+    ctx.builder.switch_to_block(walk_body);
+    ctx.builder.seal_block(walk_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    ctx.builder.ins().store(MemFlags::new(), acc, argloc, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), car, argloc, word_size as i32);
+    let next = emit_closure_call(f, argc, argloc, ctx)?;
+    ctx.builder.ins().jump(walk_block, &[cdr, next]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    emit_check_nil(rest, ctx)?;
+    Ok(acc)
+}
+
+/// Emits the code for `(for-each f list)` where both arguments have
+/// already been evaluated.
+pub(crate) fn emit_for_each(f: Value, list: Value, ctx: &mut Context) -> Result<Value, String> {
+    crate::fatal::emit_check_closure(f, ctx)?;
+
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        ctx.word.bytes(),
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+    let argc = ctx.builder.ins().iconst(ctx.word, 1);
+
+    let walk_block = ctx.builder.create_block();
+    let walk_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.ins().jump(walk_block, &[list]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, done_block, &[]);
+    ctx.builder.ins().jump(walk_body, &[]);
+
+    ctx.builder.switch_to_block(walk_body);
+    ctx.builder.seal_block(walk_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    ctx.builder.ins().store(MemFlags::new(), car, argloc, 0);
+    emit_closure_call(f, argc, argloc, ctx)?;
+    ctx.builder.ins().jump(walk_block, &[cdr]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    emit_check_nil(rest, ctx)?;
+    Ok(ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roundtrip_string;
+    use crate::test_util::{check, error_kind};
+
+    #[test]
+    fn foldr() {
+        check("(foldr cons () (quote (1 2 3)))", "(quote (1 2 3))");
+        check("(foldr sub 0 (quote (1 2 3)))", "2");
+        check("(foldr add 5 ())", "5");
+
+        // Long lists don't use up the stack.
+        let source = r#"
+(let range (fn (n acc) (if (eq n 0) acc (range (sub n 1) (cons n acc)))))
+(foldr add 0 (range 200000 ()))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            crate::Expr::Integer(20000100000)
+        );
+    }
+
+    #[test]
+    fn reduce() {
+        check("(reduce + 0 (list 1 2 3))", "6");
+        check("(reduce sub 10 (quote (1 2 3)))", "4");
+        check(
+            "(reduce (fn (acc x) (cons x acc)) () (list 1 2 3))",
+            "(quote (3 2 1))",
+        );
+        check("(reduce add 5 ())", "5");
+        check("(let f reduce) (f * 1 (list 2 3 4))", "24");
+    }
+
+    #[test]
+    fn variadic_primitives_as_values() {
+        check("(list 1 (add 1 1) 3)", "(quote (1 2 3))");
+        check("(list)", "()");
+        check("(apply + (list 1 2 3))", "6");
+        check("(apply list (list 1 2))", "(quote (1 2))");
+        check("(let f list) (cons (f) (f 1 2))", "(cons () (quote (1 2)))");
+        check(
+            "(foldr (fn (x acc) (cons (apply list (list x x)) acc)) () (list 1 2))",
+            "(quote ((1 1) (2 2)))",
+        );
+    }
+
+    #[test]
+    fn for_each() {
+        check(
+            r#"
+(let seen ())
+(let res (for-each (fn (x) (set seen (cons x seen))) (quote (1 2 3))))
+(cons res seen)
+"#,
+            "(cons () (quote (3 2 1)))",
+        );
+        check("(let f for-each) (f car ())", "()");
+    }
+
+    #[test]
+    fn errors() {
+        for (source, kind) in [
+            ("(for-each 1 (quote (1)))", "bad-call"),
+            ("(foldr 1 () (quote (1)))", "bad-call"),
+            ("(reduce 1 0 (quote (1)))", "bad-call"),
+            ("(reduce add 0 1)", "type-error"),
+            ("(for-each car 1)", "type-error"),
+            ("(foldr cons () 1)", "type-error"),
+            (
+                "(for-each (fn (x) (error (quote bad) \"no\" x)) (quote (1)))",
+                "bad",
+            ),
+        ] {
+            assert_eq!(error_kind(source), kind, "{}", source);
+        }
+    }
+}
@@ -1,32 +1,75 @@
+pub mod arity;
+pub mod asm;
+pub mod builder;
+pub mod chars;
+pub mod chunks;
 pub mod compiler;
 pub mod conditional;
+pub mod conditions;
 pub mod conversions;
+pub mod coverage;
 pub mod data;
+pub mod desugar;
+pub mod diagnostics;
+pub mod environment;
 pub mod errors;
 pub mod escape;
+pub mod exceptions;
 pub mod fatal;
+pub mod fold;
 pub mod foreign;
+pub mod forward;
+pub mod generators;
+pub mod globals;
+pub mod guards;
 pub mod heap;
+pub mod interpreter;
+pub mod iteration;
+pub mod lists;
 pub mod locals;
 pub mod location;
+pub mod output;
+pub mod packed;
+pub mod parameters;
 pub mod parser;
 pub mod primitives;
+pub mod priority;
 pub mod procedures;
+pub mod quasiquote;
 pub mod reader;
+pub mod records;
 pub mod renamer;
+pub mod serialize;
+pub mod shadow;
+pub mod sort;
+pub mod sourcemap;
+pub mod stack;
+pub mod stringbuilder;
+pub mod strings;
+pub mod sublists;
+pub mod symbols;
+pub mod tail;
+#[cfg(test)]
+mod test_util;
+pub mod testing;
 pub mod timer;
 pub mod tokenbuffer;
 pub mod tokenizer;
+pub mod unused;
+pub mod values;
+pub mod vectors;
 
 use crate::errors::Printable;
 use crate::parser::ExprVal;
 use crate::parser::Parser;
 
+pub use crate::parser::ParseError;
+
 pub(crate) type Word = i64;
 pub(crate) type UWord = u64;
 
 /// An expression as understood by the Lust compiler.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Expr {
     Integer(i64),
     Char(char),
@@ -35,6 +78,9 @@ pub enum Expr {
     List(Vec<Expr>),
     Symbol(String),
     String(String),
+    /// A closure returned by a program. Procedures have no literal
+    /// form so this is only ever the result of running one.
+    Procedure,
 }
 
 impl crate::parser::Expr {
@@ -164,10 +210,21 @@ impl Expr {
     }
 }
 
-/// Parses a string into a list of expressions.
-pub fn parse_string(input: &str) -> Result<Vec<Expr>, String> {
+/// Parses a string into a list of expressions. Every parse error is
+/// shown along with the source it is in and the first one is
+/// returned.
+pub fn parse_string(input: &str) -> Result<Vec<Expr>, ParseError> {
+    parse_string_with_locations(input).map(|(exprs, _)| exprs)
+}
+
+/// Parses a string into a list of expressions and the locations of
+/// each of them in the source.
+pub fn parse_string_with_locations(
+    input: &str,
+) -> Result<(Vec<Expr>, Vec<location::Location>), ParseError> {
     let mut parser = Parser::new(input);
     let mut exprs = Vec::new();
+    let mut locations = Vec::new();
     {
         let _t = crate::timer::timeit("parse");
         while parser.has_more() {
@@ -176,17 +233,74 @@ pub fn parse_string(input: &str) -> Result<Vec<Expr>, String> {
             for e in &res.errors {
                 e.show(input, "anonymous");
             }
-            if res.errors.is_empty() {
-                let expr = res.expr.unwrap();
-                exprs.push(expr.into_expr()?);
-            } else {
-                return Err("parse error!".to_string());
+            match res.errors.into_iter().next() {
+                Some(e) => return Err(e.kind),
+                None => push_top_level(res.expr.unwrap(), &mut exprs, &mut locations)?,
             }
         }
     }
+    Ok((exprs, locations))
+}
+
+/// Parses the program that R reads into a list of expressions. R is
+/// read a little at a time as the parser needs it so its source is
+/// never held in memory all at once. The expressions are the same as
+/// those that `parse_string` makes from the same bytes.
+///
+/// As there is no source to show, parse errors are only returned.
+pub fn parse_reader(r: impl std::io::Read) -> Result<Vec<Expr>, ParseError> {
+    let chars = reader::StreamChars::new(r);
+    let stream_error = chars.error.clone();
+    let mut parser = Parser::from_chars(chars);
+    let mut exprs = Vec::new();
+    let mut locations = Vec::new();
+    let _t = crate::timer::timeit("parse");
+    while parser.has_more() {
+        let res = parser.parse_expr();
+        if let Some(e) = stream_error.borrow_mut().take() {
+            return Err(ParseError::Read { what: e });
+        }
+        if let Some(e) = res.errors.into_iter().next() {
+            return Err(e.kind);
+        }
+        push_top_level(res.expr.unwrap(), &mut exprs, &mut locations)?;
+    }
+    if let Some(e) = stream_error.borrow_mut().take() {
+        return Err(ParseError::Read { what: e });
+    }
     Ok(exprs)
 }
 
+/// Adds a top level expression that has been parsed and its location
+/// to the end of EXPRS and LOCATIONS.
+fn push_top_level(
+    expr: parser::Expr,
+    exprs: &mut Vec<Expr>,
+    locations: &mut Vec<location::Location>,
+) -> Result<(), ParseError> {
+    let loc = expr.loc.clone();
+    let invalid = |what| ParseError::InvalidForm {
+        what,
+        at: loc.clone(),
+    };
+    let expr = expr.into_expr().map_err(invalid)?;
+    // A record definition is several top level forms that all come
+    // from the same place.
+    match crate::records::expand_define_record(&expr).map_err(invalid)? {
+        Some(definitions) => {
+            for d in definitions {
+                locations.push(loc.clone());
+                exprs.push(d);
+            }
+        }
+        None => {
+            locations.push(loc);
+            exprs.push(expr);
+        }
+    }
+    Ok(())
+}
+
 /// Roundtrips a string by spinning up a JIT and executing it. Returns
 /// the result.
 pub fn roundtrip_string(input: &str) -> Result<Expr, String> {
@@ -194,6 +308,29 @@ pub fn roundtrip_string(input: &str) -> Result<Expr, String> {
     crate::compiler::roundtrip_program(&mut exprs)
 }
 
+/// Compiles INPUT with every optimization turned on, runs it and
+/// returns the value it evaluates to. This is meant for embedding, so
+/// unlike `roundtrip_string` the JIT is in embedded mode and an error
+/// raised while the program runs is returned rather than exiting the
+/// process. Closures in the value come back as `Expr::Procedure`.
+pub fn run_str(input: &str) -> Result<Expr, String> {
+    let mut program = parse_string(input)?;
+    let mut jit = crate::compiler::JIT::new(crate::compiler::CompileOptions {
+        embedded: true,
+        optimize: true,
+        ..Default::default()
+    });
+    let id = crate::compiler::compile_program(&mut jit, &mut program)?;
+    let res = jit.invoke(id).map_err(|e| e.to_string())?;
+    Ok(Expr::from_immediate(res))
+}
+
+/// Like `roundtrip_string` but reads the program from R.
+pub fn roundtrip_reader(r: impl std::io::Read) -> Result<Expr, String> {
+    let mut exprs = parse_reader(r)?;
+    crate::compiler::roundtrip_program(&mut exprs)
+}
+
 /// Roundtrips a file by spinning up a JIT and executing it.
 pub fn roundtrip_file(name: &str) -> Result<Expr, String> {
     let contents = std::fs::read_to_string(name).map_err(|e| e.to_string())?;
@@ -213,6 +350,52 @@ mod tests {
         assert_eq!(roundtrip_file(filename).unwrap(), expected)
     }
 
+    #[test]
+    fn empty_programs() {
+        for source in ["", "  \n\t", "; nothing but a comment\n\n; and another"] {
+            assert_eq!(roundtrip_string(source).unwrap(), Expr::Nil);
+            assert_eq!(
+                crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+                Expr::Nil
+            );
+        }
+        test_file_evaluation("examples/empty.lisp", Expr::Nil);
+
+        // Later programs in the same JIT still get the data and
+        // functions they need.
+        let mut jit = crate::compiler::JIT::default();
+        let empty = crate::compiler::compile_program(&mut jit, &mut []).unwrap();
+        assert_eq!(Expr::from_immediate(jit.invoke(empty).unwrap()), Expr::Nil);
+        let mut program = parse_string("(let f (fn () (quote (1 2)))) (car (cdr (f)))").unwrap();
+        let id = crate::compiler::compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            Expr::from_immediate(jit.invoke(id).unwrap()),
+            Expr::Integer(2)
+        );
+    }
+
+    #[test]
+    fn parse_from_reader() {
+        let source = r#"
+; Comments and strings with more than one byte per character.
+(let greeting "héllo, 世界")
+(define-record point (x y))
+(let f (fn (x) (if (eq x -1) (quote (a . ,x)) x)))
+(point-x (make-point 1 2))
+"#;
+        assert_eq!(
+            parse_reader(std::io::Cursor::new(source)).unwrap(),
+            parse_string(source).unwrap()
+        );
+        assert_eq!(
+            roundtrip_reader(std::io::Cursor::new(source)).unwrap(),
+            Expr::Integer(1)
+        );
+
+        assert!(parse_reader(std::io::Cursor::new("(let x")).is_err());
+        assert!(parse_reader(&[b'(', 0xff, b')'][..]).is_err());
+    }
+
     #[test]
     fn variables() {
         let input = r#"
@@ -239,6 +422,38 @@ mod tests {
         test_string_evaluation(input, expected);
     }
 
+    #[test]
+    fn concurrent_compilation() {
+        use cranelift_module::Module;
+
+        let threads: Vec<_> = (1..=8)
+            .map(|n| {
+                std::thread::spawn(move || {
+                    let items = (1..=n).map(|i| i.to_string()).collect::<Vec<_>>();
+                    let source = format!(
+                        r#"
+(let sum (fn (l) (if (null? l) 0 (add (car l) (sum (cdr l))))))
+(sum (quote ({})))
+"#,
+                        items.join(" ")
+                    );
+                    let mut jit = compiler::JIT::default();
+                    let mut program = parse_string(&source).unwrap();
+                    let id = compiler::compile_program(&mut jit, &mut program).unwrap();
+                    let res = Expr::from_immediate(jit.invoke(id).unwrap());
+                    // Each JIT numbers its data from zero.
+                    assert!(jit.module.get_name("__anon_data_0").is_some());
+                    assert!(jit.module.get_name("__anon_data_1").is_none());
+                    (n, res)
+                })
+            })
+            .collect();
+        for t in threads {
+            let (n, res) = t.join().unwrap();
+            assert_eq!(res, Expr::Integer(n * (n + 1) / 2));
+        }
+    }
+
     #[test]
     fn primitives_file() {
         let expected = Expr::Bool(true);
@@ -266,4 +481,28 @@ mod tests {
         let expected = Expr::List(vec![Expr::Integer(1), Expr::Integer(2)]);
         test_string_evaluation(input, expected);
     }
+
+    #[test]
+    fn run_str_values() {
+        let list = |rest: &[i64]| {
+            rest.iter()
+                .rev()
+                .fold(Expr::Nil, |cdr, i| Expr::List(vec![Expr::Integer(*i), cdr]))
+        };
+        assert_eq!(run_str("(quote (1 2 3))"), Ok(list(&[1, 2, 3])));
+        assert_eq!(
+            run_str("(let f (fn (n) (if (eq n 0) () (cons n (f (sub n 1)))))) (f 3)"),
+            Ok(list(&[3, 2, 1]))
+        );
+        // Errors at runtime come back instead of exiting.
+        let e = run_str("(car 1)").unwrap_err();
+        assert!(e.contains("exit code"), "{}", e);
+        assert!(run_str("(car").is_err());
+        // Closures have no value to give back.
+        assert_eq!(run_str("(fn (x) x)"), Ok(Expr::Procedure));
+        assert_eq!(
+            run_str("(let add1 (fn (x) (add x 1))) (cons 1 add1)"),
+            Ok(Expr::List(vec![Expr::Integer(1), Expr::Procedure]))
+        );
+    }
 }
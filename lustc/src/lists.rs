@@ -0,0 +1,240 @@
+//! Primitives that search lists. Two values are `equal` if they are
+//! `eq` or if they are both pairs whose cars and cdrs are `equal`, so
+//! strings (which are lists of characters) with the same characters
+//! are equal. These are implemented by the host and walk lists
+//! without recursing so that long lists don't overflow the stack.
+//! `memq` and `assq` are `member` and `assoc` compared with `eq`,
+//! which is faster and enough for keys like symbols.
+//!
+//! `(hash x)` returns a non-negative integer that `equal` values share
+//! so that it can be used to key tables by `equal`. Pairs are hashed
+//! by their contents like `equal` compares them and everything else,
+//! vectors and closures included, is hashed by identity.
+
+use crate::conversions::{pair_parts, word_is_pair};
+use crate::{Expr, Word};
+
+/// Returns true if A and B are equal.
+pub(crate) fn words_equal(a: Word, b: Word) -> bool {
+    let mut worklist = vec![(a, b)];
+    while let Some((a, b)) = worklist.pop() {
+        if a == b {
+            continue;
+        }
+        if !(word_is_pair(a) && word_is_pair(b)) {
+            return false;
+        }
+        let (acar, acdr) = pair_parts(a);
+        let (bcar, bcdr) = pair_parts(b);
+        worklist.push((acdr, bcdr));
+        worklist.push((acar, bcar));
+    }
+    true
+}
+
+/// Implements (equal a b).
+pub extern "C" fn lustc_equal(a: Word, b: Word) -> Word {
+    Expr::Bool(words_equal(a, b)).immediate_rep()
+}
+
+/// The hash that hashing starts from. The FNV offset basis.
+pub(crate) const HASH_SEED: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Mixed into a hash before the car and cdr of a pair so that a list
+/// doesn't hash the same as its elements laid end to end.
+const PAIR_HASH: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Returns the result of mixing X into the hash H.
+pub(crate) fn mix_hash(h: u64, x: u64) -> u64 {
+    (h ^ x).wrapping_mul(0x0000_0100_0000_01b3)
+}
+
+/// Turns the hash H into a value that fits in a fixnum.
+pub(crate) fn finish_hash(h: u64) -> i64 {
+    (h >> 3) as i64
+}
+
+/// Implements (hash x).
+pub extern "C" fn lustc_hash(x: Word) -> Word {
+    let mut h = HASH_SEED;
+    let mut worklist = vec![x];
+    while let Some(x) = worklist.pop() {
+        if word_is_pair(x) {
+            let (car, cdr) = pair_parts(x);
+            h = mix_hash(h, PAIR_HASH);
+            worklist.push(cdr);
+            worklist.push(car);
+        } else {
+            h = mix_hash(h, x as u64);
+        }
+    }
+    Expr::Integer(finish_hash(h)).immediate_rep()
+}
+
+/// Returns the first sublist of LIST whose car is the same as X by
+/// SAME or nil if there isn't one.
+fn member_by(x: Word, list: Word, same: fn(Word, Word) -> bool) -> Word {
+    let mut list = list;
+    while word_is_pair(list) {
+        let (car, cdr) = pair_parts(list);
+        if same(x, car) {
+            return list;
+        }
+        list = cdr;
+    }
+    Expr::Nil.immediate_rep()
+}
+
+/// Returns the first pair in ALIST whose car is the same as KEY by
+/// SAME or nil if there isn't one. Elements of ALIST that aren't
+/// pairs are skipped.
+fn assoc_by(key: Word, alist: Word, same: fn(Word, Word) -> bool) -> Word {
+    let mut alist = alist;
+    while word_is_pair(alist) {
+        let (entry, cdr) = pair_parts(alist);
+        if word_is_pair(entry) && same(key, pair_parts(entry).0) {
+            return entry;
+        }
+        alist = cdr;
+    }
+    Expr::Nil.immediate_rep()
+}
+
+/// Returns true if A and B are eq. Symbols are interned so symbols
+/// with the same name are always eq.
+fn words_eq(a: Word, b: Word) -> bool {
+    a == b
+}
+
+/// Implements (member x list). Returns the first sublist of LIST
+/// whose car is equal to X or nil if there isn't one.
+pub extern "C" fn lustc_member(x: Word, list: Word) -> Word {
+    member_by(x, list, words_equal)
+}
+
+/// Implements (memq x list). Like member but compares with eq.
+pub extern "C" fn lustc_memq(x: Word, list: Word) -> Word {
+    member_by(x, list, words_eq)
+}
+
+/// Implements (assoc key alist). Returns the first pair in ALIST
+/// whose car is equal to KEY or nil if there isn't one. Elements of
+/// ALIST that aren't pairs are skipped.
+pub extern "C" fn lustc_assoc(key: Word, alist: Word) -> Word {
+    assoc_by(key, alist, words_equal)
+}
+
+/// Implements (assq key alist). Like assoc but compares with eq.
+pub extern "C" fn lustc_assq(key: Word, alist: Word) -> Word {
+    assoc_by(key, alist, words_eq)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::check;
+    use crate::{roundtrip_string, Expr};
+
+    #[test]
+    fn equal() {
+        check(
+            "(cons (equal (quote (1 (2 3))) (cons 1 (cons (cons 2 (cons 3 ())) ()))) (equal \"ab\" \"ac\"))",
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+        check(
+            "(cons (equal 1 1) (equal (quote a) (quote b)))",
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+    }
+
+    #[test]
+    fn member() {
+        check("(member 2 (quote (1 2 3)))", "(quote (2 3))");
+        check("(member 4 (quote (1 2 3)))", "()");
+        check("(member 4 ())", "()");
+        check(
+            "(member \"b\" (cons \"a\" (cons \"b\" ())))",
+            "(cons \"b\" ())",
+        );
+    }
+
+    #[test]
+    fn assoc() {
+        let alist = "(let alist (cons (cons (quote a) 1) (cons (cons \"b\" 2) (cons (cons (quote a) 3) ()))))";
+        check(
+            &format!("{} (assoc (quote a) alist)", alist),
+            "(cons (quote a) 1)",
+        );
+        check(&format!("{} (cdr (assoc \"b\" alist))", alist), "2");
+        check(&format!("{} (assoc (quote c) alist)", alist), "()");
+        check("(assoc 1 ())", "()");
+    }
+
+    #[test]
+    fn memq_and_assq() {
+        check("(memq (quote c) (quote (a b c d)))", "(quote (c d))");
+        check("(memq (quote e) (quote (a b c d)))", "()");
+        // Strings with the same characters aren't the same string. The
+        // string is built at runtime so that it isn't folded into the
+        // same constant as the literal.
+        check(
+            "(memq \"b\" (cons \"a\" (cons (string-append (cons (integer->char 98) ()) \"\") ())))",
+            "()",
+        );
+        check(
+            "(let s \"b\") (memq s (cons \"a\" (cons s ())))",
+            "(cons \"b\" ())",
+        );
+
+        let alist = "(let alist (cons (cons (quote a) 1) (cons (cons \"b\" 2) (cons (cons (quote a) 3) ()))))";
+        check(&format!("{} (cdr (assq (quote a) alist))", alist), "1");
+        check(
+            &format!(
+                "{} (assq (string-append (cons (integer->char 98) ()) \"\") alist)",
+                alist
+            ),
+            "()",
+        );
+        check(
+            "(let f assq) (f 2 (quote ((1 one) (2 two))))",
+            "(quote (2 two))",
+        );
+    }
+
+    #[test]
+    fn hash() {
+        check(
+            "(eq (hash \"ab\") (hash (string-append (cons (integer->char 97) ()) \"b\")))",
+            "(eq 1 1)",
+        );
+        check(
+            "(eq (hash (quote (1 (2)))) (hash (cons 1 (cons (cons 2 ()) ()))))",
+            "(eq 1 1)",
+        );
+        check("(eq (hash (quote sym)) (hash (quote sym)))", "(eq 1 1)");
+        check("(eq (hash \"ab\") (hash \"ba\"))", "(eq 1 2)");
+        check(
+            "(eq (hash (quote ((1) 2))) (hash (quote (1 2))))",
+            "(eq 1 2)",
+        );
+        check("(negative? (hash -2305843009213693952))", "(eq 1 2)");
+        // Vectors are hashed by identity.
+        check(
+            "(let v (list->vector (quote (1)))) (eq (hash v) (hash v))",
+            "(eq 1 1)",
+        );
+    }
+
+    #[test]
+    fn long_lists() {
+        let source = r#"
+(let range (fn (n acc) (if (eq n 0) acc (range (sub n 1) (cons n acc)))))
+(let a (range 200000 ()))
+(let b (range 200000 ()))
+(cons (equal a b) (car (member 200000 a)))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![Expr::Bool(true), Expr::Integer(200000)])
+        );
+    }
+}
@@ -85,6 +85,10 @@ pub(crate) fn emit_let(name: &str, val: &Expr, ctx: &mut Context) -> Result<Valu
 
 pub(crate) fn emit_set(target: &str, val: &Expr, ctx: &mut Context) -> Result<Value, String> {
     let val = emit_expr(val, ctx)?;
+    if crate::globals::is_slot(target) {
+        crate::data::emit_data_store(target, val, ctx)?;
+        return Ok(val);
+    }
     let var = ctx.env.get(target).ok_or(format!(
         "use of undeclared variable ({}) in set expression",
         target
@@ -112,7 +116,7 @@ pub(crate) fn emit_var_access(name: &str, ctx: &mut Context) -> Result<Value, St
             .ok_or(format!("internal error: {} not found in argmap", name))?;
 
         emit_make_closure(name, &free_variables, ctx)
-    } else if name.starts_with("__anon_data_") {
+    } else if name.starts_with("__anon_data_") || crate::globals::is_slot(name) {
         crate::data::emit_data_access(name, ctx)
     } else if name.starts_with("e_") {
         let var = ctx.env.get(name).ok_or(format!(
@@ -15,6 +15,83 @@ fn main() {
                     .index(1)
                     .help("the file to run"),
             )
+            .arg(
+                Arg::with_name("args")
+                    .multiple(true)
+                    .index(2)
+                    .help("arguments passed to the program"),
+            )
+            .arg(
+                Arg::with_name("emit-asm")
+                    .long("emit-asm")
+                    .required(false)
+                    .takes_value(false)
+                    .help("print the machine code generated for the program"),
+            )
+            .arg(
+                Arg::with_name("optimize")
+                    .short("O")
+                    .long("optimize")
+                    .required(false)
+                    .takes_value(false)
+                    .help("leave out checks that are known to pass"),
+            )
+            .arg(
+                Arg::with_name("trampoline")
+                    .long("trampoline")
+                    .required(false)
+                    .takes_value(false)
+                    .help("run mutually recursive tail calls in constant stack space"),
+            )
+            .arg(
+                Arg::with_name("warn-non-tail")
+                    .long("warn-non-tail")
+                    .required(false)
+                    .takes_value(false)
+                    .help("warn about functions that call themselves outside of tail position"),
+            )
+            .arg(
+                Arg::with_name("warn-unused")
+                    .long("warn-unused")
+                    .required(false)
+                    .takes_value(false)
+                    .help("warn about top level definitions that are never used"),
+            )
+            .arg(
+                Arg::with_name("warn-shadow")
+                    .long("warn-shadow")
+                    .required(false)
+                    .takes_value(false)
+                    .help("warn about definitions that shadow builtins"),
+            )
+            .arg(
+                Arg::with_name("coverage")
+                    .long("coverage")
+                    .required(false)
+                    .takes_value(false)
+                    .help("report how many times each form and branch ran"),
+            )
+            .arg(
+                Arg::with_name("pack-lists")
+                    .long("pack-lists")
+                    .required(false)
+                    .takes_value(false)
+                    .help("store lists of one or two small integers without allocating"),
+            )
+            .arg(
+                Arg::with_name("buffer-output")
+                    .long("buffer-output")
+                    .required(false)
+                    .takes_value(false)
+                    .help("hold on to output until there is a lot of it or it is flushed"),
+            )
+            .arg(
+                Arg::with_name("test")
+                    .long("test")
+                    .required(false)
+                    .takes_value(false)
+                    .help("report how many assertions passed and failed"),
+            )
             .arg(
                 Arg::with_name("timeit")
                     .short("t")
@@ -30,7 +107,109 @@ fn main() {
 
     timer::init(cli_opts.is_present("timeit"));
 
-    if let Err(s) = lustc::roundtrip_file(file) {
+    let args = cli_opts
+        .values_of("args")
+        .map(|v| v.map(|a| a.to_string()).collect())
+        .unwrap_or_default();
+    lustc::environment::set_program_args(args);
+
+    let options = lustc::compiler::CompileOptions {
+        optimize: cli_opts.is_present("optimize"),
+        trampoline: cli_opts.is_present("trampoline"),
+        coverage: cli_opts.is_present("coverage"),
+        pack_lists: cli_opts.is_present("pack-lists"),
+        ..Default::default()
+    };
+    if cli_opts.is_present("buffer-output") {
+        lustc::output::set_buffering(lustc::output::Buffering::Full);
+    }
+    let warnings = Warnings {
+        non_tail: cli_opts.is_present("warn-non-tail"),
+        unused: cli_opts.is_present("warn-unused"),
+        shadow: cli_opts.is_present("warn-shadow"),
+    };
+    let res = run_file(file, cli_opts.is_present("emit-asm"), warnings, options);
+    lustc::output::flush_output();
+    if let Err(s) = &res {
         eprintln!("error: {}", s)
     }
+    // A test file fails if any of its assertions do or if it doesn't
+    // run to the end.
+    if cli_opts.is_present("test") {
+        let results = lustc::testing::take_results();
+        println!("{}", results.summary());
+        if results.failed > 0 || res.is_err() {
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The warnings that were asked for on the command line. Errors are
+/// always printed.
+struct Warnings {
+    /// Calls that a function makes to itself outside of tail position.
+    non_tail: bool,
+    /// Top level definitions that are never used.
+    unused: bool,
+    /// Definitions that shadow builtins.
+    shadow: bool,
+}
+
+impl Warnings {
+    /// Returns true if D should be printed.
+    fn shows(&self, d: &lustc::diagnostics::Diagnostic) -> bool {
+        match d.kind {
+            "unused-definition" => self.unused,
+            "shadowed-builtin" => self.shadow,
+            _ => true,
+        }
+    }
+}
+
+/// Runs the program in FILE compiled with OPTIONS, printing its errors
+/// and the WARNINGS that were asked for with the locations of their
+/// forms first. The program isn't run if there are errors. If
+/// EMIT_ASM is set the program's machine code is printed before it
+/// runs. If OPTIONS asks for coverage the coverage report is printed
+/// after it does.
+fn run_file(
+    file: &str,
+    emit_asm: bool,
+    warnings: Warnings,
+    options: lustc::compiler::CompileOptions,
+) -> Result<lustc::Expr, String> {
+    let contents = std::fs::read_to_string(file).map_err(|e| e.to_string())?;
+    let (mut program, locations) = lustc::parse_string_with_locations(&contents)?;
+    let diagnostics = lustc::diagnostics::diagnose(&program);
+    for d in diagnostics.iter().filter(|d| warnings.shows(d)) {
+        eprintln!("{}", d.describe(&locations));
+    }
+    let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+    if errors > 0 {
+        return Err(format!(
+            "{} can't be compiled because of {} error{}",
+            file,
+            errors,
+            if errors == 1 { "" } else { "s" }
+        ));
+    }
+    if warnings.non_tail {
+        // Locations are only kept for top level forms so the warning
+        // points at the form that the call is in.
+        for call in lustc::tail::find_non_tail_self_calls(&program)? {
+            let at = &locations[call.form].start;
+            eprintln!("{} (in the form at {}:{})", call, at.line, at.col);
+        }
+    }
+    let mut jit = lustc::compiler::JIT::new(options);
+    let id = lustc::compiler::compile_program(&mut jit, &mut program)?;
+    if emit_asm {
+        print!("{}", lustc::asm::disassemble(&jit)?);
+    }
+    let res = jit.invoke(id).map_err(|e| e.to_string())?;
+    if jit.options.coverage {
+        let counts = jit.coverage_counts();
+        eprint!("{}", lustc::coverage::coverage_report(&counts, &locations));
+    }
+    Ok(lustc::Expr::from_immediate(res))
 }
@@ -0,0 +1,296 @@
+//! Where programs write their output. Everything a program prints
+//! goes through `write_output` which writes to stdout unless the
+//! output is being captured.
+//!
+//! `(display x)` writes X like print does except that strings and
+//! characters are written without quotes and procedures, which have
+//! no printed form, are written as #<procedure>. `(newline)` writes a
+//! newline. `(with-output-to-string body...)` evaluates BODY and
+//! returns everything it wrote as a string instead of writing it.
+//!
+//! ```lisp
+//! (with-output-to-string (display "hi") (print 1)) ; => "hi1"
+//! ```
+//!
+//! Captures are kept in a stack per thread so nested uses each get
+//! the output written while they are innermost and output written by
+//! a program only ends up in that program's captures. If BODY raises
+//! an error its capture is dropped along with what it wrote so far.
+//! The body is desugared into a closure the same way a try's body is.
+//!
+//! ```lisp
+//! (with-output-to-string body...) => (with-output-to-string (fn () body...))
+//! ```
+//!
+//! Output to stdout is line buffered by default, so a line shows up
+//! once it has been written in full. `set_buffering(Buffering::Full)`,
+//! or `lustc --buffer-output`, holds on to output until there is a
+//! lot of it instead, which is faster for programs that write many
+//! short lines. `(flush-output)` writes everything that is being held
+//! on to either way, so a program can show a partial line like a
+//! progress message before a long computation. Captured output isn't
+//! written anywhere until its capture ends so flushing it does
+//! nothing. Output is flushed before a program exits because of an
+//! error so that it isn't lost.
+
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{string_to_immediate, try_stringify_list};
+use crate::fatal::{self, emit_check_callable};
+use crate::heap::emit_alloc;
+use crate::procedures::emit_unchecked_closure_call;
+use crate::{Expr, Word};
+
+thread_local! {
+    /// The output captured by each with-output-to-string that is
+    /// running, innermost last.
+    static CAPTURES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+
+    static BUFFERING: Cell<Buffering> = const { Cell::new(Buffering::Line) };
+
+    /// The output written to stdout while it is fully buffered that
+    /// hasn't been flushed yet.
+    static PENDING: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// How much fully buffered output is held on to before it is written.
+const PENDING_LIMIT: usize = 8192;
+
+/// How output written to stdout is buffered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Buffering {
+    /// Written a line at a time.
+    Line,
+    /// Written once there is a lot of it or it is flushed.
+    Full,
+}
+
+/// Sets how this thread's output to stdout is buffered. Anything held
+/// on to under the old setting is flushed first.
+pub fn set_buffering(buffering: Buffering) {
+    flush_output();
+    BUFFERING.with(|b| b.set(buffering));
+}
+
+/// Writes S to the innermost capture or to stdout if nothing is
+/// being captured.
+pub(crate) fn write_output(s: &str) {
+    let captured = CAPTURES.with(|c| match c.borrow_mut().last_mut() {
+        Some(capture) => {
+            capture.push_str(s);
+            true
+        }
+        None => false,
+    });
+    if captured {
+        return;
+    }
+    match BUFFERING.with(|b| b.get()) {
+        Buffering::Line => print!("{}", s),
+        Buffering::Full => {
+            let full = PENDING.with(|p| {
+                let mut p = p.borrow_mut();
+                p.push_str(s);
+                p.len() >= PENDING_LIMIT
+            });
+            if full {
+                flush_output();
+            }
+        }
+    }
+}
+
+/// Writes all of the output to stdout that is being held on to. Does
+/// nothing while output is being captured.
+pub fn flush_output() {
+    if CAPTURES.with(|c| !c.borrow().is_empty()) {
+        return;
+    }
+    let pending = PENDING.with(|p| std::mem::take(&mut *p.borrow_mut()));
+    let mut stdout = std::io::stdout();
+    // There is nowhere to report a failure to write output to.
+    let _ = stdout.write_all(pending.as_bytes());
+    let _ = stdout.flush();
+}
+
+/// Starts capturing output.
+pub(crate) fn begin_capture() {
+    CAPTURES.with(|c| c.borrow_mut().push(String::new()))
+}
+
+/// Stops the innermost capture and returns what was written to it.
+pub(crate) fn end_capture() -> String {
+    CAPTURES.with(|c| c.borrow_mut().pop().unwrap_or_default())
+}
+
+/// Returns how E is written by display.
+pub(crate) fn display_string(e: &Expr) -> String {
+    match e {
+        Expr::Char(c) => c.to_string(),
+        e => try_stringify_list(e).unwrap_or_else(|| e.to_string()),
+    }
+}
+
+/// Implements (display x).
+pub(crate) extern "C" fn lustc_display(word: Word) -> Word {
+    write_output(&display_string(&Expr::from_immediate(word)));
+    Expr::Nil.immediate_rep()
+}
+
+/// Implements (newline).
+pub(crate) extern "C" fn lustc_newline() -> Word {
+    write_output("\n");
+    Expr::Nil.immediate_rep()
+}
+
+/// Implements (flush-output).
+pub(crate) extern "C" fn lustc_flush_output() -> Word {
+    flush_output();
+    Expr::Nil.immediate_rep()
+}
+
+/// Called at the start of a with-output-to-string.
+pub(crate) extern "C" fn lustc_begin_capture() -> Word {
+    begin_capture();
+    Expr::Nil.immediate_rep()
+}
+
+/// Called at the end of a with-output-to-string. Returns what its body
+/// wrote as a string.
+pub(crate) extern "C" fn lustc_end_capture() -> Word {
+    string_to_immediate(&end_capture())
+}
+
+impl Expr {
+    /// If the expression is a desugared with-output-to-string returns
+    /// its body closure.
+    pub(crate) fn is_with_output_to_string(&self) -> Option<&Expr> {
+        match self {
+            Expr::List(v)
+                if v.len() == 2 && v[0] == Expr::Symbol("with-output-to-string".to_string()) =>
+            {
+                Some(&v[1])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Desugars `(with-output-to-string BODY...)`. ARGS are its
+/// arguments.
+pub(crate) fn desugar_with_output_to_string(args: &[Expr]) -> Expr {
+    Expr::List(vec![
+        Expr::Symbol("with-output-to-string".to_string()),
+        crate::exceptions::closure(Expr::Nil, args),
+    ])
+}
+
+/// Emits the code for a desugared with-output-to-string expression.
+pub(crate) fn emit_with_output_to_string(body: &Expr, ctx: &mut Context) -> Result<Value, String> {
+    let body = emit_check_callable(body, ctx)?;
+
+    crate::foreign::emit_host_call("lustc_begin_capture", &[], ctx)?;
+    let no_args = emit_alloc(0, ctx)?;
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    emit_unchecked_closure_call(body, zero, no_args, ctx)?;
+    // The capture ends whether or not the body raised an error.
+    let output = crate::foreign::emit_host_call("lustc_end_capture", &[], ctx)?;
+    if ctx.options.embedded {
+        fatal::emit_check_error_pending(ctx)?;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roundtrip_string;
+    use crate::test_util::{check, error_kind, run_embedded};
+
+    #[test]
+    fn captures_output() {
+        check("(with-output-to-string (display \"hi\"))", "\"hi\"");
+        check(
+            "(with-output-to-string (print \"a\") (newline) (println (quote (1 b))) (display (integer->char 99)))",
+            "\"\\\"a\\\"\n(1 B)\nc\"",
+        );
+        check("(with-output-to-string 1)", "\"\"");
+        // Nested captures each get their own output.
+        check(
+            r#"
+(let inner ())
+(let outer (with-output-to-string
+  (display "a")
+  (set inner (with-output-to-string (display "b")))
+  (display "c")))
+(cons outer inner)
+"#,
+            "(cons \"ac\" \"b\")",
+        );
+        // Output from functions called by the body is captured too.
+        check(
+            "(let greet (fn (name) (display \"hi \") (display name))) (with-output-to-string (for-each greet (quote (a b))))",
+            "\"hi Ahi B\"",
+        );
+    }
+
+    #[test]
+    fn display_procedures() {
+        check(
+            "(with-output-to-string (display (fn (x) x)))",
+            "\"#<procedure>\"",
+        );
+        check(
+            "(with-output-to-string (display (cons 1 car)))",
+            "\"(1 . #<procedure>)\"",
+        );
+        assert_eq!(
+            run_embedded("(with-output-to-string (display (fn () 1)))")
+                .unwrap()
+                .to_string(),
+            "\"#<procedure>\""
+        );
+    }
+
+    #[test]
+    fn errors_end_capture() {
+        let source = r#"
+(let caught (try (with-output-to-string (display "lost") (car 1)) (catch e (condition-type e))))
+(cons caught (with-output-to-string (display "kept")))
+"#;
+        let expected = "(cons (quote type-error) \"kept\")";
+        check(source, expected);
+
+        assert_eq!(
+            error_kind("(with-output-to-string (display 1) (car 1))"),
+            "type-error"
+        );
+        assert_eq!(super::end_capture(), "");
+    }
+
+    #[test]
+    fn flushing() {
+        use super::{set_buffering, Buffering, PENDING};
+        let pending = || PENDING.with(|p| p.borrow().clone());
+
+        set_buffering(Buffering::Full);
+        assert_eq!(
+            roundtrip_string("(display \"working...\") 1"),
+            Ok(crate::Expr::Integer(1))
+        );
+        assert_eq!(pending(), "working...");
+        assert_eq!(roundtrip_string("(flush-output)"), Ok(crate::Expr::Nil));
+        assert_eq!(pending(), "");
+        // Flushing a capture does nothing.
+        check(
+            "(with-output-to-string (display \"a\") (flush-output) (display \"b\"))",
+            "\"ab\"",
+        );
+        check("(let f (fn (g) (g))) (f flush-output)", "()");
+        set_buffering(Buffering::Line);
+        assert_eq!(pending(), "");
+    }
+}
@@ -0,0 +1,237 @@
+//! Lists packed into a word. When `CompileOptions::pack_lists` is set
+//! a cons of a small integer onto nil, or onto a packed list of one
+//! element, makes a packed list instead of allocating a pair, so lists
+//! of one or two small integers take no space on the heap.
+//!
+//! ```lisp
+//! (cons 1 (cons 2 ())) ; => (1 2), allocating nothing
+//! ```
+//!
+//! A packed list is a pair as far as programs can tell. car, cdr,
+//! pair?, and everything that walks lists take both apart the same way
+//! and the host sees a packed list as the list it stands for, so a
+//! list can be made of packed and allocated pairs in any mix. Pairs
+//! can't be changed once they are made, so packing one is only
+//! visible to eq: two packed lists with the same elements are the
+//! same word and are eq where two allocated ones would not be.
+//! Foreign functions are handed a pair as a pointer to its car and
+//! cdr, which a packed list doesn't have, so programs that pass lists
+//! to them shouldn't pack lists.
+//!
+//! The last four bits of a packed list are PACKED_LIST_TAG, which no
+//! other value has, and the next bit is set if the list has two
+//! elements. The first element is in the top 29 bits and the second
+//! in the 29 bits under it, so the elements are integers from -2^28
+//! up to 2^28. The cdr of a list of two elements is the list of the
+//! second and is made by moving the second element up into place.
+//!
+//! ```text
+//! | first: 29 | second: 29 | 0 | two: 1 | 0111 |
+//! ```
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{FIXNUM_MASK, FIXNUM_SHIFT, NIL_VALUE, PACKED_LIST_MASK, PACKED_LIST_TAG};
+use crate::Word;
+
+/// Set in packed lists of two elements.
+const TWO_ELEMENTS: Word = 0b10000;
+
+/// Where the first element is stored.
+const FIRST_SHIFT: Word = 35;
+
+/// How far the second element is moved up to be stored where a first
+/// element is.
+const SECOND_TO_FIRST: Word = 29;
+
+/// Clears everything but the first element.
+const FIRST_MASK: Word = !((1 << FIRST_SHIFT) - 1);
+
+/// Returns true if WHAT is a packed list.
+pub(crate) fn word_is_packed_list(what: Word) -> bool {
+    what & PACKED_LIST_MASK == PACKED_LIST_TAG
+}
+
+/// Returns the car and cdr of the packed list WHAT.
+pub(crate) fn packed_parts(what: Word) -> (Word, Word) {
+    let car = (what >> (FIRST_SHIFT - FIXNUM_SHIFT)) & !FIXNUM_MASK;
+    let cdr = if what & TWO_ELEMENTS != 0 {
+        ((what << SECOND_TO_FIRST) & FIRST_MASK) | PACKED_LIST_TAG
+    } else {
+        NIL_VALUE
+    };
+    (car, cdr)
+}
+
+/// Emits the code for the car and cdr of the packed list PACKED.
+pub(crate) fn emit_packed_parts(packed: Value, ctx: &mut Context) -> (Value, Value) {
+    let car = ctx
+        .builder
+        .ins()
+        .sshr_imm(packed, FIRST_SHIFT - FIXNUM_SHIFT);
+    let car = ctx.builder.ins().band_imm(car, !FIXNUM_MASK);
+
+    let second = ctx.builder.ins().ishl_imm(packed, SECOND_TO_FIRST);
+    let second = ctx.builder.ins().band_imm(second, FIRST_MASK);
+    let second = ctx.builder.ins().bor_imm(second, PACKED_LIST_TAG);
+    let nil = ctx.builder.ins().iconst(ctx.word, NIL_VALUE);
+    let two = ctx.builder.ins().band_imm(packed, TWO_ELEMENTS);
+    let cdr = ctx.builder.ins().select(two, second, nil);
+    (car, cdr)
+}
+
+/// Emits the code to pack the pair of DATA and NEXT. Returns a value
+/// that is nonzero if they can be packed and the packed list, which
+/// is meaningless if they can't.
+pub(crate) fn emit_pack(data: Value, next: Value, ctx: &mut Context) -> (Value, Value) {
+    // DATA has to be a fixnum that stays the same when it is moved up
+    // to the first element and back.
+    let tag = ctx.builder.ins().band_imm(data, FIXNUM_MASK);
+    let is_int = ctx.builder.ins().icmp_imm(IntCC::Equal, tag, 0);
+    let first = ctx.builder.ins().ishl_imm(data, FIRST_SHIFT - FIXNUM_SHIFT);
+    let back = ctx
+        .builder
+        .ins()
+        .sshr_imm(first, FIRST_SHIFT - FIXNUM_SHIFT);
+    let fits = ctx.builder.ins().icmp(IntCC::Equal, back, data);
+    let small = ctx.builder.ins().band(is_int, fits);
+
+    // NEXT has to be nil or a packed list of one element.
+    let is_nil = ctx.builder.ins().icmp_imm(IntCC::Equal, next, NIL_VALUE);
+    let kind = ctx
+        .builder
+        .ins()
+        .band_imm(next, TWO_ELEMENTS | PACKED_LIST_MASK);
+    let is_one = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, kind, PACKED_LIST_TAG);
+    let short = ctx.builder.ins().bor(is_nil, is_one);
+    let packs = ctx.builder.ins().band(small, short);
+
+    let one = ctx.builder.ins().bor_imm(first, PACKED_LIST_TAG);
+    let second = ctx.builder.ins().ushr_imm(next, SECOND_TO_FIRST);
+    let two = ctx.builder.ins().bor(first, second);
+    let two = ctx
+        .builder
+        .ins()
+        .bor_imm(two, TWO_ELEMENTS | PACKED_LIST_TAG);
+    let packed = ctx.builder.ins().select(is_nil, one, two);
+    (packs, packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile_program, CompileOptions, JIT};
+    use crate::{parse_string, roundtrip_string, Expr};
+
+    fn run(source: &str, pack_lists: bool) -> Expr {
+        let mut jit = JIT::new(CompileOptions {
+            pack_lists,
+            ..Default::default()
+        });
+        let mut program = parse_string(source).unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        Expr::from_immediate(jit.invoke(id).unwrap())
+    }
+
+    /// Checks that SOURCE has the same result with and without packed
+    /// lists.
+    fn check(source: &str, expected: &str) {
+        let expected = roundtrip_string(expected).unwrap();
+        assert_eq!(run(source, false), expected);
+        assert_eq!(run(source, true), expected);
+    }
+
+    #[test]
+    fn allocates_nothing() {
+        // Calls allocate space for their arguments so the baseline is
+        // a function that takes the same arguments and doesn't cons.
+        let growth = |f: &str, pack_lists| {
+            let source = format!(
+                r#"
+(let f {})
+(let before (heap-stats))
+(let res (f 1 2))
+(let after (heap-stats))
+(cons res (sub (car after) (car before)))
+"#,
+                f
+            );
+            match run(&source, pack_lists) {
+                Expr::List(v) => (v[0].clone(), Expr::from_immediate(v[1].immediate_rep())),
+                e => panic!("unexpected result {:?}", e),
+            }
+        };
+        let (_, baseline) = growth("(fn (a b) (add a b))", true);
+        let (res, packed) = growth("(fn (a b) (cons a (cons b ())))", true);
+        assert_eq!(packed, baseline);
+        assert_eq!(res, roundtrip_string("(quote (1 2))").unwrap());
+        let (res, allocated) = growth("(fn (a b) (cons a (cons b ())))", false);
+        assert_ne!(allocated, baseline);
+        assert_eq!(res, roundtrip_string("(quote (1 2))").unwrap());
+    }
+
+    #[test]
+    fn behaves_like_pairs() {
+        let two = "(let f (fn (a b) (cons a (cons b ())))) (let l (f 1 (sub 0 2)))";
+        check(
+            &format!("{} (cons (car l) (cons (car (cdr l)) (cdr (cdr l))))", two),
+            "(cons 1 (cons (sub 0 2) ()))",
+        );
+        check(
+            &format!("{} (cons (length l) (cons (pair? l) (pair? (cdr l))))", two),
+            "(cons 2 (cons (eq 1 1) (eq 1 1)))",
+        );
+        let two = "(let f (fn (a b) (cons a (cons b ())))) (let l (f 1 2))";
+        check(&format!("{} (equal l (quote (1 2)))", two), "(eq 1 1)");
+        check(
+            &format!(
+                "{} (cons (eq (hash l) (hash (quote (1 2)))) (member l (quote (0 (1 2)))))",
+                two
+            ),
+            "(cons (eq 1 1) (quote ((1 2))))",
+        );
+        // Packed and allocated pairs mix.
+        check(
+            r#"
+(let append (fn (a b) (if (pair? a) (cons (car a) (append (cdr a) b)) b)))
+(let f (fn (a b) (cons a (cons b ()))))
+(let l (append (f 1 2) (cons (quote x) (f 3 4))))
+(let inc (fn (x acc) (cons (add x 1) acc)))
+(cons l (cons (foldr inc () (f 5 6)) (list-tail l 3)))
+"#,
+            "(quote ((1 2 x 3 4) (6 7) 3 4))",
+        );
+        // Integers that don't fit, other elements, and longer lists
+        // are allocated.
+        check(
+            r#"
+(let f (fn (a b) (cons a (cons b ()))))
+(let big 1000000000000)
+(cons (f big 1) (cons (f 1 big) (cons (f (quote x) 1) (cons 1 (f 2 3)))))
+"#,
+            "(quote ((1000000000000 1) (1 1000000000000) (x 1) 1 2 3))",
+        );
+    }
+
+    #[test]
+    fn host_parts() {
+        let one = (5 << FIRST_SHIFT) | PACKED_LIST_TAG;
+        let two = (-1 << FIRST_SHIFT)
+            | (5 << (FIRST_SHIFT - SECOND_TO_FIRST))
+            | TWO_ELEMENTS
+            | PACKED_LIST_TAG;
+        assert_eq!(
+            packed_parts(one),
+            (Expr::Integer(5).immediate_rep(), NIL_VALUE)
+        );
+        assert_eq!(packed_parts(two), (Expr::Integer(-1).immediate_rep(), one));
+        assert_eq!(
+            Expr::from_immediate(two),
+            roundtrip_string("(cons (sub 0 1) (cons 5 ()))").unwrap()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Dynamically scoped variables. `(make-parameter default)` makes a
+//! parameter, a function that returns the parameter's value when it
+//! is called with no arguments and sets it when it is called with one.
+//! `(parameterize ((p v)...) body...)` gives each parameter P the value
+//! V while BODY runs, which includes the functions BODY calls, and
+//! gives it back its old value when BODY is done.
+//!
+//! ```lisp
+//! (let depth (make-parameter 0))
+//! (let show (fn () (depth)))
+//! (parameterize ((depth 1)) (show)) ; => 1
+//! (show)                            ; => 0
+//! ```
+//!
+//! Both are desugared. A parameter is a closure over the variable that
+//! holds its value, and parameterize evaluates its parameters and
+//! values, saves the old values in the arguments of a function, and
+//! restores them in the cleanup of an unwind-protect so that they are
+//! restored even if BODY raises an error or throws. Each parameterize
+//! that is running has the value it replaced in its own frame so
+//! nested ones unwind to the value before each of them in turn.
+//!
+//! ```lisp
+//! (make-parameter d) => ((fn (v) (fn (& new) (if (pair? new) (set v (car new)) v))) d)
+//!
+//! (parameterize ((p v)) body...) => ((fn (p0 v0)
+//!                                      ((fn (o0)
+//!                                         (p0 v0)
+//!                                         (unwind-protect (fn () body...) (fn () (p0 o0))))
+//!                                       (p0)))
+//!                                    p v)
+//! ```
+
+use crate::desugar::temporary;
+use crate::exceptions::closure;
+use crate::Expr;
+
+fn sym(s: &str) -> Expr {
+    Expr::Symbol(s.to_string())
+}
+
+fn call(f: Expr, args: Vec<Expr>) -> Expr {
+    Expr::List(std::iter::once(f).chain(args).collect())
+}
+
+/// Desugars `(make-parameter DEFAULT)`. ARGS are the arguments to
+/// make-parameter.
+pub(crate) fn desugar_make_parameter(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let default = match args {
+        [default] => default,
+        _ => return Err("make-parameter expects a default value".to_string()),
+    };
+    let value = temporary(count);
+    let new = temporary(count);
+    let body = call(
+        sym("if"),
+        vec![
+            call(sym("pair?"), vec![new.clone()]),
+            call(
+                sym("set"),
+                vec![value.clone(), call(sym("car"), vec![new.clone()])],
+            ),
+            value.clone(),
+        ],
+    );
+    let parameter = closure(Expr::List(vec![sym("&"), new]), &[body]);
+    Ok(call(
+        closure(Expr::List(vec![value]), &[parameter]),
+        vec![default.clone()],
+    ))
+}
+
+/// Desugars `(parameterize ((P V)...) BODY...)`. ARGS are the
+/// arguments to parameterize.
+pub(crate) fn desugar_parameterize(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (bindings, body) = match args.split_first() {
+        Some((Expr::List(bindings), body)) if !body.is_empty() => (bindings, body),
+        Some((Expr::Nil, body)) if !body.is_empty() => (&Vec::new(), body),
+        _ => return Err("parameterize expects a list of bindings and a body".to_string()),
+    };
+    let mut params = vec![];
+    let mut inits = vec![];
+    let mut olds = vec![];
+    let mut installs = vec![];
+    let mut restores = vec![];
+    for binding in bindings {
+        let (p, v) = match binding {
+            Expr::List(b) if b.len() == 2 => (&b[0], &b[1]),
+            _ => {
+                return Err(format!(
+                    "parameterize expects a binding like (parameter value) and got ({:?})",
+                    binding
+                ))
+            }
+        };
+        let (pt, vt, old) = (temporary(count), temporary(count), temporary(count));
+        params.extend([pt.clone(), vt.clone()]);
+        inits.extend([p.clone(), v.clone()]);
+        installs.push(call(pt.clone(), vec![vt]));
+        restores.push(call(pt.clone(), vec![old.clone()]));
+        olds.push((old, call(pt, vec![])));
+    }
+    let protected = call(
+        sym("unwind-protect"),
+        vec![closure(Expr::Nil, body), closure(Expr::Nil, &restores)],
+    );
+    let (olds, saved): (Vec<_>, Vec<_>) = olds.into_iter().unzip();
+    let install = call(
+        closure(
+            params_list(olds),
+            &installs.into_iter().chain([protected]).collect::<Vec<_>>(),
+        ),
+        saved,
+    );
+    Ok(call(closure(params_list(params), &[install]), inits))
+}
+
+fn params_list(params: Vec<Expr>) -> Expr {
+    if params.is_empty() {
+        Expr::Nil
+    } else {
+        Expr::List(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_program, CompileOptions, JIT};
+    use crate::test_util::check;
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn dynamic_extent() {
+        let source = r#"
+(let p (make-parameter 1))
+(let get (fn () (p)))
+(let inside (parameterize ((p 2)) (get)))
+(let nested (parameterize ((p 3)) (cons (get) (parameterize ((p 4)) (get)))))
+(cons inside (cons nested (get)))
+"#;
+        check(source, "(cons 2 (cons (cons 3 4) 1))");
+        // Every value is evaluated before any parameter changes.
+        let source = r#"
+(let a (make-parameter 1))
+(let b (make-parameter 2))
+(parameterize ((a (b)) (b (a))) (cons (a) (b)))
+"#;
+        check(source, "(cons 2 1)");
+        check("(let p (make-parameter 1)) (p 5) (p)", "5");
+    }
+
+    #[test]
+    fn display_parameter() {
+        check(
+            "(with-output-to-string (display (make-parameter 1)))",
+            "\"#<procedure>\"",
+        );
+        assert_eq!(
+            crate::test_util::run_cli("(display (make-parameter 1))"),
+            ("#<procedure>".to_string(), Some(0))
+        );
+    }
+
+    #[test]
+    fn restored_after_errors() {
+        let source = r#"
+(let p (make-parameter (quote outside)))
+(let seen ())
+(let fail (fn () (set seen (p)) (car 1)))
+(let caught (try (parameterize ((p (quote inside))) (fail)) (catch e (condition-type e))))
+(let thrown (catch (quote done) (parameterize ((p 2)) (throw (quote done) (p)))))
+(cons seen (cons caught (cons thrown (p))))
+"#;
+        check(
+            source,
+            "(cons (quote inside) (cons (quote type-error) (cons 2 (quote outside))))",
+        );
+    }
+
+    #[test]
+    fn bad_forms() {
+        assert!(roundtrip_string("(parameterize ((p)) 1)")
+            .unwrap_err()
+            .starts_with("parameterize expects a binding like (parameter value)"));
+        assert_eq!(
+            roundtrip_string("(make-parameter)"),
+            Err("make-parameter expects a default value".to_string())
+        );
+        // A parameter is restored for the programs of an embedded JIT
+        // when an error reaches the caller.
+        let mut jit = JIT::new(CompileOptions {
+            embedded: true,
+            persistent: true,
+            ..Default::default()
+        });
+        let mut program =
+            parse_string("(let p (make-parameter 1)) (parameterize ((p 2)) (car 1))").unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(jit.invoke(id).unwrap_err().kind, "type-error");
+        let mut program = parse_string("(p)").unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(
+            crate::Expr::from_immediate(jit.invoke(id).unwrap()),
+            crate::Expr::Integer(1)
+        );
+    }
+}
@@ -1,11 +1,78 @@
 //! Handles parsing of Lust expressions and emits some parse errors
 //! along the way.
+//!
+//! Each error has a `ParseError` saying what kind it is so that
+//! callers can tell them apart without looking at the message. A
+//! REPL, for example, can keep reading when the input ends with
+//! `ParseError::UnexpectedEof` as more input could complete it.
 
 use crate::errors::Error;
 use crate::location::Location;
 use crate::tokenbuffer::TokenBuffer;
 use crate::tokenizer::{Token, TokenType};
 
+/// The kinds of errors that parsing a program can run into.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseError {
+    /// The input ended in the middle of a list or string, or before
+    /// the expression after a quote.
+    UnexpectedEof { at: Location },
+    /// A closing paren without a list to close.
+    UnbalancedParen { at: Location },
+    /// A string with an escape sequence that isn't supported.
+    InvalidEscape { at: Location },
+    /// Something that starts like a number but isn't one.
+    InvalidNumber { at: Location },
+    /// An integer that is too large or too small to fit in a fixnum.
+    IntegerOutOfRange { at: Location },
+    /// A form that expands into others, like define-record, was
+    /// malformed.
+    InvalidForm { what: String, at: Location },
+    /// The program's source couldn't be read.
+    Read { what: String },
+}
+
+impl ParseError {
+    /// Returns where in the source the error is, if it has a place.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            ParseError::UnexpectedEof { at }
+            | ParseError::UnbalancedParen { at }
+            | ParseError::InvalidEscape { at }
+            | ParseError::InvalidNumber { at }
+            | ParseError::IntegerOutOfRange { at }
+            | ParseError::InvalidForm { at, .. } => Some(at),
+            ParseError::Read { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = match self {
+            ParseError::UnexpectedEof { .. } => "unexpected end of input",
+            ParseError::UnbalancedParen { .. } => "unexpected closing paren",
+            ParseError::InvalidEscape { .. } => "invalid escape in string",
+            ParseError::InvalidNumber { .. } => "malformed number",
+            ParseError::IntegerOutOfRange { .. } => "integer doesn't fit in a fixnum",
+            ParseError::InvalidForm { what, .. } => what,
+            ParseError::Read { what } => return write!(f, "error reading program: {}", what),
+        };
+        let at = self.location().unwrap();
+        write!(
+            f,
+            "parse error at {}:{}: {}",
+            at.start.line, at.start.col, what
+        )
+    }
+}
+
+impl From<ParseError> for String {
+    fn from(e: ParseError) -> Self {
+        e.to_string()
+    }
+}
+
 /// Used internally by the parser to store information about the state
 /// of the parse.
 #[derive(Debug)]
@@ -20,10 +87,7 @@ pub struct ParseResult {
 /// The parser for Lust programs.
 #[derive(Debug)]
 pub(crate) struct Parser<'a> {
-    /// The source string that we're parsing. We keep a reference to
-    /// this arround for emitting errors.
-    source: &'a str,
-    /// A token buffer that will be associated with SOURCE.
+    /// A token buffer over the source that we're parsing.
     tokbuffer: TokenBuffer<'a>,
 }
 
@@ -106,11 +170,18 @@ impl ParseResult {
 impl<'a> Parser<'a> {
     pub(crate) fn new(source: &'a str) -> Self {
         Self {
-            source: source,
             tokbuffer: TokenBuffer::new(source),
         }
     }
 
+    /// Makes a parser that parses the characters CHARS as they are
+    /// read rather than all at once.
+    pub(crate) fn from_chars(chars: impl Iterator<Item = char> + 'a) -> Self {
+        Self {
+            tokbuffer: TokenBuffer::from_chars(chars),
+        }
+    }
+
     /// Parses a list from the tokenbuffer.
     fn parse_list(&mut self, oparen: Token) -> ParseResult {
         let mut res = ParseResult::new();
@@ -125,7 +196,13 @@ impl<'a> Parser<'a> {
                     _ => (),
                 },
                 None => {
-                    res.merge_err(Error::on_tok("unbalanced parenthesis", &oparen));
+                    res.merge_err(Error::on_tok(
+                        "unbalanced parenthesis",
+                        &oparen,
+                        ParseError::UnexpectedEof {
+                            at: oparen.loc.clone(),
+                        },
+                    ));
                     break;
                 }
             }
@@ -180,10 +257,25 @@ impl<'a> Parser<'a> {
                     self.parse_list(tok)
                 }
 
-                TokenType::Cparen => ParseResult::from_err(Error::on_tok(
-                    "unexpected closing paren",
-                    &buffer.advance(),
-                )),
+                TokenType::Cparen => {
+                    let tok = buffer.advance();
+                    let at = tok.loc.clone();
+                    ParseResult::from_err(Error::on_tok(
+                        "unexpected closing paren",
+                        &tok,
+                        ParseError::UnbalancedParen { at },
+                    ))
+                }
+
+                TokenType::Number(f) if !crate::conversions::integer_fits_fixnum(f) => {
+                    let tok = buffer.advance();
+                    let at = tok.loc.clone();
+                    ParseResult::from_err(Error::on_tok(
+                        &format!("integer out of range: {}", f),
+                        &tok,
+                        ParseError::IntegerOutOfRange { at },
+                    ))
+                }
 
                 TokenType::Number(f) => ParseResult::from_expr(Expr {
                     val: ExprVal::Number(f),
@@ -204,28 +296,48 @@ impl<'a> Parser<'a> {
                     let loc = buffer.advance().loc;
                     self.expand("quote", loc)
                 }
-                TokenType::Negate => {
+                TokenType::Quasiquote => {
                     let loc = buffer.advance().loc;
-                    self.expand("negate", loc)
+                    self.expand("quasiquote", loc)
                 }
-                TokenType::Quaziquote => {
+                TokenType::Comma => {
                     let loc = buffer.advance().loc;
-                    self.expand("quaziquote", loc)
+                    self.expand("unquote", loc)
                 }
-                TokenType::Comma => {
+                TokenType::CommaAt => {
                     let loc = buffer.advance().loc;
-                    self.expand("comma", loc)
+                    self.expand("unquote-splicing", loc)
+                }
+                TokenType::Unrecognized(s, expected) => {
+                    let tok = buffer.advance();
+                    let at = tok.loc.clone();
+                    let kind = match *expected {
+                        TokenType::String(_) => ParseError::InvalidEscape { at },
+                        _ => ParseError::InvalidNumber { at },
+                    };
+                    ParseResult::from_err(Error::on_tok(
+                        &format!("malformed token: {}", s),
+                        &tok,
+                        kind,
+                    ))
+                }
+                TokenType::Unterminated(s) => {
+                    let tok = buffer.advance();
+                    let at = tok.loc.clone();
+                    ParseResult::from_err(Error::on_tok(
+                        &format!("unterminated string: {}", s),
+                        &tok,
+                        ParseError::UnexpectedEof { at },
+                    ))
                 }
-                TokenType::Unrecognized(s, _) => ParseResult::from_err(Error::on_tok(
-                    &format!("malformed token: {}", s),
-                    &buffer.advance(),
-                )),
             },
             None => {
                 let mut res = ParseResult::new();
+                let at = self.tokbuffer.loc();
                 res.merge_err(Error::at_loc(
                     "unexpected end of input parsing expression",
-                    &self.tokbuffer.loc(),
+                    &at,
+                    ParseError::UnexpectedEof { at: at.clone() },
                 ));
                 res
             }
@@ -261,6 +373,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn negative_number() {
+        let mut parser = Parser::new("(-12 - 3)");
+        let vals = match parser.parse_expr().expr.map(|e| e.val) {
+            Some(ExprVal::List(v)) => v.into_iter().map(|e| e.val).collect::<Vec<_>>(),
+            e => panic!("expected a list and got {:?}", e),
+        };
+        assert_eq!(
+            vals,
+            vec![
+                ExprVal::Number(-12),
+                ExprVal::Id("-".to_string()),
+                ExprVal::Number(3)
+            ]
+        );
+        let min = crate::conversions::FIXNUM_MIN;
+        let res = Parser::new(&min.to_string()).parse_expr();
+        assert_eq!(res.expr.unwrap().val, ExprVal::Number(min));
+        let res = Parser::new(&(min - 1).to_string()).parse_expr();
+        assert!(matches!(
+            res.errors[0].kind,
+            ParseError::IntegerOutOfRange { .. }
+        ));
+    }
+
     #[test]
     fn small_list() {
         let src = "(1 hello \"hello\")";
@@ -304,4 +441,87 @@ mod tests {
         // tracks a better way to handle this.
         assert_eq!(res.errors[0].what, "unbalanced parenthesis".to_string());
     }
+
+    #[test]
+    fn error_kinds() {
+        let at = |line, col| crate::reader::Location { line, col };
+        let kind = |src: &str| crate::parse_string(src).unwrap_err();
+
+        for src in ["(1 2", "(let x (f 1)", "\"abc", "(print \"abc\\\"", "'"] {
+            assert!(
+                matches!(kind(src), ParseError::UnexpectedEof { .. }),
+                "{}",
+                src
+            );
+        }
+        assert_eq!(kind("(a\n (b)").location().unwrap().start, at(0, 0));
+        match kind("(a))") {
+            ParseError::UnbalancedParen { at: loc } => assert_eq!(loc.start, at(0, 3)),
+            e => panic!("{:?}", e),
+        }
+        match kind("(print \"a\\qb\")") {
+            ParseError::InvalidEscape { at: loc } => assert_eq!(loc.start, at(0, 7)),
+            e => panic!("{:?}", e),
+        }
+        match kind("(add 1 2x)") {
+            ParseError::InvalidNumber { at: loc } => assert_eq!(loc.start, at(0, 7)),
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(
+            kind("(define-record point)"),
+            ParseError::InvalidForm { .. }
+        ));
+        assert_eq!(
+            kind("(a))").to_string(),
+            "parse error at 0:3: unexpected closing paren"
+        );
+
+        assert!(matches!(
+            crate::parse_reader(&b"(a"[..]).unwrap_err(),
+            ParseError::UnexpectedEof { .. }
+        ));
+        assert!(matches!(
+            crate::parse_reader(&[b'(', 0xff, b')'][..]).unwrap_err(),
+            ParseError::Read { .. }
+        ));
+    }
+
+    #[test]
+    fn quote_shorthand() {
+        let same = |a: &str, b: &str| {
+            assert_eq!(
+                crate::parse_string(a).unwrap(),
+                crate::parse_string(b).unwrap()
+            )
+        };
+        same("'(1 2 3)", "(quote (1 2 3))");
+        same("'foo", "(quote foo)");
+        same("''x", "(quote (quote x))");
+        same("'()", "(quote ())");
+        same(
+            "`(a ,b ,@c)",
+            "(quasiquote (a (unquote b) (unquote-splicing c)))",
+        );
+        same("(f 'a 'b)", "(f (quote a) (quote b))");
+        // Negative numbers inside of the shorthands stay numbers.
+        assert_eq!(
+            crate::parse_string("'(1 -2 3)").unwrap(),
+            vec![crate::Expr::List(vec![
+                crate::Expr::Symbol("quote".to_string()),
+                crate::Expr::List(vec![
+                    crate::Expr::Integer(1),
+                    crate::Expr::Integer(-2),
+                    crate::Expr::Integer(3)
+                ]),
+            ])]
+        );
+        assert_eq!(
+            crate::roundtrip_string("(let x -1) `(-3 ,x (-4 ,@(list -5)))"),
+            crate::roundtrip_string("(list (sub 0 3) (sub 0 1) (list (sub 0 4) (sub 0 5)))")
+        );
+        assert_eq!(
+            crate::roundtrip_string("(car (cdr '(1 2 3)))"),
+            Ok(crate::Expr::Integer(2))
+        );
+    }
 }
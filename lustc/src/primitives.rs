@@ -4,9 +4,35 @@
 //! that we use the emit_primcall function. For primitives that are
 //! used in a higher order context we don't have such a luxury and
 //! need to actually make a function.
+//!
+//! The arguments to a primitive are evaluated from left to right
+//! before it runs, the same as the arguments to a function call and
+//! the function being called, which comes first. Programs can count
+//! on this when arguments have side effects:
+//!
+//! ```lisp
+//! (cons (next!) (next!)) ; => (1 2), never (2 1)
+//! ```
+//!
+//! Special forms like if and and only evaluate what they need to but
+//! what they do evaluate is evaluated in order as well. The
+//! interpreter follows the same order.
+//!
+//! Primitives that take any number of arguments, like add and list,
+//! take any number of arguments in a higher order context too, so
+//! they can be passed to apply and reduce:
+//!
+//! ```lisp
+//! (apply + (list 1 2 3))     ; => 6
+//! (reduce + 0 (list 1 2 3))  ; => 6
+//! ```
+//!
+//! The function is only made for primitives that are used that way
+//! so calls in the head position are still emitted inline.
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::OnceLock;
 
 use cranelift::frontend::FunctionBuilder;
 use cranelift::prelude::*;
@@ -18,6 +44,7 @@ use crate::compiler::JIT;
 use crate::conversions;
 use crate::fatal;
 use crate::fatal::emit_check_arg_count;
+use crate::foreign::emit_host_call;
 use crate::heap::emit_alloc;
 use crate::procedures::LustFn;
 use crate::Expr;
@@ -45,6 +72,12 @@ pub(crate) fn emit_primitive<F>(
 where
     F: FnMut(&mut Context) -> Result<Value, String>,
 {
+    // A program compiled earlier by the JIT may have already emitted
+    // it.
+    if let Some(cranelift_module::FuncOrDataId::Func(_)) = jit.module.get_name(name) {
+        return Ok(primitive_fn(name, arity));
+    }
+
     let word = jit.module.target_config().pointer_type();
 
     // Additional closure argument
@@ -66,6 +99,7 @@ where
         HashMap::new(),
         HashMap::new(),
         Vec::new(),
+        jit.options.clone(),
     );
 
     let entry_block = ctx.builder.create_block();
@@ -96,13 +130,20 @@ where
 
     jit.module.clear_context(&mut jit.context);
 
-    Ok(LustFn {
+    Ok(primitive_fn(name, arity))
+}
+
+/// Returns the descriptor of the primitive function NAME.
+fn primitive_fn(name: &str, arity: usize) -> LustFn {
+    LustFn {
         name: name.to_string(),
         params: (0..arity).map(|n| n.to_string()).collect(),
         body: vec![],
         free_variables: vec![],
         varadic_symbol: None,
-    })
+        form: None,
+        binding: None,
+    }
 }
 
 /// Collects all of the primitive functions that are used in higher
@@ -180,6 +221,20 @@ pub(crate) fn emit_primitives(
         })?);
     }
 
+    if higher_order_primitives.contains("abs") {
+        res.push(emit_primitive("abs", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
+            let accum = args[0];
+
+            fatal::emit_check_int(accum, ctx)?;
+
+            Ok(emit_abs(accum, ctx))
+        })?);
+    }
+
     if higher_order_primitives.contains("print") {
         res.push(emit_primitive("print", 1, jit, |ctx| {
             let block = ctx.builder.current_block().unwrap();
@@ -275,6 +330,18 @@ pub(crate) fn emit_primitives(
         })?);
     }
 
+    for name in crate::chars::CHAR_PREDICATES {
+        if higher_order_primitives.contains(name) {
+            res.push(emit_primitive(name, 1, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(1, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, 1);
+                crate::chars::emit_char_predicate(name, args[0], ctx)
+            })?);
+        }
+    }
+
     if higher_order_primitives.contains("null?") {
         res.push(emit_primitive("null?", 1, jit, |ctx| {
             let block = ctx.builder.current_block().unwrap();
@@ -311,6 +378,28 @@ pub(crate) fn emit_primitives(
         })?);
     }
 
+    for name in ["positive?", "negative?", "even?", "odd?"] {
+        if higher_order_primitives.contains(name) {
+            res.push(emit_primitive(name, 1, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(1, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, 1);
+                emit_numeric_predicate(name, args[0], ctx)
+            })?);
+        }
+    }
+
+    if higher_order_primitives.contains("identity") {
+        res.push(emit_primitive("identity", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
+            Ok(args[0])
+        })?);
+    }
+
     if higher_order_primitives.contains("not") {
         res.push(emit_primitive("not", 1, jit, |ctx| {
             let block = ctx.builder.current_block().unwrap();
@@ -319,12 +408,7 @@ pub(crate) fn emit_primitives(
             let args = get_primitive_args(ctx, block, 1);
             let accum = args[0];
 
-            fatal::emit_check_bool(accum, ctx)?;
-
-            // To get the not of a boolean, subtract one from it and
-            // then take the absolute value.
-            let accum = ctx.builder.ins().sshr_imm(accum, conversions::BOOL_SHIFT);
-            let accum = ctx.builder.ins().icmp_imm(IntCC::Equal, accum, 0);
+            let accum = emit_is_falsey(accum, ctx);
             let accum = ctx.builder.ins().bint(ctx.word, accum);
 
             Ok(emit_word_to_bool(accum, &mut ctx.builder))
@@ -367,25 +451,16 @@ pub(crate) fn emit_primitives(
         })?);
     }
 
-    if higher_order_primitives.contains("pair?") {
-        res.push(emit_primitive("pair?", 1, jit, |ctx| {
-            let block = ctx.builder.current_block().unwrap();
-            let args = ctx.builder.block_params(block);
-            emit_check_arg_count(1, args[1], ctx, false)?;
-            let args = get_primitive_args(ctx, block, 1);
-            let accum = args[0];
-
-            let accum = ctx
-                .builder
-                .ins()
-                .band_imm(accum, conversions::HEAP_TAG_MASK);
-            let accum = ctx
-                .builder
-                .ins()
-                .icmp_imm(IntCC::Equal, accum, conversions::PAIR_TAG);
-            let accum = ctx.builder.ins().bint(word, accum);
-            Ok(emit_word_to_bool(accum, &mut ctx.builder))
-        })?);
+    for name in ["pair?", "atom?"] {
+        if higher_order_primitives.contains(name) {
+            res.push(emit_primitive(name, 1, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(1, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, 1);
+                Ok(emit_pair_predicate(name, args[0], ctx))
+            })?);
+        }
     }
 
     if higher_order_primitives.contains("closure?") {
@@ -409,44 +484,111 @@ pub(crate) fn emit_primitives(
         })?);
     }
 
-    if higher_order_primitives.contains("add") {
-        res.push(emit_primitive("add", 2, jit, |ctx| {
-            let block = ctx.builder.current_block().unwrap();
-            let args = ctx.builder.block_params(block);
-            emit_check_arg_count(2, args[1], ctx, false)?;
+    for name in ["add", "sub", "mul", "div", "min", "max"] {
+        if higher_order_primitives.contains(name) {
+            let (min_args, identity, op) = arithmetic_op(name);
+            res.push(emit_primitive(name, min_args, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                let argc = args[1];
+                let argloc = args[2];
+                emit_check_arg_count(min_args, argc, ctx, true)?;
+
+                let identity = ctx.builder.ins().iconst(ctx.word, identity);
+                let zero = ctx.builder.ins().iconst(ctx.word, 0);
+
+                // Operations that take at least one argument fold
+                // left starting from their first argument unless they
+                // were only given one in which case the identity is
+                // the left operand.
+                let (accum, start) = if min_args == 1 {
+                    let first = ctx.builder.ins().load(ctx.word, MemFlags::new(), argloc, 0);
+                    fatal::emit_check_int(first, ctx)?;
+                    let one = ctx.builder.ins().iconst(ctx.word, 1);
+                    let is_unary = ctx.builder.ins().icmp_imm(IntCC::Equal, argc, 1);
+                    (
+                        ctx.builder.ins().select(is_unary, identity, first),
+                        ctx.builder.ins().select(is_unary, zero, one),
+                    )
+                } else {
+                    (identity, zero)
+                };
+
+                let header_block = ctx.builder.create_block();
+                let body_block = ctx.builder.create_block();
+                let exit_block = ctx.builder.create_block();
+                ctx.builder.append_block_param(header_block, ctx.word);
+                ctx.builder.append_block_param(header_block, ctx.word);
+                ctx.builder.append_block_param(exit_block, ctx.word);
+
+                ctx.builder.ins().jump(header_block, &[accum, start]);
+
+                ctx.builder.switch_to_block(header_block);
+                let accum = ctx.builder.block_params(header_block)[0];
+                let i = ctx.builder.block_params(header_block)[1];
+                let done = ctx.builder.ins().icmp(IntCC::Equal, i, argc);
+                ctx.builder.ins().brnz(done, exit_block, &[accum]);
+                ctx.builder.ins().jump(body_block, &[]);
+
+                ctx.builder.switch_to_block(body_block);
+                ctx.builder.seal_block(body_block);
+                let offset = ctx.builder.ins().imul_imm(i, ctx.word.bytes() as i64);
+                let address = ctx.builder.ins().iadd(argloc, offset);
+                let arg = ctx
+                    .builder
+                    .ins()
+                    .load(ctx.word, MemFlags::new(), address, 0);
+                fatal::emit_check_int(arg, ctx)?;
+                let accum = op(ctx, accum, arg)?;
+                let i = ctx.builder.ins().iadd_imm(i, 1);
+                ctx.builder.ins().jump(header_block, &[accum, i]);
+                ctx.builder.seal_block(header_block);
+
+                ctx.builder.switch_to_block(exit_block);
+                ctx.builder.seal_block(exit_block);
+                Ok(ctx.builder.block_params(exit_block)[0])
+            })?);
+        }
+    }
 
-            let args = get_primitive_args(ctx, block, 2);
-            let left = args[0];
-            let right = args[1];
+    for name in ["mod", "rem"] {
+        if higher_order_primitives.contains(name) {
+            res.push(emit_primitive(name, 2, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(2, args[1], ctx, false)?;
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+                let args = get_primitive_args(ctx, block, 2);
+                let left = args[0];
+                let right = args[1];
 
-            Ok(ctx.builder.ins().iadd(left, right))
-        })?);
+                fatal::emit_check_int(left, ctx)?;
+                fatal::emit_check_int(right, ctx)?;
+
+                emit_division(name, left, right, ctx)
+            })?);
+        }
     }
 
-    if higher_order_primitives.contains("sub") {
-        res.push(emit_primitive("sub", 2, jit, |ctx| {
+    if higher_order_primitives.contains("expt") {
+        res.push(emit_primitive("expt", 2, jit, |ctx| {
             let block = ctx.builder.current_block().unwrap();
             let args = ctx.builder.block_params(block);
             emit_check_arg_count(2, args[1], ctx, false)?;
 
             let args = get_primitive_args(ctx, block, 2);
-            let left = args[0];
-            let right = args[1];
-
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+            let base = args[0];
+            let exp = args[1];
 
-            let right = ctx.builder.ins().ineg(right);
+            fatal::emit_check_int(base, ctx)?;
+            fatal::emit_check_int(exp, ctx)?;
 
-            Ok(ctx.builder.ins().iadd(left, right))
+            emit_expt(base, exp, ctx)
         })?);
     }
 
-    if higher_order_primitives.contains("mul") {
-        res.push(emit_primitive("mul", 2, jit, |ctx| {
+    if higher_order_primitives.contains("eq") {
+        res.push(emit_primitive("eq", 2, jit, |ctx| {
             let block = ctx.builder.current_block().unwrap();
             let args = ctx.builder.block_params(block);
             emit_check_arg_count(2, args[1], ctx, false)?;
@@ -455,24 +597,14 @@ pub(crate) fn emit_primitives(
             let left = args[0];
             let right = args[1];
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
-
-            let accum = ctx.builder.ins().imul(left, right);
-
-            // At this point we've picked up an extra 2^2 so we need
-            // to right shift it out.
-            //
-            // NOTE: It is possible that it would be more reasonable
-            // to shift things out first. I'm worried here that this
-            // will cause integer overflows where we wouldn't normally
-            // expect them.
-            Ok(ctx.builder.ins().sshr_imm(accum, 2))
+            let accum = ctx.builder.ins().icmp(IntCC::Equal, left, right);
+            let accum = ctx.builder.ins().bint(word, accum);
+            Ok(emit_word_to_bool(accum, &mut ctx.builder))
         })?);
     }
 
-    if higher_order_primitives.contains("eq") {
-        res.push(emit_primitive("eq", 2, jit, |ctx| {
+    if higher_order_primitives.contains("neq") {
+        res.push(emit_primitive("neq", 2, jit, |ctx| {
             let block = ctx.builder.current_block().unwrap();
             let args = ctx.builder.block_params(block);
             emit_check_arg_count(2, args[1], ctx, false)?;
@@ -481,7 +613,7 @@ pub(crate) fn emit_primitives(
             let left = args[0];
             let right = args[1];
 
-            let accum = ctx.builder.ins().icmp(IntCC::Equal, left, right);
+            let accum = ctx.builder.ins().icmp(IntCC::NotEqual, left, right);
             let accum = ctx.builder.ins().bint(word, accum);
             Ok(emit_word_to_bool(accum, &mut ctx.builder))
         })?);
@@ -535,17 +667,7 @@ pub(crate) fn emit_primitives(
             emit_check_arg_count(2, args[1], ctx, false)?;
 
             let args = get_primitive_args(ctx, block, 2);
-            let data = args[0];
-            let next = args[1];
-
-            let storage = emit_alloc((ctx.word.bytes() * 2).into(), ctx)?;
-
-            ctx.builder.ins().store(MemFlags::new(), data, storage, 0);
-            ctx.builder
-                .ins()
-                .store(MemFlags::new(), next, storage, word.bytes() as i32);
-
-            Ok(ctx.builder.ins().bor_imm(storage, conversions::PAIR_TAG))
+            emit_cons(args[0], args[1], ctx)
         })?);
     }
 
@@ -560,9 +682,7 @@ pub(crate) fn emit_primitives(
 
             fatal::emit_check_pair(pair, ctx)?;
 
-            let address = ctx.builder.ins().band_imm(pair, conversions::HEAP_PTR_MASK);
-
-            Ok(ctx.builder.ins().load(word, MemFlags::new(), address, 0))
+            Ok(crate::vectors::emit_car(pair, ctx))
         })?);
     }
 
@@ -577,333 +697,1360 @@ pub(crate) fn emit_primitives(
 
             fatal::emit_check_pair(pair, ctx)?;
 
-            let address = ctx.builder.ins().band_imm(pair, conversions::HEAP_PTR_MASK);
+            Ok(crate::vectors::emit_cdr(pair, ctx))
+        })?);
+    }
 
-            Ok(ctx
-                .builder
-                .ins()
-                .load(word, MemFlags::new(), address, word.bytes() as i32))
+    if higher_order_primitives.contains("getenv") {
+        res.push(emit_primitive("getenv", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
+
+            emit_host_call("lustc_getenv", &args, ctx)
         })?);
     }
 
-    Ok(res)
-}
+    if higher_order_primitives.contains("display") {
+        res.push(emit_primitive("display", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
 
-pub(crate) fn emit_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
-    debug_assert!(string_is_primitive(name));
-    Ok(match name {
-        "add1" => {
-            check_arg_len("add1", args, 1)?;
-            let accum = emit_expr(&args[0], ctx)?;
+            emit_host_call("lustc_display", &args, ctx)
+        })?);
+    }
 
-            fatal::emit_check_int(accum, ctx)?;
+    if higher_order_primitives.contains("newline") {
+        res.push(emit_primitive("newline", 0, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(0, args[1], ctx, false)?;
 
-            ctx.builder
-                .ins()
-                .iadd_imm(accum, Expr::Integer(1).immediate_rep())
-        }
-        "integer->char" => {
-            check_arg_len("integer->char", args, 1)?;
+            emit_host_call("lustc_newline", &[], ctx)
+        })?);
+    }
 
-            // To convert an integer to a character we left shift by 6
-            // and then tag it with the character tag.
-            let accum = emit_expr(&args[0], ctx)?;
+    if higher_order_primitives.contains("flush-output") {
+        res.push(emit_primitive("flush-output", 0, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(0, args[1], ctx, false)?;
 
-            fatal::emit_check_int(accum, ctx)?;
+            emit_host_call("lustc_flush_output", &[], ctx)
+        })?);
+    }
 
-            let accum = ctx.builder.ins().ishl_imm(accum, 6);
-            let accum = ctx.builder.ins().bor_imm(accum, conversions::CHAR_TAG);
-            accum
-        }
-        "char->integer" => {
-            check_arg_len("char->integer", args, 1)?;
+    if higher_order_primitives.contains("command-line-args") {
+        res.push(emit_primitive("command-line-args", 0, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(0, args[1], ctx, false)?;
 
-            // To convert a char to an integer we right shift by 6 and
-            // then tag it with the integer tag.
-            //
-            // NOTE: We're skipping some of the work here because
-            // we're assuming the input is an integer and as such
-            // there is no need to tag after the right shift.
-            let accum = emit_expr(&args[0], ctx)?;
+            emit_host_call("lustc_command_line_args", &[], ctx)
+        })?);
+    }
 
-            fatal::emit_check_char(accum, ctx)?;
+    if higher_order_primitives.contains("heap-stats") {
+        res.push(emit_primitive("heap-stats", 0, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(0, args[1], ctx, false)?;
 
-            let accum = ctx.builder.ins().ushr_imm(accum, 6);
-            accum
+            let allocated = crate::data::emit_data_access(crate::heap::HEAP_ALLOCATED, ctx)?;
+            emit_host_call("lustc_heap_stats", &[allocated], ctx)
+        })?);
+    }
+
+    if higher_order_primitives.contains("make-condition") {
+        res.push(emit_primitive("make-condition", 3, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(3, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 3);
+
+            crate::conditions::emit_make_condition(args[0], args[1], args[2], ctx)
+        })?);
+    }
+
+    if higher_order_primitives.contains("condition?") {
+        res.push(emit_primitive("condition?", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
+
+            let accum = crate::conditions::emit_is_condition(args[0], ctx);
+            Ok(emit_word_to_bool(accum, &mut ctx.builder))
+        })?);
+    }
+
+    for name in ["condition-type", "condition-message", "condition-data"] {
+        if higher_order_primitives.contains(name) {
+            res.push(emit_primitive(name, 1, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(1, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, 1);
+
+                let field = crate::conditions::accessor_field(name).unwrap();
+                crate::conditions::emit_condition_field(args[0], field, ctx)
+            })?);
         }
-        "null?" => {
-            check_arg_len("null?", args, 1)?;
-            let accum = emit_expr(&args[0], ctx)?;
-            let accum = ctx
+    }
+
+    if higher_order_primitives.contains("make-vector") {
+        res.push(emit_primitive("make-vector", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            let (argc, argloc) = (args[1], args[2]);
+            emit_check_arg_count(1, argc, ctx, true)?;
+            fatal::emit_check_max_arg_count(2, argc, ctx)?;
+            let length = ctx.builder.ins().load(ctx.word, MemFlags::new(), argloc, 0);
+
+            // The fill is only there to be loaded if it was passed.
+            let fill_block = ctx.builder.create_block();
+            let done_block = ctx.builder.create_block();
+            ctx.builder.append_block_param(done_block, ctx.word);
+            let nil = ctx
                 .builder
                 .ins()
-                .icmp_imm(IntCC::Equal, accum, conversions::NIL_VALUE);
-            // The result of this comparason is a boolean value so we
-            // need to convert it back to a ctx.word before working on it.
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
-        }
-        "zero?" => {
-            check_arg_len("zero?", args, 1)?;
+                .iconst(ctx.word, Expr::Nil.immediate_rep());
+            let has_fill = ctx.builder.ins().icmp_imm(IntCC::Equal, argc, 2);
+            ctx.builder.ins().brnz(has_fill, fill_block, &[]);
+            ctx.builder.ins().jump(done_block, &[nil]);
+
+            ctx.builder.switch_to_block(fill_block);
+            ctx.builder.seal_block(fill_block);
+            let word_size = ctx.word.bytes() as i32;
+            let fill = ctx
+                .builder
+                .ins()
+                .load(ctx.word, MemFlags::new(), argloc, word_size);
+            ctx.builder.ins().jump(done_block, &[fill]);
 
-            let accum = emit_expr(&args[0], ctx)?;
+            ctx.builder.switch_to_block(done_block);
+            ctx.builder.seal_block(done_block);
+            let fill = ctx.builder.block_params(done_block)[0];
+            crate::vectors::emit_make_vector(length, fill, ctx)
+        })?);
+    }
 
-            let accum =
-                ctx.builder
-                    .ins()
-                    .icmp_imm(IntCC::Equal, accum, Expr::Integer(0).immediate_rep());
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
+    for name in [
+        "vector?",
+        "vector-length",
+        "vector-ref",
+        "vector-set!",
+        "list->vector",
+        "vector->list",
+        "vector-map",
+        "vector-for-each",
+        "vector-for-each-indexed",
+        "vector-copy",
+        "vector-fill!",
+        "vector-slice",
+        "vector-sort!",
+    ] {
+        if higher_order_primitives.contains(name) {
+            let arity = crate::vectors::vector_primitive_arity(name);
+            res.push(emit_primitive(name, arity, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(arity, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, arity);
+                crate::vectors::emit_vector_primitive(name, &args, ctx)
+            })?);
         }
-        "not" => {
-            check_arg_len("not", args, 1)?;
+    }
 
-            let accum = emit_expr(&args[0], ctx)?;
+    for name in [
+        "make-heap",
+        "heap-push",
+        "heap-pop",
+        "heap-peek",
+        "heap-size",
+    ] {
+        if higher_order_primitives.contains(name) {
+            let arity = crate::priority::priority_primitive_arity(name);
+            res.push(emit_primitive(name, arity, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(arity, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, arity);
+                crate::priority::emit_priority_primitive(name, &args, ctx)
+            })?);
+        }
+    }
 
-            fatal::emit_check_bool(accum, ctx)?;
+    for name in crate::stringbuilder::STRING_BUILDER_PRIMITIVES {
+        if higher_order_primitives.contains(name) {
+            let arity = crate::stringbuilder::string_builder_primitive_arity(name);
+            res.push(emit_primitive(name, arity, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(arity, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, arity);
+                crate::stringbuilder::emit_string_builder_primitive(name, &args, ctx)
+            })?);
+        }
+    }
 
-            // To get the not of a boolean, subtract one from it and
-            // then take the absolute value.
-            let accum = ctx.builder.ins().sshr_imm(accum, conversions::BOOL_SHIFT);
-            let accum = ctx.builder.ins().icmp_imm(IntCC::Equal, accum, 0);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
+    for name in [
+        "string-append",
+        "string-split",
+        "string-join",
+        "string-length",
+        "string-ref",
+        "string-upcase",
+        "string-downcase",
+        "string-trim",
+        "string-slice",
+        "string-index",
+        "string-contains",
+    ] {
+        if higher_order_primitives.contains(name) {
+            let arity = crate::strings::string_primitive_arity(name);
+            res.push(emit_primitive(name, arity, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(arity, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, arity);
+                crate::strings::emit_string_primitive(name, &args, ctx)
+            })?);
         }
-        "integer?" => {
-            check_arg_len("integer?", args, 1)?;
+    }
 
-            let accum = emit_expr(&args[0], ctx)?;
+    for name in ["length", "list-tail", "take", "last", "list-copy"] {
+        if higher_order_primitives.contains(name) {
+            let arity = crate::sublists::sublist_primitive_arity(name);
+            res.push(emit_primitive(name, arity, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(arity, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, arity);
+                crate::sublists::emit_sublist_primitive(name, &args, ctx)
+            })?);
+        }
+    }
 
-            let accum = ctx.builder.ins().band_imm(accum, conversions::FIXNUM_MASK);
-            let accum = ctx
-                .builder
-                .ins()
-                .icmp_imm(IntCC::Equal, accum, conversions::FIXNUM_TAG);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
+    for name in ["make-list", "iota"] {
+        if higher_order_primitives.contains(name) {
+            let (min_args, _) = crate::generators::generator_primitive_arity(name);
+            res.push(emit_primitive(name, min_args, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                let (argc, argloc) = (args[1], args[2]);
+                let args = crate::generators::emit_load_args(name, argc, argloc, ctx)?;
+                crate::generators::emit_generator_primitive(name, &args, ctx)
+            })?);
         }
-        "boolean?" => {
-            check_arg_len("boolean?", args, 1)?;
+    }
 
-            let accum = emit_expr(&args[0], ctx)?;
+    if higher_order_primitives.contains("make-record") {
+        res.push(emit_primitive("make-record", 2, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(2, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 2);
+            crate::records::emit_make_record(args[0], args[1], ctx)
+        })?);
+    }
 
-            let accum = ctx.builder.ins().band_imm(accum, conversions::BOOL_MASK);
-            let accum = ctx
-                .builder
-                .ins()
-                .icmp_imm(IntCC::Equal, accum, conversions::BOOL_TAG);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
-        }
-        "pair?" => {
-            check_arg_len("pair?", args, 1)?;
+    if higher_order_primitives.contains("record-type") {
+        res.push(emit_primitive("record-type", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
+            Ok(crate::records::emit_record_type(args[0], ctx))
+        })?);
+    }
 
-            let accum = emit_expr(&args[0], ctx)?;
+    if higher_order_primitives.contains("record-ref") {
+        res.push(emit_primitive("record-ref", 3, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(3, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 3);
+            crate::records::emit_record_ref(args[0], args[1], args[2], ctx)
+        })?);
+    }
 
-            let accum = ctx
-                .builder
-                .ins()
-                .band_imm(accum, conversions::HEAP_TAG_MASK);
-            let accum = ctx
-                .builder
-                .ins()
-                .icmp_imm(IntCC::Equal, accum, conversions::PAIR_TAG);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
-        }
-        "closure?" => {
-            check_arg_len("closure?", args, 1)?;
+    if higher_order_primitives.contains("hash") {
+        res.push(emit_primitive("hash", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
 
-            let accum = emit_expr(&args[0], ctx)?;
+            emit_host_call("lustc_hash", &args, ctx)
+        })?);
+    }
 
-            let accum = ctx
-                .builder
-                .ins()
-                .band_imm(accum, conversions::HEAP_TAG_MASK);
-            let accum = ctx
-                .builder
-                .ins()
-                .icmp_imm(IntCC::Equal, accum, conversions::CLOSURE_TAG);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
-        }
-        "add" => {
-            check_arg_len("add", args, 2)?;
+    if higher_order_primitives.contains("type-of") {
+        res.push(emit_primitive("type-of", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
 
-            let left = emit_expr(&args[0], ctx)?;
-            let right = emit_expr(&args[1], ctx)?;
+            emit_host_call("lustc_type_of", &args, ctx)
+        })?);
+    }
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+    if higher_order_primitives.contains("write") {
+        res.push(emit_primitive("write", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
 
-            ctx.builder.ins().iadd(left, right)
-        }
-        "sub" => {
-            check_arg_len("sub", args, 2)?;
+            emit_host_call("lustc_write", &args, ctx)
+        })?);
+    }
 
-            let left = emit_expr(&args[0], ctx)?;
-            let right = emit_expr(&args[1], ctx)?;
+    if higher_order_primitives.contains("read") {
+        res.push(emit_primitive("read", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+            crate::serialize::emit_read(args[0], ctx)
+        })?);
+    }
 
-            let right = ctx.builder.ins().ineg(right);
+    if higher_order_primitives.contains("assert-equal") {
+        res.push(emit_primitive("assert-equal", 2, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(2, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 2);
 
-            ctx.builder.ins().iadd(left, right)
-        }
-        "mul" => {
-            check_arg_len("mul", args, 2)?;
+            emit_host_call("lustc_assert_equal", &args, ctx)
+        })?);
+    }
 
-            let left = emit_expr(&args[0], ctx)?;
-            let right = emit_expr(&args[1], ctx)?;
+    if higher_order_primitives.contains("disassemble") {
+        res.push(emit_primitive("disassemble", 1, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(1, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 1);
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+            crate::asm::emit_disassemble(args[0], ctx)
+        })?);
+    }
 
-            let accum = ctx.builder.ins().imul(left, right);
+    if higher_order_primitives.contains("foldr") {
+        res.push(emit_primitive("foldr", 3, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(3, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 3);
+            crate::iteration::emit_foldr(args[0], args[1], args[2], ctx)
+        })?);
+    }
 
-            // At this point we've picked up an extra 2^2 so we need
-            // to right shift it out.
-            //
-            // NOTE: It is possible that it would be more reasonable
-            // to shift things out first. I'm worried here that this
-            // will cause integer overflows where we wouldn't normally
-            // expect them.
-            ctx.builder.ins().sshr_imm(accum, 2)
-        }
-        "eq" => {
-            check_arg_len("eq", args, 2)?;
+    if higher_order_primitives.contains("reduce") {
+        res.push(emit_primitive("reduce", 3, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(3, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 3);
+            crate::iteration::emit_reduce(args[0], args[1], args[2], ctx)
+        })?);
+    }
 
-            let left = emit_expr(&args[0], ctx)?;
-            let right = emit_expr(&args[1], ctx)?;
+    if higher_order_primitives.contains("list") {
+        res.push(emit_primitive("list", 0, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            let (argc, argloc) = (args[1], args[2]);
+            emit_check_arg_count(0, argc, ctx, true)?;
+            emit_contigous_to_list(ctx, argloc, argc)
+        })?);
+    }
 
-            let accum = ctx.builder.ins().icmp(IntCC::Equal, left, right);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
+    if higher_order_primitives.contains("apply") {
+        res.push(emit_primitive("apply", 2, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(2, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 2);
+            crate::procedures::emit_apply(args[0], args[1], ctx)
+        })?);
+    }
+
+    if higher_order_primitives.contains("for-each") {
+        res.push(emit_primitive("for-each", 2, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(2, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 2);
+            crate::iteration::emit_for_each(args[0], args[1], ctx)
+        })?);
+    }
+
+    if higher_order_primitives.contains("sort") {
+        res.push(emit_primitive("sort", 2, jit, |ctx| {
+            let block = ctx.builder.current_block().unwrap();
+            let args = ctx.builder.block_params(block);
+            emit_check_arg_count(2, args[1], ctx, false)?;
+            let args = get_primitive_args(ctx, block, 2);
+            crate::sort::emit_sort(args[0], args[1], ctx)
+        })?);
+    }
+
+    for name in ["equal", "member", "assoc", "memq", "assq"] {
+        if higher_order_primitives.contains(name) {
+            res.push(emit_primitive(name, 2, jit, |ctx| {
+                let block = ctx.builder.current_block().unwrap();
+                let args = ctx.builder.block_params(block);
+                emit_check_arg_count(2, args[1], ctx, false)?;
+                let args = get_primitive_args(ctx, block, 2);
+
+                emit_host_call(&format!("lustc_{}", name), &args, ctx)
+            })?);
         }
-        "lt" => {
-            check_arg_len("lt", args, 2)?;
-            let left = emit_expr(&args[0], ctx)?;
-            let right = emit_expr(&args[1], ctx)?;
+    }
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+    Ok(res)
+}
 
-            let accum = ctx.builder.ins().icmp(IntCC::SignedLessThan, left, right);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
+/// Emits the code for a call to a primitive. Takes the name of the
+/// primitive, which is how a function that emits calls to more than
+/// one of them tells which it is emitting, and its arguments, which
+/// haven't been evaluated yet.
+type PrimcallEmitter = fn(&str, &[Expr], &mut Context) -> Result<Value, String>;
+
+/// The names that are read as another primitive's name. See
+/// `primitive_alias`.
+const PRIMITIVE_ALIASES: [&str; 9] = ["+", "-", "*", "/", "<", ">", "drop", "cons?", "negate"];
+
+/// Returns the table of every primitive and the function that emits
+/// calls to it. Every call site and every list that passes look up
+/// their head in here so it is a hash table rather than a chain of
+/// string comparisons, which would take time linear in the number of
+/// primitives for each of them. The inline and library primitives
+/// each have their own emitter, as do the few that share one because
+/// they only differ in an operation. The primitives defined in other
+/// modules share their module's emitter, which picks between a
+/// handful of names. It is built the first time it is needed and is
+/// the same for every program after that.
+fn primcall_table() -> &'static HashMap<&'static str, PrimcallEmitter> {
+    static TABLE: OnceLock<HashMap<&'static str, PrimcallEmitter>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let families: [(&[&'static str], PrimcallEmitter); 8] = [
+            (&crate::vectors::VECTOR_PRIMITIVES, emit_vector_primcall),
+            (
+                &crate::priority::PRIORITY_PRIMITIVES,
+                emit_priority_primcall,
+            ),
+            (
+                &crate::stringbuilder::STRING_BUILDER_PRIMITIVES,
+                emit_string_builder_primcall,
+            ),
+            (&crate::sublists::SUBLIST_PRIMITIVES, emit_sublist_primcall),
+            (
+                &crate::generators::GENERATOR_PRIMITIVES,
+                crate::generators::emit_generator_primcall,
+            ),
+            (&crate::strings::STRING_PRIMITIVES, emit_string_primcall),
+            (&crate::chars::CHAR_PREDICATES, emit_char_primcall),
+            // Renaming replaces an alias with the name it stands for
+            // so these are only here for the passes that run before.
+            (&PRIMITIVE_ALIASES, |name, args, ctx| {
+                emit_primcall(primitive_alias(name).unwrap(), args, ctx)
+            }),
+        ];
+        let own = INLINE_PRIMCALLS.iter().chain(LIBRARY_PRIMCALLS.iter());
+        let shared = families
+            .iter()
+            .flat_map(|(names, emitter)| names.iter().map(move |name| (*name, *emitter)));
+        let mut table = HashMap::new();
+        for (name, emitter) in own.copied().chain(shared) {
+            let previous = table.insert(name, emitter);
+            debug_assert!(previous.is_none(), "{} is listed twice", name);
         }
-        "gt" => {
-            check_arg_len("gt", args, 2)?;
-            let left = emit_expr(&args[0], ctx)?;
-            let right = emit_expr(&args[1], ctx)?;
+        table
+    })
+}
 
-            fatal::emit_check_int(left, ctx)?;
-            fatal::emit_check_int(right, ctx)?;
+/// Emits the code for a call to the primitive NAME with ARGS, which
+/// haven't been evaluated yet.
+pub(crate) fn emit_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    match primcall_table().get(name) {
+        Some(emit) => emit(name, args, ctx),
+        None => panic!("non primitive in emit_primcall: {}", name),
+    }
+}
 
-            let accum = ctx
-                .builder
+/// The primitives that are emitted directly rather than calling into
+/// the host or another module and the functions that emit them.
+const INLINE_PRIMCALLS: [(&str, PrimcallEmitter); 34] = [
+    ("add1", |_, args, ctx| {
+        check_arg_len("add1", args, 1)?;
+        let accum = emit_expr(&args[0], ctx)?;
+
+        fatal::emit_check_int(accum, ctx)?;
+
+        Ok(ctx
+            .builder
+            .ins()
+            .iadd_imm(accum, Expr::Integer(1).immediate_rep()))
+    }),
+    ("abs", |_, args, ctx| {
+        check_arg_len("abs", args, 1)?;
+        let accum = emit_expr(&args[0], ctx)?;
+
+        fatal::emit_check_int(accum, ctx)?;
+
+        Ok(emit_abs(accum, ctx))
+    }),
+    ("integer->char", |_, args, ctx| {
+        check_arg_len("integer->char", args, 1)?;
+
+        // To convert an integer to a character we left shift by 6
+        // and then tag it with the character tag.
+        let accum = emit_expr(&args[0], ctx)?;
+
+        fatal::emit_check_int(accum, ctx)?;
+
+        let accum = ctx.builder.ins().ishl_imm(accum, 6);
+        let accum = ctx.builder.ins().bor_imm(accum, conversions::CHAR_TAG);
+        Ok(accum)
+    }),
+    ("char->integer", |_, args, ctx| {
+        check_arg_len("char->integer", args, 1)?;
+
+        // To convert a char to an integer we right shift by 6 and
+        // then tag it with the integer tag.
+        //
+        // NOTE: We're skipping some of the work here because
+        // we're assuming the input is an integer and as such
+        // there is no need to tag after the right shift.
+        let accum = emit_expr(&args[0], ctx)?;
+
+        fatal::emit_check_char(accum, ctx)?;
+
+        let accum = ctx.builder.ins().ushr_imm(accum, 6);
+        Ok(accum)
+    }),
+    ("null?", |_, args, ctx| {
+        check_arg_len("null?", args, 1)?;
+        let accum = emit_expr(&args[0], ctx)?;
+        let accum = ctx
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, accum, conversions::NIL_VALUE);
+        // The result of this comparason is a boolean value so we
+        // need to convert it back to a ctx.word before working on it.
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("zero?", |_, args, ctx| {
+        check_arg_len("zero?", args, 1)?;
+
+        let accum = emit_expr(&args[0], ctx)?;
+
+        let accum =
+            ctx.builder
                 .ins()
-                .icmp(IntCC::SignedGreaterThan, left, right);
-            let accum = ctx.builder.ins().bint(ctx.word, accum);
-            emit_word_to_bool(accum, &mut ctx.builder)
+                .icmp_imm(IntCC::Equal, accum, Expr::Integer(0).immediate_rep());
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("positive?", emit_numeric_predicate_primcall),
+    ("negative?", emit_numeric_predicate_primcall),
+    ("even?", emit_numeric_predicate_primcall),
+    ("odd?", emit_numeric_predicate_primcall),
+    ("identity", |_, args, ctx| {
+        check_arg_len("identity", args, 1)?;
+        emit_expr(&args[0], ctx)
+    }),
+    ("not", |_, args, ctx| {
+        check_arg_len("not", args, 1)?;
+
+        // (not (not x)) is just the truthiness of x so rather than
+        // negating twice we negate the inner check.
+        let accum = match args[0].is_primcall() {
+            Some(("not", inner)) => {
+                check_arg_len("not", inner, 1)?;
+                let accum = emit_expr(&inner[0], ctx)?;
+                let accum = emit_is_falsey(accum, ctx);
+                ctx.builder.ins().bnot(accum)
+            }
+            _ => {
+                let accum = emit_expr(&args[0], ctx)?;
+                emit_is_falsey(accum, ctx)
+            }
+        };
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("integer?", |_, args, ctx| {
+        check_arg_len("integer?", args, 1)?;
+
+        let accum = emit_expr(&args[0], ctx)?;
+
+        let accum = ctx.builder.ins().band_imm(accum, conversions::FIXNUM_MASK);
+        let accum = ctx
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, accum, conversions::FIXNUM_TAG);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("boolean?", |_, args, ctx| {
+        check_arg_len("boolean?", args, 1)?;
+
+        let accum = emit_expr(&args[0], ctx)?;
+
+        let accum = ctx.builder.ins().band_imm(accum, conversions::BOOL_MASK);
+        let accum = ctx
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, accum, conversions::BOOL_TAG);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("pair?", emit_pair_predicate_primcall),
+    ("atom?", emit_pair_predicate_primcall),
+    ("closure?", |_, args, ctx| {
+        check_arg_len("closure?", args, 1)?;
+
+        let accum = emit_expr(&args[0], ctx)?;
+
+        let accum = ctx
+            .builder
+            .ins()
+            .band_imm(accum, conversions::HEAP_TAG_MASK);
+        let accum = ctx
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, accum, conversions::CLOSURE_TAG);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("add", emit_arithmetic_primcall),
+    ("sub", emit_arithmetic_primcall),
+    ("mul", emit_arithmetic_primcall),
+    ("div", emit_arithmetic_primcall),
+    ("min", emit_arithmetic_primcall),
+    ("max", emit_arithmetic_primcall),
+    ("mod", emit_division_primcall),
+    ("rem", emit_division_primcall),
+    ("expt", |_, args, ctx| {
+        check_arg_len("expt", args, 2)?;
+
+        let base = emit_expr(&args[0], ctx)?;
+        let exp = emit_expr(&args[1], ctx)?;
+
+        fatal::emit_check_int(base, ctx)?;
+        fatal::emit_check_int(exp, ctx)?;
+
+        emit_expt(base, exp, ctx)
+    }),
+    ("eq", |_, args, ctx| {
+        check_arg_len("eq", args, 2)?;
+
+        let left = emit_expr(&args[0], ctx)?;
+        let right = emit_expr(&args[1], ctx)?;
+
+        let accum = ctx.builder.ins().icmp(IntCC::Equal, left, right);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("neq", |_, args, ctx| {
+        check_arg_len("neq", args, 2)?;
+
+        let left = emit_expr(&args[0], ctx)?;
+        let right = emit_expr(&args[1], ctx)?;
+
+        let accum = ctx.builder.ins().icmp(IntCC::NotEqual, left, right);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("lt", |_, args, ctx| {
+        check_arg_len("lt", args, 2)?;
+        let left = emit_expr(&args[0], ctx)?;
+        let right = emit_expr(&args[1], ctx)?;
+
+        fatal::emit_check_int(left, ctx)?;
+        fatal::emit_check_int(right, ctx)?;
+
+        let accum = ctx.builder.ins().icmp(IntCC::SignedLessThan, left, right);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("gt", |_, args, ctx| {
+        check_arg_len("gt", args, 2)?;
+        let left = emit_expr(&args[0], ctx)?;
+        let right = emit_expr(&args[1], ctx)?;
+
+        fatal::emit_check_int(left, ctx)?;
+        fatal::emit_check_int(right, ctx)?;
+
+        let accum = ctx
+            .builder
+            .ins()
+            .icmp(IntCC::SignedGreaterThan, left, right);
+        let accum = ctx.builder.ins().bint(ctx.word, accum);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("cons", |_, args, ctx| {
+        check_arg_len("cons", args, 2)?;
+
+        let data = emit_expr(&args[0], ctx)?;
+        let next = emit_expr(&args[1], ctx)?;
+        emit_cons(data, next, ctx)
+    }),
+    ("list", |_, args, ctx| {
+        let vals = args
+            .iter()
+            .map(|a| emit_expr(a, ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+        // The list is built back to front so that every pair can
+        // point at the one after it.
+        let mut list = ctx
+            .builder
+            .ins()
+            .iconst(ctx.word, Expr::Nil.immediate_rep());
+        for val in vals.into_iter().rev() {
+            list = emit_cons(val, list, ctx)?;
         }
+        Ok(list)
+    }),
+    ("car", |_, args, ctx| {
+        check_arg_len("car", args, 1)?;
 
-        "cons" => {
-            check_arg_len("cons", args, 2)?;
+        let pair = emit_expr(&args[0], ctx)?;
 
-            let data = emit_expr(&args[0], ctx)?;
-            let next = emit_expr(&args[1], ctx)?;
+        fatal::emit_check_pair(pair, ctx)?;
 
-            let storage = emit_alloc((ctx.word.bytes() * 2).into(), ctx)?;
+        Ok(crate::vectors::emit_car(pair, ctx))
+    }),
+    ("cdr", |_, args, ctx| {
+        check_arg_len("cdr", args, 1)?;
 
-            ctx.builder.ins().store(MemFlags::new(), data, storage, 0);
-            ctx.builder
-                .ins()
-                .store(MemFlags::new(), next, storage, ctx.word.bytes() as i32);
+        let pair = emit_expr(&args[0], ctx)?;
 
-            ctx.builder.ins().bor_imm(storage, conversions::PAIR_TAG)
-        }
-        "car" => {
-            check_arg_len("car", args, 1)?;
+        fatal::emit_check_pair(pair, ctx)?;
 
-            let pair = emit_expr(&args[0], ctx)?;
+        Ok(crate::vectors::emit_cdr(pair, ctx))
+    }),
+];
 
-            fatal::emit_check_pair(pair, ctx)?;
+fn emit_numeric_predicate_primcall(
+    name: &str,
+    args: &[Expr],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    check_arg_len(name, args, 1)?;
+    let accum = emit_expr(&args[0], ctx)?;
+    emit_numeric_predicate(name, accum, ctx)
+}
 
-            let address = ctx.builder.ins().band_imm(pair, conversions::HEAP_PTR_MASK);
+fn emit_pair_predicate_primcall(
+    name: &str,
+    args: &[Expr],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    check_arg_len(name, args, 1)?;
+    let accum = emit_expr(&args[0], ctx)?;
+    Ok(emit_pair_predicate(name, accum, ctx))
+}
 
-            ctx.builder
-                .ins()
-                .load(ctx.word, MemFlags::new(), address, 0)
-        }
-        "cdr" => {
-            check_arg_len("cdr", args, 1)?;
+fn emit_arithmetic_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let (min_args, identity, op) = arithmetic_op(name);
+    if args.len() < min_args {
+        return Err(format!(
+            "{} expected at least {} args and got {}",
+            name,
+            min_args,
+            args.len()
+        ));
+    }
 
-            let pair = emit_expr(&args[0], ctx)?;
+    let mut vals = Vec::with_capacity(args.len());
+    for arg in args {
+        let val = emit_expr(arg, ctx)?;
+        fatal::emit_check_int(val, ctx)?;
+        vals.push(val);
+    }
 
-            fatal::emit_check_pair(pair, ctx)?;
+    // (sub x) and (div x) use their identity as the left
+    // operand. Otherwise we fold left starting from the first
+    // argument.
+    let (mut accum, rest) = if vals.len() > min_args {
+        (vals[0], &vals[1..])
+    } else {
+        (ctx.builder.ins().iconst(ctx.word, identity), &vals[..])
+    };
+    for val in rest {
+        accum = op(ctx, accum, *val)?;
+    }
+    Ok(accum)
+}
 
-            let address = ctx.builder.ins().band_imm(pair, conversions::HEAP_PTR_MASK);
+fn emit_division_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    check_arg_len(name, args, 2)?;
 
-            ctx.builder
-                .ins()
-                .load(ctx.word, MemFlags::new(), address, ctx.word.bytes() as i32)
-        }
+    let left = emit_expr(&args[0], ctx)?;
+    let right = emit_expr(&args[1], ctx)?;
 
-        "print" => {
-            check_arg_len("print", args, 1)?;
-            let arg = emit_expr(&args[0], ctx)?;
-            let args = vec![arg];
+    fatal::emit_check_int(left, ctx)?;
+    fatal::emit_check_int(right, ctx)?;
 
-            let mut sig = ctx.module.make_signature();
-            sig.params.push(AbiParam::new(ctx.word));
-            sig.returns.push(AbiParam::new(ctx.word));
+    emit_division(name, left, right, ctx)
+}
 
-            let callee = ctx
-                .module
-                .declare_function("print_lustc_word", cranelift_module::Linkage::Import, &sig)
-                .map_err(|e| e.to_string())?;
+/// The primitives that call into the host or are made of a few pieces
+/// from other modules and the functions that emit them.
+const LIBRARY_PRIMCALLS: [(&str, PrimcallEmitter); 32] = [
+    ("print", |_, args, ctx| {
+        check_arg_len("print", args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        let args = vec![arg];
+
+        let mut sig = ctx.module.make_signature();
+        sig.params.push(AbiParam::new(ctx.word));
+        sig.returns.push(AbiParam::new(ctx.word));
+
+        let callee = ctx
+            .module
+            .declare_function("print_lustc_word", cranelift_module::Linkage::Import, &sig)
+            .map_err(|e| e.to_string())?;
+
+        let local_callee = ctx
+            .module
+            .declare_func_in_func(callee, &mut ctx.builder.func);
+
+        let call = ctx.builder.ins().call(local_callee, &args);
+        Ok(ctx.builder.inst_results(call)[0])
+    }),
+    ("println", |_, args, ctx| {
+        check_arg_len("println", args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        let args = vec![arg];
+
+        let mut sig = ctx.module.make_signature();
+        sig.params.push(AbiParam::new(ctx.word));
+        sig.returns.push(AbiParam::new(ctx.word));
+
+        let callee = ctx
+            .module
+            .declare_function(
+                "println_lustc_word",
+                cranelift_module::Linkage::Import,
+                &sig,
+            )
+            .map_err(|e| e.to_string())?;
+
+        let local_callee = ctx
+            .module
+            .declare_func_in_func(callee, &mut ctx.builder.func);
+
+        let call = ctx.builder.ins().call(local_callee, &args);
+        Ok(ctx.builder.inst_results(call)[0])
+    }),
+    ("getenv", |_, args, ctx| {
+        check_arg_len("getenv", args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        emit_host_call("lustc_getenv", &[arg], ctx)
+    }),
+    ("display", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        emit_host_call("lustc_display", &[arg], ctx)
+    }),
+    ("newline", |name, args, ctx| {
+        check_arg_len(name, args, 0)?;
+        emit_host_call("lustc_newline", &[], ctx)
+    }),
+    ("flush-output", |name, args, ctx| {
+        check_arg_len(name, args, 0)?;
+        emit_host_call("lustc_flush_output", &[], ctx)
+    }),
+    ("command-line-args", |_, args, ctx| {
+        check_arg_len("command-line-args", args, 0)?;
+        emit_host_call("lustc_command_line_args", &[], ctx)
+    }),
+    ("heap-stats", |_, args, ctx| {
+        check_arg_len("heap-stats", args, 0)?;
+        let allocated = crate::data::emit_data_access(crate::heap::HEAP_ALLOCATED, ctx)?;
+        emit_host_call("lustc_heap_stats", &[allocated], ctx)
+    }),
+    ("make-condition", |name, args, ctx| {
+        check_arg_len(name, args, 3)?;
+        let args = args
+            .iter()
+            .map(|a| emit_expr(a, ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+        crate::conditions::emit_make_condition(args[0], args[1], args[2], ctx)
+    }),
+    ("condition?", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let accum = emit_expr(&args[0], ctx)?;
+        let accum = crate::conditions::emit_is_condition(accum, ctx);
+        Ok(emit_word_to_bool(accum, &mut ctx.builder))
+    }),
+    ("condition-type", emit_condition_accessor_primcall),
+    ("condition-message", emit_condition_accessor_primcall),
+    ("condition-data", emit_condition_accessor_primcall),
+    ("equal", emit_host_primcall),
+    ("member", emit_host_primcall),
+    ("assoc", emit_host_primcall),
+    ("memq", emit_host_primcall),
+    ("assq", emit_host_primcall),
+    ("make-record", |name, args, ctx| {
+        check_arg_len(name, args, 2)?;
+        let type_name = emit_expr(&args[0], ctx)?;
+        let fields = emit_expr(&args[1], ctx)?;
+        crate::records::emit_make_record(type_name, fields, ctx)
+    }),
+    ("record-type", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let record = emit_expr(&args[0], ctx)?;
+        Ok(crate::records::emit_record_type(record, ctx))
+    }),
+    ("record-ref", |name, args, ctx| {
+        check_arg_len(name, args, 3)?;
+        let record = emit_expr(&args[0], ctx)?;
+        let type_name = emit_expr(&args[1], ctx)?;
+        let index = emit_expr(&args[2], ctx)?;
+        crate::records::emit_record_ref(record, type_name, index, ctx)
+    }),
+    ("hash", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let accum = emit_expr(&args[0], ctx)?;
+        emit_host_call("lustc_hash", &[accum], ctx)
+    }),
+    ("type-of", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        emit_host_call("lustc_type_of", &[arg], ctx)
+    }),
+    ("write", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        emit_host_call("lustc_write", &[arg], ctx)
+    }),
+    ("read", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        crate::serialize::emit_read(arg, ctx)
+    }),
+    ("assert-equal", |name, args, ctx| {
+        check_arg_len(name, args, 2)?;
+        let args = args
+            .iter()
+            .map(|a| emit_expr(a, ctx))
+            .collect::<Result<Vec<_>, _>>()?;
+        emit_host_call("lustc_assert_equal", &args, ctx)
+    }),
+    ("disassemble", |name, args, ctx| {
+        check_arg_len(name, args, 1)?;
+        let arg = emit_expr(&args[0], ctx)?;
+        crate::asm::emit_disassemble(arg, ctx)
+    }),
+    ("foldr", |name, args, ctx| {
+        check_arg_len(name, args, 3)?;
+        let f = emit_expr(&args[0], ctx)?;
+        let init = emit_expr(&args[1], ctx)?;
+        let list = emit_expr(&args[2], ctx)?;
+        crate::iteration::emit_foldr(f, init, list, ctx)
+    }),
+    ("reduce", |name, args, ctx| {
+        check_arg_len(name, args, 3)?;
+        let f = emit_expr(&args[0], ctx)?;
+        let init = emit_expr(&args[1], ctx)?;
+        let list = emit_expr(&args[2], ctx)?;
+        crate::iteration::emit_reduce(f, init, list, ctx)
+    }),
+    ("apply", |name, args, ctx| {
+        check_arg_len(name, args, 2)?;
+        let f = emit_expr(&args[0], ctx)?;
+        let list = emit_expr(&args[1], ctx)?;
+        crate::procedures::emit_apply(f, list, ctx)
+    }),
+    ("for-each", |name, args, ctx| {
+        check_arg_len(name, args, 2)?;
+        let f = emit_expr(&args[0], ctx)?;
+        let list = emit_expr(&args[1], ctx)?;
+        crate::iteration::emit_for_each(f, list, ctx)
+    }),
+    ("sort", |name, args, ctx| {
+        check_arg_len(name, args, 2)?;
+        let list = emit_expr(&args[0], ctx)?;
+        let less = emit_expr(&args[1], ctx)?;
+        crate::sort::emit_sort(list, less, ctx)
+    }),
+];
+
+fn emit_condition_accessor_primcall(
+    name: &str,
+    args: &[Expr],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    check_arg_len(name, args, 1)?;
+    let accum = emit_expr(&args[0], ctx)?;
+    let field = crate::conditions::accessor_field(name).unwrap();
+    crate::conditions::emit_condition_field(accum, field, ctx)
+}
 
-            let local_callee = ctx
-                .module
-                .declare_func_in_func(callee, &mut ctx.builder.func);
+fn emit_host_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    check_arg_len(name, args, 2)?;
+    let args = args
+        .iter()
+        .map(|a| emit_expr(a, ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    emit_host_call(&format!("lustc_{}", name), &args, ctx)
+}
 
-            let call = ctx.builder.ins().call(local_callee, &args);
-            ctx.builder.inst_results(call)[0]
+/// Evaluates ARGS, raising an error unless there are ARITY of them.
+fn emit_primcall_args(
+    name: &str,
+    args: &[Expr],
+    arity: usize,
+    ctx: &mut Context,
+) -> Result<Vec<Value>, String> {
+    check_arg_len(name, args, arity)?;
+    args.iter().map(|a| emit_expr(a, ctx)).collect()
+}
+
+fn emit_vector_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    if name == "make-vector" {
+        if !(1..=2).contains(&args.len()) {
+            return Err(format!(
+                "make-vector expected 1 or 2 args and got {}",
+                args.len()
+            ));
         }
+        let length = emit_expr(&args[0], ctx)?;
+        let fill = match args.get(1) {
+            Some(fill) => emit_expr(fill, ctx)?,
+            None => ctx
+                .builder
+                .ins()
+                .iconst(ctx.word, Expr::Nil.immediate_rep()),
+        };
+        return crate::vectors::emit_make_vector(length, fill, ctx);
+    }
+    let arity = crate::vectors::vector_primitive_arity(name);
+    let args = emit_primcall_args(name, args, arity, ctx)?;
+    crate::vectors::emit_vector_primitive(name, &args, ctx)
+}
 
-        "println" => {
-            check_arg_len("println", args, 1)?;
-            let arg = emit_expr(&args[0], ctx)?;
-            let args = vec![arg];
+fn emit_priority_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let arity = crate::priority::priority_primitive_arity(name);
+    let args = emit_primcall_args(name, args, arity, ctx)?;
+    crate::priority::emit_priority_primitive(name, &args, ctx)
+}
 
-            let mut sig = ctx.module.make_signature();
-            sig.params.push(AbiParam::new(ctx.word));
-            sig.returns.push(AbiParam::new(ctx.word));
+fn emit_string_builder_primcall(
+    name: &str,
+    args: &[Expr],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let arity = crate::stringbuilder::string_builder_primitive_arity(name);
+    let args = emit_primcall_args(name, args, arity, ctx)?;
+    crate::stringbuilder::emit_string_builder_primitive(name, &args, ctx)
+}
 
-            let callee = ctx
-                .module
-                .declare_function(
-                    "println_lustc_word",
-                    cranelift_module::Linkage::Import,
-                    &sig,
-                )
-                .map_err(|e| e.to_string())?;
+fn emit_sublist_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let arity = crate::sublists::sublist_primitive_arity(name);
+    let args = emit_primcall_args(name, args, arity, ctx)?;
+    crate::sublists::emit_sublist_primitive(name, &args, ctx)
+}
 
-            let local_callee = ctx
-                .module
-                .declare_func_in_func(callee, &mut ctx.builder.func);
+fn emit_string_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let arity = crate::strings::string_primitive_arity(name);
+    let args = emit_primcall_args(name, args, arity, ctx)?;
+    crate::strings::emit_string_primitive(name, &args, ctx)
+}
 
-            let call = ctx.builder.ins().call(local_callee, &args);
-            ctx.builder.inst_results(call)[0]
+fn emit_char_primcall(name: &str, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let args = emit_primcall_args(name, args, 1, ctx)?;
+    crate::chars::emit_char_predicate(name, args[0], ctx)
+}
+
+/// Emits the code to make a pair of DATA and NEXT. The pair is a
+/// packed list when it can be and `CompileOptions::pack_lists` is set.
+/// See `packed.rs`.
+pub(crate) fn emit_cons(data: Value, next: Value, ctx: &mut Context) -> Result<Value, String> {
+    if !ctx.options.pack_lists {
+        return emit_alloc_pair(data, next, ctx);
+    }
+    let alloc_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let (packs, packed) = crate::packed::emit_pack(data, next, ctx);
+    ctx.builder.ins().brnz(packs, done_block, &[packed]);
+    ctx.builder.ins().jump(alloc_block, &[]);
+
+    ctx.builder.switch_to_block(alloc_block);
+    ctx.builder.seal_block(alloc_block);
+    let pair = emit_alloc_pair(data, next, ctx)?;
+    ctx.builder.ins().jump(done_block, &[pair]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+/// Emits the code to make a pair of DATA and NEXT on the heap.
+fn emit_alloc_pair(data: Value, next: Value, ctx: &mut Context) -> Result<Value, String> {
+    let storage = emit_alloc((ctx.word.bytes() * 2).into(), ctx)?;
+
+    ctx.builder.ins().store(MemFlags::new(), data, storage, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), next, storage, ctx.word.bytes() as i32);
+
+    Ok(ctx.builder.ins().bor_imm(storage, conversions::PAIR_TAG))
+}
+
+/// Emits the code to combine two fixnums.
+type ArithmeticOp = fn(&mut Context, Value, Value) -> Result<Value, String>;
+
+/// Returns the minimum number of arguments, the tagged identity
+/// element, and the operation for the arithmetic primitive NAME. Calls
+/// with more than two arguments fold left so (sub 10 1 2) is 7.
+fn arithmetic_op(name: &str) -> (usize, i64, ArithmeticOp) {
+    match name {
+        "add" => (0, Expr::Integer(0).immediate_rep(), |ctx, l, r| {
+            Ok(ctx.builder.ins().iadd(l, r))
+        }),
+        "sub" => (1, Expr::Integer(0).immediate_rep(), |ctx, l, r| {
+            Ok(ctx.builder.ins().isub(l, r))
+        }),
+        "mul" => (0, Expr::Integer(1).immediate_rep(), |ctx, l, r| {
+            // Both operands carry a factor of 2^2 and the result
+            // should only have one. Shifting it out of L before
+            // multiplying, rather than out of the product after,
+            // means that a product that overflows wraps around at the
+            // width of a fixnum like addition does instead of losing
+            // its top two bits.
+            let l = ctx.builder.ins().sshr_imm(l, conversions::FIXNUM_SHIFT);
+            Ok(ctx.builder.ins().imul(l, r))
+        }),
+        "div" => (1, Expr::Integer(1).immediate_rep(), |ctx, l, r| {
+            emit_division("div", l, r, ctx)
+        }),
+        // Tagging preserves order so fixnums can be compared without
+        // untagging them.
+        "min" => (
+            1,
+            Expr::Integer(conversions::FIXNUM_MAX).immediate_rep(),
+            |ctx, l, r| {
+                let less = ctx.builder.ins().icmp(IntCC::SignedLessThan, l, r);
+                Ok(ctx.builder.ins().select(less, l, r))
+            },
+        ),
+        "max" => (
+            1,
+            Expr::Integer(conversions::FIXNUM_MIN).immediate_rep(),
+            |ctx, l, r| {
+                let greater = ctx.builder.ins().icmp(IntCC::SignedGreaterThan, l, r);
+                Ok(ctx.builder.ins().select(greater, l, r))
+            },
+        ),
+        _ => panic!("non arithmetic primitive in arithmetic_op: {}", name),
+    }
+}
+
+/// Emits the code for the numeric predicate NAME applied to VAL.
+/// Unlike zero?, which is false for anything that isn't zero, these
+/// raise a type error if VAL isn't an integer.
+fn emit_numeric_predicate(name: &str, val: Value, ctx: &mut Context) -> Result<Value, String> {
+    fatal::emit_check_int(val, ctx)?;
+
+    // The lowest bit of the integer is the lowest bit above the tag.
+    let low_bit = Expr::Integer(1).immediate_rep();
+    let res = match name {
+        "positive?" => ctx.builder.ins().icmp_imm(IntCC::SignedGreaterThan, val, 0),
+        "negative?" => ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, val, 0),
+        "even?" => {
+            let bit = ctx.builder.ins().band_imm(val, low_bit);
+            ctx.builder.ins().icmp_imm(IntCC::Equal, bit, 0)
+        }
+        "odd?" => {
+            let bit = ctx.builder.ins().band_imm(val, low_bit);
+            ctx.builder.ins().icmp_imm(IntCC::NotEqual, bit, 0)
         }
+        _ => panic!("non numeric predicate in emit_numeric_predicate: {}", name),
+    };
+    let res = ctx.builder.ins().bint(ctx.word, res);
+    Ok(emit_word_to_bool(res, &mut ctx.builder))
+}
+
+/// Emits the code for the absolute value of the fixnum VAL. Like
+/// `(sub x)` this wraps so the absolute value of the smallest fixnum
+/// is itself.
+fn emit_abs(val: Value, ctx: &mut Context) -> Value {
+    let negated = ctx.builder.ins().ineg(val);
+    let negative = ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, val, 0);
+    ctx.builder.ins().select(negative, negated, val)
+}
+
+/// Emits the code for the division primitive NAME (one of div, mod,
+/// and rem) applied to the fixnums LEFT and RIGHT. Dividing by zero is
+/// a runtime error for all of them.
+fn emit_division(
+    name: &str,
+    left: Value,
+    right: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_nonzero(right, name, ctx)?;
 
-        _ => panic!("non primitive in emit_primcall: {}", name),
+    Ok(match name {
+        "div" => {
+            // Both operands carry a factor of 2^2 which cancels out
+            // so we need to put it back.
+            let accum = ctx.builder.ins().sdiv(left, right);
+            ctx.builder.ins().ishl_imm(accum, conversions::FIXNUM_SHIFT)
+        }
+        // The remainder of two fixnums keeps their factor of 2^2 so
+        // these need no adjustment.
+        "rem" => ctx.builder.ins().srem(left, right),
+        "mod" => {
+            // The result of mod has the sign of its divisor whereas
+            // srem's has the sign of its dividend. When they disagree
+            // we add the divisor to the remainder.
+            let rem = ctx.builder.ins().srem(left, right);
+            let signs = ctx.builder.ins().bxor(rem, right);
+            let signs_differ = ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+            let nonzero = ctx.builder.ins().icmp_imm(IntCC::NotEqual, rem, 0);
+            let adjust = ctx.builder.ins().band(signs_differ, nonzero);
+            let adjusted = ctx.builder.ins().iadd(rem, right);
+            ctx.builder.ins().select(adjust, adjusted, rem)
+        }
+        _ => panic!("non division primitive in emit_division: {}", name),
     })
 }
 
-fn emit_word_to_bool(accum: Value, builder: &mut FunctionBuilder) -> Value {
+/// Emits the code for raising the fixnum BASE to the power of the
+/// fixnum EXP by repeated squaring. Like mul the result wraps around
+/// at the width of a fixnum. A negative EXP is a range error as there
+/// are no rationals or floats to return.
+fn emit_expt(base: Value, exp: Value, ctx: &mut Context) -> Result<Value, String> {
+    let negative_block = ctx.builder.create_block();
+    let start_block = ctx.builder.create_block();
+    let negative = ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, exp, 0);
+    ctx.builder.ins().brnz(negative, negative_block, &[]);
+    ctx.builder.ins().jump(start_block, &[]);
+
+    ctx.builder.switch_to_block(negative_block);
+    ctx.builder.seal_block(negative_block);
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_negative_exponent".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+    ctx.builder.ins().jump(start_block, &[]);
+
+    ctx.builder.switch_to_block(start_block);
+    ctx.builder.seal_block(start_block);
+    let base = ctx.builder.ins().sshr_imm(base, conversions::FIXNUM_SHIFT);
+    let exp = ctx.builder.ins().sshr_imm(exp, conversions::FIXNUM_SHIFT);
+
+    let square_block = ctx.builder.create_block();
+    let square_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    for _ in 0..3 {
+        ctx.builder.append_block_param(square_block, ctx.word);
+    }
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let one = ctx.builder.ins().iconst(ctx.word, 1);
+    ctx.builder.ins().jump(square_block, &[base, exp, one]);
+
+    ctx.builder.switch_to_block(square_block);
+    let base = ctx.builder.block_params(square_block)[0];
+    let exp = ctx.builder.block_params(square_block)[1];
+    let result = ctx.builder.block_params(square_block)[2];
+    ctx.builder.ins().brz(exp, done_block, &[result]);
+    ctx.builder.ins().jump(square_body, &[]);
+
+    ctx.builder.switch_to_block(square_body);
+    ctx.builder.seal_block(square_body);
+    let bit = ctx.builder.ins().band_imm(exp, 1);
+    let odd = ctx.builder.ins().icmp_imm(IntCC::NotEqual, bit, 0);
+    let multiplied = ctx.builder.ins().imul(result, base);
+    let result = ctx.builder.ins().select(odd, multiplied, result);
+    let base = ctx.builder.ins().imul(base, base);
+    let exp = ctx.builder.ins().sshr_imm(exp, 1);
+    ctx.builder.ins().jump(square_block, &[base, exp, result]);
+    ctx.builder.seal_block(square_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let result = ctx.builder.block_params(done_block)[0];
+    Ok(ctx
+        .builder
+        .ins()
+        .ishl_imm(result, conversions::FIXNUM_SHIFT))
+}
+
+/// Returns the name of the primitive that NAME is an alias of if it
+/// is one.
+pub(crate) fn primitive_alias(name: &str) -> Option<&'static str> {
+    match name {
+        "+" => Some("add"),
+        "-" => Some("sub"),
+        "*" => Some("mul"),
+        "/" => Some("div"),
+        "<" => Some("lt"),
+        ">" => Some("gt"),
+        "drop" => Some("list-tail"),
+        "cons?" => Some("pair?"),
+        // (sub x) negates x.
+        "negate" => Some("sub"),
+        _ => None,
+    }
+}
+
+/// Emits the code to determine if VAL is falsey. Nil and false are
+/// the only falsey values. The result is a cranelift boolean.
+pub(crate) fn emit_is_falsey(val: Value, ctx: &mut Context) -> Value {
+    let is_false = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, val, Expr::Bool(false).immediate_rep());
+    let is_nil = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, val, conversions::NIL_VALUE);
+    ctx.builder.ins().bor(is_false, is_nil)
+}
+
+/// Emits the code for `(pair? val)` or, when NAME is atom?, `(atom?
+/// val)` which is true for everything that isn't a pair, nil included.
+fn emit_pair_predicate(name: &str, val: Value, ctx: &mut Context) -> Value {
+    let cond = if name == "atom?" {
+        IntCC::NotEqual
+    } else {
+        IntCC::Equal
+    };
+    let accum = if ctx.options.pack_lists {
+        let is_pair = crate::vectors::emit_is_pair(val, ctx);
+        let is_pair = ctx.builder.ins().bint(ctx.word, is_pair);
+        ctx.builder.ins().icmp_imm(cond, is_pair, 1)
+    } else {
+        let tag = ctx.builder.ins().band_imm(val, conversions::HEAP_TAG_MASK);
+        ctx.builder.ins().icmp_imm(cond, tag, conversions::PAIR_TAG)
+    };
+    let accum = ctx.builder.ins().bint(ctx.word, accum);
+    emit_word_to_bool(accum, &mut ctx.builder)
+}
+
+pub(crate) fn emit_word_to_bool(accum: Value, builder: &mut FunctionBuilder) -> Value {
     let accum = builder.ins().ishl_imm(accum, conversions::BOOL_SHIFT);
     let accum = builder.ins().bor_imm(accum, conversions::BOOL_TAG);
     accum
@@ -918,30 +2065,87 @@ pub(crate) fn string_is_builtin(s: &str) -> bool {
         || s == "set"
         || s == "foreign-call"
         || s == "error"
+        || s == "time"
+        || s == "and"
+        || s == "or"
+        || s == "cond"
+        || s == "compose"
+        || crate::desugar::is_list_accessor(s)
+        || s == "values"
+        || s == "call-with-values"
+        || s == "try"
+        || s == "catch"
+        || s == "unwind-protect"
+        || s == "with-output-to-string"
 }
 
 pub(crate) fn string_is_primitive(s: &str) -> bool {
-    s == "add1"
-        || s == "print"
-        || s == "println"
-        || s == "integer->char"
-        || s == "char->integer"
-        || s == "null?"
-        || s == "zero?"
-        || s == "not"
-        || s == "boolean?"
-        || s == "integer?"
-        || s == "pair?"
-        || s == "closure?"
-        || s == "add"
-        || s == "sub"
-        || s == "mul"
-        || s == "eq"
-        || s == "lt"
-        || s == "gt"
-        || s == "cons"
-        || s == "car"
-        || s == "cdr"
+    primcall_table().contains_key(s)
+}
+
+/// Returns the smallest and largest number of arguments that a call to
+/// the primitive NAME can pass. Calls made by name are checked against
+/// it before anything is emitted (see `arity.rs`).
+fn primitive_arity(name: &str) -> (usize, usize) {
+    if let Some(name) = primitive_alias(name) {
+        return primitive_arity(name);
+    }
+    let fixed = |n| (n, n);
+    match name {
+        "add" | "sub" | "mul" | "div" | "min" | "max" => (arithmetic_op(name).0, usize::MAX),
+        "list" => (0, usize::MAX),
+        "newline" | "flush-output" | "command-line-args" | "heap-stats" => fixed(0),
+        "mod" | "rem" | "expt" | "eq" | "neq" | "lt" | "gt" | "cons" | "equal" | "member"
+        | "assoc" | "memq" | "assq" | "assert-equal" | "apply" | "for-each" | "sort"
+        | "make-record" => fixed(2),
+        "make-condition" | "record-ref" | "foldr" | "reduce" => fixed(3),
+        "make-vector" => (1, 2),
+        _ if crate::vectors::VECTOR_PRIMITIVES.contains(&name) => {
+            fixed(crate::vectors::vector_primitive_arity(name))
+        }
+        _ if crate::priority::PRIORITY_PRIMITIVES.contains(&name) => {
+            fixed(crate::priority::priority_primitive_arity(name))
+        }
+        _ if crate::stringbuilder::STRING_BUILDER_PRIMITIVES.contains(&name) => {
+            fixed(crate::stringbuilder::string_builder_primitive_arity(name))
+        }
+        _ if crate::sublists::SUBLIST_PRIMITIVES.contains(&name) => {
+            fixed(crate::sublists::sublist_primitive_arity(name))
+        }
+        _ if crate::strings::STRING_PRIMITIVES.contains(&name) => {
+            fixed(crate::strings::string_primitive_arity(name))
+        }
+        _ if crate::generators::GENERATOR_PRIMITIVES.contains(&name) => {
+            crate::generators::generator_primitive_arity(name)
+        }
+        // The rest of the primitives, the character predicates among
+        // them, take one argument.
+        _ => fixed(1),
+    }
+}
+
+/// Checks that the primitive NAME can be called with COUNT arguments.
+/// The error is the same one that emitting the call would raise.
+pub(crate) fn check_primcall_arity(name: &str, count: usize) -> Result<(), String> {
+    let (min, max) = primitive_arity(name);
+    if (min..=max).contains(&count) {
+        return Ok(());
+    }
+    Err(if min == max {
+        format!("{} expected {} args and got {}", name, min, count)
+    } else if max == usize::MAX {
+        format!("{} expected at least {} args and got {}", name, min, count)
+    } else if max == min + 1 {
+        format!(
+            "{} expected {} or {} args and got {}",
+            name, min, max, count
+        )
+    } else {
+        format!(
+            "{} expected between {} and {} args and got {}",
+            name, min, max, count
+        )
+    })
 }
 
 fn check_arg_len(name: &str, args: &[Expr], expected: usize) -> Result<(), String> {
@@ -1551,12 +2755,53 @@ mod tests {
         assert_eq!(Expr::Bool(true), res)
     }
 
+    #[test]
+    fn pair_and_atom() {
+        let bools = |bs: &[bool]| {
+            bs.iter()
+                .rev()
+                .fold(Expr::Nil, |l, b| Expr::List(vec![Expr::Bool(*b), l]))
+        };
+        // Each predicate is applied to a pair, nil, and an integer both
+        // directly and through a variable so that folding can't help.
+        let expected = bools(&[true, false, false, false, true, true]);
+        for p in ["pair?", "cons?"] {
+            for wrap in [false, true] {
+                let [pair, nil, int] = ["(cons 1 2)", "()", "1"].map(|x| match wrap {
+                    true => format!("(id {})", x),
+                    false => x.to_string(),
+                });
+                let source = format!(
+                    r#"
+(let id (fn (x) x))
+(cons ({p} {pair}) (cons ({p} {nil}) (cons ({p} {int})
+  (cons (atom? {pair}) (cons (atom? {nil}) (cons (atom? {int}) ()))))))
+"#,
+                    p = p,
+                    pair = pair,
+                    nil = nil,
+                    int = int
+                );
+                assert_eq!(roundtrip_string(&source).unwrap(), expected, "{}", source);
+                assert_eq!(
+                    crate::interpreter::interpret(&crate::parse_string(&source).unwrap()).unwrap(),
+                    expected
+                );
+            }
+        }
+        let source =
+            "(let f (fn (p) (cons (p (quote (1))) (cons (p \"\") ())))) (cons (f atom?) (f pair?))";
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![bools(&[false, true]), bools(&[true, false])])
+        );
+    }
+
     #[test]
     #[should_panic(expected = "builtin function (let) can not be used in a higher order context")]
     fn bad_builtin_assign() {
         let source = r#"
-(let set 10)
-(let s set)
+(let s 10)
 (let l let)
 "#;
         let res = roundtrip_string(source).unwrap();
@@ -1574,4 +2819,41 @@ mod tests {
         let res = roundtrip_string(source).unwrap();
         assert_eq!(Expr::Bool(true), res)
     }
+
+    #[test]
+    fn every_family_dispatches() {
+        // A call to a primitive from each of the tables that make up
+        // the dispatch table, including an alias.
+        let source = r#"
+(let v (make-vector 2 1))
+(vector-set! v 0 (add1 (abs (sub 0 4))))
+(let h (make-heap lt))
+(heap-push h 3)
+(list (vector->list v)
+      (string-length (string-append "ab" "c"))
+      (char-digit? (string-ref "a1" 1))
+      (length (take (iota 5) 2))
+      (heap-size h)
+      (equal (list 1 2) (quote (1 2)))
+      (+ 1 2)
+      (condition-type (make-condition (quote a) "b" ())))
+"#;
+        let expected =
+            roundtrip_string("(list (quote (5 1)) 3 (eq 1 1) 2 1 (eq 1 1) 3 (quote a))").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&crate::parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn table_lookup() {
+        for (name, _) in INLINE_PRIMCALLS.iter().chain(LIBRARY_PRIMCALLS.iter()) {
+            assert!(string_is_primitive(name), "{}", name);
+        }
+        assert!(string_is_primitive(crate::chars::CHAR_PREDICATES[4]));
+        assert!(string_is_primitive("+"));
+        assert!(!string_is_primitive("not-a-primitive"));
+    }
 }
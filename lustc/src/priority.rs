@@ -0,0 +1,407 @@
+//! Priority queues. `(make-heap less?)` makes an empty queue ordered
+//! by LESS?, a function of two arguments that returns a truthy value
+//! when its first argument belongs before its second like the one
+//! that sort takes. `(heap-push heap x)` adds X to HEAP and returns
+//! HEAP, `(heap-peek heap)` returns the element that belongs before
+//! all of the others and `(heap-pop heap)` removes that element and
+//! returns it. `(heap-size heap)` is the number of elements in HEAP.
+//! Peeking at or popping an empty heap raises a `range-error`.
+//!
+//! ```lisp
+//! (let h (make-heap lt))
+//! (heap-push (heap-push h 3) 1)
+//! (heap-pop h) ; => 1
+//! (heap-pop h) ; => 3
+//! ```
+//!
+//! The queue is a binary heap with the children of element I at 2I + 1
+//! and 2I + 2, so pushing and popping call LESS? a number of times
+//! that grows with the log of the size of the queue. Elements are
+//! moved by swapping them so if LESS? raises an error the queue still
+//! holds every element, though maybe not in order. Elements that
+//! neither belong before the other come out in no particular order.
+//!
+//! A queue shares VALUES_TAG with tuples. It is four words:
+//! PRIORITY_QUEUE_HEADER, LESS?, the number of elements, which is not
+//! a fixnum, and a pointer to the storage of a vector that holds
+//! them. The vector's length is the queue's capacity and when it is
+//! full the elements are copied into a vector twice as long. Nothing
+//! on the heap is ever freed so the old vector is left where it is.
+//! When a queue is returned to the host it is seen as a list of its
+//! elements in the order they are stored.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{FIXNUM_SHIFT, HEAP_PTR_MASK, PRIORITY_QUEUE_HEADER, VALUES_TAG};
+use crate::fatal;
+use crate::heap::emit_alloc;
+use crate::primitives::emit_is_falsey;
+use crate::procedures::emit_closure_call;
+use crate::vectors::{emit_alloc_vector, emit_element_address, emit_index_loop};
+use crate::Word;
+
+/// The words of a queue after its header.
+const LESS: i32 = 1;
+const COUNT: i32 = 2;
+const STORAGE: i32 = 3;
+
+/// The number of elements a new queue has room for.
+const INITIAL_CAPACITY: i64 = 4;
+
+/// The names of the priority queue primitives.
+pub(crate) const PRIORITY_PRIMITIVES: [&str; 5] = [
+    "make-heap",
+    "heap-push",
+    "heap-pop",
+    "heap-peek",
+    "heap-size",
+];
+
+/// Returns the number of arguments that the priority queue primitive
+/// NAME takes.
+pub(crate) fn priority_primitive_arity(name: &str) -> usize {
+    match name {
+        "heap-push" => 2,
+        _ => 1,
+    }
+}
+
+/// Returns the number of elements in QUEUE and a pointer to the
+/// storage that holds them.
+pub(crate) fn queue_elements(queue: Word) -> (usize, *const Word) {
+    let ptr = (queue & HEAP_PTR_MASK) as *const Word;
+    unsafe {
+        (
+            *ptr.add(COUNT as usize) as usize,
+            *ptr.add(STORAGE as usize) as *const Word,
+        )
+    }
+}
+
+/// Emits the code for the priority queue primitive NAME applied to
+/// ARGS which have already been evaluated.
+pub(crate) fn emit_priority_primitive(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    match name {
+        "make-heap" => emit_make_heap(args[0], ctx),
+        "heap-push" => emit_heap_push(args[0], args[1], ctx),
+        "heap-pop" => emit_heap_pop(args[0], ctx),
+        "heap-peek" => {
+            let ptr = emit_queue_ptr(args[0], ctx)?;
+            let count = load_field(ptr, COUNT, ctx);
+            fatal::emit_check_range(count, ctx)?;
+            let storage = load_field(ptr, STORAGE, ctx);
+            let zero = ctx.builder.ins().iconst(ctx.word, 0);
+            Ok(emit_load_element(storage, zero, ctx))
+        }
+        "heap-size" => {
+            let ptr = emit_queue_ptr(args[0], ctx)?;
+            let count = load_field(ptr, COUNT, ctx);
+            Ok(ctx.builder.ins().ishl_imm(count, FIXNUM_SHIFT))
+        }
+        _ => panic!(
+            "non priority queue primitive in emit_priority_primitive: {}",
+            name
+        ),
+    }
+}
+
+fn load_field(ptr: Value, field: i32, ctx: &mut Context) -> Value {
+    let offset = field * ctx.word.bytes() as i32;
+    ctx.builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, offset)
+}
+
+fn store_field(ptr: Value, field: i32, val: Value, ctx: &mut Context) {
+    let offset = field * ctx.word.bytes() as i32;
+    ctx.builder.ins().store(MemFlags::new(), val, ptr, offset);
+}
+
+fn emit_load_element(storage: Value, index: Value, ctx: &mut Context) -> Value {
+    let address = emit_element_address(storage, index, ctx);
+    ctx.builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), address, 0)
+}
+
+fn emit_store_element(storage: Value, index: Value, val: Value, ctx: &mut Context) {
+    let address = emit_element_address(storage, index, ctx);
+    ctx.builder.ins().store(MemFlags::new(), val, address, 0);
+}
+
+/// Emits the code to check that QUEUE is a priority queue. Returns a
+/// pointer to it.
+fn emit_queue_ptr(queue: Value, ctx: &mut Context) -> Result<Value, String> {
+    let is_queue = crate::conditions::emit_has_header(queue, PRIORITY_QUEUE_HEADER, ctx);
+    fatal::emit_check_type(is_queue, ctx)?;
+    Ok(ctx.builder.ins().band_imm(queue, HEAP_PTR_MASK))
+}
+
+/// Emits the code to swap elements I and J of STORAGE.
+fn emit_swap(storage: Value, i: Value, j: Value, ctx: &mut Context) {
+    let x = emit_load_element(storage, i, ctx);
+    let y = emit_load_element(storage, j, ctx);
+    emit_store_element(storage, i, y, ctx);
+    emit_store_element(storage, j, x, ctx);
+}
+
+/// Emits the code to ask the queue at PTR if element I belongs
+/// before element J. The result is nonzero if it doesn't. ARGLOC is
+/// where the two arguments to LESS? go.
+fn emit_not_before(
+    ptr: Value,
+    i: Value,
+    j: Value,
+    argloc: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    // LESS? could push onto the queue and move its storage so it is
+    // read again for every comparison.
+    let storage = load_field(ptr, STORAGE, ctx);
+    let x = emit_load_element(storage, i, ctx);
+    let y = emit_load_element(storage, j, ctx);
+    let word_size = ctx.word.bytes() as i32;
+    ctx.builder.ins().store(MemFlags::new(), x, argloc, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), y, argloc, word_size);
+    let less = load_field(ptr, LESS, ctx);
+    let argc = ctx.builder.ins().iconst(ctx.word, 2);
+    let before = emit_closure_call(less, argc, argloc, ctx)?;
+    Ok(emit_is_falsey(before, ctx))
+}
+
+fn emit_argloc(ctx: &mut Context) -> Value {
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * ctx.word.bytes(),
+    ));
+    ctx.builder.ins().stack_addr(ctx.word, argloc, 0)
+}
+
+fn emit_make_heap(less: Value, ctx: &mut Context) -> Result<Value, String> {
+    fatal::emit_check_closure(less, ctx)?;
+    let capacity = ctx.builder.ins().iconst(ctx.word, INITIAL_CAPACITY);
+    let storage = emit_alloc_vector(capacity, ctx)?;
+    let ptr = emit_alloc(4 * ctx.word.bytes() as i64, ctx)?;
+    let header = ctx.builder.ins().iconst(ctx.word, PRIORITY_QUEUE_HEADER);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().store(MemFlags::new(), header, ptr, 0);
+    store_field(ptr, LESS, less, ctx);
+    store_field(ptr, COUNT, zero, ctx);
+    store_field(ptr, STORAGE, storage, ctx);
+    Ok(ctx.builder.ins().bor_imm(ptr, VALUES_TAG))
+}
+
+fn emit_heap_push(queue: Value, x: Value, ctx: &mut Context) -> Result<Value, String> {
+    let ptr = emit_queue_ptr(queue, ctx)?;
+    let count = load_field(ptr, COUNT, ctx);
+    let storage = load_field(ptr, STORAGE, ctx);
+    let capacity = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), storage, 0);
+
+    let grow_block = ctx.builder.create_block();
+    let push_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(push_block, ctx.word);
+    let full = ctx.builder.ins().icmp(IntCC::Equal, count, capacity);
+    ctx.builder.ins().brnz(full, grow_block, &[]);
+    ctx.builder.ins().jump(push_block, &[storage]);
+
+    ctx.builder.switch_to_block(grow_block);
+    ctx.builder.seal_block(grow_block);
+    let capacity = ctx.builder.ins().imul_imm(capacity, 2);
+    let grown = emit_alloc_vector(capacity, ctx)?;
+    emit_index_loop(count, ctx, |i, ctx| {
+        let x = emit_load_element(storage, i, ctx);
+        emit_store_element(grown, i, x, ctx);
+        Ok(())
+    })?;
+    store_field(ptr, STORAGE, grown, ctx);
+    ctx.builder.ins().jump(push_block, &[grown]);
+
+    ctx.builder.switch_to_block(push_block);
+    ctx.builder.seal_block(push_block);
+    let storage = ctx.builder.block_params(push_block)[0];
+    emit_store_element(storage, count, x, ctx);
+    let pushed = ctx.builder.ins().iadd_imm(count, 1);
+    store_field(ptr, COUNT, pushed, ctx);
+
+    // Swap X with its parent until it doesn't belong before it.
+    let argloc = emit_argloc(ctx);
+    let up_block = ctx.builder.create_block();
+    let compare_block = ctx.builder.create_block();
+    let swap_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(up_block, ctx.word);
+    ctx.builder.ins().jump(up_block, &[count]);
+
+    ctx.builder.switch_to_block(up_block);
+    let i = ctx.builder.block_params(up_block)[0];
+    ctx.builder.ins().brz(i, done_block, &[]);
+    ctx.builder.ins().jump(compare_block, &[]);
+
+    ctx.builder.switch_to_block(compare_block);
+    ctx.builder.seal_block(compare_block);
+    let parent = ctx.builder.ins().iadd_imm(i, -1);
+    let parent = ctx.builder.ins().ushr_imm(parent, 1);
+    let stays = emit_not_before(ptr, i, parent, argloc, ctx)?;
+    ctx.builder.ins().brnz(stays, done_block, &[]);
+    ctx.builder.ins().jump(swap_block, &[]);
+
+    ctx.builder.switch_to_block(swap_block);
+    ctx.builder.seal_block(swap_block);
+    let storage = load_field(ptr, STORAGE, ctx);
+    emit_swap(storage, i, parent, ctx);
+    ctx.builder.ins().jump(up_block, &[parent]);
+    ctx.builder.seal_block(up_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(queue)
+}
+
+fn emit_heap_pop(queue: Value, ctx: &mut Context) -> Result<Value, String> {
+    let ptr = emit_queue_ptr(queue, ctx)?;
+    let count = load_field(ptr, COUNT, ctx);
+    fatal::emit_check_range(count, ctx)?;
+    let storage = load_field(ptr, STORAGE, ctx);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    let top = emit_load_element(storage, zero, ctx);
+
+    // The last element takes the place of the first.
+    let count = ctx.builder.ins().iadd_imm(count, -1);
+    store_field(ptr, COUNT, count, ctx);
+    emit_swap(storage, zero, count, ctx);
+
+    // Swap it with the child that belongs first until neither of its
+    // children belong before it.
+    let argloc = emit_argloc(ctx);
+    let down_block = ctx.builder.create_block();
+    let left_block = ctx.builder.create_block();
+    let right_block = ctx.builder.create_block();
+    let child_block = ctx.builder.create_block();
+    let swap_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(down_block, ctx.word);
+    ctx.builder.append_block_param(child_block, ctx.word);
+    ctx.builder.ins().jump(down_block, &[zero]);
+
+    ctx.builder.switch_to_block(down_block);
+    let i = ctx.builder.block_params(down_block)[0];
+    let left = ctx.builder.ins().ishl_imm(i, 1);
+    let left = ctx.builder.ins().iadd_imm(left, 1);
+    let right = ctx.builder.ins().iadd_imm(left, 1);
+    let has_left = ctx.builder.ins().icmp(IntCC::SignedLessThan, left, count);
+    ctx.builder.ins().brz(has_left, done_block, &[]);
+    ctx.builder.ins().jump(left_block, &[]);
+
+    ctx.builder.switch_to_block(left_block);
+    ctx.builder.seal_block(left_block);
+    let has_right = ctx.builder.ins().icmp(IntCC::SignedLessThan, right, count);
+    ctx.builder.ins().brz(has_right, child_block, &[left]);
+    ctx.builder.ins().jump(right_block, &[]);
+
+    ctx.builder.switch_to_block(right_block);
+    ctx.builder.seal_block(right_block);
+    let left_first = emit_not_before(ptr, right, left, argloc, ctx)?;
+    ctx.builder.ins().brnz(left_first, child_block, &[left]);
+    ctx.builder.ins().jump(child_block, &[right]);
+
+    ctx.builder.switch_to_block(child_block);
+    ctx.builder.seal_block(child_block);
+    let child = ctx.builder.block_params(child_block)[0];
+    let stays = emit_not_before(ptr, child, i, argloc, ctx)?;
+    ctx.builder.ins().brnz(stays, done_block, &[]);
+    ctx.builder.ins().jump(swap_block, &[]);
+
+    ctx.builder.switch_to_block(swap_block);
+    ctx.builder.seal_block(swap_block);
+    let storage = load_field(ptr, STORAGE, ctx);
+    emit_swap(storage, i, child, ctx);
+    ctx.builder.ins().jump(down_block, &[child]);
+    ctx.builder.seal_block(down_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind};
+
+    #[test]
+    fn pops_in_order() {
+        let numbers = (0..50).map(|i| (i * 37) % 53).collect::<Vec<_>>();
+        let mut sorted = numbers.clone();
+        sorted.sort_unstable();
+        let pushes = numbers
+            .iter()
+            .map(|n| format!("(heap-push h {})", n))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let source = format!(
+            r#"
+(let h (make-heap lt))
+{}
+(let drain (fn (acc) (if (eq (heap-size h) 0) acc (drain (cons (heap-pop h) acc)))))
+(drain ())
+"#,
+            pushes
+        );
+        let expected = format!(
+            "(quote ({}))",
+            sorted
+                .iter()
+                .rev()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        check(&source, &expected);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        check(
+            "(let h (make-heap lt)) (heap-push (heap-push h 3) 1) (heap-peek h)",
+            "1",
+        );
+        check(
+            r#"
+(let h (make-heap (fn (a b) (gt (car a) (car b)))))
+(heap-push h (cons 1 (quote one)))
+(heap-push h (cons 3 (quote three)))
+(let first (cdr (heap-pop h)))
+(heap-push h (cons 2 (quote two)))
+(let second (cdr (heap-pop h)))
+(let third (cdr (heap-pop h)))
+(cons first (cons second (cons third (cons (heap-size h) ()))))
+"#,
+            "(quote (three two one 0))",
+        );
+        check("(heap-size (make-heap lt))", "0");
+        check(
+            "(let f heap-push) (let g heap-pop) (g (f (make-heap lt) 5))",
+            "5",
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error_kind("(heap-pop (make-heap lt))"), "range-error");
+        assert_eq!(error_kind("(heap-peek (make-heap lt))"), "range-error");
+        assert_eq!(error_kind("(heap-push (quote (1)) 1)"), "type-error");
+        assert_eq!(
+            error_kind("(let h (make-heap (fn (a b) (error (quote bad) \"no\" a)))) (heap-push (heap-push h 1) 2)"),
+            "bad"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
@@ -6,8 +7,8 @@ use crate::heap::emit_alloc;
 use crate::locals::emit_var_decl_and_assign;
 use crate::primitives::emit_contigous_to_list;
 use crate::primitives::string_is_builtin;
-use crate::Expr;
 use crate::{compiler::Context, fatal::emit_check_callable};
+use crate::{Expr, Word};
 use cranelift::prelude::*;
 use cranelift_module::{Linkage, Module};
 
@@ -75,6 +76,7 @@ pub fn emit_procedure(
     params: &[String],
     body: &[Expr],
     varadic_symbol: &Option<String>,
+    form: Option<usize>,
     fnmap: &HashMap<String, LustFn>,
 ) -> Result<(), String> {
     let word = jit.module.target_config().pointer_type();
@@ -99,6 +101,10 @@ pub fn emit_procedure(
     builder.switch_to_block(entry_block);
     builder.seal_block(entry_block);
 
+    if let Some(form) = form {
+        crate::sourcemap::set_form(&mut builder, form);
+    }
+
     let mut ctx = Context::new(
         builder,
         &mut jit.module,
@@ -106,6 +112,7 @@ pub fn emit_procedure(
         HashMap::new(),
         fnmap.clone(),
         Vec::new(),
+        jit.options.clone(),
     );
 
     let closure_ptr = ctx.builder.block_params(entry_block)[0];
@@ -120,14 +127,26 @@ pub fn emit_procedure(
 
     let argloc = ctx.builder.block_params(entry_block)[2];
 
-    // Assign regular arguments
-    for (i, p) in params.iter().enumerate() {
-        let val = ctx.builder.ins().load(
+    // Self tail calls jump back to the loop block with new values
+    // for the params so it takes them as block params.
+    let loop_block = ctx.builder.create_block();
+    let mut initial = Vec::with_capacity(params.len());
+    for i in 0..params.len() {
+        initial.push(ctx.builder.ins().load(
             word,
             MemFlags::new(),
             argloc,
             (i * word.bytes() as usize) as i32,
-        );
+        ));
+        ctx.builder.append_block_param(loop_block, word);
+    }
+    ctx.builder.ins().jump(loop_block, &initial);
+    ctx.builder.switch_to_block(loop_block);
+    ctx.tail_call_block = Some(loop_block);
+
+    // Assign regular arguments
+    for (i, p) in params.iter().enumerate() {
+        let val = ctx.builder.block_params(loop_block)[i];
 
         // Params that are escaped need to be initialized
         // appropriately.
@@ -193,10 +212,20 @@ pub fn emit_procedure(
         .declare_function(name, Linkage::Export, &jit.context.func.signature)
         .map_err(|e| e.to_string())?;
 
-    jit.module
+    let compiled = jit
+        .module
         .define_function(id, &mut jit.context)
         .map_err(|e| e.to_string())?;
 
+    jit.source_map.record(name, &jit.context);
+    jit.compiled_functions.push(crate::asm::CompiledFunction {
+        name: name.to_string(),
+        id,
+        size: compiled.size,
+        binding: fnmap.get(name).and_then(|f| f.binding.clone()),
+        object_code: crate::asm::object_code(&jit.context),
+    });
+
     // If you want to dump the generated IR this is the way:
     // println!("{}", jit.context.func.display(jit.module.isa()));
 
@@ -209,8 +238,85 @@ pub fn emit_procedure(
 /// anonymous function emits a direct call. Otherwise, emits an
 /// indirect one to the function pointed to by the argument variable.
 pub(crate) fn emit_fncall(head: &Expr, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
-    let closure_ptr = emit_check_callable(head, ctx)?;
+    let closure = emit_check_callable(head, ctx)?;
+    let (arg_count, argloc) = emit_args(args, ctx)?;
+    emit_closure_call(closure, arg_count, argloc, ctx)
+}
+
+/// Evaluates ARGS and stores them on the heap the way functions take
+/// them. Returns the number of arguments and where they are.
+///
+/// Functions take their arguments from memory rather than from
+/// registers so how many they take is only limited by the heap. There
+/// is nothing to spill when a function takes more arguments than the
+/// target has registers to pass them in.
+fn emit_args(args: &[Expr], ctx: &mut Context) -> Result<(Value, Value), String> {
+    let word = ctx.module.target_config().pointer_type();
+
+    // Allocate space for arguments and stash them away.
+    let argloc = emit_alloc((args.len() * word.bytes() as usize) as i64, ctx)?;
+    for (i, arg) in args.iter().enumerate() {
+        let val = emit_expr(arg, ctx)?;
+        ctx.builder.ins().store(
+            MemFlags::new(),
+            val,
+            argloc,
+            (i * word.bytes() as usize) as i32,
+        );
+    }
+
+    let arg_count = ctx.builder.ins().iconst(word, args.len() as i64);
+    Ok((arg_count, argloc))
+}
+
+/// Emits a call to CLOSURE, which must already have been checked to
+/// be a closure, with ARG_COUNT arguments stored at ARGLOC.
+pub(crate) fn emit_closure_call(
+    closure: Value,
+    arg_count: Value,
+    argloc: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let res = emit_unchecked_closure_call(closure, arg_count, argloc, ctx)?;
+
+    if ctx.options.embedded {
+        crate::fatal::emit_check_error_pending(ctx)?;
+    }
 
+    Ok(res)
+}
+
+/// Emits the code for `(apply f args)` which calls F with the
+/// elements of the list ARGS as its arguments.
+///
+/// Every function has a single entry that takes its arguments as a
+/// count and a pointer to a vector of them so there is no separate
+/// entry for calls whose number of arguments isn't known until they
+/// are made. A vector's elements come right after its length so
+/// copying ARGS into one gives both the count and the pointer and F
+/// is called the same way a direct call would call it. Arity checks
+/// and varadic arguments work just like they do for direct calls.
+pub(crate) fn emit_apply(f: Value, args: Value, ctx: &mut Context) -> Result<Value, String> {
+    crate::fatal::emit_check_closure(f, ctx)?;
+    let vector = crate::vectors::emit_list_to_vector(args, ctx)?;
+    let ptr = ctx
+        .builder
+        .ins()
+        .band_imm(vector, crate::conversions::HEAP_PTR_MASK);
+    let arg_count = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+    let argloc = ctx.builder.ins().iadd_imm(ptr, ctx.word.bytes() as i64);
+    emit_closure_call(f, arg_count, argloc, ctx)
+}
+
+/// Like `emit_closure_call` but doesn't return from the current
+/// function if the call raised an error. The caller is responsible for
+/// checking for one.
+pub(crate) fn emit_unchecked_closure_call(
+    closure: Value,
+    arg_count: Value,
+    argloc: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
     let word = ctx.module.target_config().pointer_type();
 
     let mut sig = ctx.module.make_signature();
@@ -232,37 +338,118 @@ pub(crate) fn emit_fncall(head: &Expr, args: &[Expr], ctx: &mut Context) -> Resu
     let closure_ptr = ctx
         .builder
         .ins()
-        .band_imm(closure_ptr, crate::conversions::HEAP_PTR_MASK);
+        .band_imm(closure, crate::conversions::HEAP_PTR_MASK);
 
     let fn_ptr = ctx
         .builder
         .ins()
         .load(ctx.word, MemFlags::new(), closure_ptr, 0);
 
-    let mut argsc = vec![closure_ptr];
-
-    // Second argument is the number of arguments we're going to pass in.
-    argsc.push(ctx.builder.ins().iconst(word, args.len() as i64));
-
-    // Allocate space for arguments and stash them away.
-    let argloc = emit_alloc((args.len() * word.bytes() as usize) as i64, ctx)?;
-    for (i, arg) in args.iter().enumerate() {
-        let val = emit_expr(arg, ctx)?;
-        ctx.builder.ins().store(
-            MemFlags::new(),
-            val,
-            argloc,
-            (i * word.bytes() as usize) as i32,
-        );
-    }
-    argsc.push(argloc);
+    // Second argument is the number of arguments we're going to pass
+    // in and the third is where they are.
+    let argsc = vec![closure_ptr, arg_count, argloc];
 
     let sig_ref = ctx.builder.import_signature(sig);
 
     let call = ctx.builder.ins().call_indirect(sig_ref, fn_ptr, &argsc);
-    let res = ctx.builder.inst_results(call)[0];
+    Ok(ctx.builder.inst_results(call)[0])
+}
 
-    Ok(res)
+/// Emits a call that a function makes to itself in tail position as
+/// a jump back to the start of the function with ARGS as its new
+/// params. See `tail.rs`.
+pub(crate) fn emit_self_tail_call(args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let loop_block = ctx
+        .tail_call_block
+        .ok_or("internal error: self tail call outside of a function".to_string())?;
+    let vals = args
+        .iter()
+        .map(|e| emit_expr(e, ctx))
+        .collect::<Result<Vec<_>, _>>()?;
+    ctx.builder.ins().jump(loop_block, &vals);
+
+    // Like after an error the code after the jump is unreachable but
+    // callers expect a value so we make one up.
+    let unreachable_block = ctx.builder.create_block();
+    ctx.builder.switch_to_block(unreachable_block);
+    ctx.builder.seal_block(unreachable_block);
+    Ok(ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep()))
+}
+
+thread_local! {
+    /// The closure, argument count, and arguments of the call that the
+    /// last bounce left for its trampoline to make.
+    static PENDING_CALL: Cell<[Word; 3]> = const { Cell::new([0; 3]) };
+}
+
+/// Returns where the call a bounce leaves for its trampoline is
+/// stored.
+pub(crate) extern "C" fn lustc_pending_call() -> Word {
+    PENDING_CALL.with(|p| p.as_ptr() as Word)
+}
+
+/// Emits a tail call to HEAD with ARGS that leaves the call for the
+/// trampoline it returns to. See `tail.rs`.
+pub(crate) fn emit_bounce(head: &Expr, args: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    let closure = emit_check_callable(head, ctx)?;
+    let (arg_count, argloc) = emit_args(args, ctx)?;
+    let pending = crate::foreign::emit_host_call("lustc_pending_call", &[], ctx)?;
+    let word_size = ctx.word.bytes() as i32;
+    for (i, val) in [closure, arg_count, argloc].iter().enumerate() {
+        ctx.builder
+            .ins()
+            .store(MemFlags::new(), *val, pending, i as i32 * word_size);
+    }
+    Ok(ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, crate::conversions::BOUNCE_VALUE))
+}
+
+/// Emits a call to HEAD with ARGS followed by a trampoline that makes
+/// the calls left by bounces until one of them returns a value.
+pub(crate) fn emit_trampolined_call(
+    head: &Expr,
+    args: &[Expr],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let res = emit_fncall(head, args, ctx)?;
+
+    let trampoline_block = ctx.builder.create_block();
+    let bounce_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(trampoline_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.ins().jump(trampoline_block, &[res]);
+
+    ctx.builder.switch_to_block(trampoline_block);
+    let res = ctx.builder.block_params(trampoline_block)[0];
+    let bounced = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, res, crate::conversions::BOUNCE_VALUE);
+    ctx.builder.ins().brz(bounced, done_block, &[res]);
+    ctx.builder.ins().jump(bounce_block, &[]);
+
+    ctx.builder.switch_to_block(bounce_block);
+    ctx.builder.seal_block(bounce_block);
+    let pending = crate::foreign::emit_host_call("lustc_pending_call", &[], ctx)?;
+    let word_size = ctx.word.bytes() as i32;
+    let call = [0, 1, 2].map(|i| {
+        ctx.builder
+            .ins()
+            .load(ctx.word, MemFlags::new(), pending, i * word_size)
+    });
+    let res = emit_closure_call(call[0], call[1], call[2], ctx)?;
+    ctx.builder.ins().jump(trampoline_block, &[res]);
+    ctx.builder.seal_block(trampoline_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
 }
 
 /// A descriptor of an anonymous function.
@@ -279,9 +466,14 @@ pub struct LustFn {
     pub free_variables: Vec<String>,
     /// The symbol varadic arguments should be bound to if any.
     pub varadic_symbol: Option<String>,
+    /// The index of the top level form the function was defined in.
+    pub form: Option<usize>,
+    /// The name the function is bound to by let, before renaming, if
+    /// it is bound to one.
+    pub binding: Option<String>,
 }
 
-fn is_varadic_param(p: &str) -> bool {
+pub(crate) fn is_varadic_param(p: &str) -> bool {
     p.chars().last() == Some('&')
 }
 
@@ -301,12 +493,12 @@ fn get_and_validate_varadic(sig: &[&String]) -> Result<String, String> {
 }
 
 /// Collects all of the anonymous functions in a program and returns a
-/// list of them.
-pub(crate) fn collect_functions(program: &[Expr]) -> Result<Vec<LustFn>, String> {
+/// list of them. Functions are numbered from FIRST.
+pub(crate) fn collect_functions(program: &[Expr], first: usize) -> Result<Vec<LustFn>, String> {
     let _t = crate::timer::timeit("function collection pass");
     let mut res = Vec::new();
 
-    for e in program {
+    for (form, e) in program.iter().enumerate() {
         e.postorder_traverse_res::<_, String>(&mut |e: &Expr| {
             if let Some((params, body)) = e.is_fndef() {
                 let (varadic_symbol, params) = if is_varadic_signature(&params) {
@@ -325,11 +517,13 @@ pub(crate) fn collect_functions(program: &[Expr]) -> Result<Vec<LustFn>, String>
 
                 let params = params.iter().map(|&s| s.clone()).collect();
                 res.push(LustFn {
-                    name: format!("__anon_fn_{}", res.len()),
+                    name: format!("__anon_fn_{}", first + res.len()),
                     params: params,
                     body: body.iter().map(|e| e.clone()).collect(),
                     free_variables: vec![],
                     varadic_symbol,
+                    form: Some(form),
+                    binding: None,
                 });
             }
             Ok(())
@@ -364,8 +558,14 @@ pub(crate) fn replace_functions(program: &mut [Expr], functions: &mut [LustFn])
                     panic!("fndef outside of a list")
                 }
 
-                *e = Expr::Symbol(format!("__anon_fn_{}", count));
+                *e = Expr::Symbol(functions[count].name.clone());
                 count += 1;
+            } else if let Some((name, Expr::Symbol(value))) = e.is_let() {
+                // The function has been replaced by the time its let
+                // is reached.
+                if let Some(f) = functions[..count].iter_mut().find(|f| &f.name == value) {
+                    f.binding = Some(crate::renamer::original_name(name).to_string());
+                }
             }
         })
     }
@@ -452,7 +652,11 @@ pub(crate) fn annotate_free_variables(f: &mut LustFn) {
         bound.extend(newbound);
     }
 
-    f.free_variables = free.into_iter().cloned().collect();
+    // Sorted so that closures have the same layout every time the
+    // program is compiled.
+    let mut free: Vec<String> = free.into_iter().cloned().collect();
+    free.sort();
+    f.free_variables = free;
 }
 
 /// Emits code to allocate a closure and returns a pointer to it.
@@ -560,6 +764,7 @@ mod tests {
     use crate::roundtrip_string;
 
     use super::*;
+    use crate::test_util::error_kind;
 
     #[test]
     fn test_free_annotation() {
@@ -576,7 +781,7 @@ mod tests {
 "#;
         let mut exprs = parse_string(source).unwrap();
 
-        let mut functions = collect_functions(&exprs).unwrap();
+        let mut functions = collect_functions(&exprs, 0).unwrap();
         for mut f in &mut functions {
             annotate_free_variables(&mut f)
         }
@@ -610,12 +815,12 @@ mod tests {
 "#;
         let mut exprs = parse_string(source).unwrap();
 
-        let mut functions = collect_functions(&exprs).unwrap();
+        let mut functions = collect_functions(&exprs, 0).unwrap();
         assert_eq!(functions.len(), 6);
 
         replace_functions(&mut exprs, &mut functions);
 
-        let functions = collect_functions(&exprs).unwrap();
+        let functions = collect_functions(&exprs, 0).unwrap();
         assert_eq!(functions.len(), 0);
     }
 
@@ -630,7 +835,7 @@ mod tests {
 "#;
         let exprs = parse_string(source).unwrap();
 
-        let functions = collect_functions(&exprs).unwrap();
+        let functions = collect_functions(&exprs, 0).unwrap();
 
         assert_eq!(functions.len(), 6)
     }
@@ -767,7 +972,7 @@ mod tests {
 (foo 26)
 "#;
         let exprs = parse_string(source).unwrap();
-        let functions = collect_functions(&exprs).unwrap();
+        let functions = collect_functions(&exprs, 0).unwrap();
         let function = functions.first().unwrap();
         assert_eq!(Some("m".to_string()), function.varadic_symbol);
         assert_eq!(1, function.params.len())
@@ -787,4 +992,119 @@ mod tests {
         let res = roundtrip_string(source).unwrap();
         assert_eq!(Expr::Integer(4), res)
     }
+
+    #[test]
+    fn apply() {
+        let source = r#"
+(let reg (fn (a b) (sub a b)))
+(let v (fn (a & c) (cons a c)))
+(let direct (cons (reg 5 2) (cons (v 1 2 3) (cons (v 1) (add1 4)))))
+(let applied (cons (apply reg (quote (5 2)))
+                   (cons (apply v (quote (1 2 3)))
+                         (cons (apply v (cons 1 ())) (apply add1 (quote (4)))))))
+(cons (equal direct applied) applied)
+"#;
+        let expected = roundtrip_string(
+            "(cons (eq 1 1) (cons 3 (cons (quote (1 2 3)) (cons (quote (1)) 5))))",
+        )
+        .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        for (source, kind) in [
+            ("(apply (fn (a b) a) (quote (1)))", "arity-error"),
+            ("(apply 1 ())", "bad-call"),
+            ("(apply car 1)", "type-error"),
+        ] {
+            assert_eq!(error_kind(source), kind, "{}", source);
+        }
+    }
+
+    #[test]
+    fn arguments_evaluate_left_to_right() {
+        let source = r#"
+(let log ())
+(let note (fn (x) (set log (cons x log)) x))
+(let f (fn (a b c) a))
+(let g (fn (a b) (fn (c) b)))
+(let v (fn (a & rest) rest))
+(let h f)
+(cons (note 1) (note 2))
+(add (note 3) (note 4) (note 5))
+(lt (note 6) (note 7))
+(f (note 8) (note 9) (note 10))
+((g (note 11) (note 12)) (note 13))
+(v (note 14) (note 15) (note 16))
+(h (note 17) (note 18) (note 19))
+(iota (note 1) (note 20) (note 1))
+(foldr add (note 21) (note (quote (22))))
+(try (error (note (quote oops)) (note "bad") (note 23)) (catch e ()))
+log
+"#;
+        let expected = roundtrip_string(
+            "(quote (23 \"bad\" oops (22) 21 1 20 1 19 18 17 16 15 14 13 12 11 10 9 8 7 6 5 4 3 2 1))",
+        )
+        .unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn many_params() {
+        let params = (0..12).map(|i| format!("p{}", i)).collect::<Vec<_>>();
+        let args = (1..=12).map(|i| i.to_string()).collect::<Vec<_>>();
+        let sum = format!(
+            "(let sum (fn ({}) (add {})))",
+            params.join(" "),
+            params.join(" ")
+        );
+        let expected = Ok(crate::Expr::Integer(78));
+        assert_eq!(
+            roundtrip_string(&format!("{} (sum {})", sum, args.join(" "))),
+            expected
+        );
+        // Through a closure call and apply.
+        assert_eq!(
+            roundtrip_string(&format!(
+                "{} (let call (fn (f {}) (f {}))) (call sum {})",
+                sum,
+                params.join(" "),
+                params.join(" "),
+                args.join(" ")
+            )),
+            expected
+        );
+        assert_eq!(
+            roundtrip_string(&format!("{} (apply sum (list {}))", sum, args.join(" "))),
+            expected
+        );
+        // Each argument ends up in its own parameter.
+        let source = format!(
+            "(let f (fn ({}) (list {}))) (f {})",
+            params.join(" "),
+            params.iter().rev().cloned().collect::<Vec<_>>().join(" "),
+            args.join(" ")
+        );
+        let reversed = format!(
+            "(quote ({}))",
+            args.iter().rev().cloned().collect::<Vec<_>>().join(" ")
+        );
+        assert_eq!(roundtrip_string(&source), roundtrip_string(&reversed));
+        // A function that calls itself in tail position with all of
+        // its arguments moved along.
+        let source = format!(
+            "(let f (fn (n {}) (if (eq n 0) (add {}) (f (sub n 1) {} p0)))) (f 5 {})",
+            params.join(" "),
+            params.join(" "),
+            params[1..].join(" "),
+            args.join(" ")
+        );
+        assert_eq!(roundtrip_string(&source), expected);
+    }
 }
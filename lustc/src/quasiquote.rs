@@ -0,0 +1,166 @@
+//! Quasiquote. `(quasiquote template)`, written `` `template ``, is
+//! TEMPLATE as data like a quote except that the value of each
+//! `(unquote e)`, written `,e`, in it takes its place and the elements
+//! of each `(unquote-splicing e)`, written `,@e`, are spliced into the
+//! list it is in.
+//!
+//! ```lisp
+//! (let x 2)
+//! `(1 ,x 3)                  ; => (1 2 3)
+//! `(0 ,@(list 1 2) (a ,x))   ; => (0 1 2 (a 2))
+//! ```
+//!
+//! A quasiquote is desugared into the calls that build its value. The
+//! parts of the template without unquotes are known at compile time
+//! so they are quoted and become constants in the program's data
+//! rather than being built at runtime. A template with no unquotes at
+//! all is a quote, and the elements after the last unquote in a list
+//! are the single constant list that the rest is consed onto.
+//!
+//! ```lisp
+//! `(1 2 3)       => (quote (1 2 3))
+//! `(1 ,x 3)      => (cons (quote 1) (cons x (quote (3))))
+//! `(,@xs (a b))  => (foldr cons (quote ((a b))) xs)
+//! ```
+//!
+//! The cons and foldr are the builtin ones even where the program
+//! binds those names (see `desugar::builtin`).
+//!
+//! Quasiquotes don't nest. An unquote inside of a quasiquote inside of
+//! another belongs to the outer one.
+
+use crate::desugar::builtin;
+use crate::Expr;
+
+fn sym(s: &str) -> Expr {
+    Expr::Symbol(s.to_string())
+}
+
+fn quote(e: &Expr) -> Expr {
+    Expr::List(vec![sym("quote"), e.clone()])
+}
+
+/// If E is a call to unquote or unquote-splicing returns the name of
+/// the one it is and the expression being unquoted.
+fn is_unquote(e: &Expr) -> Option<(&str, &Expr)> {
+    match e {
+        Expr::List(v) if v.len() == 2 => match &v[0] {
+            Expr::Symbol(s) if s == "unquote" || s == "unquote-splicing" => Some((s, &v[1])),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns true if there is an unquote anywhere in TEMPLATE.
+fn has_unquote(template: &Expr) -> bool {
+    is_unquote(template).is_some() || matches!(template, Expr::List(v) if v.iter().any(has_unquote))
+}
+
+/// Desugars `(quasiquote TEMPLATE)`. ARGS are the arguments to
+/// quasiquote.
+pub(crate) fn desugar_quasiquote(args: &[Expr]) -> Result<Expr, String> {
+    match args {
+        [template] => expand(template),
+        _ => Err("quasiquote expects one template".to_string()),
+    }
+}
+
+fn expand(template: &Expr) -> Result<Expr, String> {
+    if !has_unquote(template) {
+        return Ok(quote(template));
+    }
+    match is_unquote(template) {
+        Some(("unquote", e)) => return Ok(e.clone()),
+        Some(_) => return Err("unquote-splicing is only allowed inside of a list".to_string()),
+        None => (),
+    }
+    let items = match template {
+        Expr::List(v) => v,
+        _ => unreachable!("only lists can contain unquotes"),
+    };
+    // Everything after the last element with an unquote in it is the
+    // constant the rest of the list is built onto.
+    let dynamic = items.iter().rposition(has_unquote).unwrap() + 1;
+    let mut res = match &items[dynamic..] {
+        [] => Expr::Nil,
+        suffix => quote(&Expr::List(suffix.to_vec())),
+    };
+    for item in items[..dynamic].iter().rev() {
+        res = match is_unquote(item) {
+            Some(("unquote-splicing", e)) => {
+                Expr::List(vec![builtin("foldr"), builtin("cons"), res, e.clone()])
+            }
+            _ => Expr::List(vec![builtin("cons"), expand(item)?, res]),
+        };
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::check;
+    use crate::{parse_string, roundtrip_string};
+
+    /// Returns the number of constants in the program's data once
+    /// SOURCE is desugared and folded.
+    fn data_count(source: &str) -> usize {
+        let mut program = parse_string(source).unwrap();
+        crate::desugar::desugar(&mut program).unwrap();
+        crate::renamer::make_names_unique(&mut program).unwrap();
+        crate::fold::fold_constants(&mut program);
+        crate::data::extract_data(&mut program, 0).len()
+    }
+
+    #[test]
+    fn static_templates() {
+        assert_eq!(data_count("`(1 2 (3 \"four\") five)"), 1);
+        check(
+            "`(1 2 (3 \"four\") five)",
+            "(quote (1 2 (3 \"four\") five))",
+        );
+        check("`()", "()");
+        check("`a", "(quote a)");
+    }
+
+    #[test]
+    fn unquotes() {
+        let mut program = parse_string("`(1 ,x 3)").unwrap();
+        crate::desugar::desugar(&mut program).unwrap();
+        assert_eq!(
+            program,
+            parse_string("(__anon_builtin_cons (quote 1) (__anon_builtin_cons x (quote (3))))")
+                .unwrap()
+        );
+        // Only the list after x is in the program's data. The x and 1
+        // aren't constants that need storage.
+        assert_eq!(data_count("(let x 2) `(1 ,x 3)"), 1);
+
+        check("(let x 2) `(1 ,x 3)", "(quote (1 2 3))");
+        check(
+            "(let x 2) `(0 ,@(list 1 x) (a ,x) ,@())",
+            "(quote (0 1 2 (a 2)))",
+        );
+        check("(let x 2) `,(add x 1)", "3");
+        check("(let xs (list 1 2)) `(,@xs ,@xs)", "(quote (1 2 1 2))");
+        // The template is built with the builtin cons and foldr.
+        check(
+            "(let f (fn (cons foldr) `(1 ,cons ,@(list foldr)))) (f 2 3)",
+            "(quote (1 2 3))",
+        );
+        // Unquoted expressions are desugared like any other.
+        check("(let x 2) `(,(and x 3) ,`(,x))", "(quote (3 (2)))");
+    }
+
+    #[test]
+    fn bad_templates() {
+        assert_eq!(
+            roundtrip_string("`,@(list 1)"),
+            Err("unquote-splicing is only allowed inside of a list".to_string())
+        );
+        assert_eq!(
+            roundtrip_string("(quasiquote 1 2)"),
+            Err("quasiquote expects one template".to_string())
+        );
+    }
+}
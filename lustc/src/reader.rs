@@ -1,7 +1,13 @@
 //! A small reader that reads non-whitespace tokens from an input
-//! string and keeps track of its visual location in the string.
+//! string and keeps track of its visual location in the string. The
+//! input is any source of characters so a program can be read from a
+//! stream a little at a time without holding all of its source in
+//! memory.
 
-use std::iter;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::rc::Rc;
 
 /// A location in a string.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -12,26 +18,49 @@ pub struct Location {
 
 /// A reader which yields characters from a SOURCE. The reader keeps
 /// track of its location in LOC and never yields whitespace tokens.
-#[derive(Debug)]
 pub(crate) struct Reader<'a> {
     // Tracks the visible location of the reader. This means that tabs
     // do not count as a single character.
     pub loc: Location,
-    source: &'a str,
-    chars: iter::Peekable<std::str::Chars<'a>>,
+    chars: Box<dyn Iterator<Item = char> + 'a>,
+    // Characters that have been peeked at but not read yet.
+    lookahead: VecDeque<char>,
+}
+
+impl std::fmt::Debug for Reader<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Reader")
+            .field("loc", &self.loc)
+            .field("lookahead", &self.lookahead)
+            .finish()
+    }
 }
 
 impl<'a> Reader<'a> {
     /// Makes a new reader at line zero and column zero in SOURCE.
     pub(crate) fn new(source: &'a str) -> Self {
+        Self::from_chars(source.chars())
+    }
+
+    /// Makes a new reader at line zero and column zero in the
+    /// characters CHARS.
+    pub(crate) fn from_chars(chars: impl Iterator<Item = char> + 'a) -> Self {
         Self {
             loc: Location::new(),
-            source,
-            chars: source.chars().peekable(),
+            chars: Box::new(chars),
+            lookahead: VecDeque::new(),
+        }
+    }
+
+    /// Makes sure that at least N characters have been peeked at if
+    /// there are that many left.
+    fn fill_lookahead(&mut self, n: usize) {
+        while self.lookahead.len() < n {
+            match self.chars.next() {
+                Some(c) => self.lookahead.push_back(c),
+                None => break,
+            }
         }
-        // Prime the reader.
-        // s.skip_whitespace();
-        // s
     }
 
     /// Updates the location of the reader based on C. If C is a tab
@@ -70,7 +99,8 @@ impl<'a> Reader<'a> {
     fn next_char(&mut self) -> Option<char> {
         // We assume that our past self has skipped whitespace so we
         // can skip the call here.
-        let c = self.chars.next();
+        self.fill_lookahead(1);
+        let c = self.lookahead.pop_front();
         if let Some(c) = c {
             self.update_loc(c);
         }
@@ -108,7 +138,8 @@ impl<'a> Reader<'a> {
 
     /// Peek at the current character.
     pub(crate) fn peek(&mut self) -> Option<&char> {
-        self.chars.peek()
+        self.fill_lookahead(1);
+        self.lookahead.front()
     }
 
     /// Get the current character and advance the reader.
@@ -116,13 +147,9 @@ impl<'a> Reader<'a> {
         self.next_char()
     }
 
-    pub(crate) fn peek_2(&self) -> Option<char> {
-        let mut copy = self.chars.clone();
-        copy.next();
-        match copy.peek() {
-            Some(s) => Some(s.clone()),
-            None => None,
-        }
+    pub(crate) fn peek_2(&mut self) -> Option<char> {
+        self.fill_lookahead(2);
+        self.lookahead.get(1).copied()
     }
 
     /// Get the readers current location.
@@ -131,6 +158,71 @@ impl<'a> Reader<'a> {
     }
 }
 
+/// The characters of a UTF-8 encoded stream. Reading stops at the
+/// first error, which is left in ERROR for whoever is reading to
+/// report once they are done.
+pub(crate) struct StreamChars<R: Read> {
+    bytes: std::io::Bytes<std::io::BufReader<R>>,
+    pub(crate) error: Rc<RefCell<Option<String>>>,
+}
+
+impl<R: Read> StreamChars<R> {
+    pub(crate) fn new(r: R) -> Self {
+        Self {
+            bytes: std::io::BufReader::new(r).bytes(),
+            error: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        match self.bytes.next()? {
+            Ok(b) => Some(b),
+            Err(e) => {
+                *self.error.borrow_mut() = Some(e.to_string());
+                None
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.error.borrow().is_some() {
+            return None;
+        }
+        let first = self.next_byte()?;
+        // The number of ones before the first zero in the first byte
+        // of a character is how many bytes it takes up.
+        let len = match first.leading_ones() {
+            0 => 1,
+            n @ 2..=4 => n as usize,
+            _ => 0,
+        };
+        let mut buf = [first, 0, 0, 0];
+        for b in buf.iter_mut().take(len).skip(1) {
+            match self.next_byte() {
+                Some(next) => *b = next,
+                None => break,
+            }
+        }
+        match std::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|s| s.chars().next())
+        {
+            Some(c) if len > 0 => Some(c),
+            _ => {
+                let mut error = self.error.borrow_mut();
+                if error.is_none() {
+                    *error = Some("stream did not contain valid UTF-8".to_string());
+                }
+                None
+            }
+        }
+    }
+}
+
 impl Location {
     /// Makes a new location at position (0, 0)
     pub(crate) fn new() -> Self {
@@ -0,0 +1,353 @@
+//! Records are values with a type and a fixed set of named fields.
+//! `define-record` names the type and its fields and defines a
+//! constructor, a predicate and an accessor for every field.
+//!
+//! ```lisp
+//! (define-record point (x y))
+//! (let p (make-point 1 2))
+//! (point? p)  ; => true
+//! (point-y p) ; => 2
+//! ```
+//!
+//! A record lives on the heap and shares VALUES_TAG with tuples. It
+//! is three words: RECORD_HEADER, the record's type, which is a
+//! symbol with the type's name, and a vector of its fields in the
+//! order they were named. Nothing else can see the vector so the
+//! fields can't be changed and no other value is mistaken for a
+//! record. `type-of` says a record's type is `record`.
+//!
+//! `(make-record type fields)` makes a record of TYPE that keeps the
+//! vector FIELDS, `(record-type x)` returns the type of X or nil if
+//! it isn't a record, and `(record-ref r type index)` returns field
+//! INDEX of R, raising a type error if R isn't a record of TYPE.
+//!
+//! `define-record` forms are expanded when the program is desugared
+//! and only at the top level since a single one turns into a top level
+//! let for each definition. The definitions call the builtins even if
+//! the program binds their names (see `desugar.rs`):
+//!
+//! ```lisp
+//! (let make-point (fn (x y) (make-record (quote point) (list->vector (list x y)))))
+//! (let point? (fn (v) (eq (record-type v) (quote point))))
+//! (let point-x (fn (v) (record-ref v (quote point) 0)))
+//! (let point-y (fn (v) (record-ref v (quote point) 1)))
+//! ```
+//!
+//! Records have no setters. The definitions are never reported as
+//! unused since the program didn't write them.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{
+    FIXNUM_SHIFT, HEAP_PTR_MASK, HEAP_TAG_MASK, NIL_VALUE, RECORD_HEADER, SYMBOL_TAG, VALUES_TAG,
+};
+use crate::desugar::{builtin, BUILTIN_PREFIX};
+use crate::fatal;
+use crate::heap::emit_alloc;
+use crate::vectors::{emit_element_address, emit_vector_parts};
+use crate::{Expr, Word};
+
+/// The builtins that the definitions of a record call.
+const RECORD_BUILTINS: [&str; 3] = ["make-record", "record-type", "record-ref"];
+
+fn sym(s: &str) -> Expr {
+    Expr::Symbol(s.to_string())
+}
+
+fn list(v: Vec<Expr>) -> Expr {
+    Expr::List(v)
+}
+
+fn quote(e: Expr) -> Expr {
+    list(vec![sym("quote"), e])
+}
+
+/// Returns the type and the vector of fields of RECORD.
+pub(crate) fn record_parts(record: Word) -> (Word, Word) {
+    let ptr = (record & HEAP_PTR_MASK) as *const Word;
+    unsafe { (*ptr.add(1), *ptr.add(2)) }
+}
+
+/// If E is a `define-record` form returns the definitions that it
+/// stands for.
+pub(crate) fn expand_define_record(e: &Expr) -> Result<Option<Vec<Expr>>, String> {
+    let v = match e {
+        Expr::List(v) if v.first() == Some(&sym("define-record")) => v,
+        _ => return Ok(None),
+    };
+    let (name, fields) = match v.as_slice() {
+        [_, Expr::Symbol(name), Expr::List(fields)] => (name, fields.as_slice()),
+        [_, Expr::Symbol(name), Expr::Nil] => (name, &[][..]),
+        _ => {
+            return Err(format!(
+                "define-record expects a name and a list of fields and got ({:?})",
+                e
+            ))
+        }
+    };
+    let fields = fields
+        .iter()
+        .map(|f| match f {
+            Expr::Symbol(f) => Ok(f),
+            _ => Err(format!("record field ({:?}) should be a symbol", f)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let define = |fn_name: String, params: Vec<Expr>, body: Expr| {
+        let params = if params.is_empty() {
+            Expr::Nil
+        } else {
+            list(params)
+        };
+        list(vec![
+            sym("let"),
+            Expr::Symbol(fn_name),
+            list(vec![sym("fn"), params, body]),
+        ])
+    };
+    let type_name = quote(sym(name));
+    let mut res = Vec::with_capacity(fields.len() + 2);
+
+    let elements = std::iter::once(builtin("list"))
+        .chain(fields.iter().map(|f| sym(f)))
+        .collect();
+    res.push(define(
+        format!("make-{}", name),
+        fields.iter().map(|f| sym(f)).collect(),
+        list(vec![
+            builtin("make-record"),
+            type_name.clone(),
+            list(vec![builtin("list->vector"), list(elements)]),
+        ]),
+    ));
+
+    res.push(define(
+        format!("{}?", name),
+        vec![sym("v")],
+        list(vec![
+            builtin("eq"),
+            list(vec![builtin("record-type"), sym("v")]),
+            type_name.clone(),
+        ]),
+    ));
+
+    for (i, field) in fields.iter().enumerate() {
+        res.push(define(
+            format!("{}-{}", name, field),
+            vec![sym("v")],
+            list(vec![
+                builtin("record-ref"),
+                sym("v"),
+                type_name.clone(),
+                Expr::Integer(i as i64),
+            ]),
+        ));
+    }
+    Ok(Some(res))
+}
+
+/// Returns true if VALUE, the value of a top level let, is one of the
+/// functions that `define-record` defines. The program can't refer to
+/// the builtins by the names those functions use so it can't have
+/// written one.
+pub(crate) fn is_record_definition(value: &Expr) -> bool {
+    // Returns the name of the builtin that E calls.
+    fn called(e: &Expr) -> Option<&str> {
+        match e {
+            Expr::List(v) => match v.first() {
+                Some(Expr::Symbol(s)) => s.strip_prefix(BUILTIN_PREFIX),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    let body = match value {
+        Expr::List(v) if v.len() == 3 && v[0] == sym("fn") => &v[2],
+        _ => return false,
+    };
+    match (called(body), body) {
+        (Some("eq"), Expr::List(v)) => v.get(1).and_then(called) == Some("record-type"),
+        (Some(name), _) => RECORD_BUILTINS.contains(&name),
+        _ => false,
+    }
+}
+
+/// Emits the code for `(make-record type fields)` where both arguments
+/// have already been evaluated.
+pub(crate) fn emit_make_record(
+    type_name: Value,
+    fields: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_tag(type_name, SYMBOL_TAG, HEAP_TAG_MASK, ctx)?;
+    emit_vector_parts(fields, ctx)?;
+
+    let word_size = ctx.word.bytes() as i32;
+    let ptr = emit_alloc((3 * word_size).into(), ctx)?;
+    let header = ctx.builder.ins().iconst(ctx.word, RECORD_HEADER);
+    for (i, val) in [header, type_name, fields].iter().enumerate() {
+        ctx.builder
+            .ins()
+            .store(MemFlags::new(), *val, ptr, i as i32 * word_size);
+    }
+    Ok(ctx.builder.ins().bor_imm(ptr, VALUES_TAG))
+}
+
+/// Emits the code for `(record-type x)` where X has already been
+/// evaluated.
+pub(crate) fn emit_record_type(val: Value, ctx: &mut Context) -> Value {
+    let is_record = crate::conditions::emit_has_header(val, RECORD_HEADER, ctx);
+
+    let record_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let nil = ctx.builder.ins().iconst(ctx.word, NIL_VALUE);
+    ctx.builder.ins().brz(is_record, done_block, &[nil]);
+    ctx.builder.ins().jump(record_block, &[]);
+
+    ctx.builder.switch_to_block(record_block);
+    ctx.builder.seal_block(record_block);
+    let ptr = ctx.builder.ins().band_imm(val, HEAP_PTR_MASK);
+    let type_name = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, ctx.word.bytes() as i32);
+    ctx.builder.ins().jump(done_block, &[type_name]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    ctx.builder.block_params(done_block)[0]
+}
+
+/// Emits the code for `(record-ref record type index)` where every
+/// argument has already been evaluated.
+pub(crate) fn emit_record_ref(
+    record: Value,
+    type_name: Value,
+    index: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    // Anything that isn't a record has a type of nil, which isn't a
+    // symbol, so this checks both at once.
+    let actual = emit_record_type(record, ctx);
+    let same_type = ctx.builder.ins().icmp(IntCC::Equal, actual, type_name);
+    fatal::emit_check_type(same_type, ctx)?;
+
+    let ptr = ctx.builder.ins().band_imm(record, HEAP_PTR_MASK);
+    let fields =
+        ctx.builder
+            .ins()
+            .load(ctx.word, MemFlags::new(), ptr, 2 * ctx.word.bytes() as i32);
+    let (ptr, length) = emit_vector_parts(fields, ctx)?;
+    fatal::emit_check_int(index, ctx)?;
+    fatal::emit_check_index(index, length, ctx)?;
+    let index = ctx.builder.ins().sshr_imm(index, FIXNUM_SHIFT);
+    let address = emit_element_address(ptr, index, ctx);
+    Ok(ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), address, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_string;
+    use crate::test_util::{check, error_kind};
+    use crate::Expr;
+
+    #[test]
+    fn point() {
+        let point = r#"
+(define-record point (x y))
+(let p (make-point 1 2))
+"#;
+        check(
+            &format!("{} (cons (point? p) (point? (quote (point 1 2))))", point),
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+        check(
+            &format!("{} (cons (point-x p) (point-y p))", point),
+            "(cons 1 2)",
+        );
+        check("(define-record empty ()) (empty? (make-empty))", "(eq 1 1)");
+    }
+
+    #[test]
+    fn records_are_not_vectors() {
+        let point = r#"
+(define-record point (x y))
+(let p (make-point 1 2))
+"#;
+        check(
+            &format!(
+                "{} (list (point? (list->vector (list (quote point) 1 2))) (vector? p) (type-of p) (record-type p) (record-type 1))",
+                point
+            ),
+            "(list (eq 1 2) (eq 1 2) (quote record) (quote point) ())",
+        );
+        assert_eq!(
+            error_kind(&format!("{} (vector-set! p 0 (quote line))", point)),
+            "type-error"
+        );
+        // Binding the builtins doesn't change what the definitions do.
+        check(
+            "(let list 1) (define-record point (x)) (point-x (make-point 2))",
+            "2",
+        );
+    }
+
+    #[test]
+    fn built_programs() {
+        let mut program = vec![
+            Expr::list(vec![
+                Expr::sym("define-record"),
+                Expr::sym("point"),
+                Expr::list(vec![Expr::sym("x"), Expr::sym("y")]),
+            ]),
+            Expr::list(vec![
+                Expr::sym("point-y"),
+                Expr::list(vec![Expr::sym("make-point"), Expr::int(1), Expr::int(2)]),
+            ]),
+        ];
+        assert_eq!(
+            crate::compiler::roundtrip_program(&mut program).unwrap(),
+            Expr::int(2)
+        );
+        assert_eq!(
+            crate::interpreter::interpret(&program).unwrap(),
+            Expr::int(2)
+        );
+        assert!(
+            crate::roundtrip_string("(let f (fn () (define-record point (x)) 1)) (f)").is_err()
+        );
+    }
+
+    #[test]
+    fn wrong_type() {
+        let source = r#"
+(define-record point (x y))
+(define-record line (x y))
+"#;
+        for access in [
+            "(point-x (make-line 1 2))",
+            "(point-y (list->vector (quote (point 1 2))))",
+            "(point-x 1)",
+            "(make-record 1 (list->vector ()))",
+            "(make-record (quote point) ())",
+        ] {
+            assert_eq!(error_kind(&format!("{} {}", source, access)), "type-error");
+        }
+        assert_eq!(
+            error_kind(&format!(
+                "{} (point-y (make-record (quote point) (list->vector (list 1))))",
+                source
+            )),
+            "range-error"
+        );
+
+        assert!(parse_string("(define-record point x)").is_err());
+        assert!(parse_string("(define-record point (1))").is_err());
+    }
+}
@@ -1,10 +1,12 @@
 //! Pass to give all variables unique names. Makes later program
 //! analysis easier and ensures that builtin names like __anon_fn and
-//! __anon_data don't conflict with user defined names.
+//! __anon_data don't conflict with user defined names. The references
+//! to builtins that desugaring makes are resolved to the builtin
+//! before the variables in scope are looked at.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::primitives::string_is_builtin;
+use crate::primitives::{primitive_alias, string_is_builtin};
 use crate::Expr;
 use crate::PreorderStatus;
 
@@ -90,6 +92,15 @@ impl Expr {
         ))
     }
 
+    /// If the expression is a reference made with `desugar::builtin`
+    /// returns the name of the builtin.
+    fn is_builtin_reference(&self) -> Option<&str> {
+        match self {
+            Self::Symbol(s) => s.strip_prefix(crate::desugar::BUILTIN_PREFIX),
+            _ => None,
+        }
+    }
+
     fn get_fn_body_mut(&mut self) -> Result<&mut [Expr], String> {
         if let Some(_) = self.is_fndef() {
             if let Self::List(v) = self {
@@ -103,19 +114,31 @@ impl Expr {
     }
 }
 
+/// Renames the variables in EXPR. ENV maps the variables in scope to
+/// their new names and PENDING maps the top level definitions that
+/// come after EXPR to the counts their names will be given, so that
+/// functions can call definitions that come after them. A variable
+/// that isn't defined is left as it is and its error is added to
+/// UNDEFINED.
 fn make_expr_names_unique(
     expr: &mut Expr,
     env: &mut HashMap<String, String>,
+    pending: &HashMap<String, usize>,
     count: &mut usize,
+    undefined: &mut Vec<String>,
 ) -> Result<(), String> {
     expr.preorder_traverse_mut_res::<_, String>(&mut |expr| {
+        // Symbols in quoted data are data and not variables.
+        if expr.is_quote().is_some() {
+            return Ok(PreorderStatus::Skip);
+        }
         if let Some(_) = expr.is_let() {
             let old_name = expr.get_let_name()?;
 
             let name_exists = env.contains_key(&old_name) || string_is_builtin(&old_name);
 
             if name_exists {
-                make_expr_names_unique(expr.get_let_value_mut()?, env, count)?;
+                make_expr_names_unique(expr.get_let_value_mut()?, env, pending, count, undefined)?;
             }
 
             expr.rename_let_binding(*count)?;
@@ -129,7 +152,7 @@ fn make_expr_names_unique(
             // the body using the new variable name so that recursion
             // works as expected.
             if !name_exists {
-                make_expr_names_unique(expr.get_let_value_mut()?, env, count)?;
+                make_expr_names_unique(expr.get_let_value_mut()?, env, pending, count, undefined)?;
             }
 
             // Because we've already traversed the let expression's
@@ -143,17 +166,39 @@ fn make_expr_names_unique(
             let mut nenv = env.clone();
             expr.rename_fn_params(count, &mut nenv)?;
             for e in expr.get_fn_body_mut()? {
-                make_expr_names_unique(e, &mut nenv, count)?;
+                make_expr_names_unique(e, &mut nenv, pending, count, undefined)?;
             }
             // We've already traversed the body so we don't want the
             // traversal to continue on this expr.
             return Ok(PreorderStatus::Skip);
+        } else if let Some(name) = expr.is_builtin_reference() {
+            // Desugaring refers to builtins in a way that the
+            // program's variables can't capture.
+            *expr = Expr::Symbol(primitive_alias(name).unwrap_or(name).to_string());
         } else if let Expr::Symbol(s) = expr {
+            // Aliases of primitives like + are replaced with the name
+            // of the primitive they stand for so that later passes
+            // only need to know about one name.
             let newname = env
                 .get(s)
-                .or_else(|| if string_is_builtin(s) { Some(s) } else { None })
-                .ok_or(format!("undefined variable ({})", s))?;
-            *s = newname.to_string();
+                .cloned()
+                .or_else(|| {
+                    if string_is_builtin(s) {
+                        Some(primitive_alias(s).unwrap_or(s).to_string())
+                    } else {
+                        None
+                    }
+                })
+                .or_else(|| pending.get(s).map(|count| format!("{}_{}", count, s)));
+            match newname {
+                Some(newname) => *s = newname,
+                None => {
+                    let e = format!("undefined variable ({})", s);
+                    if !undefined.contains(&e) {
+                        undefined.push(e);
+                    }
+                }
+            }
         }
 
         Ok(PreorderStatus::Continue)
@@ -161,14 +206,91 @@ fn make_expr_names_unique(
     Ok(())
 }
 
+/// Returns the name that the renamed symbol NAME had before renaming.
+pub(crate) fn original_name(name: &str) -> &str {
+    match name.split_once('_') {
+        Some((count, original)) if count.chars().all(|c| c.is_ascii_digit()) => original,
+        _ => name,
+    }
+}
+
 pub fn make_names_unique(program: &mut [Expr]) -> Result<(), String> {
+    make_names_unique_with_globals(program, &HashSet::new())
+}
+
+/// Like `make_names_unique` but the variables in GLOBALS are defined
+/// and are renamed to their slots. See `globals.rs`.
+pub(crate) fn make_names_unique_with_globals(
+    program: &mut [Expr],
+    globals: &HashSet<String>,
+) -> Result<(), String> {
+    rename_forms(program, globals).map_err(|(_, e)| e)
+}
+
+/// Like `make_names_unique_with_globals` but returns the index of the
+/// form an error is in along with the error.
+pub(crate) fn rename_forms(
+    program: &mut [Expr],
+    globals: &HashSet<String>,
+) -> Result<(), (usize, String)> {
+    match rename_each_form(program, globals).into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Like `rename_forms` but carries on past an error so that every
+/// undefined variable in each form is returned along with the index
+/// of its form. The variables that aren't defined keep their names.
+pub(crate) fn rename_each_form(
+    program: &mut [Expr],
+    globals: &HashSet<String>,
+) -> Vec<(usize, String)> {
     let _t = crate::timer::timeit("symbol renaming pass");
     let mut count = 0;
-    let mut env = HashMap::new();
+    let mut env: HashMap<String, String> = globals
+        .iter()
+        .map(|name| (name.clone(), crate::globals::slot_name(name)))
+        .collect();
 
-    for e in program {
-        make_expr_names_unique(e, &mut env, &mut count)?;
+    // Names the top level definitions of variables that aren't
+    // defined yet ahead of time so that the forms before them can
+    // refer to them. See `forward.rs`.
+    let mut pending = HashMap::new();
+    for e in program.iter() {
+        if let Some((name, _)) = e.is_let() {
+            if !env.contains_key(name) && !string_is_builtin(name) && !pending.contains_key(name) {
+                pending.insert(name.clone(), count);
+                count += 1;
+            }
+        }
     }
 
-    Ok(())
+    let mut errors = Vec::new();
+    for (form, e) in program.iter_mut().enumerate() {
+        let mut undefined = Vec::new();
+        let early = e.is_let().and_then(|(name, _)| pending.remove_entry(name));
+        let res = match early {
+            Some((name, early)) => {
+                // Like a let whose name is new, the value is renamed
+                // after the name so that recursion works.
+                e.rename_let_binding(early).and_then(|_| {
+                    env.insert(name, e.get_let_name()?);
+                    make_expr_names_unique(
+                        e.get_let_value_mut()?,
+                        &mut env,
+                        &pending,
+                        &mut count,
+                        &mut undefined,
+                    )
+                })
+            }
+            None => make_expr_names_unique(e, &mut env, &pending, &mut count, &mut undefined),
+        };
+        errors.extend(undefined.into_iter().map(|e| (form, e)));
+        if let Err(e) = res {
+            errors.push((form, e));
+        }
+    }
+    errors
 }
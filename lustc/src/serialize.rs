@@ -0,0 +1,203 @@
+//! Reading and writing values as text while a program runs.
+//!
+//! `(write x)` returns the external representation of X as a string.
+//! It is what print writes except that symbols keep their case and
+//! strings are quoted with their quotes, backslashes, newlines and
+//! tabs escaped so that `(read s)` can parse the string back into an
+//! `equal` value. read uses the same reader as programs do and
+//! returns the datum in S. It raises a read error if S is empty, has
+//! unbalanced parens or has anything after the datum, and a type error
+//! if S isn't a string.
+//!
+//! ```lisp
+//! (write (quote (a "b" 1)))       ; => "(a \"b\" 1)"
+//! (read "(a \"b\" 1)")            ; => (a "b" 1)
+//! ```
+//!
+//! Proper lists, strings, symbols and integers read back as they were
+//! written. Dotted pairs, characters, bools and closures are written
+//! in forms that the reader doesn't understand. Closures are written
+//! as #<procedure>.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{string_to_immediate, try_stringify_list};
+use crate::fatal;
+use crate::foreign::emit_host_call;
+use crate::parser::Parser;
+use crate::strings::expr_to_string;
+use crate::{Expr, Word};
+
+/// The value the host returns when read is given something that isn't
+/// a string. The reader never makes bools so neither this nor
+/// UNREADABLE can be mistaken for a datum.
+const NOT_A_STRING: Expr = Expr::Bool(false);
+
+/// The value the host returns when the string isn't one datum.
+const UNREADABLE: Expr = Expr::Bool(true);
+
+/// Returns the external representation of E as written by write.
+pub(crate) fn write_string(e: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(e, &mut out);
+    out
+}
+
+fn write_expr(e: &Expr, out: &mut String) {
+    match e {
+        Expr::Nil => out.push_str("()"),
+        Expr::Symbol(s) => out.push_str(s),
+        Expr::String(s) => write_quoted(s, out),
+        Expr::List(l) => match try_stringify_list(e) {
+            Some(s) => write_quoted(&s, out),
+            None => write_pairs(l, out),
+        },
+        e => out.push_str(&e.to_string()),
+    }
+}
+
+/// Writes the list whose first pair is PAIR.
+fn write_pairs(pair: &[Expr], out: &mut String) {
+    out.push('(');
+    write_expr(&pair[0], out);
+    let mut rest = &pair[1];
+    loop {
+        match rest {
+            Expr::Nil => break,
+            Expr::List(l) => {
+                out.push(' ');
+                write_expr(&l[0], out);
+                rest = &l[1];
+            }
+            e => {
+                out.push_str(" . ");
+                write_expr(e, out);
+                break;
+            }
+        }
+    }
+    out.push(')');
+}
+
+fn write_quoted(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Returns the datum in S or None if S doesn't hold exactly one.
+/// Unlike `parse_string` nothing is shown when S can't be read.
+pub(crate) fn read_string(s: &str) -> Option<Expr> {
+    let mut parser = Parser::new(s);
+    if !parser.has_more() {
+        return None;
+    }
+    let res = parser.parse_expr();
+    if !res.errors.is_empty() || parser.has_more() {
+        return None;
+    }
+    res.expr?.into_expr().ok()
+}
+
+/// Implements (write x).
+pub(crate) extern "C" fn lustc_write(word: Word) -> Word {
+    string_to_immediate(&write_string(&Expr::from_immediate(word)))
+}
+
+/// Implements (read s). Returns NOT_A_STRING or UNREADABLE if S
+/// can't be read.
+pub(crate) extern "C" fn lustc_read(word: Word) -> Word {
+    match expr_to_string(&Expr::from_immediate(word)) {
+        Some(s) => read_string(&s).unwrap_or(UNREADABLE),
+        None => NOT_A_STRING,
+    }
+    .immediate_rep()
+}
+
+/// Emits the code for `(read s)` where ARG is S.
+pub(crate) fn emit_read(arg: Value, ctx: &mut Context) -> Result<Value, String> {
+    let res = emit_host_call("lustc_read", &[arg], ctx)?;
+    let is_string = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::NotEqual, res, NOT_A_STRING.immediate_rep());
+    fatal::emit_check_type(is_string, ctx)?;
+    let readable = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::NotEqual, res, UNREADABLE.immediate_rep());
+    fatal::emit_check_readable(readable, ctx)?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_string;
+    use crate::test_util::{check, error_kind};
+
+    #[test]
+    fn write_and_read() {
+        check(
+            "(write (cons (quote a) (cons (quote (b \"c d\")) (cons (sub 0 12) ()))))",
+            "\"(a (b \\\"c d\\\") -12)\"",
+        );
+        check("(write (cons 1 2))", "\"(1 . 2)\"");
+        check(r#"(write "say \"hi\"\n")"#, r#""\"say \\\"hi\\\"\\n\"""#);
+        check(
+            "(read \"(1 (two \\\"three\\\") 4)\")",
+            "(quote (1 (two \"three\") 4))",
+        );
+        check("(read \"(-3 4)\")", "(cons (sub 0 3) (quote (4)))");
+        check("(read \" 7 \")", "7");
+        check("(let f write) (let g read) (g (f 5))", "5");
+        check("(write (fn (x) x))", "\"#<procedure>\"");
+        check(
+            "(write (cons 1 (make-parameter 2)))",
+            "\"(1 . #<procedure>)\"",
+        );
+    }
+
+    #[test]
+    fn read_errors() {
+        for source in [
+            "(read \"\")",
+            "(read \"  \")",
+            "(read \"(1 2\")",
+            "(read \"1)\")",
+            "(read \"12 13\")",
+            "(let r read) (r \"(a) b\")",
+        ] {
+            assert_eq!(error_kind(source), "read-error", "{}", source);
+            assert_eq!(
+                crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap_err(),
+                "fatal error: read expects a string holding one datum",
+                "{}",
+                source
+            );
+        }
+        assert_eq!(error_kind("(read 1)"), "type-error");
+        check(
+            "(try (read \"(1\") (catch e (condition-type e)))",
+            "(quote read-error)",
+        );
+    }
+
+    #[test]
+    fn roundtrip_nested_list_with_string() {
+        let source = r#"
+(let v (cons (sub 0 40)
+  (quote (2000000000000000000 ("two \"2\"" (three)) "" (a "b\\c")))))
+(equal (read (write v)) v)
+"#;
+        check(source, "(eq 1 1)");
+    }
+}
@@ -0,0 +1,113 @@
+//! Finds bindings that shadow a builtin. A let or a function
+//! parameter may use the name of a builtin like `cons` or `add`.
+//! Inside the binding's scope the name refers to the binding and
+//! outside of it the builtin is back:
+//!
+//! ```lisp
+//! (let f (fn () (let add sub) (add 3 1))) ; (f) => 2
+//! (add 3 1)                               ; => 4
+//! ```
+//!
+//! The renamer is what makes this work. It gives the binding a name
+//! of its own and only leaves a symbol alone as a builtin if no
+//! binding for it is in scope. That is legal but easy to do by
+//! accident so each shadow is reported as a warning, which `lustc
+//! --warn-shadow` prints.
+
+use crate::primitives::string_is_builtin;
+use crate::Expr;
+use crate::PreorderStatus;
+
+/// A binding that hides a builtin with the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowedBuiltin {
+    /// The name of the builtin.
+    pub name: String,
+    /// The index of the top level form that contains the binding.
+    pub form: usize,
+}
+
+impl std::fmt::Display for ShadowedBuiltin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "warning: ({}) shadows a builtin", self.name)
+    }
+}
+
+/// Returns the bindings in PROGRAM that shadow a builtin in the order
+/// that they appear. Like unused definitions this is only a warning
+/// and the program may still be compiled.
+pub fn find_shadowed_builtins(program: &[Expr]) -> Result<Vec<ShadowedBuiltin>, String> {
+    let mut program = program.to_vec();
+    crate::desugar::desugar(&mut program)?;
+
+    let mut res = Vec::new();
+    for (form, e) in program.iter().enumerate() {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            let names = match (e.is_let(), e.is_fndef()) {
+                (Some((name, _)), _) => vec![name],
+                (_, Some((params, _))) => params,
+                _ => vec![],
+            };
+            for name in names {
+                if string_is_builtin(name) {
+                    res.push(ShadowedBuiltin {
+                        name: name.clone(),
+                        form,
+                    });
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn shadowed_inside_restored_outside() {
+        let source = r#"
+(let f (fn () (let + sub) (+ 3 1)))
+(cons (f) (+ 3 1))
+"#;
+        let expected = roundtrip_string("(cons 2 4)").unwrap();
+        assert_eq!(roundtrip_string(source).unwrap(), expected);
+        assert_eq!(
+            crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+            expected
+        );
+
+        assert_eq!(
+            find_shadowed_builtins(&parse_string(source).unwrap()).unwrap(),
+            vec![ShadowedBuiltin {
+                name: "+".to_string(),
+                form: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn parameters_and_quotes() {
+        let source = r#"
+(let pair (fn (cons car) (add cons car)))
+(pair 1 2)
+(quote (let add 1))
+"#;
+        let names: Vec<_> = find_shadowed_builtins(&parse_string(source).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["cons", "car"]);
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(quote (let add 1))").unwrap()
+        );
+    }
+}
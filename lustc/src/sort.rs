@@ -0,0 +1,484 @@
+//! `(sort list less?)` returns a new list with the elements of LIST
+//! sorted by LESS?, a function of two arguments that returns a truthy
+//! value when its first argument belongs before its second.
+//!
+//! ```lisp
+//! (sort (quote (3 1 2)) lt) ; => (1 2 3)
+//! ```
+//!
+//! The sort is a bottom up merge sort. The list is copied into a
+//! vector, runs of doubling width are merged back and forth between
+//! it and a second buffer, and the result is turned back into a list.
+//! An element from the right run is only taken before one from the
+//! left when LESS? says it belongs before it so the sort is stable.
+//! LESS? is called like any other closure so errors it raises unwind
+//! through the sort.
+//!
+//! `(vector-sort! vector less?)` sorts the elements of VECTOR in place
+//! and returns VECTOR. It doesn't allocate so it is a heapsort, which
+//! isn't stable: elements that neither belong before the other may
+//! come out in any order. Sorting a slice sorts the part of the vector
+//! it was made from that it sees.
+//!
+//! ```lisp
+//! (let v (list->vector (quote (3 1 2))))
+//! (vector-sort! v lt)
+//! (vector->list v) ; => (1 2 3)
+//! ```
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::HEAP_PTR_MASK;
+use crate::heap::emit_alloc_value;
+use crate::primitives::emit_is_falsey;
+use crate::procedures::emit_closure_call;
+use crate::vectors::{
+    emit_element_address, emit_elements_to_list, emit_list_to_vector, emit_vector_parts,
+};
+
+/// Emits the code to load element INDEX of the storage at PTR.
+fn emit_load_element(ptr: Value, index: Value, ctx: &mut Context) -> Value {
+    let address = emit_element_address(ptr, index, ctx);
+    ctx.builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), address, 0)
+}
+
+/// Emits the code to store VAL in element INDEX of the storage at
+/// PTR.
+fn emit_store_element(ptr: Value, index: Value, val: Value, ctx: &mut Context) {
+    let address = emit_element_address(ptr, index, ctx);
+    ctx.builder.ins().store(MemFlags::new(), val, address, 0);
+}
+
+/// Emits the code for the smaller of the untagged words L and R.
+fn emit_min(l: Value, r: Value, ctx: &mut Context) -> Value {
+    let less = ctx.builder.ins().icmp(IntCC::SignedLessThan, l, r);
+    ctx.builder.ins().select(less, l, r)
+}
+
+/// Emits the code for `(sort LIST LESS)` where both arguments have
+/// already been evaluated.
+pub(crate) fn emit_sort(list: Value, less: Value, ctx: &mut Context) -> Result<Value, String> {
+    crate::fatal::emit_check_closure(less, ctx)?;
+
+    let vector = emit_list_to_vector(list, ctx)?;
+    let first = ctx.builder.ins().band_imm(vector, HEAP_PTR_MASK);
+    let length = ctx.builder.ins().load(ctx.word, MemFlags::new(), first, 0);
+
+    // The second buffer is laid out like the vector so that the two
+    // can trade places.
+    let word_size = ctx.word.bytes() as i64;
+    let size = ctx.builder.ins().iadd_imm(length, 1);
+    let size = ctx.builder.ins().imul_imm(size, word_size);
+    let second = emit_alloc_value(size, ctx)?;
+
+    // Every call to LESS? passes its arguments in the same place.
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * ctx.word.bytes(),
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+    let argc = ctx.builder.ins().iconst(ctx.word, 2);
+
+    // A pass merges every pair of runs of WIDTH elements in SRC into
+    // DST. Passes continue until a run is the whole list.
+    let pass_block = ctx.builder.create_block();
+    let pass_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    for _ in 0..3 {
+        ctx.builder.append_block_param(pass_block, ctx.word);
+    }
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let one = ctx.builder.ins().iconst(ctx.word, 1);
+    ctx.builder.ins().jump(pass_block, &[one, first, second]);
+
+    ctx.builder.switch_to_block(pass_block);
+    let width = ctx.builder.block_params(pass_block)[0];
+    let src = ctx.builder.block_params(pass_block)[1];
+    let dst = ctx.builder.block_params(pass_block)[2];
+    let sorted = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::SignedGreaterThanOrEqual, width, length);
+    ctx.builder.ins().brnz(sorted, done_block, &[src]);
+    ctx.builder.ins().jump(pass_body, &[]);
+
+    ctx.builder.switch_to_block(pass_body);
+    ctx.builder.seal_block(pass_body);
+
+    // Each iteration of the run loop merges the runs that start at LO
+    // and LO + WIDTH.
+    let run_block = ctx.builder.create_block();
+    let run_body = ctx.builder.create_block();
+    let next_pass = ctx.builder.create_block();
+    ctx.builder.append_block_param(run_block, ctx.word);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(run_block, &[zero]);
+
+    ctx.builder.switch_to_block(run_block);
+    let lo = ctx.builder.block_params(run_block)[0];
+    let passed = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::SignedGreaterThanOrEqual, lo, length);
+    ctx.builder.ins().brnz(passed, next_pass, &[]);
+    ctx.builder.ins().jump(run_body, &[]);
+
+    ctx.builder.switch_to_block(run_body);
+    ctx.builder.seal_block(run_body);
+    let mid = ctx.builder.ins().iadd(lo, width);
+    let mid = emit_min(mid, length, ctx);
+    let hi = ctx.builder.ins().iadd(mid, width);
+    let hi = emit_min(hi, length, ctx);
+
+    // Merge SRC[LO..MID] and SRC[MID..HI] into DST[LO..HI]. I and J
+    // are the next elements of the left and right runs and K is the
+    // next element of DST.
+    let merge_block = ctx.builder.create_block();
+    let merge_body = ctx.builder.create_block();
+    let compare_block = ctx.builder.create_block();
+    let check_right = ctx.builder.create_block();
+    let take_left = ctx.builder.create_block();
+    let take_right = ctx.builder.create_block();
+    for _ in 0..3 {
+        ctx.builder.append_block_param(merge_block, ctx.word);
+    }
+    ctx.builder.ins().jump(merge_block, &[lo, mid, lo]);
+
+    ctx.builder.switch_to_block(merge_block);
+    let i = ctx.builder.block_params(merge_block)[0];
+    let j = ctx.builder.block_params(merge_block)[1];
+    let k = ctx.builder.block_params(merge_block)[2];
+    let merged = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::SignedGreaterThanOrEqual, k, hi);
+    ctx.builder.ins().brnz(merged, run_block, &[hi]);
+    ctx.builder.ins().jump(merge_body, &[]);
+
+    ctx.builder.switch_to_block(merge_body);
+    ctx.builder.seal_block(merge_body);
+    let left_done = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::SignedGreaterThanOrEqual, i, mid);
+    ctx.builder.ins().brnz(left_done, take_right, &[]);
+    ctx.builder.ins().jump(check_right, &[]);
+
+    ctx.builder.switch_to_block(check_right);
+    ctx.builder.seal_block(check_right);
+    let right_done = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::SignedGreaterThanOrEqual, j, hi);
+    ctx.builder.ins().brnz(right_done, take_left, &[]);
+    ctx.builder.ins().jump(compare_block, &[]);
+
+    ctx.builder.switch_to_block(compare_block);
+    ctx.builder.seal_block(compare_block);
+    let left = emit_load_element(src, i, ctx);
+    let right = emit_load_element(src, j, ctx);
+    ctx.builder.ins().store(MemFlags::new(), right, argloc, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), left, argloc, word_size as i32);
+    let right_first = emit_closure_call(less, argc, argloc, ctx)?;
+    let keep_order = emit_is_falsey(right_first, ctx);
+    ctx.builder.ins().brnz(keep_order, take_left, &[]);
+    ctx.builder.ins().jump(take_right, &[]);
+
+    ctx.builder.switch_to_block(take_left);
+    ctx.builder.seal_block(take_left);
+    let left = emit_load_element(src, i, ctx);
+    emit_store_element(dst, k, left, ctx);
+    let next_i = ctx.builder.ins().iadd_imm(i, 1);
+    let next_k = ctx.builder.ins().iadd_imm(k, 1);
+    ctx.builder.ins().jump(merge_block, &[next_i, j, next_k]);
+
+    ctx.builder.switch_to_block(take_right);
+    ctx.builder.seal_block(take_right);
+    let right = emit_load_element(src, j, ctx);
+    emit_store_element(dst, k, right, ctx);
+    let next_j = ctx.builder.ins().iadd_imm(j, 1);
+    let next_k = ctx.builder.ins().iadd_imm(k, 1);
+    ctx.builder.ins().jump(merge_block, &[i, next_j, next_k]);
+    ctx.builder.seal_block(merge_block);
+    ctx.builder.seal_block(run_block);
+
+    ctx.builder.switch_to_block(next_pass);
+    ctx.builder.seal_block(next_pass);
+    let width = ctx.builder.ins().imul_imm(width, 2);
+    ctx.builder.ins().jump(pass_block, &[width, dst, src]);
+    ctx.builder.seal_block(pass_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let sorted = ctx.builder.block_params(done_block)[0];
+    emit_elements_to_list(sorted, length, ctx)
+}
+
+/// Emits the code to store X and Y in ARGLOC and call LESS on them.
+/// The result is nonzero if X doesn't belong before Y.
+fn emit_not_before(
+    less: Value,
+    x: Value,
+    y: Value,
+    argloc: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let word_size = ctx.word.bytes() as i32;
+    ctx.builder.ins().store(MemFlags::new(), x, argloc, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), y, argloc, word_size);
+    let argc = ctx.builder.ins().iconst(ctx.word, 2);
+    let before = emit_closure_call(less, argc, argloc, ctx)?;
+    Ok(emit_is_falsey(before, ctx))
+}
+
+/// Emits the code to move element START of the storage at PTR down the
+/// heap made of the elements before END until neither of its children
+/// belong after it.
+fn emit_sift_down(
+    ptr: Value,
+    start: Value,
+    end: Value,
+    less: Value,
+    argloc: Value,
+    ctx: &mut Context,
+) -> Result<(), String> {
+    let down_block = ctx.builder.create_block();
+    let left_block = ctx.builder.create_block();
+    let right_block = ctx.builder.create_block();
+    let child_block = ctx.builder.create_block();
+    let swap_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(down_block, ctx.word);
+    ctx.builder.append_block_param(child_block, ctx.word);
+    ctx.builder.ins().jump(down_block, &[start]);
+
+    ctx.builder.switch_to_block(down_block);
+    let i = ctx.builder.block_params(down_block)[0];
+    let left = ctx.builder.ins().ishl_imm(i, 1);
+    let left = ctx.builder.ins().iadd_imm(left, 1);
+    let has_left = ctx.builder.ins().icmp(IntCC::SignedLessThan, left, end);
+    ctx.builder.ins().brz(has_left, done_block, &[]);
+    ctx.builder.ins().jump(left_block, &[]);
+
+    ctx.builder.switch_to_block(left_block);
+    ctx.builder.seal_block(left_block);
+    let right = ctx.builder.ins().iadd_imm(left, 1);
+    let has_right = ctx.builder.ins().icmp(IntCC::SignedLessThan, right, end);
+    ctx.builder.ins().brz(has_right, child_block, &[left]);
+    ctx.builder.ins().jump(right_block, &[]);
+
+    // The child that is swapped with is the one that belongs last.
+    ctx.builder.switch_to_block(right_block);
+    ctx.builder.seal_block(right_block);
+    let x = emit_load_element(ptr, left, ctx);
+    let y = emit_load_element(ptr, right, ctx);
+    let left_last = emit_not_before(less, x, y, argloc, ctx)?;
+    ctx.builder.ins().brnz(left_last, child_block, &[left]);
+    ctx.builder.ins().jump(child_block, &[right]);
+
+    ctx.builder.switch_to_block(child_block);
+    ctx.builder.seal_block(child_block);
+    let child = ctx.builder.block_params(child_block)[0];
+    let x = emit_load_element(ptr, i, ctx);
+    let y = emit_load_element(ptr, child, ctx);
+    let stays = emit_not_before(less, x, y, argloc, ctx)?;
+    ctx.builder.ins().brnz(stays, done_block, &[]);
+    ctx.builder.ins().jump(swap_block, &[]);
+
+    ctx.builder.switch_to_block(swap_block);
+    ctx.builder.seal_block(swap_block);
+    emit_swap(ptr, i, child, ctx);
+    ctx.builder.ins().jump(down_block, &[child]);
+    ctx.builder.seal_block(down_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(())
+}
+
+/// Emits the code to swap elements I and J of the storage at PTR.
+fn emit_swap(ptr: Value, i: Value, j: Value, ctx: &mut Context) {
+    let x = emit_load_element(ptr, i, ctx);
+    let y = emit_load_element(ptr, j, ctx);
+    emit_store_element(ptr, i, y, ctx);
+    emit_store_element(ptr, j, x, ctx);
+}
+
+/// Emits the code for `(vector-sort! VECTOR LESS)` where both
+/// arguments have already been evaluated.
+pub(crate) fn emit_vector_sort(
+    vector: Value,
+    less: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    crate::fatal::emit_check_closure(less, ctx)?;
+
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * ctx.word.bytes(),
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+
+    // Make a heap with the element that belongs last at the front by
+    // sifting down every element that has children, last to first.
+    let heap_block = ctx.builder.create_block();
+    let heap_body = ctx.builder.create_block();
+    let sort_block = ctx.builder.create_block();
+    let sort_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(heap_block, ctx.word);
+    ctx.builder.append_block_param(sort_block, ctx.word);
+    let parents = ctx.builder.ins().ushr_imm(length, 1);
+    ctx.builder.ins().jump(heap_block, &[parents]);
+
+    ctx.builder.switch_to_block(heap_block);
+    let i = ctx.builder.block_params(heap_block)[0];
+    ctx.builder.ins().brz(i, sort_block, &[length]);
+    ctx.builder.ins().jump(heap_body, &[]);
+
+    ctx.builder.switch_to_block(heap_body);
+    ctx.builder.seal_block(heap_body);
+    let i = ctx.builder.ins().iadd_imm(i, -1);
+    emit_sift_down(ptr, i, length, less, argloc, ctx)?;
+    ctx.builder.ins().jump(heap_block, &[i]);
+    ctx.builder.seal_block(heap_block);
+
+    // Then move the front of the heap to the end of it and shrink the
+    // heap by one until one element is left.
+    ctx.builder.switch_to_block(sort_block);
+    let end = ctx.builder.block_params(sort_block)[0];
+    let sorted = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::SignedLessThanOrEqual, end, 1);
+    ctx.builder.ins().brnz(sorted, done_block, &[]);
+    ctx.builder.ins().jump(sort_body, &[]);
+
+    ctx.builder.switch_to_block(sort_body);
+    ctx.builder.seal_block(sort_body);
+    let end = ctx.builder.ins().iadd_imm(end, -1);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    emit_swap(ptr, zero, end, ctx);
+    emit_sift_down(ptr, zero, end, less, argloc, ctx)?;
+    ctx.builder.ins().jump(sort_block, &[end]);
+    ctx.builder.seal_block(sort_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(vector)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind};
+
+    #[test]
+    fn ascending() {
+        check("(sort (quote (3 1 2)) lt)", "(quote (1 2 3))");
+        check(
+            "(sort (quote (3 1 2)) (fn (a b) (gt a b)))",
+            "(quote (3 2 1))",
+        );
+        check("(sort () lt)", "()");
+        check("(sort (quote (1)) lt)", "(quote (1))");
+
+        let numbers = (0..100).map(|i| (i * 37) % 101).collect::<Vec<_>>();
+        let mut sorted = numbers.clone();
+        sorted.sort_unstable();
+        let list = |v: &[i32]| {
+            format!(
+                "(quote ({}))",
+                v.iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        };
+        check(&format!("(sort {} lt)", list(&numbers)), &list(&sorted));
+    }
+
+    #[test]
+    fn stable() {
+        // Elements are compared by their cars so the rest shows
+        // whether equal elements kept their order.
+        check(
+            r#"
+(let by-car (fn (a b) (lt (car a) (car b))))
+(sort (quote ((2 a) (1 b) (2 c) (1 d) (0 e))) by-car)
+"#,
+            "(quote ((0 e) (1 b) (1 d) (2 a) (2 c)))",
+        );
+        // The input isn't modified.
+        check("(let l (quote (2 1))) (sort l lt) l", "(quote (2 1))");
+    }
+
+    #[test]
+    fn in_place() {
+        check(
+            r#"
+(let v (list->vector (quote (3 1 2))))
+(let sorted (vector-sort! v lt))
+(cons (eq sorted v) (vector->list v))
+"#,
+            "(cons (eq 1 1) (quote (1 2 3)))",
+        );
+        check("(vector->list (vector-sort! (list->vector ()) lt))", "()");
+        check(
+            "(vector->list (vector-sort! (list->vector (quote (1))) lt))",
+            "(quote (1))",
+        );
+
+        let numbers = (0..100).map(|i| (i * 37) % 101).collect::<Vec<_>>();
+        let mut sorted = numbers.clone();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+        let list = |v: &[i32]| {
+            format!(
+                "(quote ({}))",
+                v.iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        };
+        check(
+            &format!(
+                "(let f (fn (s v) (s v gt))) (vector->list (f vector-sort! (list->vector {})))",
+                list(&numbers)
+            ),
+            &list(&sorted),
+        );
+        // Sorting a slice sorts the part of the vector it sees.
+        check(
+            r#"
+(let v (list->vector (quote (4 3 2 1))))
+(vector-sort! (vector-slice v 1 3) lt)
+(vector->list v)
+"#,
+            "(quote (4 2 3 1))",
+        );
+    }
+
+    #[test]
+    fn comparator_errors() {
+        assert_eq!(
+            error_kind("(sort (quote (2 1)) (fn (a b) (error (quote bad) \"no\" a)))"),
+            "bad"
+        );
+        assert_eq!(
+            error_kind(
+                "(vector-sort! (list->vector (quote (2 1))) (fn (a b) (error (quote bad) \"no\" a)))"
+            ),
+            "bad"
+        );
+        assert_eq!(error_kind("(vector-sort! (quote (2 1)) lt)"), "type-error");
+    }
+}
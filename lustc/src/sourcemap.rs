@@ -0,0 +1,164 @@
+//! Maps generated machine code back to the top level forms of the
+//! program that it came from. Every instruction emitted while
+//! compiling a top level form, or a function defined inside of one, is
+//! tagged with the index of that form. A profiler sampling the program
+//! counter can use the resulting table to attribute time to forms.
+
+use cranelift::codegen::ir::SourceLoc;
+use cranelift::prelude::{FunctionBuilder, InstBuilder, Value};
+
+use crate::compiler::Context;
+
+use crate::location::Location;
+
+/// A range of machine code generated for a top level form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMapEntry {
+    /// The name of the function the code belongs to.
+    pub function: String,
+    /// The offset of the first byte of the code from the start of the
+    /// function.
+    pub start: u32,
+    /// The offset one past the last byte of the code from the start
+    /// of the function.
+    pub end: u32,
+    /// The index of the top level form in the program.
+    pub form: usize,
+}
+
+/// A table mapping machine code to top level forms.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    pub entries: Vec<SourceMapEntry>,
+}
+
+impl SourceMap {
+    /// Records the source locations of FUNCTION which has just been
+    /// defined using CONTEXT.
+    pub(crate) fn record(&mut self, function: &str, context: &cranelift::codegen::Context) {
+        let result = match context.mach_compile_result.as_ref() {
+            Some(r) => r,
+            None => return,
+        };
+        for loc in result.buffer.get_srclocs_sorted() {
+            if loc.loc.is_default() {
+                continue;
+            }
+            self.entries.push(SourceMapEntry {
+                function: function.to_string(),
+                start: loc.start,
+                end: loc.end,
+                form: loc.loc.bits() as usize,
+            })
+        }
+    }
+
+    /// Returns the index of the top level form that generated the code
+    /// at OFFSET in FUNCTION.
+    pub fn form_at(&self, function: &str, offset: u32) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|e| e.function == function && e.start <= offset && offset < e.end)
+            .map(|e| e.form)
+    }
+
+    /// Returns the location in the source of the top level form that
+    /// generated the code at OFFSET in FUNCTION. LOCATIONS are the
+    /// locations of the program's top level forms as returned by
+    /// `parse_string_with_locations`.
+    pub fn location_at<'a>(
+        &self,
+        function: &str,
+        offset: u32,
+        locations: &'a [Location],
+    ) -> Option<&'a Location> {
+        self.form_at(function, offset)
+            .and_then(|form| locations.get(form))
+    }
+}
+
+/// Tags the code emitted by BUILDER from now on as belonging to the
+/// top level form FORM.
+pub(crate) fn set_form(builder: &mut FunctionBuilder, form: usize) {
+    builder.set_srcloc(SourceLoc::new(form as u32));
+}
+
+/// Emits the untagged index of the top level form that code emitted
+/// now belongs to, or -1 if there isn't one, so that the runtime can
+/// say where an error came from.
+pub(crate) fn emit_form(ctx: &mut Context) -> Value {
+    // The builder doesn't say what its source location is, but it
+    // gives it to every instruction it inserts.
+    let form = ctx.builder.ins().iconst(ctx.word, -1);
+    let inst = ctx.builder.func.dfg.value_def(form).unwrap_inst();
+    let loc = ctx.builder.func.srclocs[inst];
+    if !loc.is_default() {
+        ctx.builder
+            .func
+            .dfg
+            .replace(inst)
+            .iconst(ctx.word, loc.bits() as i64);
+    }
+    form
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_program, CompileOptions, JIT};
+    use crate::parse_string_with_locations;
+
+    #[test]
+    fn entry_source_map() {
+        let source = "(let a (car (cons 1 2)))\n(let f (fn (x) (add x a)))\n(f (add 1 2))";
+        let (mut program, locations) = parse_string_with_locations(source).unwrap();
+        let mut jit = JIT::default();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert!(jit.invoke(id).is_ok());
+
+        let entries = jit
+            .source_map
+            .entries
+            .iter()
+            .filter(|e| e.function == "lust_entry")
+            .collect::<Vec<_>>();
+        for form in 0..3 {
+            assert!(entries.iter().any(|e| e.form == form));
+        }
+        let last = entries.iter().find(|e| e.form == 2).unwrap();
+        let loc = jit
+            .source_map
+            .location_at("lust_entry", last.start, &locations)
+            .unwrap();
+        assert_eq!(loc.start.line, 2);
+        assert_eq!(loc.start.col, 0);
+
+        // The body of f is tagged with the form it was defined in.
+        assert!(jit
+            .source_map
+            .entries
+            .iter()
+            .any(|e| e.function == "__anon_fn_0" && e.form == 1));
+    }
+
+    #[test]
+    fn error_form() {
+        let source = "(let a 1)\n(let f (fn (x) (car x)))\n(add a 2)\n(f a)";
+        let (mut program, locations) = parse_string_with_locations(source).unwrap();
+        let mut jit = JIT::new(CompileOptions {
+            embedded: true,
+            ..Default::default()
+        });
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        let error = jit.invoke(id).unwrap_err();
+        // The car that fails is in f so the error names the form that
+        // defined f rather than the call on the last line.
+        assert_eq!(error.form, Some(1));
+        assert_eq!(locations[error.form.unwrap()].start.line, 1);
+
+        let mut program = parse_string_with_locations("(let a 1)\n(error \"b\")")
+            .unwrap()
+            .0;
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        assert_eq!(jit.invoke(id).unwrap_err().form, Some(1));
+    }
+}
@@ -0,0 +1,204 @@
+//! Allocates pairs that never leave the function that made them on
+//! the stack instead of the heap.
+//!
+//! Lots of functions build a pair just to take it apart again:
+//!
+//! ```lisp
+//! (let f (fn (a b) (let p (cons a b)) (add (car p) (cdr p))))
+//! ```
+//!
+//! Once f returns nothing can reach p so there is no reason for it to
+//! take up space on the heap. This pass finds conses like that and
+//! marks them so that they are emitted into a stack slot in the
+//! function's frame.
+//!
+//! The analysis is deliberately simple because getting it wrong
+//! leaves a dangling pointer behind. A cons is only moved to the stack
+//! if it is the value of a let that is directly in a function's body
+//! and isn't that body's last form, the variable isn't captured by a
+//! closure, and every other use of the variable is as the argument to
+//! car or cdr. Those only ever read the fields out of the pair so the
+//! pair itself can't be returned, stored somewhere, or passed to
+//! another function. Anything else keeps its cons on the heap.
+
+use cranelift::prelude::*;
+
+use crate::compiler::{emit_expr, Context};
+use crate::conversions::PAIR_TAG;
+use crate::procedures::LustFn;
+use crate::Expr;
+
+/// The name that conses which live on the stack are renamed to.
+const STACK_CONS: &str = "__anon_stack_cons";
+
+impl Expr {
+    /// Determines if the expression is a cons that has been moved to
+    /// the stack and if it is returns its car and cdr.
+    pub(crate) fn is_stack_cons(&self) -> Option<(&Expr, &Expr)> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == STACK_CONS && v.len() == 3 {
+                    return Some((&v[1], &v[2]));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns true if every use of the variable NAME in EXPR is as the
+/// argument to car or cdr.
+fn only_fields_read(name: &str, expr: &Expr) -> bool {
+    match expr {
+        Expr::Symbol(s) => s != name,
+        Expr::List(v) => match v.as_slice() {
+            [Expr::Symbol(f), Expr::Symbol(arg)] if (f == "car" || f == "cdr") && arg == name => {
+                true
+            }
+            v => v.iter().all(|e| only_fields_read(name, e)),
+        },
+        _ => true,
+    }
+}
+
+fn is_cons(expr: &Expr) -> bool {
+    matches!(expr.is_primcall(), Some(("cons", args)) if args.len() == 2)
+}
+
+/// Marks the conses in BODY that can live on the stack.
+fn stack_allocate_body(body: &mut [Expr]) {
+    let last = body.len().saturating_sub(1);
+    for i in 0..last {
+        let name = match body[i].is_let() {
+            // Variables that start with e_ are captured by a closure
+            // and live on the heap. See `escape.rs`.
+            Some((name, value)) if !name.starts_with("e_") && is_cons(value) => name.clone(),
+            _ => continue,
+        };
+        let escapes = body
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .any(|(_, e)| !only_fields_read(&name, e));
+        if escapes {
+            continue;
+        }
+        if let Expr::List(v) = &mut body[i] {
+            if let Expr::List(cons) = &mut v[2] {
+                cons[0] = Expr::Symbol(STACK_CONS.to_string());
+            }
+        }
+    }
+}
+
+/// Marks the conses in FUNCTIONS that can live on the stack. This
+/// needs to happen after escaped variables have been annotated so
+/// that variables captured by closures can be told apart.
+pub(crate) fn stack_allocate_conses(functions: &mut [LustFn]) {
+    let _t = crate::timer::timeit("stack allocation");
+    for f in functions {
+        stack_allocate_body(&mut f.body);
+    }
+}
+
+/// Emits the code for a cons of CAR and CDR that lives in a stack
+/// slot in the current function's frame.
+pub(crate) fn emit_stack_cons(car: &Expr, cdr: &Expr, ctx: &mut Context) -> Result<Value, String> {
+    let car = emit_expr(car, ctx)?;
+    let cdr = emit_expr(cdr, ctx)?;
+
+    let word_size = ctx.word.bytes();
+    // Slots are word aligned so the low bits of the address are free
+    // for the tag.
+    let slot = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * word_size,
+    ));
+    let storage = ctx.builder.ins().stack_addr(ctx.word, slot, 0);
+    ctx.builder.ins().store(MemFlags::new(), car, storage, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), cdr, storage, word_size as i32);
+
+    Ok(ctx.builder.ins().bor_imm(storage, PAIR_TAG))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_program, JIT};
+    use crate::{parse_string, roundtrip_string, Expr};
+
+    /// Runs a program that calls F with 1 and 2. Returns the result
+    /// and the number of bytes allocated by the call.
+    fn call(f: &str) -> (Expr, i64) {
+        let source = format!(
+            r#"
+(let f {})
+(let before (heap-stats))
+(let res (f 1 2))
+(let after (heap-stats))
+(cons res (sub (car after) (car before)))
+"#,
+            f
+        );
+        let mut jit = JIT::default();
+        let mut program = parse_string(&source).unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        match Expr::from_immediate(jit.invoke(id).unwrap()) {
+            Expr::List(v) => match v.as_slice() {
+                [res, Expr::Integer(growth)] => (res.clone(), *growth),
+                v => panic!("unexpected result {:?}", v),
+            },
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn non_escaping_cons() {
+        // Calls allocate space for their arguments so the baseline is
+        // a function that takes the same arguments and doesn't cons.
+        let (res, baseline) = call("(fn (a b) (add a b))");
+        assert_eq!(res, Expr::Integer(3));
+
+        let (res, growth) = call(
+            "(fn (a b) (let p (cons a b)) (let q (cons (cdr p) (car p))) (sub (car q) (cdr q)))",
+        );
+        assert_eq!(res, Expr::Integer(1));
+        assert_eq!(growth, baseline);
+    }
+
+    #[test]
+    fn escaping_cons() {
+        let cell = 2 * std::mem::size_of::<crate::Word>() as i64;
+        let (_, baseline) = call("(fn (a b) (add a b))");
+
+        // Returned, passed to a function, captured, and assigned.
+        for f in [
+            "(fn (a b) (let p (cons a b)) p)",
+            "(fn (a b) (let p (cons a b)) (let id (fn (x) x)) (car (id p)))",
+            "(fn (a b) (let p (cons a b)) (let g (fn () (car p))) (g))",
+            "(fn (a b) (let p (cons a b)) (let c (cons p ())) (car (car c)))",
+            "(fn (a b) (let p (cons a b)) (set p 1) p)",
+        ] {
+            let (_, growth) = call(f);
+            assert!(growth >= baseline + cell, "{} didn't allocate", f);
+        }
+
+        assert_eq!(
+            roundtrip_string("(let f (fn (a b) (let p (cons a b)) p)) (f 1 2)").unwrap(),
+            Expr::List(vec![Expr::Integer(1), Expr::Integer(2)])
+        );
+    }
+
+    #[test]
+    fn loops() {
+        // Every iteration reuses the same slot.
+        let source = r#"
+(let sum (fn (n acc)
+  (let p (cons n acc))
+  (if (eq (car p) 0) (cdr p) (sum (sub (car p) 1) (add (car p) (cdr p))))))
+(sum 100 0)
+"#;
+        assert_eq!(roundtrip_string(source).unwrap(), Expr::Integer(5050));
+    }
+}
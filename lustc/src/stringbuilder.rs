@@ -0,0 +1,308 @@
+//! String builders. A string is a list of characters so appending to
+//! one with string-append copies all of it, and building a string out
+//! of N pieces that way takes time quadratic in its length. A builder
+//! is a buffer of characters that grows in place instead.
+//! `(make-string-builder)` makes an empty builder, `(sb-append! sb s)`
+//! adds the characters of the string S to the end of SB and returns
+//! SB, and `(sb->string sb)` returns a new string with the characters
+//! that have been added to SB so far. SB can be appended to after it
+//! has been turned into a string and the string doesn't change.
+//!
+//! ```lisp
+//! (let sb (make-string-builder))
+//! (sb-append! (sb-append! sb "ab") "c")
+//! (sb->string sb) ; => "abc"
+//! ```
+//!
+//! Appending to something that isn't a builder or appending something
+//! that isn't a string raises a `type-error`. The characters of S are
+//! added as they are checked so a string that turns out to be
+//! improper leaves the characters before the problem in SB.
+//!
+//! A builder shares VALUES_TAG with tuples. It is three words:
+//! STRING_BUILDER_HEADER, the number of characters, which is not a
+//! fixnum, and a pointer to the storage of a vector that holds them.
+//! The vector's length is the builder's capacity and when it is full
+//! the characters are copied into a vector twice as long, so appending
+//! takes time linear in the length of S once that is spread over every
+//! append. The storage only ever holds characters, which are
+//! immediates, so there is nothing inside it for a collector to follow
+//! and it would only need to keep the storage itself alive. There
+//! isn't a collector yet (see `gc.md`) and nothing is freed, so the
+//! old vector is left where it is. When a builder is returned to the
+//! host it is seen as the string it holds.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{HEAP_PTR_MASK, STRING_BUILDER_HEADER, VALUES_TAG};
+use crate::fatal;
+use crate::heap::emit_alloc;
+use crate::vectors::{
+    emit_alloc_vector, emit_check_nil, emit_element_address, emit_elements_onto_list,
+    emit_index_loop, emit_is_pair, emit_pair_parts,
+};
+use crate::{Expr, Word};
+
+/// The words of a builder after its header.
+const COUNT: i32 = 1;
+const STORAGE: i32 = 2;
+
+/// The number of characters a new builder has room for.
+const INITIAL_CAPACITY: i64 = 16;
+
+/// The names of the string builder primitives.
+pub(crate) const STRING_BUILDER_PRIMITIVES: [&str; 3] =
+    ["make-string-builder", "sb-append!", "sb->string"];
+
+/// Returns the number of arguments that the string builder primitive
+/// NAME takes.
+pub(crate) fn string_builder_primitive_arity(name: &str) -> usize {
+    match name {
+        "make-string-builder" => 0,
+        "sb-append!" => 2,
+        _ => 1,
+    }
+}
+
+/// Returns the number of characters in BUILDER and a pointer to the
+/// storage that holds them.
+pub(crate) fn builder_chars(builder: Word) -> (usize, *const Word) {
+    let ptr = (builder & HEAP_PTR_MASK) as *const Word;
+    unsafe {
+        (
+            *ptr.add(COUNT as usize) as usize,
+            *ptr.add(STORAGE as usize) as *const Word,
+        )
+    }
+}
+
+/// Emits the code for the string builder primitive NAME applied to
+/// ARGS which have already been evaluated.
+pub(crate) fn emit_string_builder_primitive(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    match name {
+        "make-string-builder" => emit_make_string_builder(ctx),
+        "sb-append!" => emit_sb_append(args[0], args[1], ctx),
+        "sb->string" => {
+            let ptr = emit_builder_ptr(args[0], ctx)?;
+            let count = load_field(ptr, COUNT, ctx);
+            let storage = load_field(ptr, STORAGE, ctx);
+            let nil = ctx
+                .builder
+                .ins()
+                .iconst(ctx.word, Expr::Nil.immediate_rep());
+            emit_elements_onto_list(storage, count, nil, ctx)
+        }
+        _ => panic!(
+            "non string builder primitive in emit_string_builder_primitive: {}",
+            name
+        ),
+    }
+}
+
+fn load_field(ptr: Value, field: i32, ctx: &mut Context) -> Value {
+    let offset = field * ctx.word.bytes() as i32;
+    ctx.builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, offset)
+}
+
+fn store_field(ptr: Value, field: i32, val: Value, ctx: &mut Context) {
+    let offset = field * ctx.word.bytes() as i32;
+    ctx.builder.ins().store(MemFlags::new(), val, ptr, offset);
+}
+
+/// Emits the code to check that BUILDER is a string builder. Returns
+/// a pointer to it.
+fn emit_builder_ptr(builder: Value, ctx: &mut Context) -> Result<Value, String> {
+    let is_builder = crate::conditions::emit_has_header(builder, STRING_BUILDER_HEADER, ctx);
+    fatal::emit_check_type(is_builder, ctx)?;
+    Ok(ctx.builder.ins().band_imm(builder, HEAP_PTR_MASK))
+}
+
+fn emit_make_string_builder(ctx: &mut Context) -> Result<Value, String> {
+    let capacity = ctx.builder.ins().iconst(ctx.word, INITIAL_CAPACITY);
+    let storage = emit_alloc_vector(capacity, ctx)?;
+    let ptr = emit_alloc(3 * ctx.word.bytes() as i64, ctx)?;
+    let header = ctx.builder.ins().iconst(ctx.word, STRING_BUILDER_HEADER);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().store(MemFlags::new(), header, ptr, 0);
+    store_field(ptr, COUNT, zero, ctx);
+    store_field(ptr, STORAGE, storage, ctx);
+    Ok(ctx.builder.ins().bor_imm(ptr, VALUES_TAG))
+}
+
+fn emit_sb_append(builder: Value, s: Value, ctx: &mut Context) -> Result<Value, String> {
+    let ptr = emit_builder_ptr(builder, ctx)?;
+
+    let walk_block = ctx.builder.create_block();
+    let char_block = ctx.builder.create_block();
+    let grow_block = ctx.builder.create_block();
+    let store_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(store_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.ins().jump(walk_block, &[s]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, done_block, &[rest]);
+    ctx.builder.ins().jump(char_block, &[]);
+
+    ctx.builder.switch_to_block(char_block);
+    ctx.builder.seal_block(char_block);
+    let (c, next) = emit_pair_parts(rest, ctx);
+    fatal::emit_check_char(c, ctx)?;
+    let count = load_field(ptr, COUNT, ctx);
+    let storage = load_field(ptr, STORAGE, ctx);
+    let capacity = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), storage, 0);
+    let full = ctx.builder.ins().icmp(IntCC::Equal, count, capacity);
+    ctx.builder.ins().brnz(full, grow_block, &[]);
+    ctx.builder.ins().jump(store_block, &[storage]);
+
+    ctx.builder.switch_to_block(grow_block);
+    ctx.builder.seal_block(grow_block);
+    let capacity = ctx.builder.ins().imul_imm(capacity, 2);
+    let grown = emit_alloc_vector(capacity, ctx)?;
+    emit_index_loop(count, ctx, |i, ctx| {
+        let from = emit_element_address(storage, i, ctx);
+        let c = ctx.builder.ins().load(ctx.word, MemFlags::new(), from, 0);
+        let to = emit_element_address(grown, i, ctx);
+        ctx.builder.ins().store(MemFlags::new(), c, to, 0);
+        Ok(())
+    })?;
+    store_field(ptr, STORAGE, grown, ctx);
+    ctx.builder.ins().jump(store_block, &[grown]);
+
+    ctx.builder.switch_to_block(store_block);
+    ctx.builder.seal_block(store_block);
+    let storage = ctx.builder.block_params(store_block)[0];
+    let to = emit_element_address(storage, count, ctx);
+    ctx.builder.ins().store(MemFlags::new(), c, to, 0);
+    let count = ctx.builder.ins().iadd_imm(count, 1);
+    store_field(ptr, COUNT, count, ctx);
+    ctx.builder.ins().jump(walk_block, &[next]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let end = ctx.builder.block_params(done_block)[0];
+    emit_check_nil(end, ctx)?;
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{compile_program, JIT};
+    use crate::test_util::{check, error_kind};
+    use crate::{parse_string, roundtrip_string, Expr};
+
+    #[test]
+    fn string_builders() {
+        check(
+            "(let sb (make-string-builder)) (sb->string (sb-append! (sb-append! sb \"ab\") \"c\"))",
+            "\"abc\"",
+        );
+        check("(sb->string (make-string-builder))", "\"\"");
+        check(
+            r#"
+(let sb (make-string-builder))
+(sb-append! sb "ab")
+(let s (sb->string sb))
+(sb-append! sb "cd")
+(list s (sb->string sb))
+"#,
+            "(quote (\"ab\" \"abcd\"))",
+        );
+        check(
+            "(let sb (make-string-builder)) (sb-append! sb \"\") (sb-append! sb \"x\")",
+            "\"x\"",
+        );
+        check(
+            "(let f sb-append!) (let g sb->string) (g (f (make-string-builder) \"hi\"))",
+            "\"hi\"",
+        );
+        check("(type-of (make-string-builder))", "(quote string-builder)");
+    }
+
+    #[test]
+    fn many_pieces() {
+        // Well past the initial capacity so that the storage is grown
+        // several times.
+        let source = r#"
+(let sb (make-string-builder))
+(dotimes (i 1000) (sb-append! sb (if (eq (rem i 2) 0) "ab" "c")))
+(let s (sb->string sb))
+(list (string-length s) (string-slice s 0 5) (string-slice s 1495 1500))
+"#;
+        // The interpreter runs out of stack long before a thousand
+        // iterations of dotimes so this is only compiled.
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            roundtrip_string("(quote (1500 \"abcab\" \"bcabc\"))").unwrap()
+        );
+    }
+
+    #[test]
+    fn faster_than_appending() {
+        let time = |source: &str| {
+            let mut jit = JIT::default();
+            let mut program = parse_string(source).unwrap();
+            let id = compile_program(&mut jit, &mut program).unwrap();
+            let start = std::time::Instant::now();
+            let res = Expr::from_immediate(jit.invoke(id).unwrap());
+            (start.elapsed(), res)
+        };
+        let (built, built_length) = time(
+            r#"
+(let sb (make-string-builder))
+(dotimes (i 2000) (sb-append! sb "abc"))
+(string-length (sb->string sb))
+"#,
+        );
+        let (appended, appended_length) = time(
+            r#"
+(let build (fn (s i) (if (eq i 0) s (build (string-append s "abc") (sub i 1)))))
+(string-length (build "" 2000))
+"#,
+        );
+        assert_eq!(built_length, Expr::Integer(6000));
+        assert_eq!(appended_length, built_length);
+        // Appending copies about six million characters and building
+        // copies fewer than twenty thousand, so this leaves plenty of
+        // room for noise.
+        assert!(
+            built < appended,
+            "building took {:?} and appending took {:?}",
+            built,
+            appended
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error_kind("(sb-append! (quote (1)) \"a\")"), "type-error");
+        assert_eq!(error_kind("(sb->string (make-heap lt))"), "type-error");
+        assert_eq!(
+            error_kind("(sb-append! (make-string-builder) (quote (1 2)))"),
+            "type-error"
+        );
+        assert_eq!(
+            error_kind("(sb-append! (make-string-builder) (cons (string-ref \"a\" 0) 2))"),
+            "type-error"
+        );
+        assert_eq!(
+            error_kind("(sb-append! (make-string-builder) 1)"),
+            "type-error"
+        );
+    }
+}
@@ -0,0 +1,606 @@
+//! Primitives that work on strings. A string is a list of characters
+//! so anything that works on lists works on strings too and these are
+//! the operations that only make sense for text.
+//!
+//! ```lisp
+//! (string-append "a" "bc") ; => "abc"
+//! ```
+//!
+//! `(string-append a b)` returns a new string with the characters of A
+//! followed by those of B. A is copied and the copy's last pair points
+//! at B so the result shares its tail with B.
+//!
+//! `(string-split s sep)` returns a list of the pieces of S between
+//! occurrences of the string SEP, which can't be empty. Separators next
+//! to each other or at either end of S have an empty string between
+//! them so the empty string splits into a list of one empty string.
+//! `(string-join list sep)` goes the other way and returns the strings
+//! in LIST with SEP between each of them.
+//!
+//! ```lisp
+//! (string-split "a,,b" ",")          ; => ("a" "" "b")
+//! (string-join (quote ("a" "b")) "-") ; => "a-b"
+//! ```
+//!
+//! `(string-length s)` is the number of characters in S and
+//! `(string-ref s i)` is its character at index I. Characters are
+//! unicode scalar values so these count characters and not bytes.
+//! `(string-ref "héllo" 2)` is l no matter how many bytes é takes up
+//! when encoded. An index outside of S raises a `range-error`. Both
+//! walk the list so they take time linear in the index or length.
+//!
+//! `(string-upcase s)` and `(string-downcase s)` return new strings
+//! with the characters of S in upper or lower case and `(string-trim
+//! s)` returns a new string without the whitespace at either end of S,
+//! so a string of only whitespace trims to the empty string. S itself
+//! is never changed.
+//!
+//! ```lisp
+//! (string-upcase "Hi!")   ; => "HI!"
+//! (string-trim "  a b\n") ; => "a b"
+//! ```
+//!
+//! Case is changed with Unicode's case mappings and not just for
+//! ASCII, so `(string-upcase "é")` is "É". Some characters become more
+//! than one in the other case, like ß which upcases to SS, so the new
+//! string can be longer than S. Whitespace is anything Unicode
+//! considers whitespace, which includes the ASCII space, tab and
+//! newline.
+//!
+//! `(string-slice s start end)` returns the characters of S from
+//! START up to but not including END and raises a range error unless
+//! `0 <= start <= end <= (string-length s)`. As a string is a list a
+//! slice that runs to the end of S is what is left of S after START
+//! characters and shares its pairs with S. Any other slice has to end
+//! in nil where S doesn't so its characters are copied into a new
+//! string. Nothing changes the pairs of a string once it has been
+//! made so sharing them is never seen except by eq, and a slice of a
+//! string in a program's quoted data is as unchanging as the string
+//! is.
+//!
+//! ```lisp
+//! (string-slice "hello" 1 3) ; => "el"
+//! (string-slice "hello" 3 5) ; => "lo", the last two pairs of "hello"
+//! ```
+//!
+//! `(string-index s needle)` is the index of the first character of
+//! the first occurrence of the string NEEDLE in S, or nil if NEEDLE
+//! isn't in S, and `(string-contains s needle)` is true if NEEDLE is
+//! in S. The index counts characters like string-ref and string-slice
+//! do, so `(string-slice s i (add i (string-length needle)))` is
+//! NEEDLE when I is the index. The empty string is in every string at
+//! index 0.
+//!
+//! ```lisp
+//! (string-index "hello" "ll")    ; => 2
+//! (string-index "hello" "x")     ; => ()
+//! (string-contains "hello" "lo") ; => true
+//! ```
+//!
+//! Splitting, joining, changing case, trimming and searching are done
+//! by the host which makes the new strings itself, like `getenv` does.
+//! A type error is raised if an argument isn't a string or SEP is
+//! empty.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{
+    list_to_immediate, string_to_immediate, try_stringify_list, FIXNUM_SHIFT, HEAP_PTR_MASK,
+};
+use crate::fatal;
+use crate::foreign::emit_host_call;
+use crate::primitives::emit_word_to_bool;
+use crate::vectors::{
+    emit_check_nil, emit_elements_onto_list, emit_is_pair, emit_list_to_vector, emit_pair_parts,
+};
+use crate::{Expr, Word};
+
+/// The names of the string primitives.
+pub(crate) const STRING_PRIMITIVES: [&str; 11] = [
+    "string-append",
+    "string-split",
+    "string-join",
+    "string-length",
+    "string-ref",
+    "string-upcase",
+    "string-downcase",
+    "string-trim",
+    "string-slice",
+    "string-index",
+    "string-contains",
+];
+
+/// Returns the number of arguments that the string primitive NAME
+/// takes.
+pub(crate) fn string_primitive_arity(name: &str) -> usize {
+    match name {
+        "string-length" | "string-upcase" | "string-downcase" | "string-trim" => 1,
+        "string-append" | "string-split" | "string-join" | "string-ref" | "string-index"
+        | "string-contains" => 2,
+        "string-slice" => 3,
+        _ => panic!("non string primitive in string_primitive_arity: {}", name),
+    }
+}
+
+/// Emits the code for the string primitive NAME applied to ARGS which
+/// have already been evaluated.
+pub(crate) fn emit_string_primitive(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    match name {
+        "string-append" => emit_string_append(args[0], args[1], ctx),
+        "string-split" => emit_list_host_call("lustc_string_split", args, ctx),
+        "string-join" => emit_list_host_call("lustc_string_join", args, ctx),
+        "string-length" => emit_string_length(args[0], ctx),
+        "string-ref" => emit_string_ref(args[0], args[1], ctx),
+        "string-upcase" => emit_list_host_call("lustc_string_upcase", args, ctx),
+        "string-downcase" => emit_list_host_call("lustc_string_downcase", args, ctx),
+        "string-trim" => emit_list_host_call("lustc_string_trim", args, ctx),
+        "string-slice" => emit_string_slice(args[0], args[1], args[2], ctx),
+        "string-index" => emit_string_index(args, ctx),
+        "string-contains" => {
+            let index = emit_string_index(args, ctx)?;
+            let found =
+                ctx.builder
+                    .ins()
+                    .icmp_imm(IntCC::NotEqual, index, Expr::Nil.immediate_rep());
+            let found = ctx.builder.ins().bint(ctx.word, found);
+            Ok(emit_word_to_bool(found, &mut ctx.builder))
+        }
+        _ => panic!("non string primitive in emit_string_primitive: {}", name),
+    }
+}
+
+fn emit_string_append(a: Value, b: Value, ctx: &mut Context) -> Result<Value, String> {
+    // Copying A into a vector first means its length is known and its
+    // characters can be read back to front.
+    let vector = emit_list_to_vector(a, ctx)?;
+    let ptr = ctx.builder.ins().band_imm(vector, HEAP_PTR_MASK);
+    let length = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+    emit_elements_onto_list(ptr, length, b, ctx)
+}
+
+fn emit_string_slice(
+    s: Value,
+    start: Value,
+    end: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_int(end, ctx)?;
+    let rest = crate::sublists::emit_list_tail(s, start, ctx)?;
+    // Both are fixnums so their difference is too.
+    let length = ctx.builder.ins().isub(end, start);
+    let after = crate::sublists::emit_list_tail(rest, length, ctx)?;
+
+    let copy_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let at_end = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, after, Expr::Nil.immediate_rep());
+    ctx.builder.ins().brnz(at_end, done_block, &[rest]);
+    ctx.builder.ins().jump(copy_block, &[]);
+
+    ctx.builder.switch_to_block(copy_block);
+    ctx.builder.seal_block(copy_block);
+    let copy = crate::sublists::emit_take(rest, length, ctx)?;
+    ctx.builder.ins().jump(done_block, &[copy]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+fn emit_string_length(s: Value, ctx: &mut Context) -> Result<Value, String> {
+    let count_block = ctx.builder.create_block();
+    let count_body = ctx.builder.create_block();
+    let counted_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(count_block, ctx.word);
+    ctx.builder.append_block_param(count_block, ctx.word);
+    ctx.builder.append_block_param(counted_block, ctx.word);
+    ctx.builder.append_block_param(counted_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(count_block, &[s, zero]);
+
+    ctx.builder.switch_to_block(count_block);
+    let rest = ctx.builder.block_params(count_block)[0];
+    let length = ctx.builder.block_params(count_block)[1];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder
+        .ins()
+        .brz(is_pair, counted_block, &[rest, length]);
+    ctx.builder.ins().jump(count_body, &[]);
+
+    ctx.builder.switch_to_block(count_body);
+    ctx.builder.seal_block(count_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    fatal::emit_check_char(car, ctx)?;
+    let length = ctx.builder.ins().iadd_imm(length, 1);
+    ctx.builder.ins().jump(count_block, &[cdr, length]);
+    ctx.builder.seal_block(count_block);
+
+    ctx.builder.switch_to_block(counted_block);
+    ctx.builder.seal_block(counted_block);
+    let end = ctx.builder.block_params(counted_block)[0];
+    let length = ctx.builder.block_params(counted_block)[1];
+    emit_check_nil(end, ctx)?;
+    Ok(ctx.builder.ins().ishl_imm(length, FIXNUM_SHIFT))
+}
+
+fn emit_string_ref(s: Value, index: Value, ctx: &mut Context) -> Result<Value, String> {
+    fatal::emit_check_int(index, ctx)?;
+    let index = ctx.builder.ins().sshr_imm(index, FIXNUM_SHIFT);
+
+    // Walk the string until the character at INDEX is reached or it
+    // runs out. A negative index is never reached.
+    let walk_block = ctx.builder.create_block();
+    let walk_body = ctx.builder.create_block();
+    let next_block = ctx.builder.create_block();
+    let end_block = ctx.builder.create_block();
+    let found_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(found_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(walk_block, &[s, zero]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let i = ctx.builder.block_params(walk_block)[1];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, end_block, &[]);
+    ctx.builder.ins().jump(walk_body, &[]);
+
+    ctx.builder.switch_to_block(walk_body);
+    ctx.builder.seal_block(walk_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    let reached = ctx.builder.ins().icmp(IntCC::Equal, i, index);
+    ctx.builder.ins().brnz(reached, found_block, &[car]);
+    ctx.builder.ins().jump(next_block, &[]);
+
+    ctx.builder.switch_to_block(next_block);
+    ctx.builder.seal_block(next_block);
+    let i = ctx.builder.ins().iadd_imm(i, 1);
+    ctx.builder.ins().jump(walk_block, &[cdr, i]);
+    ctx.builder.seal_block(walk_block);
+
+    // Running out of characters is only a range error if the string
+    // ended properly.
+    ctx.builder.switch_to_block(end_block);
+    ctx.builder.seal_block(end_block);
+    emit_check_nil(rest, ctx)?;
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_out_of_range".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+    ctx.builder.ins().jump(found_block, &[nil]);
+
+    ctx.builder.switch_to_block(found_block);
+    ctx.builder.seal_block(found_block);
+    let c = ctx.builder.block_params(found_block)[0];
+    fatal::emit_check_char(c, ctx)?;
+    Ok(c)
+}
+
+/// The value the host returns when it is given something that isn't
+/// a string. Everything else it returns is a list.
+const NOT_A_STRING: Expr = Expr::Bool(false);
+
+/// Emits a call to the host function NAME which returns a list or
+/// NOT_A_STRING. A type error is raised if it doesn't return a list.
+fn emit_list_host_call(name: &str, args: &[Value], ctx: &mut Context) -> Result<Value, String> {
+    let res = emit_host_call(name, args, ctx)?;
+    let is_pair = emit_is_pair(res, ctx);
+    let is_nil = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, res, Expr::Nil.immediate_rep());
+    let is_list = ctx.builder.ins().bor(is_pair, is_nil);
+    fatal::emit_check_type(is_list, ctx)?;
+    Ok(res)
+}
+
+/// Emits the code for `(string-index s needle)` where ARGS are S and
+/// NEEDLE.
+fn emit_string_index(args: &[Value], ctx: &mut Context) -> Result<Value, String> {
+    let res = emit_host_call("lustc_string_index", args, ctx)?;
+    let is_string = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::NotEqual, res, NOT_A_STRING.immediate_rep());
+    fatal::emit_check_type(is_string, ctx)?;
+    Ok(res)
+}
+
+/// Returns the string E if it is one.
+pub(crate) fn expr_to_string(e: &Expr) -> Option<String> {
+    match e {
+        Expr::Nil => Some(String::new()),
+        e => try_stringify_list(e),
+    }
+}
+
+/// Returns the elements of the proper list E.
+fn expr_elements(e: &Expr) -> Option<Vec<&Expr>> {
+    let mut res = Vec::new();
+    let mut e = e;
+    loop {
+        match e {
+            Expr::Nil => return Some(res),
+            Expr::List(v) if v.len() == 2 => {
+                res.push(&v[0]);
+                e = &v[1];
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Returns the pieces of the string S between occurrences of the
+/// string SEP.
+pub(crate) fn string_split(s: &Expr, sep: &Expr) -> Option<Vec<String>> {
+    let (s, sep) = (expr_to_string(s)?, expr_to_string(sep)?);
+    if sep.is_empty() {
+        return None;
+    }
+    Some(s.split(sep.as_str()).map(|p| p.to_string()).collect())
+}
+
+/// Returns the index of the first character of the first occurrence
+/// of the string NEEDLE in the string S, if there is one.
+pub(crate) fn string_index(s: &Expr, needle: &Expr) -> Option<Option<usize>> {
+    let (s, needle) = (expr_to_string(s)?, expr_to_string(needle)?);
+    Some(s.find(needle.as_str()).map(|i| s[..i].chars().count()))
+}
+
+/// Returns the strings in LIST joined by the string SEP.
+pub(crate) fn string_join(list: &Expr, sep: &Expr) -> Option<String> {
+    let sep = expr_to_string(sep)?;
+    let strings = expr_elements(list)?
+        .into_iter()
+        .map(expr_to_string)
+        .collect::<Option<Vec<_>>>()?;
+    Some(strings.join(&sep))
+}
+
+/// Returns the string S changed by the string primitive NAME, which
+/// is one of string-upcase, string-downcase or string-trim.
+pub(crate) fn string_transform(name: &str, s: &Expr) -> Option<String> {
+    let s = expr_to_string(s)?;
+    Some(match name {
+        "string-upcase" => s.to_uppercase(),
+        "string-downcase" => s.to_lowercase(),
+        "string-trim" => s.trim().to_string(),
+        _ => panic!(
+            "non transforming string primitive in string_transform: {}",
+            name
+        ),
+    })
+}
+
+fn transform_immediate(name: &str, s: Word) -> Word {
+    match string_transform(name, &Expr::from_immediate(s)) {
+        Some(s) => string_to_immediate(&s),
+        None => NOT_A_STRING.immediate_rep(),
+    }
+}
+
+/// Implements (string-upcase s).
+pub extern "C" fn lustc_string_upcase(s: Word) -> Word {
+    transform_immediate("string-upcase", s)
+}
+
+/// Implements (string-downcase s).
+pub extern "C" fn lustc_string_downcase(s: Word) -> Word {
+    transform_immediate("string-downcase", s)
+}
+
+/// Implements (string-trim s).
+pub extern "C" fn lustc_string_trim(s: Word) -> Word {
+    transform_immediate("string-trim", s)
+}
+
+/// Implements (string-split s sep).
+pub extern "C" fn lustc_string_split(s: Word, sep: Word) -> Word {
+    match string_split(&Expr::from_immediate(s), &Expr::from_immediate(sep)) {
+        Some(pieces) => {
+            list_to_immediate(&pieces.into_iter().map(Expr::String).collect::<Vec<_>>())
+        }
+        None => NOT_A_STRING.immediate_rep(),
+    }
+}
+
+/// Implements (string-index s needle).
+pub extern "C" fn lustc_string_index(s: Word, needle: Word) -> Word {
+    match string_index(&Expr::from_immediate(s), &Expr::from_immediate(needle)) {
+        Some(Some(i)) => Expr::Integer(i as Word).immediate_rep(),
+        Some(None) => Expr::Nil.immediate_rep(),
+        None => NOT_A_STRING.immediate_rep(),
+    }
+}
+
+/// Implements (string-join list sep).
+pub extern "C" fn lustc_string_join(list: Word, sep: Word) -> Word {
+    match string_join(&Expr::from_immediate(list), &Expr::from_immediate(sep)) {
+        Some(s) => string_to_immediate(&s),
+        None => NOT_A_STRING.immediate_rep(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind};
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn string_append() {
+        check("(string-append \"ab\" \"cd\")", "\"abcd\"");
+        check("(string-append \"\" \"cd\")", "\"cd\"");
+        check("(string-append \"ab\" \"\")", "\"ab\"");
+        check("(let f string-append) (f \"a\" (f \"b\" \"c\"))", "\"abc\"");
+        // The first string isn't modified.
+        check("(let a \"ab\") (string-append a \"c\") a", "\"ab\"");
+    }
+
+    #[test]
+    fn split_and_join() {
+        check(
+            "(string-join (string-split \"a,b,c\" \",\") \",\")",
+            "\"a,b,c\"",
+        );
+        check(
+            "(string-split \"a,b,c\" \",\")",
+            "(cons \"a\" (cons \"b\" (cons \"c\" ())))",
+        );
+        check(
+            "(string-split \",a,,b\" \",\")",
+            "(cons \"\" (cons \"a\" (cons \"\" (cons \"b\" ()))))",
+        );
+        check(
+            "(string-split \"a::b\" \"::\")",
+            "(cons \"a\" (cons \"b\" ()))",
+        );
+        check("(string-split \"\" \",\")", "(cons \"\" ())");
+        check("(string-join () \",\")", "\"\"");
+        check(
+            "(string-join (cons \"a\" (cons \"\" (cons \"b\" ()))) \", \")",
+            "\"a, , b\"",
+        );
+    }
+
+    #[test]
+    fn search() {
+        check("(string-index \"hello\" \"ll\")", "2");
+        check("(string-index \"hello\" \"x\")", "()");
+        check("(string-index \"hello\" \"\")", "0");
+        check("(string-index \"\" \"a\")", "()");
+        check(
+            "(cons (string-contains \"hello\" \"lo\") (string-contains \"hello\" \"ol\"))",
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+        // The index counts characters so it can be handed to
+        // string-slice.
+        check(
+            "(let s \"h\u{e9}llo\") (let i (string-index s \"llo\")) (cons i (string-slice s i (add i 2)))",
+            "(cons 2 \"ll\")",
+        );
+    }
+
+    #[test]
+    fn split_and_join_errors() {
+        for source in [
+            "(string-split 1 \",\")",
+            "(string-split \"a\" \"\")",
+            "(string-join (cons 1 ()) \",\")",
+            "(string-join \"a\" 2)",
+            "(string-index 1 \"a\")",
+            "(string-contains \"a\" (quote (1)))",
+        ] {
+            assert_eq!(error_kind(source), "type-error", "{}", source);
+            assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn case_and_trim() {
+        check("(string-upcase \"Hello, World!\")", "\"HELLO, WORLD!\"");
+        check("(string-downcase \"Hello, World!\")", "\"hello, world!\"");
+        check("(string-upcase \"\")", "\"\"");
+        check("(string-trim \"  a b\t\n\")", "\"a b\"");
+        check("(string-trim \"ab\")", "\"ab\"");
+        check("(string-trim \" \t\n \")", "\"\"");
+        check("(string-trim \"\")", "\"\"");
+        check("(let f string-trim) (f \" x \")", "\"x\"");
+        // Case mappings aren't only for ASCII.
+        check("(string-upcase \"h\u{e9}\")", "\"H\u{c9}\"");
+        check("(string-upcase \"stra\u{df}e\")", "\"STRASSE\"");
+        check("(string-downcase \"\u{c9}T\u{c9}\")", "\"\u{e9}t\u{e9}\"");
+        // The originals are unchanged.
+        check(
+            r#"
+(let s " Ab ")
+(let changed (cons (string-upcase s) (cons (string-downcase s) (string-trim s))))
+(cons s changed)
+"#,
+            "(cons \" Ab \" (cons \" AB \" (cons \" ab \" \"Ab\")))",
+        );
+        for source in ["(string-upcase 1)", "(string-trim (quote (1 2)))"] {
+            assert_eq!(error_kind(source), "type-error", "{}", source);
+            assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn slices() {
+        let s = "(let s \"h\u{e9}llo\")";
+        check(&format!("{} (string-slice s 1 3)", s), "\"\u{e9}l\"");
+        check(&format!("{} (string-slice s 0 5)", s), "\"h\u{e9}llo\"");
+        check(&format!("{} (string-slice s 2 2)", s), "\"\"");
+        check(
+            &format!(
+                "{} (cons (equal (string-slice s 1 4) (take (drop s 1) 3)) (string-slice s 4 5))",
+                s
+            ),
+            "(cons (eq 1 1) \"o\")",
+        );
+        // A slice that runs to the end of the string shares its pairs.
+        check(
+            &format!(
+                "{} (cons (eq (string-slice s 3 5) (drop s 3)) (eq (string-slice s 1 3) (drop s 1)))",
+                s
+            ),
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+        check("(let f (fn (g) (g \"abc\" 1 2))) (f string-slice)", "\"b\"");
+    }
+
+    #[test]
+    fn length_and_ref() {
+        // There is no syntax for characters so b is written as a
+        // conversion.
+        check("(string-ref \"abc\" 1)", "(integer->char 98)");
+        assert_eq!(
+            roundtrip_string("(string-ref \"abc\" 1)").unwrap(),
+            crate::Expr::Char('b')
+        );
+        check(
+            "(cons (string-length \"abc\") (string-length \"\"))",
+            "(cons 3 0)",
+        );
+        // Indexes count characters and not bytes.
+        check("(string-ref \"h\u{e9}llo\" 2)", "(integer->char 108)");
+        check("(string-ref \"\u{1f6a8}!\" 1)", "(integer->char 33)");
+        check("(string-length \"h\u{e9}llo \u{1f6a8}\")", "7");
+    }
+
+    #[test]
+    fn ref_errors() {
+        for (source, kind) in [
+            ("(string-ref \"abc\" 3)", "range-error"),
+            ("(string-ref \"abc\" -1)", "range-error"),
+            ("(string-ref \"\" 0)", "range-error"),
+            ("(string-ref \"abc\" (quote a))", "type-error"),
+            ("(string-ref (quote (1 2)) 0)", "type-error"),
+            ("(string-length (quote (1 2)))", "type-error"),
+            ("(string-slice \"abc\" 2 4)", "range-error"),
+            ("(string-slice \"abc\" 2 1)", "range-error"),
+            ("(string-slice \"abc\" -1 1)", "range-error"),
+            ("(string-slice \"abc\" 0 (quote a))", "type-error"),
+        ] {
+            assert_eq!(error_kind(source), kind, "{}", source);
+            assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! Primitives that walk the pairs of a list.
+//!
+//! `(length list)` is the number of elements in LIST, raising a type
+//! error if LIST doesn't end in nil.
+//!
+//! `(list-tail list n)`, or `(drop list n)`, returns what is left of
+//! LIST after its first N pairs and shares its storage with LIST.
+//! `(take list n)` returns a new list of the first N elements of
+//! LIST.
+//!
+//! ```lisp
+//! (take (quote (1 2 3 4)) 2) ; => (1 2)
+//! (drop (quote (1 2 3 4)) 2) ; => (3 4)
+//! ```
+//!
+//! Both of those raise a range error if LIST has fewer than N elements or N
+//! is negative. Taking more elements than a list has could instead
+//! return the whole list but then a mistake in computing N would go
+//! unnoticed and `(take l n)` wouldn't always have N elements.
+//!
+//! `(last list)` is the last element of LIST and raises a range error
+//! if LIST is nil. `(list-copy list)` returns a new list with the same
+//! elements as LIST. Only the pairs are copied so the elements are
+//! shared with LIST and a list that doesn't end in nil is copied with
+//! the same end.
+//!
+//! ```lisp
+//! (last (quote (1 2 3)))      ; => 3
+//! (list-copy (quote (1 2 3))) ; => (1 2 3)
+//! ```
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{FIXNUM_SHIFT, PAIR_TAG};
+use crate::fatal;
+use crate::heap::emit_alloc;
+use crate::vectors::{emit_check_nil, emit_is_pair, emit_pair_parts};
+use crate::Expr;
+
+/// The names of the sublist primitives.
+pub(crate) const SUBLIST_PRIMITIVES: [&str; 5] =
+    ["length", "list-tail", "take", "last", "list-copy"];
+
+/// Returns the number of arguments that the sublist primitive NAME
+/// takes.
+pub(crate) fn sublist_primitive_arity(name: &str) -> usize {
+    match name {
+        "length" | "last" | "list-copy" => 1,
+        "list-tail" | "take" => 2,
+        _ => panic!("non sublist primitive in sublist_primitive_arity: {}", name),
+    }
+}
+
+/// Emits the code for the sublist primitive NAME applied to ARGS
+/// which have already been evaluated.
+pub(crate) fn emit_sublist_primitive(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    match name {
+        "length" => emit_length(args[0], ctx),
+        "list-tail" => emit_list_tail(args[0], args[1], ctx),
+        "take" => emit_take(args[0], args[1], ctx),
+        "last" => emit_last(args[0], ctx),
+        "list-copy" => emit_list_copy(args[0], ctx),
+        _ => panic!("non sublist primitive in emit_sublist_primitive: {}", name),
+    }
+}
+
+/// Emits the code for when REST, what is left of a list, ran out
+/// before enough elements were reached. This is a range error if the
+/// list ended properly and a type error otherwise.
+fn emit_too_short(rest: Value, ctx: &mut Context) -> Result<(), String> {
+    emit_check_nil(rest, ctx)?;
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_out_of_range".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+    Ok(())
+}
+
+fn emit_length(list: Value, ctx: &mut Context) -> Result<Value, String> {
+    let count_block = ctx.builder.create_block();
+    let count_body = ctx.builder.create_block();
+    let counted_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(count_block, ctx.word);
+    ctx.builder.append_block_param(count_block, ctx.word);
+    ctx.builder.append_block_param(counted_block, ctx.word);
+    ctx.builder.append_block_param(counted_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(count_block, &[list, zero]);
+
+    ctx.builder.switch_to_block(count_block);
+    let rest = ctx.builder.block_params(count_block)[0];
+    let length = ctx.builder.block_params(count_block)[1];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder
+        .ins()
+        .brz(is_pair, counted_block, &[rest, length]);
+    ctx.builder.ins().jump(count_body, &[]);
+
+    ctx.builder.switch_to_block(count_body);
+    ctx.builder.seal_block(count_body);
+    let (_, cdr) = emit_pair_parts(rest, ctx);
+    let length = ctx.builder.ins().iadd_imm(length, 1);
+    ctx.builder.ins().jump(count_block, &[cdr, length]);
+    ctx.builder.seal_block(count_block);
+
+    ctx.builder.switch_to_block(counted_block);
+    ctx.builder.seal_block(counted_block);
+    let end = ctx.builder.block_params(counted_block)[0];
+    let length = ctx.builder.block_params(counted_block)[1];
+    emit_check_nil(end, ctx)?;
+    Ok(ctx.builder.ins().ishl_imm(length, FIXNUM_SHIFT))
+}
+
+pub(crate) fn emit_list_tail(list: Value, n: Value, ctx: &mut Context) -> Result<Value, String> {
+    fatal::emit_check_int(n, ctx)?;
+    let n = ctx.builder.ins().sshr_imm(n, FIXNUM_SHIFT);
+
+    // A negative N is never reached so the list runs out.
+    let walk_block = ctx.builder.create_block();
+    let check_block = ctx.builder.create_block();
+    let walk_body = ctx.builder.create_block();
+    let short_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(walk_block, &[list, zero]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let i = ctx.builder.block_params(walk_block)[1];
+    let reached = ctx.builder.ins().icmp(IntCC::Equal, i, n);
+    ctx.builder.ins().brnz(reached, done_block, &[rest]);
+    ctx.builder.ins().jump(check_block, &[]);
+
+    ctx.builder.switch_to_block(check_block);
+    ctx.builder.seal_block(check_block);
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, short_block, &[]);
+    ctx.builder.ins().jump(walk_body, &[]);
+
+    ctx.builder.switch_to_block(walk_body);
+    ctx.builder.seal_block(walk_body);
+    let (_, cdr) = emit_pair_parts(rest, ctx);
+    let i = ctx.builder.ins().iadd_imm(i, 1);
+    ctx.builder.ins().jump(walk_block, &[cdr, i]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(short_block);
+    ctx.builder.seal_block(short_block);
+    emit_too_short(rest, ctx)?;
+    ctx.builder.ins().jump(done_block, &[rest]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+pub(crate) fn emit_take(list: Value, n: Value, ctx: &mut Context) -> Result<Value, String> {
+    fatal::emit_check_int(n, ctx)?;
+    let n = ctx.builder.ins().sshr_imm(n, FIXNUM_SHIFT);
+    let word_size = ctx.word.bytes() as i32;
+
+    // The new list is built front to back. Each pair is stored into
+    // the cdr of the pair before it, starting with a pair on the stack
+    // whose cdr ends up being the new list.
+    let head = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * word_size as u32,
+    ));
+    let head = ctx.builder.ins().stack_addr(ctx.word, head, 0);
+
+    let walk_block = ctx.builder.create_block();
+    let check_block = ctx.builder.create_block();
+    let walk_body = ctx.builder.create_block();
+    let short_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(walk_block, &[list, zero, head]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let i = ctx.builder.block_params(walk_block)[1];
+    let last = ctx.builder.block_params(walk_block)[2];
+    let reached = ctx.builder.ins().icmp(IntCC::Equal, i, n);
+    ctx.builder.ins().brnz(reached, done_block, &[last]);
+    ctx.builder.ins().jump(check_block, &[]);
+
+    ctx.builder.switch_to_block(check_block);
+    ctx.builder.seal_block(check_block);
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, short_block, &[]);
+    ctx.builder.ins().jump(walk_body, &[]);
+
+    ctx.builder.switch_to_block(walk_body);
+    ctx.builder.seal_block(walk_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    let pair = emit_alloc((2 * word_size).into(), ctx)?;
+    ctx.builder.ins().store(MemFlags::new(), car, pair, 0);
+    let tagged = ctx.builder.ins().bor_imm(pair, PAIR_TAG);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), tagged, last, word_size);
+    let i = ctx.builder.ins().iadd_imm(i, 1);
+    ctx.builder.ins().jump(walk_block, &[cdr, i, pair]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(short_block);
+    ctx.builder.seal_block(short_block);
+    emit_too_short(rest, ctx)?;
+    ctx.builder.ins().jump(done_block, &[last]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let last = ctx.builder.block_params(done_block)[0];
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), nil, last, word_size);
+    Ok(ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), head, word_size))
+}
+
+fn emit_last(list: Value, ctx: &mut Context) -> Result<Value, String> {
+    let empty_block = ctx.builder.create_block();
+    let walk_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let is_pair = emit_is_pair(list, ctx);
+    ctx.builder.ins().brz(is_pair, empty_block, &[]);
+    ctx.builder.ins().jump(walk_block, &[list]);
+
+    ctx.builder.switch_to_block(empty_block);
+    ctx.builder.seal_block(empty_block);
+    emit_too_short(list, ctx)?;
+    ctx.builder.ins().jump(done_block, &[list]);
+
+    // Walks to the last pair of the list.
+    ctx.builder.switch_to_block(walk_block);
+    let pair = ctx.builder.block_params(walk_block)[0];
+    let (car, cdr) = emit_pair_parts(pair, ctx);
+    let is_pair = emit_is_pair(cdr, ctx);
+    ctx.builder.ins().brz(is_pair, done_block, &[car]);
+    ctx.builder.ins().jump(walk_block, &[cdr]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+fn emit_list_copy(list: Value, ctx: &mut Context) -> Result<Value, String> {
+    let word_size = ctx.word.bytes() as i32;
+
+    // Built front to back in the same way as take.
+    let head = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        2 * word_size as u32,
+    ));
+    let head = ctx.builder.ins().stack_addr(ctx.word, head, 0);
+
+    let walk_block = ctx.builder.create_block();
+    let walk_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(walk_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    ctx.builder.ins().jump(walk_block, &[list, head]);
+
+    ctx.builder.switch_to_block(walk_block);
+    let rest = ctx.builder.block_params(walk_block)[0];
+    let last = ctx.builder.block_params(walk_block)[1];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder.ins().brz(is_pair, done_block, &[rest, last]);
+    ctx.builder.ins().jump(walk_body, &[]);
+
+    ctx.builder.switch_to_block(walk_body);
+    ctx.builder.seal_block(walk_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    let pair = emit_alloc((2 * word_size).into(), ctx)?;
+    ctx.builder.ins().store(MemFlags::new(), car, pair, 0);
+    let tagged = ctx.builder.ins().bor_imm(pair, PAIR_TAG);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), tagged, last, word_size);
+    ctx.builder.ins().jump(walk_block, &[cdr, pair]);
+    ctx.builder.seal_block(walk_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let end = ctx.builder.block_params(done_block)[0];
+    let last = ctx.builder.block_params(done_block)[1];
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), end, last, word_size);
+    Ok(ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), head, word_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_string;
+    use crate::test_util::{check, error_kind};
+
+    #[test]
+    fn length() {
+        check("(let id (fn (x) x)) (length (id (quote (1 2 3))))", "3");
+        check("(let id (fn (x) x)) (length (id \"abcd\"))", "4");
+        check("(length (cons 1 (cons 2 ())))", "2");
+        check("(let f (fn (g l) (g l))) (f length (quote (1 2)))", "2");
+    }
+
+    #[test]
+    fn take_and_drop() {
+        let l = "(let l (quote (1 2 3 4)))";
+        check(&format!("{} (take l 2)", l), "(quote (1 2))");
+        check(&format!("{} (drop l 2)", l), "(quote (3 4))");
+        check(&format!("{} (list-tail l 4)", l), "()");
+        check(&format!("{} (take l 0)", l), "()");
+        check(
+            &format!("{} (cons (take l 4) (eq (list-tail l 0) l))", l),
+            "(cons (quote (1 2 3 4)) (eq 1 1))",
+        );
+        check(
+            "(let f (fn (g l) (g l 1))) (cons (f take (quote (5 6))) (f drop (quote (5 6))))",
+            "(cons (quote (5)) (quote (6)))",
+        );
+        // Drop shares its storage with the list and take doesn't.
+        check(
+            &format!(
+                "{} (cons (eq (drop l 2) (cdr (cdr l))) (eq (take l 4) l))",
+                l
+            ),
+            "(cons (eq 1 1) (eq 1 2))",
+        );
+    }
+
+    #[test]
+    fn last_and_list_copy() {
+        check("(last (quote (1 2 3)))", "3");
+        check("(last (cons 1 ()))", "1");
+        check("(last (cons 1 (cons 2 3)))", "2");
+        check("(let f (fn (g l) (g l))) (f last (quote (4 5)))", "5");
+        let l = "(let l (quote (1 (2) 3)))";
+        check(&format!("{} (list-copy l)", l), "(quote (1 (2) 3))");
+        check("(list-copy ())", "()");
+        check("(list-copy (cons 1 (cons 2 3)))", "(cons 1 (cons 2 3))");
+        check(
+            "(let f (fn (g l) (g l))) (f list-copy (quote (4 5)))",
+            "(quote (4 5))",
+        );
+        // The copy of a quoted list is made of new pairs and shares its
+        // elements with the original.
+        check(
+            &format!(
+                "{} (let c (list-copy l)) (cons (eq c l) (cons (eq (cdr c) (cdr l)) (eq (car (cdr c)) (car (cdr l)))))",
+                l
+            ),
+            "(cons (eq 1 2) (cons (eq 1 2) (eq 1 1)))",
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for (source, kind) in [
+            ("(take (quote (1 2)) 3)", "range-error"),
+            ("(drop (quote (1 2)) 3)", "range-error"),
+            ("(take (quote (1 2)) -1)", "range-error"),
+            ("(list-tail (cons 1 2) 2)", "type-error"),
+            ("(take (quote (1 2)) (quote a))", "type-error"),
+            ("(length (cons 1 2))", "type-error"),
+            ("(length 1)", "type-error"),
+            ("(last ())", "range-error"),
+            ("(last 1)", "type-error"),
+        ] {
+            assert_eq!(error_kind(source), kind, "{}", source);
+            assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Symbols are interned so that every symbol with a given name is
+//! the same object. This lets eq compare symbols by comparing words
+//! like it does for everything else. A symbol's word is a pointer to
+//! its name tagged with SYMBOL_TAG. Names are never freed. The table
+//! is shared by every JIT in the process behind a lock so symbols
+//! stay eq when data is passed between programs, even ones compiled
+//! on different threads.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::conversions::{HEAP_PTR_MASK, SYMBOL_TAG};
+use crate::Word;
+
+/// Every symbol that has been interned keyed on its name.
+static SYMBOLS: OnceLock<Mutex<HashMap<String, Word>>> = OnceLock::new();
+
+/// Returns the word for the symbol named NAME.
+pub(crate) fn intern(name: &str) -> Word {
+    let mut symbols = SYMBOLS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    *symbols.entry(name.to_string()).or_insert_with(|| {
+        let name = Box::new(name.to_string());
+        Box::into_raw(name) as Word | SYMBOL_TAG
+    })
+}
+
+/// Returns the name of the symbol WORD.
+pub(crate) fn symbol_name(word: Word) -> &'static str {
+    let name = (word & HEAP_PTR_MASK) as *const String;
+    unsafe { &*name }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{roundtrip_string, Expr};
+
+    #[test]
+    fn interned() {
+        assert_eq!(intern("foo"), intern("foo"));
+        assert_ne!(intern("foo"), intern("bar"));
+        assert_eq!(symbol_name(intern("foo")), "foo");
+    }
+
+    #[test]
+    fn quote_atoms() {
+        assert_eq!(roundtrip_string("(quote 5)").unwrap(), Expr::Integer(5));
+        assert_eq!(roundtrip_string("(quote ())").unwrap(), Expr::Nil);
+        assert_eq!(
+            roundtrip_string("(quote foo)").unwrap(),
+            Expr::Symbol("foo".to_string())
+        );
+        // Quoted symbols are not variables even if a variable with
+        // the same name exists.
+        assert_eq!(
+            roundtrip_string("(let foo 1) (cons foo (quote (foo bar)))").unwrap(),
+            roundtrip_string("(cons 1 (cons (quote foo) (cons (quote bar) ())))").unwrap()
+        );
+        let eq = "(let f (fn () (quote foo))) (cons (eq (f) (quote foo)) (eq (f) (quote bar)))";
+        assert_eq!(
+            roundtrip_string(eq).unwrap(),
+            Expr::List(vec![Expr::Bool(true), Expr::Bool(false)])
+        );
+        // Negative numbers are data like any other number.
+        assert_eq!(roundtrip_string("(quote -5)").unwrap(), Expr::Integer(-5));
+        assert_eq!(
+            roundtrip_string("(quote (1 -2 3))").unwrap(),
+            roundtrip_string("(cons 1 (cons (sub 0 2) (cons 3 ())))").unwrap()
+        );
+    }
+}
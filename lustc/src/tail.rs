@@ -0,0 +1,750 @@
+//! Tail calls. A call is in tail position if the function making it
+//! returns the call's result without doing anything else with it. The
+//! last expression in a function's body is in tail position and so are
+//! both branches of an if in tail position. Because `and`, `or`, and
+//! `cond` are desugared into ifs the last argument of an and or or and
+//! the body of each cond clause are too.
+//!
+//! Cranelift can't jump to another function (see `docs/tco.md`) so for
+//! now the only tail calls that are optimized are ones a function makes
+//! to itself. Those are rewritten into a jump back to the start of the
+//! function so that a loop written with recursion runs in constant
+//! stack space.
+//!
+//! With `CompileOptions::trampoline` set, mutually recursive functions
+//! run in constant stack space too, at the cost of some work on every
+//! call between them. Functions that tail call each other in a cycle
+//! don't make those calls. Instead they store the function and its
+//! arguments and return `BOUNCE_VALUE` to a trampoline, a loop around
+//! every other call to one of the functions that makes the stored
+//! call and keeps going until a real value comes back.
+//!
+//! ```lisp
+//! (let od? ())
+//! (let ev? (fn (n) (or (eq n 0) (od? (sub n 1)))))
+//! (set od? (fn (n) (and (not (eq n 0)) (ev? (sub n 1)))))
+//! (ev? 1000000)
+//! ```
+//!
+//! Here the calls to od? and ev? bounce and `(ev? 1000000)` is a
+//! trampoline. Only functions that don't take varadic arguments and
+//! are only ever called by name take part, and the variable they are
+//! in has to be bound to them once, either by its let or by the one
+//! set to it like od? above. Writing od?'s definition after ev? with
+//! a let works too, even though ev? reads od? from its slot (see
+//! `forward.rs`). A function that is passed around as a value could be
+//! called by code that doesn't know to run the trampoline, and so
+//! could a function defined at the top level of a persistent program,
+//! so those are left alone. Calls that don't go around a cycle are
+//! made as usual.
+//!
+//! `find_non_tail_self_calls` goes the other way and finds the calls
+//! that a function makes to itself which aren't in tail position and
+//! so can't be turned into jumps, like the call in `(mul n (fact (sub n
+//! 1)))`. `lustc --warn-non-tail` prints a warning for each of them.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::procedures::is_varadic_param;
+use crate::Expr;
+use crate::PreorderStatus;
+
+/// The head of a self tail call after it has been rewritten.
+pub(crate) const SELF_TAIL_CALL: &str = "__anon_self_tail_call";
+
+/// The heads of tail calls that return to a trampoline and of calls
+/// that run one.
+const BOUNCE: &str = "__anon_bounce";
+const TRAMPOLINE: &str = "__anon_trampoline";
+
+/// If E is a call with HEAD before its function returns the function
+/// and the arguments.
+fn marked_call<'a>(e: &'a Expr, head: &str) -> Option<(&'a Expr, &'a [Expr])> {
+    match e {
+        Expr::List(v) if v.len() >= 2 && v[0] == Expr::Symbol(head.to_string()) => {
+            Some((&v[1], &v[2..]))
+        }
+        _ => None,
+    }
+}
+
+impl Expr {
+    /// If the expression is a call that a function makes to itself in
+    /// tail position returns the arguments to the call.
+    pub(crate) fn is_self_tail_call(&self) -> Option<&[Expr]> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == SELF_TAIL_CALL {
+                    return Some(&v[1..]);
+                }
+            }
+        }
+        None
+    }
+
+    /// If the expression is a tail call that returns to a trampoline
+    /// instead of being made returns the function and arguments.
+    pub(crate) fn is_bounce(&self) -> Option<(&Expr, &[Expr])> {
+        marked_call(self, BOUNCE)
+    }
+
+    /// If the expression is a call that runs a trampoline returns the
+    /// function and arguments.
+    pub(crate) fn is_trampolined_call(&self) -> Option<(&Expr, &[Expr])> {
+        marked_call(self, TRAMPOLINE)
+    }
+}
+
+/// Calls F on every expression in tail position in E assuming that E
+/// is itself in tail position.
+fn visit_tail_positions<F>(e: &mut Expr, f: &mut F)
+where
+    F: FnMut(&mut Expr),
+{
+    if e.is_conditional().is_some() {
+        if let Expr::List(v) = e {
+            visit_tail_positions(&mut v[2], f);
+            visit_tail_positions(&mut v[3], f);
+        }
+    } else {
+        f(e)
+    }
+}
+
+/// Returns true if an expression equal to TARGET is in tail position
+/// in one of the functions in PROGRAM. Expressions at the top level
+/// aren't in a function so they are never in tail position. PROGRAM
+/// is desugared first so the positions are the ones the rest of the
+/// compiler sees.
+pub fn is_tail_position(program: &[Expr], target: &Expr) -> bool {
+    let mut program = program.to_vec();
+    if crate::desugar::desugar(&mut program).is_err() {
+        return false;
+    }
+
+    let mut found = false;
+    for e in program.iter_mut() {
+        e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if e.is_fndef().is_some() {
+                if let Expr::List(v) = e {
+                    visit_tail_positions(v.last_mut().unwrap(), &mut |e: &mut Expr| {
+                        found |= e == target
+                    });
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+    found
+}
+
+/// Rewrites the calls that functions bound with let make to themselves
+/// in tail position. A call is only rewritten if it passes the right
+/// number of arguments to a function that doesn't take varadic
+/// arguments and the variable it is bound to is never assigned to
+/// with set. Needs to run after renaming so that a name in a call
+/// can't refer to some other variable with the same name.
+pub(crate) fn mark_self_tail_calls(program: &mut [Expr]) {
+    let _t = crate::timer::timeit("tail call pass");
+
+    let mut assigned = HashSet::new();
+    for e in program.iter() {
+        e.preorder_traverse(&mut |e: &Expr| {
+            if let Some((name, _)) = e.is_set() {
+                assigned.insert(name.clone());
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    for e in program.iter_mut() {
+        e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            let name = match e.is_let() {
+                Some((name, binding)) if !assigned.contains(name) => match binding.is_fndef() {
+                    Some((params, _)) if !params.iter().any(|p| is_varadic_param(p)) => {
+                        Some((name.clone(), params.len()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((name, arity)) = name {
+                if let Expr::List(v) = e {
+                    if let Expr::List(f) = &mut v[2] {
+                        if let Some(last) = f.last_mut() {
+                            visit_tail_positions(last, &mut |e: &mut Expr| {
+                                if let Expr::List(call) = e {
+                                    if call.len() == arity + 1
+                                        && call[0] == Expr::Symbol(name.clone())
+                                    {
+                                        call[0] = Expr::Symbol(SELF_TAIL_CALL.to_string());
+                                    }
+                                }
+                            });
+                        }
+                    }
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+}
+
+/// Collects the names of the functions that calls in tail position
+/// in E, which is itself in tail position, are made to.
+fn tail_callees<'a>(e: &'a Expr, callees: &mut Vec<&'a String>) {
+    if let Some((_, then, else_)) = e.is_conditional() {
+        tail_callees(then, callees);
+        tail_callees(else_, callees);
+    } else if let Expr::List(v) = e {
+        if let Some(Expr::Symbol(s)) = v.first() {
+            callees.push(s);
+        }
+    }
+}
+
+/// Collects the variables that E uses other than by calling them.
+fn value_uses<'a>(e: &'a Expr, uses: &mut HashSet<&'a String>) {
+    if e.is_quote().is_some() {
+        return;
+    }
+    match e {
+        Expr::Symbol(s) => {
+            uses.insert(s);
+        }
+        Expr::List(v) => {
+            // Neither the name a let or set binds nor the function a
+            // call is made to are values.
+            let skip = if e.is_let().is_some() || e.is_set().is_some() {
+                2
+            } else if matches!(v.first(), Some(Expr::Symbol(_))) {
+                1
+            } else {
+                0
+            };
+            for e in &v[skip..] {
+                value_uses(e, uses);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Rewrites the calls between functions that tail call each other in
+/// a cycle into bounces and trampolines. If PERSISTENT is set the
+/// functions defined at the top level of PROGRAM are left alone. Needs
+/// to run after renaming and after self tail calls have been marked so
+/// that those stay jumps. FORWARD maps the definitions that are used
+/// before they are made to the slots those uses read (see
+/// `forward.rs`) so that a call to a slot is a call to its definition.
+pub(crate) fn mark_trampolined_calls(
+    program: &mut [Expr],
+    persistent: bool,
+    forward: &HashMap<String, String>,
+) {
+    let _t = crate::timer::timeit("trampoline pass");
+
+    let definitions: HashMap<&String, &String> =
+        forward.iter().map(|(name, slot)| (slot, name)).collect();
+    let callee = |s: &String| -> String { definitions.get(s).copied().unwrap_or(s).clone() };
+
+    let mut uses = HashSet::new();
+    let mut assignments: HashMap<String, usize> = HashMap::new();
+    let mut exported = HashSet::new();
+    // The arity of each function bound to a name, the functions it
+    // calls in tail position, and whether it was bound by set.
+    let mut bindings: HashMap<String, Vec<(usize, Vec<String>, bool)>> = HashMap::new();
+    for e in program.iter() {
+        value_uses(e, &mut uses);
+        if let Some((name, _)) = e.is_let() {
+            if persistent {
+                exported.insert(name.clone());
+            }
+        }
+        e.preorder_traverse(&mut |e: &Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            let (name, binding, is_set) = match (e.is_let(), e.is_set()) {
+                (Some((name, binding)), _) => (name, binding, false),
+                (_, Some((name, binding))) => {
+                    *assignments.entry(name.clone()).or_default() += 1;
+                    (name, binding, true)
+                }
+                _ => return PreorderStatus::Continue,
+            };
+            if let Some((params, body)) = binding.is_fndef() {
+                let mut callees = Vec::new();
+                if let Some(last) = body.last() {
+                    tail_callees(last, &mut callees);
+                }
+                let callees = callees.into_iter().map(callee).collect();
+                let varadic = params.iter().any(|p| is_varadic_param(p));
+                let arity = if varadic { usize::MAX } else { params.len() };
+                bindings
+                    .entry(name.clone())
+                    .or_default()
+                    .push((arity, callees, is_set));
+            }
+            PreorderStatus::Continue
+        });
+    }
+    // A name takes part if the only function it is ever bound to is
+    // its definition, or the one time it is assigned to, which is how
+    // mutually recursive functions are written.
+    let functions: HashMap<String, (usize, Vec<String>)> = bindings
+        .into_iter()
+        .filter_map(|(name, mut fns)| match fns.as_slice() {
+            [(arity, _, is_set)]
+                if *arity != usize::MAX
+                    && assignments.get(&name).copied().unwrap_or(0) == *is_set as usize
+                    && !uses.contains(&name)
+                    && !exported.contains(&name) =>
+            {
+                let (arity, callees, _) = fns.pop().unwrap();
+                Some((name, (arity, callees)))
+            }
+            _ => None,
+        })
+        .collect();
+
+    // The functions that each function can reach with tail calls.
+    let mut reaches: HashMap<&String, HashSet<&String>> = HashMap::new();
+    for name in functions.keys() {
+        let mut seen = HashSet::new();
+        let mut stack = vec![name];
+        while let Some(f) = stack.pop() {
+            for callee in &functions[f].1 {
+                if functions.contains_key(callee) && seen.insert(callee) {
+                    stack.push(callee);
+                }
+            }
+        }
+        reaches.insert(name, seen);
+    }
+    // A bounce from F to G is only worth it if G can get back to F.
+    let cycle = |f: &String, g: &String| f != g && reaches[f].contains(g) && reaches[g].contains(f);
+    let trampolined: HashMap<String, usize> = functions
+        .iter()
+        .filter(|(f, _)| reaches[f].iter().any(|g| cycle(f, g)))
+        .map(|(f, (arity, _))| (f.clone(), *arity))
+        .collect();
+    let bounces: HashSet<(String, String)> = trampolined
+        .keys()
+        .flat_map(|f| {
+            trampolined
+                .keys()
+                .filter(move |g| cycle(f, g))
+                .map(move |g| (f.clone(), g.clone()))
+        })
+        .collect();
+    if trampolined.is_empty() {
+        return;
+    }
+
+    for e in program.iter_mut() {
+        e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            let name = match e.is_let().or_else(|| e.is_set()) {
+                Some((name, binding))
+                    if trampolined.contains_key(name) && binding.is_fndef().is_some() =>
+                {
+                    name.clone()
+                }
+                _ => return PreorderStatus::Continue,
+            };
+            if let Expr::List(v) = e {
+                if let Expr::List(f) = &mut v[2] {
+                    if let Some(last) = f.last_mut() {
+                        visit_tail_positions(last, &mut |e: &mut Expr| {
+                            if let Expr::List(call) = e {
+                                if let Expr::Symbol(s) = &call[0] {
+                                    let callee = callee(s);
+                                    if bounces.contains(&(name.clone(), callee.clone()))
+                                        && trampolined[&callee] + 1 == call.len()
+                                    {
+                                        call.insert(0, Expr::Symbol(BOUNCE.to_string()));
+                                    }
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+
+    // Every other call to one of the functions runs a trampoline.
+    for e in program.iter_mut() {
+        e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            if let Expr::List(v) = e {
+                if matches!(v.first(), Some(Expr::Symbol(s)) if trampolined.contains_key(&callee(s))) {
+                    v.insert(0, Expr::Symbol(TRAMPOLINE.to_string()));
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+}
+
+/// A call that a function makes to itself outside of tail position,
+/// which takes a stack frame for every level of recursion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonTailSelfCall {
+    /// The name of the function as it appears in the source.
+    pub name: String,
+    /// The index of the top level form that contains the call.
+    pub form: usize,
+}
+
+impl std::fmt::Display for NonTailSelfCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "warning: ({}) calls itself outside of tail position so each call uses stack",
+            self.name
+        )
+    }
+}
+
+/// Returns a warning for each call in PROGRAM that a function bound
+/// with let or set makes to itself outside of tail position, in the
+/// order that they appear. Calls from functions nested inside the
+/// function count as they aren't tail calls of it either. This is
+/// only a hint so the program is left alone and may still be
+/// compiled.
+pub fn find_non_tail_self_calls(program: &[Expr]) -> Result<Vec<NonTailSelfCall>, String> {
+    let mut program = program.to_vec();
+    crate::desugar::desugar(&mut program)?;
+    crate::renamer::make_names_unique(&mut program)?;
+
+    let mut res = Vec::new();
+    for (form, e) in program.iter_mut().enumerate() {
+        e.preorder_traverse_mut(&mut |e: &mut Expr| {
+            if e.is_quote().is_some() {
+                return PreorderStatus::Skip;
+            }
+            let name = match e.is_let().or_else(|| e.is_set()) {
+                Some((name, binding)) if binding.is_fndef().is_some() => name.clone(),
+                _ => return PreorderStatus::Continue,
+            };
+            if let Expr::List(v) = e {
+                if let Expr::List(f) = &mut v[2] {
+                    // Tail calls are marked first so that every call
+                    // left over is one that isn't.
+                    if let Some(last) = f.last_mut() {
+                        visit_tail_positions(last, &mut |e: &mut Expr| {
+                            if let Expr::List(call) = e {
+                                if call[0] == Expr::Symbol(name.clone()) {
+                                    call[0] = Expr::Symbol(SELF_TAIL_CALL.to_string());
+                                }
+                            }
+                        });
+                    }
+                    for body in &f[2..] {
+                        body.preorder_traverse(&mut |e: &Expr| {
+                            if e.is_quote().is_some() {
+                                return PreorderStatus::Skip;
+                            }
+                            if let Expr::List(call) = e {
+                                if call[0] == Expr::Symbol(name.clone()) {
+                                    res.push(NonTailSelfCall {
+                                        name: crate::renamer::original_name(&name).to_string(),
+                                        form,
+                                    });
+                                }
+                            }
+                            PreorderStatus::Continue
+                        });
+                    }
+                }
+            }
+            PreorderStatus::Continue
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_string, roundtrip_string};
+
+    fn marked(source: &str) -> Vec<Expr> {
+        let mut program = parse_string(source).unwrap();
+        crate::desugar::desugar(&mut program).unwrap();
+        mark_self_tail_calls(&mut program);
+        program
+    }
+
+    fn tail_call(args: &str) -> String {
+        format!("({} {})", SELF_TAIL_CALL, args)
+    }
+
+    #[test]
+    fn tail_positions() {
+        assert_eq!(
+            marked("(let f (fn (n) (if (eq n 0) 0 (f (sub n 1)))))"),
+            parse_string(&format!(
+                "(let f (fn (n) (if (eq n 0) 0 {})))",
+                tail_call("(sub n 1)")
+            ))
+            .unwrap()
+        );
+        // Only the last argument to and is in tail position.
+        assert_eq!(
+            marked("(let f (fn (n) (and (f n) (f n))))"),
+            marked(&format!("(let f (fn (n) (and (f n) {})))", tail_call("n")))
+        );
+        // Each cond clause body is in tail position but the tests are not.
+        assert_eq!(
+            marked("(let f (fn (n) (cond ((f n) (f n)) (else (f n)))))"),
+            marked(&format!(
+                "(let f (fn (n) (cond ((f n) {}) (else {}))))",
+                tail_call("n"),
+                tail_call("n")
+            ))
+        );
+        // Calls that aren't the last thing the function does.
+        let not_tail = "(let f (fn (n) (f n) (add1 (f n)) (let x (f n))))";
+        assert_eq!(marked(not_tail), parse_string(not_tail).unwrap());
+        // Calls with the wrong number of arguments and calls to
+        // functions that are reassigned are left alone.
+        let left_alone = "(let f (fn (n) (f n n))) (let g (fn (n) (g n))) (set g f)";
+        assert_eq!(marked(left_alone), parse_string(left_alone).unwrap());
+    }
+
+    #[test]
+    fn tail_position_query() {
+        let source = r#"
+(let f (fn (n)
+  (g n)
+  (let x (h n))
+  (if (test n) (a (b n)) (cond ((c n) (d n)) (else (and (e n) (k n)))))))
+(top n)
+"#;
+        let program = parse_string(source).unwrap();
+        let is_tail = |e: &str| is_tail_position(&program, &parse_string(e).unwrap()[0]);
+        for tail in ["(a (b n))", "(d n)", "(k n)"] {
+            assert!(is_tail(tail), "{} should be in tail position", tail);
+        }
+        for not_tail in [
+            "(g n)", "(h n)", "(test n)", "(b n)", "(c n)", "(e n)", "(top n)",
+        ] {
+            assert!(
+                !is_tail(not_tail),
+                "{} shouldn't be in tail position",
+                not_tail
+            );
+        }
+
+        // The query agrees with the calls that run in constant stack.
+        let source = "(let count (fn (n) (if (eq n 0) 0 (count (sub n 1))))) (count 1000000)";
+        let program = parse_string(source).unwrap();
+        assert!(is_tail_position(
+            &program,
+            &parse_string("(count (sub n 1))").unwrap()[0]
+        ));
+        assert_eq!(roundtrip_string(source).unwrap(), Expr::Integer(0));
+    }
+
+    #[test]
+    fn loop_in_constant_stack() {
+        // Without the rewrite this would need a stack frame for each of
+        // the million calls which is more than a test thread has.
+        let source = r#"
+(let count (fn (n acc)
+  (cond ((eq n 0) acc)
+        ((eq (rem n 2) 0) (count (sub n 1) (add acc 2)))
+        (else (count (sub n 1) acc)))))
+(count 1000000 0)
+"#;
+        assert_eq!(roundtrip_string(source).unwrap(), Expr::Integer(1000000));
+
+        let source = r#"
+(let any (fn (l) (and (not (null? l)) (or (eq (car l) 3) (any (cdr l))))))
+(let range (fn (n acc) (if (eq n 0) acc (range (sub n 1) (cons n acc)))))
+(let big (range 200000 ()))
+(cons (any big) (any (quote (1 2))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![Expr::Bool(true), Expr::Bool(false)])
+        );
+    }
+
+    fn trampolined(source: &str) -> Vec<Expr> {
+        let mut program = marked(source);
+        mark_trampolined_calls(&mut program, false, &HashMap::new());
+        program
+    }
+
+    #[test]
+    fn trampolined_calls() {
+        let even_odd = r#"
+(let od? ())
+(let ev? (fn (n) (if (eq n 0) 1 (od? (sub n 1)))))
+(set od? (fn (n) (if (eq n 0) 0 (ev? (sub n 1)))))
+"#;
+        assert_eq!(
+            trampolined(&format!("{} (add1 (ev? 10))", even_odd)),
+            marked(&format!(
+                r#"
+(let od? ())
+(let ev? (fn (n) (if (eq n 0) 1 ({bounce} od? (sub n 1)))))
+(set od? (fn (n) (if (eq n 0) 0 ({bounce} ev? (sub n 1)))))
+(add1 ({trampoline} ev? 10))
+"#,
+                bounce = BOUNCE,
+                trampoline = TRAMPOLINE
+            ))
+        );
+        // A call in the middle of a cycle is a trampoline.
+        assert_eq!(
+            trampolined(&format!("{} (let f (fn (n) (ev? n) 1))", even_odd))[3],
+            marked(&format!("(let f (fn (n) ({} ev? n) 1))", TRAMPOLINE))[0]
+        );
+        // Functions that are passed around, assigned to again, or only
+        // call themselves are left alone.
+        for source in [
+            format!("{} (map ev? (quote (1 2)))", even_odd),
+            format!("{} (set od? (fn (n) (ev? n)))", even_odd),
+            format!("{} (set ev? od?)", even_odd),
+            "(let f (fn (n) (f n)))".to_string(),
+        ] {
+            assert_eq!(trampolined(&source), marked(&source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn mutual_recursion_in_constant_stack() {
+        let run = |source: &'static str| {
+            // Compiling and running both fit in this stack but a million
+            // frames wouldn't.
+            std::thread::Builder::new()
+                .stack_size(4 * 1024 * 1024)
+                .spawn(move || {
+                    let mut jit = crate::compiler::JIT::new(crate::compiler::CompileOptions {
+                        trampoline: true,
+                        embedded: true,
+                        ..Default::default()
+                    });
+                    let mut program = parse_string(source).unwrap();
+                    let id = crate::compiler::compile_program(&mut jit, &mut program).unwrap();
+                    jit.invoke(id).map(Expr::from_immediate).map_err(|e| e.kind)
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        let source = r#"
+(let od? ())
+(let ev? (fn (n) (or (eq n 0) (od? (sub n 1)))))
+(set od? (fn (n) (and (not (eq n 0)) (ev? (sub n 1)))))
+(cons (ev? 1000000) (od? 1000001))
+"#;
+        assert_eq!(
+            run(source),
+            Ok(Expr::List(vec![Expr::Bool(true), Expr::Bool(true)]))
+        );
+        // Calls to a function defined after the one making them go
+        // through its slot.
+        let source = r#"
+(let ev? (fn (n) (or (eq n 0) (od? (sub n 1)))))
+(let od? (fn (n) (and (not (eq n 0)) (ev? (sub n 1)))))
+(cons (ev? 1000000) (od? 1000001))
+"#;
+        assert_eq!(
+            run(source),
+            Ok(Expr::List(vec![Expr::Bool(true), Expr::Bool(true)]))
+        );
+        // A cycle of three with arguments that change on the way round.
+        let source = r#"
+(let b ())
+(let c ())
+(let a (fn (n acc) (if (eq n 0) acc (b (sub n 1) (add acc 1)))))
+(set b (fn (n acc) (c n (add acc 2))))
+(set c (fn (n acc) (if (eq n 0) acc (a (sub n 1) acc))))
+(a 1000000 0)
+"#;
+        assert_eq!(run(source), Ok(Expr::Integer(1500000)));
+        // Errors raised partway through still unwind.
+        let source = r#"
+(let pong ())
+(let ping (fn (n) (if (eq n 0) (car n) (pong (sub n 1)))))
+(set pong (fn (n) (ping n)))
+(ping 1000000)
+"#;
+        assert_eq!(run(source), Err("type-error".to_string()));
+    }
+
+    #[test]
+    fn tail_call_rebinds_escaped_params() {
+        // Each closure sees the value of n from the iteration that made
+        // it even though n is boxed because it is assigned to.
+        let source = r#"
+(let make (fn (n acc)
+  (if (eq n 0)
+      acc
+      (make (sub n 1) (cons (fn () (set n (add n 10)) n) acc)))))
+(let fs (make 3 ()))
+(cons ((car fs)) (cons ((car (cdr fs))) ((car (cdr (cdr fs))))))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![
+                Expr::Integer(11),
+                Expr::List(vec![Expr::Integer(12), Expr::Integer(13)])
+            ])
+        );
+    }
+
+    #[test]
+    fn non_tail_self_calls() {
+        let warnings = |source: &str| {
+            find_non_tail_self_calls(&parse_string(source).unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|w| (w.name, w.form))
+                .collect::<Vec<_>>()
+        };
+
+        let fact = "(let fact (fn (n) (if (eq n 0) 1 (mul n (fact (sub n 1))))))";
+        assert_eq!(warnings(fact), vec![("fact".to_string(), 0)]);
+        let fact =
+            "(let x 1) (let fact (fn (n acc) (if (eq n 0) acc (fact (sub n 1) (mul n acc)))))";
+        assert_eq!(warnings(fact), vec![]);
+
+        // Tail positions are found after desugaring and calls from a
+        // nested function aren't tail calls of the outer one.
+        assert_eq!(
+            warnings("(let f (fn (n) (cond ((eq n 0) 0) (else (f (sub n 1))))))"),
+            vec![]
+        );
+        assert_eq!(
+            warnings("(let f (fn (l) (f (car l)) ((fn (x) (f x)) l)))"),
+            vec![("f".to_string(), 0), ("f".to_string(), 0)]
+        );
+        // A local with the same name isn't the function.
+        assert_eq!(
+            warnings("(let f (fn (n) (let g (fn (f) (add1 (f n)))) (g add1)))"),
+            vec![]
+        );
+        assert_eq!(
+            warnings("(let g ()) (set g (fn (n) (if (eq n 0) 0 (add1 (g (sub n 1))))))"),
+            vec![("g".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! Helpers shared by the tests of every module.
+
+use crate::compiler::{compile_program, CompileOptions, JIT};
+use crate::fatal::LustError;
+use crate::{parse_string, roundtrip_string, Expr};
+
+/// Checks that SOURCE evaluates to the value of EXPECTED both when it
+/// is compiled and when it is interpreted.
+pub(crate) fn check(source: &str, expected: &str) {
+    let expected = roundtrip_string(expected).unwrap();
+    assert_eq!(roundtrip_string(source).unwrap(), expected);
+    assert_eq!(
+        crate::interpreter::interpret(&parse_string(source).unwrap()).unwrap(),
+        expected
+    );
+}
+
+/// Compiles SOURCE with a new JIT in embedded mode and runs it.
+pub(crate) fn run_embedded(source: &str) -> Result<Expr, LustError> {
+    let mut jit = JIT::new(CompileOptions {
+        embedded: true,
+        ..Default::default()
+    });
+    let mut program = parse_string(source).unwrap();
+    let id = compile_program(&mut jit, &mut program).unwrap();
+    jit.invoke(id).map(Expr::from_immediate)
+}
+
+/// Returns the type of the condition raised by running SOURCE in
+/// embedded mode.
+pub(crate) fn error_kind(source: &str) -> String {
+    run_embedded(source).unwrap_err().kind
+}
+
+/// The variable that holds the program for `run_cli` to run.
+const CLI_SOURCE: &str = "LUSTC_TEST_CLI_SOURCE";
+
+/// Runs SOURCE outside of embedded mode, where errors exit the
+/// process, by running the test binary again with only `cli_child`
+/// selected. Returns what the program printed and its exit code.
+pub(crate) fn run_cli(source: &str) -> (String, Option<i32>) {
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_util::cli_child", "--nocapture"])
+        .args(["--test-threads", "1", "--quiet"])
+        .env(CLI_SOURCE, source)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The test harness announces the test before the program runs.
+    let printed = stdout.split_once("running 1 test\n").unwrap().1;
+    (printed.to_string(), output.status.code())
+}
+
+/// Runs the program that `run_cli` was given if it started this
+/// process.
+#[test]
+fn cli_child() {
+    if let Ok(source) = std::env::var(CLI_SOURCE) {
+        let mut jit = JIT::default();
+        let mut program = parse_string(&source).unwrap();
+        let id = compile_program(&mut jit, &mut program).unwrap();
+        jit.invoke(id).unwrap();
+        // Exit the way the program would have so that nothing from
+        // the test harness follows its output.
+        crate::output::flush_output();
+        std::process::exit(0);
+    }
+}
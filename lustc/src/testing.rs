@@ -0,0 +1,126 @@
+//! Tests written in lisp. `(assert-equal expected actual)` compares
+//! EXPECTED and ACTUAL with equal, records whether the assertion
+//! passed, and returns true if it did. A failed assertion writes what
+//! was expected and what was there instead the way `write` writes
+//! them and the program carries on, so every assertion in a file
+//! reports rather than just the first one that fails.
+//!
+//! ```lisp
+//! (assert-equal (quote (1 2)) (list 1 2)) ; => true
+//! (assert-equal 3 (add 1 1))              ; writes "assertion failed: expected 3 but got 2"
+//! ```
+//!
+//! `lustc --test file` runs FILE and then writes how many of its
+//! assertions passed and failed, and exits with a nonzero status if
+//! any failed. The results are kept per thread, like captured output
+//! is, so programs running on other threads don't count towards
+//! each other's.
+
+use std::cell::Cell;
+
+use crate::serialize::write_string;
+use crate::{Expr, Word};
+
+thread_local! {
+    static RESULTS: Cell<TestResults> = const {
+        Cell::new(TestResults { passed: 0, failed: 0 })
+    };
+}
+
+/// How many of the assertions that have run passed and failed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TestResults {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+impl TestResults {
+    /// Returns the line `lustc --test` writes once a file has run.
+    pub fn summary(&self) -> String {
+        format!("{} passed, {} failed", self.passed, self.failed)
+    }
+}
+
+/// Returns the results of the assertions that have run on this thread
+/// since the last time they were taken.
+pub fn take_results() -> TestResults {
+    RESULTS.with(|r| r.replace(TestResults::default()))
+}
+
+/// Records an assertion that EXPECTED and ACTUAL are equal, which they
+/// are if EQUAL is set, and writes the failure if they aren't.
+pub(crate) fn record_assertion(expected: &Expr, actual: &Expr, equal: bool) {
+    RESULTS.with(|r| {
+        let mut results = r.get();
+        if equal {
+            results.passed += 1;
+        } else {
+            results.failed += 1;
+        }
+        r.set(results);
+    });
+    if !equal {
+        crate::output::write_output(&format!(
+            "assertion failed: expected {} but got {}\n",
+            write_string(expected),
+            write_string(actual)
+        ));
+    }
+}
+
+/// Implements (assert-equal expected actual).
+pub(crate) extern "C" fn lustc_assert_equal(expected: Word, actual: Word) -> Word {
+    let equal = crate::lists::words_equal(expected, actual);
+    record_assertion(
+        &Expr::from_immediate(expected),
+        &Expr::from_immediate(actual),
+        equal,
+    );
+    Expr::Bool(equal).immediate_rep()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roundtrip_file;
+
+    #[test]
+    fn failures_dont_stop_the_run() {
+        take_results();
+        let res = roundtrip_file("examples/assertions.lisp").unwrap();
+        // The program runs to the end after the failed assertion.
+        assert_eq!(res, Expr::Symbol("done".to_string()));
+        let results = take_results();
+        assert_eq!(
+            results,
+            TestResults {
+                passed: 1,
+                failed: 1
+            }
+        );
+        assert_eq!(results.summary(), "1 passed, 1 failed");
+        assert_eq!(take_results(), TestResults::default());
+    }
+
+    #[test]
+    fn failures_are_written() {
+        take_results();
+        let source = r#"
+(with-output-to-string
+  (assert-equal (quote (1 "a")) (list 1 "b"))
+  (assert-equal 1 1))
+"#;
+        let output = crate::roundtrip_string(source).unwrap();
+        assert_eq!(
+            crate::conversions::try_stringify_list(&output).unwrap(),
+            "assertion failed: expected (1 \"a\") but got (1 \"b\")\n"
+        );
+        assert_eq!(
+            take_results(),
+            TestResults {
+                passed: 1,
+                failed: 1
+            }
+        );
+    }
+}
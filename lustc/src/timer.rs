@@ -1,22 +1,190 @@
-use std::time::Instant;
+//! Timing of the compiler's passes and of `(time ...)` expressions.
+//!
+//! Each pass is timed by holding the timer returned by `timeit` while
+//! it runs. Timers started while another is held are nested under it
+//! and when times are shown they are printed indented by how deeply
+//! they are nested as each timer is dropped. Code generation is timed
+//! under "codegen" with a timer for each function named after the
+//! variable it is bound to.
+//!
+//! `start_report` and `take_report` collect the times measured on the
+//! current thread into a report rather than printing them.
 
-static mut SHOW_TIMES: bool = false;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use cranelift::prelude::Value;
+
+use crate::compiler::{emit_expr, Context};
+use crate::foreign::emit_host_call;
+use crate::Expr;
+use crate::Word;
+
+/// Set by `init` for the whole process. It is atomic so that timers
+/// can be dropped on any thread that is compiling.
+static SHOW_TIMES: AtomicBool = AtomicBool::new(false);
 
 pub fn init(show_times: bool) {
-    unsafe {
-        SHOW_TIMES = show_times;
-    }
+    SHOW_TIMES.store(show_times, Ordering::Relaxed);
+}
+
+/// A time in a timing report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimerEntry {
+    pub label: String,
+    /// The number of timers this one was nested under.
+    pub depth: usize,
+    pub elapsed: Duration,
+}
+
+thread_local! {
+    /// The number of timers currently held on this thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// The report being collected on this thread, if any. Entries
+    /// are added as their timers are dropped so nested timers come
+    /// before the timer they are nested under.
+    static REPORT: RefCell<Option<Vec<TimerEntry>>> = const { RefCell::new(None) };
+}
+
+/// Starts collecting the times measured on this thread into a report,
+/// discarding any report that was already being collected.
+pub fn start_report() {
+    REPORT.with(|r| *r.borrow_mut() = Some(Vec::new()));
+}
+
+/// Stops collecting the report started by `start_report` and returns
+/// it. Returns an empty report if none was started.
+pub fn take_report() -> Vec<TimerEntry> {
+    REPORT.with(|r| r.borrow_mut().take()).unwrap_or_default()
 }
 
 // source: https://github.com/matklad/hashbench/blob/master/src/main.rs#L39-L47
-pub fn timeit(label: &'static str) -> impl Drop {
-    struct Timer(&'static str, Instant);
+pub fn timeit(label: impl Into<Cow<'static, str>>) -> impl Drop {
+    struct Timer(Cow<'static, str>, usize, Instant);
     impl Drop for Timer {
         fn drop(&mut self) {
-            if unsafe { SHOW_TIMES } {
-                println!("{:<33} {:.2?}", self.0, self.1.elapsed());
+            let elapsed = self.2.elapsed();
+            DEPTH.with(|d| d.set(self.1));
+            if SHOW_TIMES.load(Ordering::Relaxed) {
+                let label = format!("{:indent$}{}", "", self.0, indent = 2 * self.1);
+                println!("{:<33} {:.2?}", label, elapsed);
             }
+            REPORT.with(|r| {
+                if let Some(report) = r.borrow_mut().as_mut() {
+                    report.push(TimerEntry {
+                        label: self.0.to_string(),
+                        depth: self.1,
+                        elapsed,
+                    })
+                }
+            });
+        }
+    }
+    let depth = DEPTH.with(|d| d.replace(d.get() + 1));
+    Timer(label.into(), depth, Instant::now())
+}
+
+impl Expr {
+    /// Determines if the expression is a time expression and if it is
+    /// returns the expression being timed.
+    pub(crate) fn is_time(&self) -> Option<&Expr> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "time" && v.len() == 2 {
+                    return Some(&v[1]);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The instant that times measured by `(time ...)` are relative
+/// to. Programs only ever see the difference between two of them.
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Writes the line that `(time ...)` reports how long its expression
+/// took with.
+pub(crate) fn write_time(elapsed: Duration, out: &mut impl Write) {
+    writeln!(out, "time: {:.2?}", elapsed).unwrap();
+}
+
+/// Called at the start of a time expression. Returns the number of
+/// nanoseconds since EPOCH which is handed back to `lustc_time_end`.
+pub(crate) extern "C" fn lustc_time_start() -> Word {
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as Word
+}
+
+/// Called at the end of a time expression with the value returned by
+/// `lustc_time_start`. Prints the time that has passed to stderr.
+pub(crate) extern "C" fn lustc_time_end(start: Word) -> Word {
+    let now = EPOCH.get_or_init(Instant::now).elapsed();
+    write_time(
+        now - Duration::from_nanos(start as u64),
+        &mut std::io::stderr(),
+    );
+    Expr::Nil.immediate_rep()
+}
+
+/// Emits the code for `(time EXPR)`. Only the evaluation of EXPR is
+/// timed as it has already been compiled by the time this code runs.
+pub(crate) fn emit_time(expr: &Expr, ctx: &mut Context) -> Result<Value, String> {
+    let start = emit_host_call("lustc_time_start", &[], ctx)?;
+    let res = emit_expr(expr, ctx)?;
+    emit_host_call("lustc_time_end", &[start], ctx)?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roundtrip_string;
+
+    #[test]
+    fn time() {
+        assert_eq!(
+            roundtrip_string("(time (+ 1 2))").unwrap(),
+            Expr::Integer(3)
+        );
+        let source = r#"
+(let count (fn (n) (if (eq n 0) 0 (add1 (count (sub n 1))))))
+(cons (time (count 1000)) (time (time 5)))
+"#;
+        assert_eq!(
+            roundtrip_string(source).unwrap(),
+            Expr::List(vec![Expr::Integer(1000), Expr::Integer(5)])
+        );
+        assert!(roundtrip_string("(let f time)").is_err());
+    }
+
+    #[test]
+    fn time_line() {
+        let mut out = Vec::new();
+        write_time(Duration::from_micros(1500), &mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "time: 1.50ms\n");
+
+        let start = lustc_time_start();
+        std::thread::sleep(Duration::from_millis(1));
+        let elapsed = lustc_time_start() - start;
+        assert!(elapsed >= 1_000_000);
+    }
+
+    #[test]
+    fn function_times() {
+        start_report();
+        roundtrip_string("(let f (fn (x) (add x 1))) (let g (fn (x) (f (f x)))) (g 1)").unwrap();
+        let report = take_report();
+        let codegen = report.iter().position(|e| e.label == "codegen").unwrap();
+        for name in ["f", "g"] {
+            let entry = report.iter().position(|e| e.label == name).unwrap();
+            // Nested timers are reported before the timer they are in.
+            assert!(entry < codegen);
+            assert!(report[entry].depth > report[codegen].depth);
         }
+        assert!(take_report().is_empty());
     }
-    Timer(label, Instant::now())
 }
@@ -69,7 +69,15 @@ pub(crate) struct CheckedTokenBuffer<'a, 'b> {
 impl<'a> TokenBuffer<'a> {
     /// Creates a new token buffer at the beginning of input.
     pub fn new(input: &'a str) -> Self {
-        let mut tokenizer = Tokenizer::new(input);
+        Self::from_tokenizer(Tokenizer::new(input))
+    }
+
+    /// Creates a new token buffer at the beginning of CHARS.
+    pub fn from_chars(chars: impl Iterator<Item = char> + 'a) -> Self {
+        Self::from_tokenizer(Tokenizer::from_chars(chars))
+    }
+
+    fn from_tokenizer(mut tokenizer: Tokenizer<'a>) -> Self {
         let peek = tokenizer.next_token();
         Self { tokenizer, peek }
     }
@@ -4,12 +4,12 @@ use crate::reader::{self, Reader};
 /// A token type. When paired with a location makes a token.
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
-    /// A number. Anything that matches the regex [0-9]+.[0-9]+.
+    /// A number. Anything that matches the regex -?[0-9]+.
     Number(i64),
     /// A string. Strings are made up of a sequence of non-newline
     /// characters that begin and end with '"'. The enclosed string
-    /// does not contain the opening and closing quotes. The \n and \t
-    /// escape sequences are supported.
+    /// does not contain the opening and closing quotes. The \n, \t, \"
+    /// and \\ escape sequences are supported.
     String(String),
     /// Opening parenthesis.
     Oparen,
@@ -17,12 +17,12 @@ pub enum TokenType {
     Cparen,
     /// A quote '
     Quote,
-    // A quaziquote `
-    Quaziquote,
-    // A comma
+    /// A quasiquote `
+    Quasiquote,
+    /// A comma
     Comma,
-    // A - sign
-    Negate,
+    /// A comma followed by an at sign ,@
+    CommaAt,
     /// An identifier. This is any sequence of characters not matched
     /// by the above rules.
     Id(String),
@@ -31,6 +31,9 @@ pub enum TokenType {
     /// and the TokenType is the type of token we were parsing when it
     /// failed.
     Unrecognized(String, Box<TokenType>),
+    /// A string that the input ends in the middle of. The enclosed
+    /// string is the text that was read, including the opening quote.
+    Unterminated(String),
 }
 
 /// A token that the tokenizer will emit.
@@ -57,6 +60,13 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// Makes a new tokenizer that starts at the beginning of CHARS.
+    pub(crate) fn from_chars(chars: impl Iterator<Item = char> + 'a) -> Self {
+        Self {
+            reader: Reader::from_chars(chars),
+        }
+    }
+
     /// Gets the current location of the tokenizer in the source
     /// string.
     pub(crate) fn loc(&self) -> Location {
@@ -82,10 +92,18 @@ impl<'a> Tokenizer<'a> {
                 '(' => self.eat_token_at_point(TokenType::Oparen),
                 ')' => self.eat_token_at_point(TokenType::Cparen),
                 '\'' => self.eat_token_at_point(TokenType::Quote),
-                '`' => self.eat_token_at_point(TokenType::Quaziquote),
-                ',' => self.eat_token_at_point(TokenType::Comma),
+                '`' => self.eat_token_at_point(TokenType::Quasiquote),
+                ',' => match self.reader.peek_2() {
+                    Some('@') => {
+                        let start = self.reader.loc();
+                        self.reader.next();
+                        self.reader.next();
+                        Token::new(start, self.reader.loc(), TokenType::CommaAt)
+                    }
+                    _ => self.eat_token_at_point(TokenType::Comma),
+                },
                 '-' => match self.reader.peek_2() {
-                    Some('0'..='9') => self.eat_token_at_point(TokenType::Negate),
+                    Some('0'..='9') => self.tokenize_number(),
                     _ => self.eat_token_at_point(TokenType::Id("-".to_string())),
                 },
                 '"' => self.tokenize_string(),
@@ -112,6 +130,10 @@ impl<'a> Tokenizer<'a> {
     fn tokenize_number(&mut self) -> Token {
         let start = self.reader.loc();
         let mut res = String::new();
+        if self.reader.peek() == Some(&'-') {
+            res.push('-');
+            self.reader.next();
+        }
         // Advance until we hit something that would break a number
         loop {
             if let Some(c) = self.reader.peek() {
@@ -164,13 +186,15 @@ impl<'a> Tokenizer<'a> {
         Token::new(start, self.reader.loc(), TokenType::Id(res))
     }
 
-    /// Tokenizes a string. If the string has an invalid excape or
-    /// does not close itself before a newline returns an unrecognized
-    /// tokeh with the amount of the input that it consumed.
+    /// Tokenizes a string. If the string has an invalid excape returns
+    /// an unrecognized token and if the input ends before the string
+    /// does returns an unterminated one. Either way the token has the
+    /// amount of the input that it consumed.
     fn tokenize_string(&mut self) -> Token {
         let start = self.reader.loc();
         let mut res = String::new();
         let mut valid = true;
+        let mut terminated = true;
         // Eat opening quote.
         self.reader.next();
         loop {
@@ -181,13 +205,14 @@ impl<'a> Tokenizer<'a> {
                             'n' => res.push('\n'),
                             't' => res.push('\t'),
                             '"' => res.push('"'),
+                            '\\' => res.push('\\'),
                             c => {
                                 valid = false;
                                 res.push_str(&format!("\\{}", c).to_string());
                             }
                         },
                         None => {
-                            valid = false;
+                            terminated = false;
                             break;
                         }
                     },
@@ -195,12 +220,18 @@ impl<'a> Tokenizer<'a> {
                     c => res.push(c),
                 },
                 None => {
-                    valid = false;
+                    terminated = false;
                     break;
                 }
             }
         }
-        if valid {
+        if !terminated {
+            Token::new(
+                start,
+                self.reader.loc(),
+                TokenType::Unterminated(format!("\"{}", res)),
+            )
+        } else if valid {
             Token::new(start, self.reader.loc(), TokenType::String(res))
         } else {
             Token::new(
@@ -0,0 +1,175 @@
+//! Finds top level definitions that a program never uses. A
+//! definition is used if it can be reached from one of the program's
+//! top level expressions, or from a definition named main, by
+//! following the names that each definition refers to. Definitions
+//! that only refer to each other are unused unless something
+//! reachable refers to one of them. A top level set of a defined name
+//! is treated as part of that name's definition so that functions made
+//! mutually recursive with set are handled the same way. The
+//! definitions that `define-record` makes are never reported since
+//! the program didn't write them. `lustc --warn-unused` prints the
+//! rest.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::renamer::{make_names_unique, original_name};
+use crate::Expr;
+use crate::PreorderStatus;
+
+/// A top level definition that can't be reached from the program's
+/// entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnusedDefinition {
+    /// The name of the definition as it appears in the source.
+    pub name: String,
+    /// The index of the top level form that makes the definition.
+    pub form: usize,
+}
+
+impl std::fmt::Display for UnusedDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "warning: ({}) is defined but never used", self.name)
+    }
+}
+
+/// Collects every symbol that appears in E outside of a quote.
+fn collect_references(e: &Expr, references: &mut Vec<String>) {
+    e.preorder_traverse(&mut |e: &Expr| {
+        if e.is_quote().is_some() {
+            return PreorderStatus::Skip;
+        }
+        if let Expr::Symbol(s) = e {
+            references.push(s.clone());
+        }
+        PreorderStatus::Continue
+    });
+}
+
+/// Returns the top level definitions in PROGRAM that are never
+/// used. This is only a warning so the program is left alone and may
+/// still be compiled.
+pub fn find_unused_definitions(program: &[Expr]) -> Result<Vec<UnusedDefinition>, String> {
+    let generated: HashSet<usize> = program
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| {
+            e.is_let()
+                .is_some_and(|(_, value)| crate::records::is_record_definition(value))
+        })
+        .map(|(form, _)| form)
+        .collect();
+    // Rename a copy so that a local that shares its name with a top
+    // level definition doesn't count as a use of it.
+    let mut program = program.to_vec();
+    crate::desugar::desugar(&mut program)?;
+    make_names_unique(&mut program)?;
+
+    let mut definitions = Vec::new();
+    let mut references = HashMap::new();
+    let mut worklist = Vec::new();
+
+    for (form, e) in program.iter().enumerate() {
+        match e.is_let() {
+            Some((name, value)) => {
+                let mut refs = Vec::new();
+                collect_references(value, &mut refs);
+                references.insert(name.clone(), refs);
+                definitions.push((name.clone(), form));
+                if original_name(name) == "main" {
+                    worklist.push(name.clone());
+                }
+            }
+            None => match e.is_set() {
+                Some((name, value)) if references.contains_key(name) => {
+                    let refs = references.get_mut(name).unwrap();
+                    collect_references(value, refs);
+                }
+                _ => collect_references(e, &mut worklist),
+            },
+        }
+    }
+
+    let mut reachable = HashSet::new();
+    while let Some(name) = worklist.pop() {
+        if reachable.insert(name.clone()) {
+            if let Some(refs) = references.get(&name) {
+                worklist.extend(refs.iter().cloned());
+            }
+        }
+    }
+
+    Ok(definitions
+        .into_iter()
+        .filter(|(name, form)| !reachable.contains(name) && !generated.contains(form))
+        .map(|(name, form)| UnusedDefinition {
+            name: original_name(&name).to_string(),
+            form,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_string;
+
+    fn unused_names(source: &str) -> Vec<String> {
+        let program = parse_string(source).unwrap();
+        find_unused_definitions(&program)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.name)
+            .collect()
+    }
+
+    #[test]
+    fn unused_helper() {
+        let source = r#"
+(let used (fn (n) (add1 n)))
+(let unused (fn (n) (sub n 1)))
+(used 1)
+"#;
+        let program = parse_string(source).unwrap();
+        assert_eq!(
+            find_unused_definitions(&program).unwrap(),
+            vec![UnusedDefinition {
+                name: "unused".to_string(),
+                form: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn unreachable_cycle() {
+        let source = r#"
+(let odd ())
+(let even (fn (n) (if (eq n 0) (eq 0 0) (odd (sub n 1)))))
+(set odd (fn (n) (if (eq n 0) (eq 0 1) (even (sub n 1)))))
+(let helper (fn () 1))
+(let main (fn () (helper)))
+"#;
+        assert_eq!(unused_names(source), vec!["odd", "even"]);
+    }
+
+    #[test]
+    fn transitive_and_shadowed_uses() {
+        let source = r#"
+(let a 1)
+(let b (fn () a))
+(let c 2)
+(let d (fn (c) c))
+(d (b))
+"#;
+        assert_eq!(unused_names(source), vec!["c"]);
+    }
+
+    #[test]
+    fn record_definitions() {
+        let source = r#"
+(define-record point (x y))
+(let origin (fn () (point-x 1)))
+(let point-z (fn (v) (record-ref v (quote point) 2)))
+"#;
+        assert_eq!(unused_names(source), vec!["origin", "point-z"]);
+    }
+}
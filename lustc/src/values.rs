@@ -0,0 +1,462 @@
+//! Multiple return values. `(values a b c)` packages its arguments
+//! into a tuple and `(call-with-values producer consumer)` calls
+//! PRODUCER with no arguments and then calls CONSUMER with the values
+//! it returned as its arguments.
+//!
+//! ```lisp
+//! (call-with-values (fn () (values 7 2)) (fn (q r) (add q r))) ; => 9
+//! ```
+//!
+//! A tuple lives on the heap and is tagged with VALUES_TAG. Its first
+//! word is the number of values and the values follow, which is the
+//! same layout that functions expect their arguments in, so
+//! call-with-values can hand a tuple to its consumer without copying
+//! it. `(values x)` is just x and a producer that returns something
+//! other than a tuple is treated as having returned one value.
+//! Conditions and the other objects that share the tuple tag (see
+//! `conversions.rs`) are single values too. When a tuple is returned
+//! to the host it is seen as its first value.
+//!
+//! Values only pass through tail positions. Anywhere else that a
+//! value is used, as an argument, an operand to a primitive, the
+//! condition of an if, or the binding of a let or set, only the first
+//! one is seen and no values is seen as nil.
+//!
+//! ```lisp
+//! (add (values 1 2) 3) ; => 4
+//! ```
+//!
+//! `take_first_values` wraps every expression outside of tail position
+//! that could return a tuple (a call, a values with other than one
+//! argument, or a call-with-values) with `FIRST_VALUE` which takes the
+//! tuple apart when it runs.
+//!
+//! `(let-values (((name...) e)...) body...)` binds each list of names
+//! to the values of its E in BODY. The lists of names are like a
+//! function's parameters, so `(a & rest)` binds the values after the
+//! first as a list, and the number of values has to match the names or
+//! an arity error is raised the same way it is for a consumer. Every E
+//! is evaluated in order before any name is bound so an E can't see
+//! the names of the clauses before it.
+//!
+//! ```lisp
+//! (let-values (((q r) (divmod 17 5))) (cons q r)) ; => (3 . 2)
+//! ```
+//!
+//! let-values is desugared into a call-with-values for each clause
+//! whose consumer takes the values as temporaries and a function that
+//! binds the names to them once they've all been made.
+//!
+//! ```lisp
+//! (let-values (((a b) e1) ((c) e2)) body...) => (call-with-values (fn () e1) (fn (t0 t1)
+//!                                                  (call-with-values (fn () e2) (fn (t2)
+//!                                                    ((fn (a b c) body...) t0 t1 t2)))))
+//! ```
+
+use cranelift::prelude::*;
+
+use crate::compiler::{emit_expr, Context};
+use crate::conversions::VALUES_TAG;
+use crate::desugar::temporary;
+use crate::exceptions::closure;
+use crate::fatal::emit_check_callable;
+use crate::heap::emit_alloc;
+use crate::primitives::string_is_builtin;
+use crate::procedures::emit_closure_call;
+use crate::Expr;
+
+/// The head of an expression whose first value is used.
+const FIRST_VALUE: &str = "__anon_first_value";
+
+impl Expr {
+    /// If the expression is a values expression and returns the
+    /// values.
+    pub(crate) fn is_values(&self) -> Option<&[Expr]> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "values" {
+                    return Some(&v[1..]);
+                }
+            }
+        }
+        None
+    }
+
+    /// If the expression is a call-with-values expression returns its
+    /// producer and consumer.
+    pub(crate) fn is_call_with_values(&self) -> Option<(&Expr, &Expr)> {
+        if let Expr::List(v) = self {
+            if let Some(Expr::Symbol(s)) = v.first() {
+                if s == "call-with-values" && v.len() == 3 {
+                    return Some((&v[1], &v[2]));
+                }
+            }
+        }
+        None
+    }
+
+    /// If the expression only has its first value used returns the
+    /// expression whose value it is.
+    pub(crate) fn is_first_value(&self) -> Option<&Expr> {
+        if let Expr::List(v) = self {
+            if let [Expr::Symbol(s), e] = v.as_slice() {
+                if s == FIRST_VALUE {
+                    return Some(e);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Desugars `(let-values (((NAME...) E)...) BODY...)`. ARGS are the
+/// arguments to let-values.
+pub(crate) fn desugar_let_values(args: &[Expr], count: &mut usize) -> Result<Expr, String> {
+    let (clauses, body) = match args.split_first() {
+        Some((Expr::List(clauses), body)) if !body.is_empty() => (clauses.as_slice(), body),
+        Some((Expr::Nil, body)) if !body.is_empty() => (&[][..], body),
+        _ => return Err("let-values expects a list of clauses and a body".to_string()),
+    };
+    let mut producers = vec![];
+    let mut names = vec![];
+    let mut temporaries = vec![];
+    for clause in clauses {
+        let (formals, e) = match clause {
+            Expr::List(c) if c.len() == 2 => match &c[0] {
+                Expr::List(formals) => (formals.as_slice(), &c[1]),
+                Expr::Nil => (&[][..], &c[1]),
+                _ => return Err(bad_clause(clause)),
+            },
+            _ => return Err(bad_clause(clause)),
+        };
+        let mut params = vec![];
+        for formal in formals {
+            match formal {
+                Expr::Symbol(s) if s == "&" => params.push(formal.clone()),
+                Expr::Symbol(_) => {
+                    let t = temporary(count);
+                    names.push(formal.clone());
+                    temporaries.push(t.clone());
+                    params.push(t);
+                }
+                _ => return Err(bad_clause(clause)),
+            }
+        }
+        producers.push((e, params));
+    }
+    let bind = |params: Vec<Expr>| {
+        if params.is_empty() {
+            Expr::Nil
+        } else {
+            Expr::List(params)
+        }
+    };
+    let mut res = Expr::List(
+        std::iter::once(closure(bind(names), body))
+            .chain(temporaries)
+            .collect(),
+    );
+    for (e, params) in producers.into_iter().rev() {
+        res = Expr::List(vec![
+            Expr::Symbol("call-with-values".to_string()),
+            closure(Expr::Nil, std::slice::from_ref(e)),
+            closure(bind(params), &[res]),
+        ]);
+    }
+    Ok(res)
+}
+
+fn bad_clause(clause: &Expr) -> String {
+    format!(
+        "let-values expects a clause like ((name...) expression) and got ({:?})",
+        clause
+    )
+}
+
+/// Emits the code for `(values VALS...)`.
+pub(crate) fn emit_values(vals: &[Expr], ctx: &mut Context) -> Result<Value, String> {
+    if vals.len() == 1 {
+        return emit_expr(&vals[0], ctx);
+    }
+    let word_size = ctx.word.bytes() as usize;
+    let tuple = emit_alloc(((vals.len() + 1) * word_size) as i64, ctx)?;
+    let count = ctx.builder.ins().iconst(ctx.word, vals.len() as i64);
+    ctx.builder.ins().store(MemFlags::new(), count, tuple, 0);
+    for (i, e) in vals.iter().enumerate() {
+        let val = emit_expr(e, ctx)?;
+        ctx.builder
+            .ins()
+            .store(MemFlags::new(), val, tuple, ((i + 1) * word_size) as i32);
+    }
+    Ok(ctx.builder.ins().bor_imm(tuple, VALUES_TAG))
+}
+
+/// Emits the code for `(call-with-values PRODUCER CONSUMER)`.
+pub(crate) fn emit_call_with_values(
+    producer: &Expr,
+    consumer: &Expr,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let producer = emit_check_callable(producer, ctx)?;
+    let consumer = emit_check_callable(consumer, ctx)?;
+
+    let no_args = emit_alloc(0, ctx)?;
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    let res = emit_closure_call(producer, zero, no_args, ctx)?;
+
+    let tuple_block = ctx.builder.create_block();
+    let single_block = ctx.builder.create_block();
+    let call_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(call_block, ctx.word);
+    ctx.builder.append_block_param(call_block, ctx.word);
+
+    let is_tuple = crate::foreign::emit_is(res, VALUES_TAG, crate::conversions::HEAP_TAG_MASK, ctx);
+    ctx.builder.ins().brz(is_tuple, single_block, &[]);
+    ctx.builder.ins().jump(tuple_block, &[]);
+
+    // A tuple is already laid out like a list of arguments. The
+    // objects that share the tuple tag have a negative length.
+    ctx.builder.switch_to_block(tuple_block);
+    ctx.builder.seal_block(tuple_block);
+    let tuple = ctx
+        .builder
+        .ins()
+        .band_imm(res, crate::conversions::HEAP_PTR_MASK);
+    let count = ctx.builder.ins().load(ctx.word, MemFlags::new(), tuple, 0);
+    let argloc = ctx.builder.ins().iadd_imm(tuple, ctx.word.bytes() as i64);
+    let is_object = ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, count, 0);
+    ctx.builder.ins().brnz(is_object, single_block, &[]);
+    ctx.builder.ins().jump(call_block, &[count, argloc]);
+
+    ctx.builder.switch_to_block(single_block);
+    ctx.builder.seal_block(single_block);
+    let argloc = emit_alloc(ctx.word.bytes().into(), ctx)?;
+    ctx.builder.ins().store(MemFlags::new(), res, argloc, 0);
+    let one = ctx.builder.ins().iconst(ctx.word, 1);
+    ctx.builder.ins().jump(call_block, &[one, argloc]);
+
+    ctx.builder.switch_to_block(call_block);
+    ctx.builder.seal_block(call_block);
+    let count = ctx.builder.block_params(call_block)[0];
+    let argloc = ctx.builder.block_params(call_block)[1];
+    emit_closure_call(consumer, count, argloc, ctx)
+}
+
+/// Returns true if E could return more or less than one value.
+fn may_return_values(e: &Expr) -> bool {
+    if let Some(vals) = e.is_values() {
+        return vals.len() != 1;
+    }
+    if e.is_call_with_values().is_some() || e.is_trampolined_call().is_some() {
+        return true;
+    }
+    if e.is_self_tail_call().is_some() || e.is_bounce().is_some() {
+        return false;
+    }
+    match e {
+        Expr::List(v) => match v.first() {
+            Some(Expr::Symbol(s)) => s == "apply" || !string_is_builtin(s),
+            Some(Expr::List(_)) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Wraps every expression in E that could return a tuple and isn't in
+/// tail position with FIRST_VALUE. TAIL is true if E is in tail
+/// position.
+fn wrap_first_values(e: &mut Expr, tail: bool) {
+    if e.is_quote().is_some() {
+        return;
+    }
+    let is_fndef = e.is_fndef().is_some();
+    let is_conditional = e.is_conditional().is_some();
+    let is_coverage_count = e.is_coverage_count().is_some();
+    let wrap = !tail && may_return_values(e);
+    if let Expr::List(v) = e {
+        if is_fndef {
+            let last = v.len() - 1;
+            for (i, e) in v.iter_mut().enumerate().skip(2) {
+                wrap_first_values(e, i == last);
+            }
+        } else if tail && is_conditional {
+            wrap_first_values(&mut v[1], false);
+            wrap_first_values(&mut v[2], true);
+            wrap_first_values(&mut v[3], true);
+        } else if tail && is_coverage_count {
+            wrap_first_values(&mut v[2], true);
+        } else {
+            for e in v.iter_mut() {
+                wrap_first_values(e, false);
+            }
+        }
+    }
+    if wrap {
+        let inner = std::mem::replace(e, Expr::Nil);
+        *e = Expr::List(vec![Expr::Symbol(FIRST_VALUE.to_string()), inner]);
+    }
+}
+
+/// Marks the expressions in PROGRAM whose first value is all that is
+/// used. Expressions at the top level aren't in a function so they
+/// are never in tail position.
+pub(crate) fn take_first_values(program: &mut [Expr]) {
+    for e in program {
+        wrap_first_values(e, false);
+    }
+}
+
+/// Emits the code for E when only its first value is used.
+pub(crate) fn emit_first_value(e: &Expr, ctx: &mut Context) -> Result<Value, String> {
+    let val = emit_expr(e, ctx)?;
+
+    let tuple_block = ctx.builder.create_block();
+    let count_block = ctx.builder.create_block();
+    let some_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let is_tuple = crate::foreign::emit_is(val, VALUES_TAG, crate::conversions::HEAP_TAG_MASK, ctx);
+    ctx.builder.ins().brz(is_tuple, done_block, &[val]);
+    ctx.builder.ins().jump(tuple_block, &[]);
+
+    // The objects that share the tuple tag have a negative length and
+    // are left as they are.
+    ctx.builder.switch_to_block(tuple_block);
+    ctx.builder.seal_block(tuple_block);
+    let tuple = ctx
+        .builder
+        .ins()
+        .band_imm(val, crate::conversions::HEAP_PTR_MASK);
+    let count = ctx.builder.ins().load(ctx.word, MemFlags::new(), tuple, 0);
+    let is_object = ctx.builder.ins().icmp_imm(IntCC::SignedLessThan, count, 0);
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+    ctx.builder.ins().brnz(is_object, done_block, &[val]);
+    ctx.builder.ins().jump(count_block, &[]);
+
+    ctx.builder.switch_to_block(count_block);
+    ctx.builder.seal_block(count_block);
+    ctx.builder.ins().brz(count, done_block, &[nil]);
+    ctx.builder.ins().jump(some_block, &[]);
+
+    ctx.builder.switch_to_block(some_block);
+    ctx.builder.seal_block(some_block);
+    let first = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), tuple, ctx.word.bytes() as i32);
+    ctx.builder.ins().jump(done_block, &[first]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind, run_embedded};
+    use crate::{parse_string, roundtrip_string};
+
+    #[test]
+    fn divmod() {
+        let source = r#"
+(let divmod (fn (n d) (values (div n d) (rem n d))))
+(call-with-values (fn () (divmod 17 5)) (fn (q r) (cons q r)))
+"#;
+        check(source, "(cons 3 2)");
+    }
+
+    #[test]
+    fn value_counts() {
+        check("(call-with-values (fn () (values)) (fn () 1))", "1");
+        check(
+            "(call-with-values (fn () (values 2)) (fn (x) (add1 x)))",
+            "3",
+        );
+        check("(call-with-values (fn () 2) (fn (x) (add1 x)))", "3");
+        check(
+            "(call-with-values (fn () (values 1 2 3 4)) (fn (a & rest) (cons a rest)))",
+            "(quote (1 2 3 4))",
+        );
+        // Outside of call-with-values only the first value is seen.
+        check("(values 1 2)", "1");
+    }
+
+    #[test]
+    fn first_value() {
+        check("(add (values 1 2) 3)", "4");
+        check("(let f (fn () (values 1 2))) (add (f) 3)", "4");
+        check(
+            "(let f (fn (n) (if (eq n 0) (values 1 2) (f (sub n 1))))) (mul (f 3) 5)",
+            "5",
+        );
+        check("(let x (values 7 8)) (sub x 1)", "6");
+        check("(if (values (eq 1 1) 2) 1 2)", "1");
+        check(
+            "(let f (fn (a b) (add a b))) (f (values 1 2) (values 3))",
+            "4",
+        );
+        check("(null? (values))", "(eq 1 1)");
+        // In tail position the values are passed along.
+        check(
+            "(let f (fn () (values 1 2))) (call-with-values (fn () (if (eq 1 1) (f) 0)) (fn (a b) (add a b)))",
+            "3",
+        );
+    }
+
+    #[test]
+    fn consumer_arity() {
+        let source = "(call-with-values (fn () (values 1 2)) (fn (a b c) a))";
+        assert_eq!(
+            run_embedded(source).unwrap_err().message,
+            "fatal error: wrong number of arguments in function call"
+        );
+        assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+    }
+
+    #[test]
+    fn let_values() {
+        let divmod = "(let divmod (fn (n d) (values (div n d) (rem n d))))";
+        check(
+            &format!(
+                "{} (let-values (((q r) (divmod 17 5))) (add (mul q 10) r))",
+                divmod
+            ),
+            "32",
+        );
+        // Nested let-values, clauses of other shapes, and names that
+        // the values of later clauses can't see.
+        let source = format!(
+            r#"
+{}
+(let q 100)
+(let-values (((q r) (divmod 17 5))
+             ((x) (add q 1))
+             (() (values))
+             ((a & rest) (values 1 2 3)))
+  (let-values (((q2 r2) (divmod q r)))
+    (list q r x a rest q2 r2)))
+"#,
+            divmod
+        );
+        check(&source, "(quote (3 2 101 1 (2 3) 1 1))");
+    }
+
+    #[test]
+    fn let_values_errors() {
+        let source = "(let-values (((a b c) (values 1 2))) a)";
+        assert_eq!(error_kind(source), "arity-error");
+        assert!(crate::interpreter::interpret(&parse_string(source).unwrap()).is_err());
+        assert!(roundtrip_string("(let-values (((a) 1 2)) a)")
+            .unwrap_err()
+            .starts_with("let-values expects a clause like ((name...) expression)"));
+        assert_eq!(
+            roundtrip_string("(let-values (((a) 1)))"),
+            Err("let-values expects a list of clauses and a body".to_string())
+        );
+    }
+}
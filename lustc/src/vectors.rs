@@ -0,0 +1,934 @@
+//! Vectors are fixed length sequences of values that can be indexed
+//! in constant time.
+//!
+//! ```lisp
+//! (let v (list->vector (quote (1 2 3))))
+//! (vector-ref v 1) ; => 2
+//! (vector->list v) ; => (1 2 3)
+//! ```
+//!
+//! `(list->vector list)` and `(vector->list vector)` convert between
+//! vectors and lists, both making new storage for the result,
+//! `(vector-length vector)` returns the number of elements, and
+//! `(vector? x)` determines if x is a vector. Indexing outside of a
+//! vector raises a `range-error`.
+//!
+//! `(make-vector n x)` makes a vector of N elements that are all X,
+//! and `(make-vector n)` one whose elements are all nil. Every element
+//! is the same X, not a copy of it, so changing a list or vector that
+//! X is shows up in all of them. `(vector-set! vector i x)` sets
+//! element I of VECTOR to X and returns VECTOR.
+//!
+//! ```lisp
+//! (let v (make-vector 2 0))
+//! (vector->list (vector-set! v 1 5)) ; => (0 5)
+//! ```
+//!
+//! `(vector-map f vector)` makes a new vector of the results of
+//! calling F on each element, `(vector-copy vector)` makes a new
+//! vector with the same elements, and `(vector-fill! vector x)` sets
+//! every element of VECTOR to X and returns VECTOR. Vectors are sorted
+//! in place by `vector-sort!`, see `sort.rs`.
+//!
+//! `(vector-for-each f vector)` calls F on each element of VECTOR from
+//! the first to the last for its side effects and returns nil, and
+//! `(vector-for-each-indexed f vector)` does the same but calls F with
+//! the index of each element as well as the element.
+//!
+//! ```lisp
+//! (vector-for-each-indexed (fn (i x) (println (cons i x))) v) ; => ()
+//! ```
+//!
+//! A vector lives on the heap and is tagged with VECTOR_TAG. Its
+//! first word is the number of elements, which is not a fixnum, and
+//! the elements follow. When a vector is returned to the host it is
+//! seen as a list of its elements.
+//!
+//! `(vector-slice vector start end)` returns a slice of the elements
+//! of VECTOR from START up to but not including END without copying
+//! them. A slice is a vector, so everything that works on vectors
+//! works on slices, but it shares its elements with the vector it was
+//! made from and changing one changes the other. Indexing a slice is
+//! checked against the slice's length and not the vector's. A range
+//! error is raised unless `0 <= start <= end <= (vector-length
+//! vector)`.
+//!
+//! ```lisp
+//! (let s (vector-slice (list->vector (quote (1 2 3 4))) 1 3))
+//! (vector->list s) ; => (2 3)
+//! ```
+//!
+//! A slice is three words: VECTOR_SLICE_HEADER, a pointer that is
+//! used as if it were a vector's storage, which is the storage of the
+//! sliced vector moved along by START elements, and the slice's
+//! length. Slicing a slice makes a slice of the original vector.
+//! Nothing on the heap is ever freed so the vector a slice was made
+//! from lives as long as it does.
+
+use cranelift::prelude::*;
+
+use crate::compiler::Context;
+use crate::conversions::{
+    FIXNUM_SHIFT, HEAP_PTR_MASK, HEAP_TAG_MASK, PACKED_LIST_MASK, PACKED_LIST_TAG, PAIR_TAG,
+    VECTOR_SLICE_HEADER, VECTOR_TAG,
+};
+use crate::fatal;
+use crate::foreign::emit_is;
+use crate::heap::{emit_alloc, emit_alloc_value};
+use crate::primitives::emit_word_to_bool;
+use crate::Expr;
+
+/// The names of the vector primitives.
+pub(crate) const VECTOR_PRIMITIVES: [&str; 14] = [
+    "vector?",
+    "vector-length",
+    "vector-ref",
+    "make-vector",
+    "vector-set!",
+    "list->vector",
+    "vector->list",
+    "vector-map",
+    "vector-for-each",
+    "vector-for-each-indexed",
+    "vector-copy",
+    "vector-fill!",
+    "vector-slice",
+    "vector-sort!",
+];
+
+/// Returns the number of arguments that the vector primitive NAME
+/// takes. make-vector takes one or two and this is two.
+pub(crate) fn vector_primitive_arity(name: &str) -> usize {
+    match name {
+        "vector-ref"
+        | "make-vector"
+        | "vector-map"
+        | "vector-for-each"
+        | "vector-for-each-indexed"
+        | "vector-fill!"
+        | "vector-sort!" => 2,
+        "vector-slice" | "vector-set!" => 3,
+        _ => 1,
+    }
+}
+
+/// Emits the code for the vector primitive NAME applied to ARGS which
+/// have already been evaluated.
+pub(crate) fn emit_vector_primitive(
+    name: &str,
+    args: &[Value],
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    match name {
+        "vector?" => {
+            let is_vector = emit_is(args[0], VECTOR_TAG, HEAP_TAG_MASK, ctx);
+            let is_vector = ctx.builder.ins().bint(ctx.word, is_vector);
+            Ok(emit_word_to_bool(is_vector, &mut ctx.builder))
+        }
+        "vector-length" => {
+            let (_, length) = emit_vector_parts(args[0], ctx)?;
+            Ok(ctx.builder.ins().ishl_imm(length, FIXNUM_SHIFT))
+        }
+        "vector-ref" => emit_vector_ref(args[0], args[1], ctx),
+        "make-vector" => emit_make_vector(args[0], args[1], ctx),
+        "vector-set!" => emit_vector_set(args[0], args[1], args[2], ctx),
+        "list->vector" => emit_list_to_vector(args[0], ctx),
+        "vector->list" => emit_vector_to_list(args[0], ctx),
+        "vector-map" => emit_vector_map(args[0], args[1], ctx),
+        "vector-for-each" => emit_vector_for_each(args[0], args[1], false, ctx),
+        "vector-for-each-indexed" => emit_vector_for_each(args[0], args[1], true, ctx),
+        "vector-copy" => emit_vector_copy(args[0], ctx),
+        "vector-fill!" => emit_vector_fill(args[0], args[1], ctx),
+        "vector-slice" => emit_vector_slice(args[0], args[1], args[2], ctx),
+        "vector-sort!" => crate::sort::emit_vector_sort(args[0], args[1], ctx),
+        _ => panic!("non vector primitive in emit_vector_primitive: {}", name),
+    }
+}
+
+/// Emits the code to check that VECTOR is a vector. Returns a pointer
+/// to its storage and its length. For a slice these are the pointer
+/// and length stored in it.
+pub(crate) fn emit_vector_parts(
+    vector: Value,
+    ctx: &mut Context,
+) -> Result<(Value, Value), String> {
+    fatal::emit_check_tag(vector, VECTOR_TAG, HEAP_TAG_MASK, ctx)?;
+    let ptr = ctx.builder.ins().band_imm(vector, HEAP_PTR_MASK);
+    let header = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+
+    let slice_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let is_slice = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, header, VECTOR_SLICE_HEADER);
+    ctx.builder.ins().brnz(is_slice, slice_block, &[]);
+    ctx.builder.ins().jump(done_block, &[ptr, header]);
+
+    ctx.builder.switch_to_block(slice_block);
+    ctx.builder.seal_block(slice_block);
+    let word_size = ctx.word.bytes() as i32;
+    let storage = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, word_size);
+    let length = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, 2 * word_size);
+    ctx.builder.ins().jump(done_block, &[storage, length]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let params = ctx.builder.block_params(done_block);
+    Ok((params[0], params[1]))
+}
+
+/// Emits the code to compute the address of element INDEX, an
+/// untagged index, of the vector whose storage starts at PTR.
+pub(crate) fn emit_element_address(ptr: Value, index: Value, ctx: &mut Context) -> Value {
+    let word_size = ctx.word.bytes() as i64;
+    let offset = ctx.builder.ins().imul_imm(index, word_size);
+    let address = ctx.builder.ins().iadd(ptr, offset);
+    // Skip the length.
+    ctx.builder.ins().iadd_imm(address, word_size)
+}
+
+fn emit_vector_ref(vector: Value, index: Value, ctx: &mut Context) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    fatal::emit_check_int(index, ctx)?;
+    fatal::emit_check_index(index, length, ctx)?;
+    let index = ctx.builder.ins().sshr_imm(index, FIXNUM_SHIFT);
+    let address = emit_element_address(ptr, index, ctx);
+    Ok(ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), address, 0))
+}
+
+/// Emits the code for `(vector-set! VECTOR INDEX X)`.
+fn emit_vector_set(
+    vector: Value,
+    index: Value,
+    x: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    fatal::emit_check_int(index, ctx)?;
+    fatal::emit_check_index(index, length, ctx)?;
+    let index = ctx.builder.ins().sshr_imm(index, FIXNUM_SHIFT);
+    let address = emit_element_address(ptr, index, ctx);
+    ctx.builder.ins().store(MemFlags::new(), x, address, 0);
+    Ok(vector)
+}
+
+/// Emits the code for `(make-vector LENGTH FILL)`. A negative LENGTH
+/// raises a range error.
+pub(crate) fn emit_make_vector(
+    length: Value,
+    fill: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_int(length, ctx)?;
+    let length = ctx.builder.ins().sshr_imm(length, FIXNUM_SHIFT);
+    let in_range = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::SignedGreaterThanOrEqual, length, 0);
+    fatal::emit_check_range(in_range, ctx)?;
+    let storage = emit_alloc_vector(length, ctx)?;
+    emit_index_loop(length, ctx, |i, ctx| {
+        let address = emit_element_address(storage, i, ctx);
+        ctx.builder.ins().store(MemFlags::new(), fill, address, 0);
+        Ok(())
+    })?;
+    Ok(ctx.builder.ins().bor_imm(storage, VECTOR_TAG))
+}
+
+fn emit_vector_slice(
+    vector: Value,
+    start: Value,
+    end: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    fatal::emit_check_int(start, ctx)?;
+    fatal::emit_check_int(end, ctx)?;
+    let start = ctx.builder.ins().sshr_imm(start, FIXNUM_SHIFT);
+    let end = ctx.builder.ins().sshr_imm(end, FIXNUM_SHIFT);
+
+    // Negative bounds are huge when compared unsigned so a negative END
+    // is past LENGTH and a negative START is past END.
+    let fits = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::UnsignedLessThanOrEqual, end, length);
+    let ordered = ctx
+        .builder
+        .ins()
+        .icmp(IntCC::UnsignedLessThanOrEqual, start, end);
+    let in_range = ctx.builder.ins().band(fits, ordered);
+    fatal::emit_check_range(in_range, ctx)?;
+
+    let word_size = ctx.word.bytes() as i32;
+    let slice = emit_alloc((3 * word_size).into(), ctx)?;
+    let header = ctx.builder.ins().iconst(ctx.word, VECTOR_SLICE_HEADER);
+    ctx.builder.ins().store(MemFlags::new(), header, slice, 0);
+    let offset = ctx.builder.ins().imul_imm(start, word_size as i64);
+    let storage = ctx.builder.ins().iadd(ptr, offset);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), storage, slice, word_size);
+    let length = ctx.builder.ins().isub(end, start);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), length, slice, 2 * word_size);
+    Ok(ctx.builder.ins().bor_imm(slice, VECTOR_TAG))
+}
+
+/// Emits a loop that runs BODY with every index from zero up to
+/// LENGTH, both untagged.
+pub(crate) fn emit_index_loop(
+    length: Value,
+    ctx: &mut Context,
+    mut body: impl FnMut(Value, &mut Context) -> Result<(), String>,
+) -> Result<(), String> {
+    let header_block = ctx.builder.create_block();
+    let body_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(header_block, ctx.word);
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(header_block, &[zero]);
+
+    ctx.builder.switch_to_block(header_block);
+    let i = ctx.builder.block_params(header_block)[0];
+    let done = ctx.builder.ins().icmp(IntCC::Equal, i, length);
+    ctx.builder.ins().brnz(done, done_block, &[]);
+    ctx.builder.ins().jump(body_block, &[]);
+
+    ctx.builder.switch_to_block(body_block);
+    ctx.builder.seal_block(body_block);
+    body(i, ctx)?;
+    let i = ctx.builder.ins().iadd_imm(i, 1);
+    ctx.builder.ins().jump(header_block, &[i]);
+    ctx.builder.seal_block(header_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(())
+}
+
+/// Emits the code to allocate storage for a vector of LENGTH
+/// elements and set its length. The elements are left for the caller
+/// to fill in.
+pub(crate) fn emit_alloc_vector(length: Value, ctx: &mut Context) -> Result<Value, String> {
+    let size = ctx.builder.ins().iadd_imm(length, 1);
+    let size = ctx.builder.ins().imul_imm(size, ctx.word.bytes() as i64);
+    let storage = emit_alloc_value(size, ctx)?;
+    ctx.builder.ins().store(MemFlags::new(), length, storage, 0);
+    Ok(storage)
+}
+
+fn emit_vector_map(f: Value, vector: Value, ctx: &mut Context) -> Result<Value, String> {
+    fatal::emit_check_closure(f, ctx)?;
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    let storage = emit_alloc_vector(length, ctx)?;
+
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        ctx.word.bytes(),
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+    let argc = ctx.builder.ins().iconst(ctx.word, 1);
+
+    emit_index_loop(length, ctx, |i, ctx| {
+        let address = emit_element_address(ptr, i, ctx);
+        let element = ctx
+            .builder
+            .ins()
+            .load(ctx.word, MemFlags::new(), address, 0);
+        ctx.builder.ins().store(MemFlags::new(), element, argloc, 0);
+        let res = crate::procedures::emit_closure_call(f, argc, argloc, ctx)?;
+        let address = emit_element_address(storage, i, ctx);
+        ctx.builder.ins().store(MemFlags::new(), res, address, 0);
+        Ok(())
+    })?;
+    Ok(ctx.builder.ins().bor_imm(storage, VECTOR_TAG))
+}
+
+/// Emits the code for vector-for-each, or vector-for-each-indexed if
+/// INDEXED is set, which passes F the index of each element before it.
+fn emit_vector_for_each(
+    f: Value,
+    vector: Value,
+    indexed: bool,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    fatal::emit_check_closure(f, ctx)?;
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+
+    let argc = if indexed { 2 } else { 1 };
+    let argloc = ctx.builder.create_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        argc * ctx.word.bytes(),
+    ));
+    let argloc = ctx.builder.ins().stack_addr(ctx.word, argloc, 0);
+    let argc = ctx.builder.ins().iconst(ctx.word, argc as i64);
+
+    emit_index_loop(length, ctx, |i, ctx| {
+        let address = emit_element_address(ptr, i, ctx);
+        let element = ctx
+            .builder
+            .ins()
+            .load(ctx.word, MemFlags::new(), address, 0);
+        let offset = if indexed {
+            let index = ctx.builder.ins().ishl_imm(i, FIXNUM_SHIFT);
+            ctx.builder.ins().store(MemFlags::new(), index, argloc, 0);
+            ctx.word.bytes() as i32
+        } else {
+            0
+        };
+        ctx.builder
+            .ins()
+            .store(MemFlags::new(), element, argloc, offset);
+        crate::procedures::emit_closure_call(f, argc, argloc, ctx)?;
+        Ok(())
+    })?;
+    Ok(ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep()))
+}
+
+fn emit_vector_copy(vector: Value, ctx: &mut Context) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    let storage = emit_alloc_vector(length, ctx)?;
+    emit_index_loop(length, ctx, |i, ctx| {
+        let address = emit_element_address(ptr, i, ctx);
+        let element = ctx
+            .builder
+            .ins()
+            .load(ctx.word, MemFlags::new(), address, 0);
+        let address = emit_element_address(storage, i, ctx);
+        ctx.builder
+            .ins()
+            .store(MemFlags::new(), element, address, 0);
+        Ok(())
+    })?;
+    Ok(ctx.builder.ins().bor_imm(storage, VECTOR_TAG))
+}
+
+fn emit_vector_fill(vector: Value, x: Value, ctx: &mut Context) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    emit_index_loop(length, ctx, |i, ctx| {
+        let address = emit_element_address(ptr, i, ctx);
+        ctx.builder.ins().store(MemFlags::new(), x, address, 0);
+        Ok(())
+    })?;
+    Ok(vector)
+}
+
+/// Emits the code to raise a type error if VAL isn't nil. Lists are
+/// walked until they run out of pairs and this makes sure they ended
+/// properly.
+pub(crate) fn emit_check_nil(val: Value, ctx: &mut Context) -> Result<(), String> {
+    let is_nil = ctx
+        .builder
+        .ins()
+        .icmp_imm(IntCC::Equal, val, Expr::Nil.immediate_rep());
+
+    let error_block = ctx.builder.create_block();
+    let ok_block = ctx.builder.create_block();
+
+    ctx.builder.ins().brz(is_nil, error_block, &[]);
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(error_block);
+    ctx.builder.seal_block(error_block);
+
+    fatal::emit_error(
+        &Expr::Symbol("__anon_data_bad_arg_type".to_string()),
+        &Expr::Integer(-1),
+        ctx,
+    )?;
+
+    ctx.builder.ins().jump(ok_block, &[]);
+
+    ctx.builder.switch_to_block(ok_block);
+    ctx.builder.seal_block(ok_block);
+    Ok(())
+}
+
+/// Emits the code to check if VAL is a pair, which may be a packed
+/// list if `CompileOptions::pack_lists` is set. See `packed.rs`.
+pub(crate) fn emit_is_pair(val: Value, ctx: &mut Context) -> Value {
+    let is_pair = emit_is(val, PAIR_TAG, HEAP_TAG_MASK, ctx);
+    if !ctx.options.pack_lists {
+        return is_pair;
+    }
+    let is_packed = emit_is(val, PACKED_LIST_TAG, PACKED_LIST_MASK, ctx);
+    ctx.builder.ins().bor(is_pair, is_packed)
+}
+
+/// Emits the code to load the car and cdr of the pair PAIR.
+pub(crate) fn emit_pair_parts(pair: Value, ctx: &mut Context) -> (Value, Value) {
+    if !ctx.options.pack_lists {
+        return emit_load_pair_parts(pair, ctx);
+    }
+    let heap_block = ctx.builder.create_block();
+    let packed_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+
+    let is_packed = emit_is(pair, PACKED_LIST_TAG, PACKED_LIST_MASK, ctx);
+    ctx.builder.ins().brnz(is_packed, packed_block, &[]);
+    ctx.builder.ins().jump(heap_block, &[]);
+
+    ctx.builder.switch_to_block(heap_block);
+    ctx.builder.seal_block(heap_block);
+    let (car, cdr) = emit_load_pair_parts(pair, ctx);
+    ctx.builder.ins().jump(done_block, &[car, cdr]);
+
+    ctx.builder.switch_to_block(packed_block);
+    ctx.builder.seal_block(packed_block);
+    let (car, cdr) = crate::packed::emit_packed_parts(pair, ctx);
+    ctx.builder.ins().jump(done_block, &[car, cdr]);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    let params = ctx.builder.block_params(done_block);
+    (params[0], params[1])
+}
+
+/// Emits the code to load the car and cdr of the pair on the heap
+/// PAIR.
+fn emit_load_pair_parts(pair: Value, ctx: &mut Context) -> (Value, Value) {
+    let ptr = ctx.builder.ins().band_imm(pair, HEAP_PTR_MASK);
+    let car = ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0);
+    let cdr = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, ctx.word.bytes() as i32);
+    (car, cdr)
+}
+
+/// Emits the code to load the car of the pair PAIR.
+pub(crate) fn emit_car(pair: Value, ctx: &mut Context) -> Value {
+    if ctx.options.pack_lists {
+        return emit_pair_parts(pair, ctx).0;
+    }
+    let ptr = ctx.builder.ins().band_imm(pair, HEAP_PTR_MASK);
+    ctx.builder.ins().load(ctx.word, MemFlags::new(), ptr, 0)
+}
+
+/// Emits the code to load the cdr of the pair PAIR.
+pub(crate) fn emit_cdr(pair: Value, ctx: &mut Context) -> Value {
+    if ctx.options.pack_lists {
+        return emit_pair_parts(pair, ctx).1;
+    }
+    let ptr = ctx.builder.ins().band_imm(pair, HEAP_PTR_MASK);
+    ctx.builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), ptr, ctx.word.bytes() as i32)
+}
+
+pub(crate) fn emit_list_to_vector(list: Value, ctx: &mut Context) -> Result<Value, String> {
+    // The list is walked twice. Once to find out how much space the
+    // vector needs and once to fill it in.
+    let count_block = ctx.builder.create_block();
+    let count_body = ctx.builder.create_block();
+    let counted_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(count_block, ctx.word);
+    ctx.builder.append_block_param(count_block, ctx.word);
+    ctx.builder.append_block_param(counted_block, ctx.word);
+    ctx.builder.append_block_param(counted_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(count_block, &[list, zero]);
+
+    ctx.builder.switch_to_block(count_block);
+    let rest = ctx.builder.block_params(count_block)[0];
+    let length = ctx.builder.block_params(count_block)[1];
+    let is_pair = emit_is_pair(rest, ctx);
+    ctx.builder
+        .ins()
+        .brz(is_pair, counted_block, &[rest, length]);
+    ctx.builder.ins().jump(count_body, &[]);
+
+    ctx.builder.switch_to_block(count_body);
+    ctx.builder.seal_block(count_body);
+    let (_, cdr) = emit_pair_parts(rest, ctx);
+    let length = ctx.builder.ins().iadd_imm(length, 1);
+    ctx.builder.ins().jump(count_block, &[cdr, length]);
+    ctx.builder.seal_block(count_block);
+
+    ctx.builder.switch_to_block(counted_block);
+    ctx.builder.seal_block(counted_block);
+    let end = ctx.builder.block_params(counted_block)[0];
+    let length = ctx.builder.block_params(counted_block)[1];
+    emit_check_nil(end, ctx)?;
+
+    let storage = emit_alloc_vector(length, ctx)?;
+
+    let fill_block = ctx.builder.create_block();
+    let fill_body = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(fill_block, ctx.word);
+    ctx.builder.append_block_param(fill_block, ctx.word);
+
+    let zero = ctx.builder.ins().iconst(ctx.word, 0);
+    ctx.builder.ins().jump(fill_block, &[list, zero]);
+
+    ctx.builder.switch_to_block(fill_block);
+    let rest = ctx.builder.block_params(fill_block)[0];
+    let i = ctx.builder.block_params(fill_block)[1];
+    let done = ctx.builder.ins().icmp(IntCC::Equal, i, length);
+    ctx.builder.ins().brnz(done, done_block, &[]);
+    ctx.builder.ins().jump(fill_body, &[]);
+
+    ctx.builder.switch_to_block(fill_body);
+    ctx.builder.seal_block(fill_body);
+    let (car, cdr) = emit_pair_parts(rest, ctx);
+    let address = emit_element_address(storage, i, ctx);
+    ctx.builder.ins().store(MemFlags::new(), car, address, 0);
+    let i = ctx.builder.ins().iadd_imm(i, 1);
+    ctx.builder.ins().jump(fill_block, &[cdr, i]);
+    ctx.builder.seal_block(fill_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.ins().bor_imm(storage, VECTOR_TAG))
+}
+
+fn emit_vector_to_list(vector: Value, ctx: &mut Context) -> Result<Value, String> {
+    let (ptr, length) = emit_vector_parts(vector, ctx)?;
+    emit_elements_to_list(ptr, length, ctx)
+}
+
+/// Emits the code to make a list of the LENGTH elements of the vector
+/// whose storage starts at PTR.
+pub(crate) fn emit_elements_to_list(
+    ptr: Value,
+    length: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    let nil = ctx
+        .builder
+        .ins()
+        .iconst(ctx.word, Expr::Nil.immediate_rep());
+    emit_elements_onto_list(ptr, length, nil, ctx)
+}
+
+/// Emits the code to make a list of the LENGTH elements of the vector
+/// whose storage starts at PTR followed by the elements of TAIL.
+pub(crate) fn emit_elements_onto_list(
+    ptr: Value,
+    length: Value,
+    tail: Value,
+    ctx: &mut Context,
+) -> Result<Value, String> {
+    // The list is built back to front so that every pair can point at
+    // the one after it.
+    let header_block = ctx.builder.create_block();
+    let body_block = ctx.builder.create_block();
+    let done_block = ctx.builder.create_block();
+    ctx.builder.append_block_param(header_block, ctx.word);
+    ctx.builder.append_block_param(header_block, ctx.word);
+    ctx.builder.append_block_param(done_block, ctx.word);
+    ctx.builder.ins().jump(header_block, &[length, tail]);
+
+    ctx.builder.switch_to_block(header_block);
+    let i = ctx.builder.block_params(header_block)[0];
+    let list = ctx.builder.block_params(header_block)[1];
+    ctx.builder.ins().brz(i, done_block, &[list]);
+    ctx.builder.ins().jump(body_block, &[]);
+
+    ctx.builder.switch_to_block(body_block);
+    ctx.builder.seal_block(body_block);
+    let i = ctx.builder.ins().iadd_imm(i, -1);
+    let address = emit_element_address(ptr, i, ctx);
+    let element = ctx
+        .builder
+        .ins()
+        .load(ctx.word, MemFlags::new(), address, 0);
+    let word_size = ctx.word.bytes() as i32;
+    let pair = emit_alloc((2 * word_size).into(), ctx)?;
+    ctx.builder.ins().store(MemFlags::new(), element, pair, 0);
+    ctx.builder
+        .ins()
+        .store(MemFlags::new(), list, pair, word_size);
+    let list = ctx.builder.ins().bor_imm(pair, PAIR_TAG);
+    ctx.builder.ins().jump(header_block, &[i, list]);
+    ctx.builder.seal_block(header_block);
+
+    ctx.builder.switch_to_block(done_block);
+    ctx.builder.seal_block(done_block);
+    Ok(ctx.builder.block_params(done_block)[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{check, error_kind};
+    use crate::{parse_string, roundtrip_string, Expr};
+
+    #[test]
+    fn round_trips() {
+        check(
+            "(vector->list (list->vector (quote (1 2 3))))",
+            "(quote (1 2 3))",
+        );
+        check(
+            "(list->vector (vector->list (list->vector (quote (a (b) \"c\")))))",
+            "(quote (a (b) \"c\"))",
+        );
+        check("(vector->list (list->vector ()))", "()");
+        check(
+            "(let v (list->vector ())) (cons (vector? v) (vector-length v))",
+            "(cons (eq 1 1) 0)",
+        );
+    }
+
+    #[test]
+    fn access() {
+        check(
+            r#"
+(let v (list->vector (quote (10 20 30))))
+(let get (fn (f v i) (f v i)))
+(cons (vector-ref v 0)
+      (cons (get vector-ref v 2)
+            (cons (vector-length v)
+                  (cons (vector? v) (vector? (quote (10 20 30)))))))
+"#,
+            "(cons 10 (cons 30 (cons 3 (cons (eq 1 1) (eq 1 2)))))",
+        );
+    }
+
+    #[test]
+    fn map_copy_fill() {
+        check(
+            "(vector->list (vector-map add1 (list->vector (quote (1 2 3)))))",
+            "(quote (2 3 4))",
+        );
+        check(
+            r#"
+(let v (list->vector (quote (1 2 3))))
+(let w (vector-copy v))
+(let filled (vector-fill! v 0))
+(cons (eq filled v) (cons (vector->list v) (vector->list w)))
+"#,
+            "(cons (eq 1 1) (cons (quote (0 0 0)) (quote (1 2 3))))",
+        );
+        check(
+            r#"
+(let get (fn (f a b) (f a b)))
+(let v (get vector-map (fn (x) (cons x x)) (list->vector (quote (1)))))
+(cons (vector-length (vector-copy (list->vector ()))) (vector->list v))
+"#,
+            "(cons 0 (cons (cons 1 1) ()))",
+        );
+    }
+
+    #[test]
+    fn make_and_set() {
+        check(
+            "(vector->list (make-vector 3 (quote x)))",
+            "(quote (x x x))",
+        );
+        check(
+            "(let v (make-vector 3)) (cons (vector-length v) (vector->list v))",
+            "(quote (3 () () ()))",
+        );
+        check("(vector-length (make-vector 0 1))", "0");
+        check(
+            r#"
+(let v (list->vector (quote (1 2 3))))
+(let w (vector-set! v 1 (quote b)))
+(vector-set! (vector-slice v 2 3) 0 (quote c))
+(cons (eq v w) (vector->list v))
+"#,
+            "(cons (eq 1 1) (quote (1 b c)))",
+        );
+        // The fill is shared between the elements.
+        check(
+            r#"
+(let inner (make-vector 1 0))
+(let outer (make-vector 2 inner))
+(vector-set! (vector-ref outer 0) 0 5)
+(cons (vector-ref (vector-ref outer 1) 0) (eq (vector-ref outer 0) (vector-ref outer 1)))
+"#,
+            "(cons 5 (eq 1 1))",
+        );
+        check(
+            r#"
+(let call1 (fn (f a) (f a)))
+(let call2 (fn (f a b) (f a b)))
+(let call3 (fn (f a b c) (f a b c)))
+(let v (call2 make-vector 2 1))
+(call3 vector-set! v 0 2)
+(cons (vector->list v) (vector->list (call1 make-vector 1)))
+"#,
+            "(quote ((2 1) ()))",
+        );
+    }
+
+    #[test]
+    fn for_each() {
+        check(
+            r#"
+(let v (list->vector (quote (10 20 30))))
+(let sum 0)
+(let res (vector-for-each-indexed (fn (i x) (set sum (add sum (mul i x)))) v))
+(cons res sum)
+"#,
+            "(cons () 80)",
+        );
+        // Elements are visited in order and the function can be any
+        // closure, including a primitive passed as a value.
+        check(
+            r#"
+(let seen ())
+(let get (fn (f a b) (f a b)))
+(get vector-for-each (fn (x) (set seen (cons x seen))) (list->vector (quote (1 2 3))))
+(let indices ())
+(vector-for-each-indexed (fn (i x) (set indices (cons i indices))) (vector-slice (list->vector (quote (a b c d))) 1 4))
+(vector-for-each println (list->vector ()))
+(cons seen indices)
+"#,
+            "(quote ((3 2 1) 2 1 0))",
+        );
+    }
+
+    #[test]
+    fn slices() {
+        let v = "(let v (list->vector (quote (1 2 3 4 5))))";
+        check(
+            &format!("{} (vector->list (vector-slice v 1 4))", v),
+            "(take (drop (quote (1 2 3 4 5)) 1) 3)",
+        );
+        check(
+            &format!(
+                "{} (let s (vector-slice v 1 4)) (cons (vector-length s) (cons (vector-ref s 0) (vector-ref s 2)))",
+                v
+            ),
+            "(cons 3 (cons 2 4))",
+        );
+        check(
+            &format!(
+                "{} (vector->list (vector-slice (vector-slice v 1 5) 2 4))",
+                v
+            ),
+            "(quote (4 5))",
+        );
+        check(
+            &format!(
+                "{} (vector->list (vector-map add1 (vector-slice v 3 5)))",
+                v
+            ),
+            "(quote (5 6))",
+        );
+        check(&format!("{} (vector-length (vector-slice v 5 5))", v), "0");
+        check(
+            &format!(
+                "{} (let f (fn (g) (g v 0 1))) (vector->list (f vector-slice))",
+                v
+            ),
+            "(quote (1))",
+        );
+        // A slice shares its elements with the vector it was made from.
+        check(
+            &format!(
+                "{} (vector-fill! (vector-slice v 1 3) 0) (cons (vector->list v) (vector->list (vector-copy (vector-slice v 0 2))))",
+                v
+            ),
+            "(cons (quote (1 0 0 4 5)) (quote (1 0)))",
+        );
+        // Slicing a vector allocates the slice and not its elements.
+        let vector = format!("(list->vector (quote ({})))", "1 ".repeat(100));
+        let source = format!(
+            "(let v {}) (let before (car (heap-stats))) (let s (vector-slice v 10 90)) (sub (car (heap-stats)) before)",
+            vector
+        );
+        let slice = 3 * std::mem::size_of::<crate::Word>() as i64;
+        assert_eq!(roundtrip_string(&source), Ok(Expr::Integer(slice)));
+    }
+
+    #[test]
+    fn errors() {
+        let v = "(let v (list->vector (quote (1 2))))";
+        assert_eq!(
+            error_kind(&format!("{} (vector-ref v 2)", v)),
+            "range-error"
+        );
+        assert_eq!(
+            error_kind(&format!("{} (vector-ref v -1)", v)),
+            "range-error"
+        );
+        assert_eq!(error_kind("(vector-length (quote (1 2)))"), "type-error");
+        assert_eq!(error_kind("(list->vector (cons 1 2))"), "type-error");
+        assert_eq!(error_kind("(vector-map add1 (quote (1)))"), "type-error");
+        assert_eq!(error_kind(&format!("{} (vector-map 1 v)", v)), "bad-call");
+        assert_eq!(
+            error_kind("(vector-for-each add1 (quote (1)))"),
+            "type-error"
+        );
+        assert_eq!(
+            error_kind(&format!("{} (vector-for-each 1 v)", v)),
+            "bad-call"
+        );
+        // The index is an argument like any other.
+        assert_eq!(
+            error_kind(&format!("{} (vector-for-each-indexed add1 v)", v)),
+            "arity-error"
+        );
+        assert_eq!(error_kind("(vector-fill! () 1)"), "type-error");
+        assert_eq!(
+            error_kind(&format!("{} (vector-set! v 2 0)", v)),
+            "range-error"
+        );
+        assert_eq!(error_kind("(vector-set! (quote (1)) 0 0)"), "type-error");
+        assert_eq!(error_kind("(make-vector (sub 0 1))"), "range-error");
+        assert_eq!(error_kind("(make-vector (quote a) 1)"), "type-error");
+        let apply = "(let f (fn (g & args) (apply g args)))";
+        assert_eq!(
+            error_kind(&format!("{} (f make-vector)", apply)),
+            "arity-error"
+        );
+        assert_eq!(
+            error_kind(&format!("{} (f make-vector 1 2 3)", apply)),
+            "arity-error"
+        );
+        assert_eq!(
+            roundtrip_string("(make-vector 1 2 3)"),
+            Err("make-vector expected 1 or 2 args and got 3".to_string())
+        );
+        assert_eq!(error_kind("(vector-copy 1)"), "type-error");
+        assert_eq!(
+            error_kind(&format!("{} (vector-slice v 1 3)", v)),
+            "range-error"
+        );
+        assert_eq!(
+            error_kind(&format!("{} (vector-slice v 2 1)", v)),
+            "range-error"
+        );
+        assert_eq!(
+            error_kind(&format!("{} (vector-slice v -1 1)", v)),
+            "range-error"
+        );
+        assert_eq!(
+            error_kind(&format!("{} (vector-ref (vector-slice v 0 1) 1)", v)),
+            "range-error"
+        );
+        assert_eq!(error_kind("(vector-slice (quote (1 2)) 0 1)"), "type-error");
+
+        let source = format!("{} (vector-ref v 2)", v);
+        assert!(crate::interpreter::interpret(&parse_string(&source).unwrap()).is_err());
+        assert_eq!(
+            roundtrip_string(&format!("{} (vector-ref v 1)", v)),
+            Ok(Expr::Integer(2))
+        );
+    }
+}